@@ -0,0 +1,376 @@
+// Copyright (c) 2024 DDN. All rights reserved.
+// Use of this source code is governed by a MIT-style
+// license that can be found in the LICENSE file.
+
+use anyhow::{anyhow, Result};
+use lopdf::{Dictionary, Document, Object, ObjectId, Stream, StringFormat};
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap},
+    fs,
+    hash::{Hash, Hasher},
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+// Bump whenever the on-disk layout changes so stale caches are re-parsed.
+const CACHE_MAGIC: &[u8] = b"VPPDFOC";
+const CACHE_VERSION: u32 = 1;
+
+/// Cache hit/miss counters reported in the merge summary
+#[derive(Debug, Default)]
+pub struct CacheStats {
+    pub hits: usize,
+    pub misses: usize,
+}
+
+/// On-disk cache of parsed PDF objects.
+///
+/// Parsing hundreds of Chrome generated PDFs dominates `--merge_only` runs, so
+/// we store the parsed objects of each source PDF in a compact binary form keyed
+/// by the source path, size and mtime. Any mismatch, corruption or version change
+/// is treated as a miss and the PDF is parsed again.
+pub struct MergeCache {
+    dir: PathBuf,
+    pub stats: CacheStats,
+}
+
+// Identifies the exact source PDF a cache entry was generated from.
+#[derive(Debug, PartialEq)]
+struct CacheKey {
+    path: String,
+    size: u64,
+    mtime_secs: u64,
+    mtime_nanos: u32,
+}
+
+impl CacheKey {
+    fn new(path: &Path) -> Result<Self> {
+        let meta = fs::metadata(path)?;
+        let mtime = meta.modified()?.duration_since(UNIX_EPOCH)?;
+        Ok(CacheKey {
+            path: path.canonicalize()?.to_string_lossy().to_string(),
+            size: meta.len(),
+            mtime_secs: mtime.as_secs(),
+            mtime_nanos: mtime.subsec_nanos(),
+        })
+    }
+}
+
+impl MergeCache {
+    pub fn new(dir: &Path) -> Result<Self> {
+        fs::create_dir_all(dir)?;
+        Ok(MergeCache {
+            dir: dir.to_path_buf(),
+            stats: CacheStats::default(),
+        })
+    }
+
+    fn entry_path(&self, key: &CacheKey) -> PathBuf {
+        let mut hasher = DefaultHasher::new();
+        key.path.hash(&mut hasher);
+        self.dir.join(format!("{:016x}.cache", hasher.finish()))
+    }
+
+    /// Load `path` from the cache, falling back to parsing the PDF and refreshing the cache.
+    pub fn load(&mut self, path: &Path) -> Result<Document> {
+        let key = CacheKey::new(path)?;
+        let entry = self.entry_path(&key);
+
+        if let Ok(doc) = fs::read(&entry)
+            .map_err(Into::into)
+            .and_then(|b| decode(&b, &key))
+        {
+            self.stats.hits += 1;
+            return Ok(doc);
+        }
+
+        self.stats.misses += 1;
+        let doc = Document::load(path)?;
+        if let Err(e) = fs::write(&entry, encode(&doc, &key)) {
+            eprintln!("Unable to write merge cache {}: {e}", entry.display());
+        }
+        Ok(doc)
+    }
+}
+
+fn encode(doc: &Document, key: &CacheKey) -> Vec<u8> {
+    let mut w = Writer(Vec::new());
+    w.0.extend_from_slice(CACHE_MAGIC);
+    w.u32(CACHE_VERSION);
+    w.bytes(env!("CARGO_PKG_VERSION").as_bytes());
+    w.bytes(key.path.as_bytes());
+    w.u64(key.size);
+    w.u64(key.mtime_secs);
+    w.u32(key.mtime_nanos);
+    w.bytes(doc.version.as_bytes());
+    w.u32(doc.max_id);
+    w.dictionary(&doc.trailer);
+    w.u64(doc.objects.len() as u64);
+    for ((id, gen), object) in &doc.objects {
+        w.u32(*id);
+        w.u16(*gen);
+        w.object(object);
+    }
+    w.0
+}
+
+fn decode(bytes: &[u8], key: &CacheKey) -> Result<Document> {
+    let mut r = Reader { bytes, pos: 0 };
+    if r.take(CACHE_MAGIC.len())? != CACHE_MAGIC
+        || r.u32()? != CACHE_VERSION
+        || r.take_bytes()? != env!("CARGO_PKG_VERSION").as_bytes()
+    {
+        return Err(anyhow!("Merge cache version mismatch"));
+    }
+    let cached = CacheKey {
+        path: String::from_utf8(r.take_bytes()?.to_vec())?,
+        size: r.u64()?,
+        mtime_secs: r.u64()?,
+        mtime_nanos: r.u32()?,
+    };
+    if &cached != key {
+        return Err(anyhow!("Merge cache entry is stale"));
+    }
+
+    let mut doc = Document::with_version(String::from_utf8(r.take_bytes()?.to_vec())?);
+    doc.max_id = r.u32()?;
+    doc.trailer = r.dictionary()?;
+
+    let mut objects: BTreeMap<ObjectId, Object> = BTreeMap::new();
+    for _ in 0..r.u64()? {
+        let id = (r.u32()?, r.u16()?);
+        objects.insert(id, r.object()?);
+    }
+    doc.objects = objects;
+
+    if r.pos != bytes.len() {
+        return Err(anyhow!("Trailing data in merge cache entry"));
+    }
+    Ok(doc)
+}
+
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn u16(&mut self, v: u16) {
+        self.0.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn u32(&mut self, v: u32) {
+        self.0.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn u64(&mut self, v: u64) {
+        self.0.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn bytes(&mut self, v: &[u8]) {
+        self.u64(v.len() as u64);
+        self.0.extend_from_slice(v);
+    }
+
+    fn dictionary(&mut self, dict: &Dictionary) {
+        self.u64(dict.len() as u64);
+        for (k, v) in dict.iter() {
+            self.bytes(k);
+            self.object(v);
+        }
+    }
+
+    fn object(&mut self, object: &Object) {
+        match object {
+            Object::Null => self.0.push(0),
+            Object::Boolean(b) => {
+                self.0.push(1);
+                self.0.push(*b as u8);
+            }
+            Object::Integer(i) => {
+                self.0.push(2);
+                self.0.extend_from_slice(&i.to_le_bytes());
+            }
+            Object::Real(_) => {
+                self.0.push(3);
+                self.u32(object.as_float().unwrap_or_default().to_bits());
+            }
+            Object::Name(n) => {
+                self.0.push(4);
+                self.bytes(n);
+            }
+            Object::String(s, format) => {
+                self.0.push(5);
+                self.0.push(match format {
+                    StringFormat::Literal => 0,
+                    StringFormat::Hexadecimal => 1,
+                });
+                self.bytes(s);
+            }
+            Object::Array(a) => {
+                self.0.push(6);
+                self.u64(a.len() as u64);
+                a.iter().for_each(|o| self.object(o));
+            }
+            Object::Dictionary(d) => {
+                self.0.push(7);
+                self.dictionary(d);
+            }
+            Object::Stream(s) => {
+                self.0.push(8);
+                self.0.push(s.allows_compression as u8);
+                self.dictionary(&s.dict);
+                self.bytes(&s.content);
+            }
+            Object::Reference((id, gen)) => {
+                self.0.push(9);
+                self.u32(*id);
+                self.u16(*gen);
+            }
+        }
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|end| *end <= self.bytes.len())
+            .ok_or(anyhow!("Truncated merge cache entry"))?;
+        let slice = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(slice)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into()?))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into()?))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into()?))
+    }
+
+    fn take_bytes(&mut self) -> Result<&'a [u8]> {
+        let len = usize::try_from(self.u64()?)?;
+        self.take(len)
+    }
+
+    fn dictionary(&mut self) -> Result<Dictionary> {
+        let mut dict = Dictionary::new();
+        for _ in 0..self.u64()? {
+            let key = self.take_bytes()?.to_vec();
+            dict.set(key, self.object()?);
+        }
+        Ok(dict)
+    }
+
+    fn object(&mut self) -> Result<Object> {
+        Ok(match self.u8()? {
+            0 => Object::Null,
+            1 => Object::Boolean(self.u8()? != 0),
+            2 => Object::Integer(i64::from_le_bytes(self.take(8)?.try_into()?)),
+            3 => Object::Real(f32::from_bits(self.u32()?).into()),
+            4 => Object::Name(self.take_bytes()?.to_vec()),
+            5 => {
+                let format = match self.u8()? {
+                    0 => StringFormat::Literal,
+                    _ => StringFormat::Hexadecimal,
+                };
+                Object::String(self.take_bytes()?.to_vec(), format)
+            }
+            6 => {
+                let len = self.u64()?;
+                let mut array = vec![];
+                for _ in 0..len {
+                    array.push(self.object()?);
+                }
+                Object::Array(array)
+            }
+            7 => Object::Dictionary(self.dictionary()?),
+            8 => {
+                let allows_compression = self.u8()? != 0;
+                let dict = self.dictionary()?;
+                let mut stream = Stream::new(dict, self.take_bytes()?.to_vec());
+                stream.allows_compression = allows_compression;
+                Object::Stream(stream)
+            }
+            9 => Object::Reference((self.u32()?, self.u16()?)),
+            t => return Err(anyhow!("Unknown object tag {t} in merge cache entry")),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lopdf::dictionary;
+    use tempfile::tempdir;
+
+    fn generate_pdf() -> Document {
+        let mut doc = Document::with_version("1.5");
+        let pages_id = doc.new_object_id();
+        let content_id = doc.add_object(Stream::new(dictionary! {}, b"BT ET".to_vec()));
+        let page_id = doc.add_object(dictionary! {
+            "Type" => "Page",
+            "Parent" => pages_id,
+            "Contents" => content_id,
+            "MediaBox" => vec![0.into(), 0.into(), 595.5.into(), 842.into()],
+            "Title" => Object::string_literal("Hello"),
+        });
+        doc.objects.insert(
+            pages_id,
+            Object::Dictionary(dictionary! {
+                "Type" => "Pages",
+                "Kids" => vec![page_id.into()],
+                "Count" => 1,
+            }),
+        );
+        let catalog_id = doc.add_object(dictionary! {
+            "Type" => "Catalog",
+            "Pages" => pages_id,
+        });
+        doc.trailer.set("Root", catalog_id);
+        doc
+    }
+
+    #[test]
+    fn test_cache_hit_and_corruption() {
+        let dir = tempdir().unwrap();
+        let pdf = dir.path().join("0.pdf");
+        generate_pdf().save(&pdf).unwrap();
+
+        let mut cache = MergeCache::new(&dir.path().join("cache")).unwrap();
+        let parsed = cache.load(&pdf).unwrap();
+        let cached = cache.load(&pdf).unwrap();
+
+        assert_eq!(cache.stats.misses, 1);
+        assert_eq!(cache.stats.hits, 1);
+        assert_eq!(
+            format!("{:?}", parsed.objects),
+            format!("{:?}", cached.objects)
+        );
+        assert_eq!(cached.get_pages().len(), 1);
+
+        // A corrupt entry is transparently re-parsed
+        let entry = cache.entry_path(&CacheKey::new(&pdf).unwrap());
+        fs::write(&entry, b"VPPDFOC garbage").unwrap();
+        let reparsed = cache.load(&pdf).unwrap();
+
+        assert_eq!(cache.stats.misses, 2);
+        assert_eq!(
+            format!("{:?}", parsed.objects),
+            format!("{:?}", reparsed.objects)
+        );
+    }
+}
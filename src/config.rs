@@ -1,11 +1,15 @@
 // Copyright (c) 2024 DDN. All rights reserved.
 // Use of this source code is governed by a MIT-style
 // license that can be found in the LICENSE file.
+use crate::{font::Sfnt, progress::ProgressMode};
 use anyhow::{anyhow, Result};
+#[cfg(feature = "render")]
 use headless_chrome::types::PrintToPdfOptions;
-use indexmap::{indexset, set::IndexSet};
+use indexmap::{set::IndexSet, IndexMap};
+use quick_xml::{events::Event as XmlEvent, Reader};
+use regex::Regex;
 use serde::Deserialize;
-use std::{fs, path::PathBuf};
+use std::{fs, path::PathBuf, process::Command, time::Duration};
 
 // Represents the whole file. Used because if`Config` was the top level struct
 // Deserialization fails if you put variables after the `pdf_options` map.
@@ -14,6 +18,20 @@ struct ConfigFile {
     pub config: Config,
 }
 
+// Just the `print_to_pdf` table of the file, which `overrides` are merged into
+#[cfg(feature = "render")]
+#[derive(Deserialize)]
+struct PrintToPdfFile {
+    config: PrintToPdfTable,
+}
+
+#[cfg(feature = "render")]
+#[derive(Deserialize)]
+struct PrintToPdfTable {
+    #[serde(default)]
+    print_to_pdf: toml::Table,
+}
+
 /// Page Number Color
 #[derive(Debug, Deserialize)]
 pub struct Color {
@@ -49,118 +67,3241 @@ impl Color {
 pub struct PageNumber {
     /// Font Color
     pub color: Color,
-    /// Font Name
+    /// Font Name, one of the standard Type 1 fonts
+    #[serde(default = "default_page_number_font")]
     pub font: String,
+    /// TrueType font embedded and used instead of `font`
+    pub font_file: Option<PathBuf>,
     /// Font size
     pub size: i16,
-    /// Page Number X offset (in inches) from the top left corner
+    /// Where the page number goes across the page
+    #[serde(default)]
+    pub align: Align,
+    /// Page Number X offset (in inches) from the left edge, centre or right edge depending on `align`
+    #[serde(default)]
     pub x: f64,
-    /// Page Number Y offset (in inches) from the top left corner
+    /// Page Number Y offset (in inches) of the baseline from the edge given by `y_from`
     pub y: f64,
+    /// Which edge of the page `y` is measured from
+    #[serde(default)]
+    pub y_from: YFrom,
+    /// Text printed with `{page}` and `{total}` replaced
+    #[serde(default = "default_page_number_format")]
+    pub format: String,
+    /// Number of pages after any cover numbered in `front_matter_style`, 1 is the page after them
+    #[serde(default)]
+    pub front_matter_pages: usize,
+    /// How the front matter pages are numbered
+    #[serde(default)]
+    pub front_matter_style: FrontMatterStyle,
+}
+
+/// Horizontal alignment of the page number, measured against each page's own width
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Align {
+    /// `x` is the offset of the start of the number from the left edge
+    #[default]
+    Left,
+    /// `x` moves the centred number right, or left when negative
+    Center,
+    /// `x` is the offset of the end of the number in from the right edge
+    Right,
+}
+
+/// Edge of the page the page number's `y` offset is measured from
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum YFrom {
+    /// Down from the top edge
+    #[default]
+    Top,
+    /// Up from the bottom edge, so the number keeps its distance from it on any paper size
+    Bottom,
+}
+
+/// How front matter pages are numbered
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum FrontMatterStyle {
+    /// Lowercase roman numerals, i, ii, iii
+    #[default]
+    Roman,
+    /// Not numbered
+    None,
+}
+
+// Lowercase roman numeral of `n`, which must be greater than 0
+fn roman(mut n: usize) -> String {
+    const NUMERALS: [(usize, &str); 13] = [
+        (1000, "m"),
+        (900, "cm"),
+        (500, "d"),
+        (400, "cd"),
+        (100, "c"),
+        (90, "xc"),
+        (50, "l"),
+        (40, "xl"),
+        (10, "x"),
+        (9, "ix"),
+        (5, "v"),
+        (4, "iv"),
+        (1, "i"),
+    ];
+    let mut numeral = String::new();
+    for (value, digits) in NUMERALS {
+        while n >= value {
+            numeral.push_str(digits);
+            n -= value;
+        }
+    }
+    numeral
+}
+
+fn default_page_number_font() -> String {
+    "Helvetica".to_string()
+}
+
+fn default_page_number_format() -> String {
+    "Page {page}".to_string()
+}
+
+const TYPE1_FONTS: [&str; 12] = [
+    "Times−Roman",
+    "Times−Bold",
+    "Times−Italic",
+    "Times−BoldItalic",
+    "Helvetica",
+    "Helvetica−Bold",
+    "Helvetica−Oblique",
+    "Helvetica−BoldOblique",
+    "Courier",
+    "Courier−Bold",
+    "Courier−Oblique",
+    "Courier−BoldOblique",
+];
+
+fn check_type1_font(font: &str) -> Result<()> {
+    if !TYPE1_FONTS.contains(&font) {
+        return Err(anyhow!(
+            "Invalid font name {font}. Only PDF Type 1 Fonts are supported"
+        ));
+    }
+    Ok(())
+}
+
+// Check the `font_file` of the `option` table, or `font` if there's none
+fn check_font(option: &str, font: &str, font_file: Option<&PathBuf>) -> Result<()> {
+    match font_file {
+        Some(path) => {
+            let data = fs::read(path).map_err(|e| {
+                anyhow!("Unable to read {option}.font_file {}: {e}", path.display())
+            })?;
+            let font = Sfnt::parse(data).map_err(|e| {
+                anyhow!(
+                    "{option}.font_file {} is not a usable TrueType font: {e}",
+                    path.display()
+                )
+            })?;
+            if font.is_cff {
+                return Err(anyhow!(
+                    "{option}.font_file {} has CFF outlines, only TrueType fonts are supported",
+                    path.display()
+                ));
+            }
+            Ok(())
+        }
+        None => check_type1_font(font),
+    }
+}
+
+// Check the `format` of the `option` table only uses the `supported` placeholders
+fn check_placeholders(option: &str, format: &str, supported: &[&str]) -> Result<()> {
+    let re = Regex::new(r"\{([^}]*)\}")?;
+    let unknown: Vec<&str> = re
+        .captures_iter(format)
+        .map(|caps| caps.get(1).unwrap().as_str())
+        .filter(|name| !supported.contains(name))
+        .collect();
+    if !unknown.is_empty() {
+        return Err(anyhow!(
+            "Unknown placeholder(s) {} in {option}.format, only {} are supported",
+            unknown.join(","),
+            supported
+                .iter()
+                .map(|name| format!("{{{name}}}"))
+                .collect::<Vec<_>>()
+                .join(" and ")
+        ));
+    }
+    Ok(())
 }
 
 impl PageNumber {
     fn valid(&self) -> Result<()> {
         self.color.valid()?;
-        let type1_fonts = [
-            "Times−Roman",
-            "Times−Bold",
-            "Times−Italic",
-            "Times−BoldItalic",
-            "Helvetica",
-            "Helvetica−Bold",
-            "Helvetica−Oblique",
-            "Helvetica−BoldOblique",
-            "Courier",
-            "Courier−Bold",
-            "Courier−Oblique",
-            "Courier−BoldOblique",
-        ];
-        if !type1_fonts.contains(&self.font.as_str()) {
-            return Err(anyhow!(
-                "Invalid font name {}. Only PDF Type 1 Fonts are supported",
-                self.font
-            ));
+        check_font("page_number", &self.font, self.font_file.as_ref())?;
+        check_placeholders("page_number", &self.format, &["page", "total"])
+    }
+
+    /// Page number of the `n`th numbered page, counting from 1, if it has one
+    pub fn label(&self, n: usize) -> Option<String> {
+        match n.checked_sub(self.front_matter_pages) {
+            Some(page) if page > 0 => Some(page.to_string()),
+            _ => match self.front_matter_style {
+                FrontMatterStyle::Roman => Some(roman(n)),
+                FrontMatterStyle::None => None,
+            },
+        }
+    }
+
+    /// The text printed on the `n`th numbered page, `total` is the number of pages after the front matter
+    pub fn text(&self, n: usize, total: usize) -> Option<String> {
+        self.label(n).map(|page| {
+            self.format
+                .replace("{page}", &page)
+                .replace("{total}", &total.to_string())
+        })
+    }
+}
+
+/// Title of the section each page belongs to, printed at the top of its pages
+#[derive(Debug, Deserialize)]
+pub struct RunningHeader {
+    /// Font Color
+    pub color: Color,
+    /// Font Name, one of the standard Type 1 fonts
+    #[serde(default = "default_page_number_font")]
+    pub font: String,
+    /// TrueType font embedded and used instead of `font`
+    pub font_file: Option<PathBuf>,
+    /// Font size
+    pub size: i16,
+    /// Where the header goes across the page
+    #[serde(default)]
+    pub align: Align,
+    /// X offset (in inches) from the left edge, centre or right edge depending on `align`
+    #[serde(default)]
+    pub x: f64,
+    /// Y offset (in inches) of the baseline from the edge given by `y_from`
+    pub y: f64,
+    /// Which edge of the page `y` is measured from
+    #[serde(default)]
+    pub y_from: YFrom,
+    /// Text printed with `{title}` and `{chapter}` replaced
+    #[serde(default = "default_running_header_format")]
+    pub format: String,
+    /// Swap `left` and `right` alignment on even pages, so the header stays on the outer edge
+    #[serde(default)]
+    pub mirror: bool,
+}
+
+fn default_running_header_format() -> String {
+    "{title}".to_string()
+}
+
+impl RunningHeader {
+    fn valid(&self) -> Result<()> {
+        self.color.valid()?;
+        check_font("running_header", &self.font, self.font_file.as_ref())?;
+        check_placeholders("running_header", &self.format, &["title", "chapter"])
+    }
+
+    /// The text printed on a page of the section `title`, in the chapter titled `chapter`
+    pub fn text(&self, title: &str, chapter: &str) -> String {
+        // In one pass, so placeholders in the titles themselves are printed as they are
+        let re = Regex::new(r"\{(title|chapter)\}").unwrap();
+        re.replace_all(&self.format, |caps: &regex::Captures| match &caps[1] {
+            "title" => title.to_string(),
+            _ => chapter.to_string(),
+        })
+        .into_owned()
+    }
+
+    /// Alignment on the `n`th page of the PDF, counting from 1
+    pub fn align(&self, n: usize) -> Align {
+        match (self.mirror && n % 2 == 0, self.align) {
+            (true, Align::Left) => Align::Right,
+            (true, Align::Right) => Align::Left,
+            (_, align) => align,
+        }
+    }
+}
+
+/// A PDF appended after the rendered pages
+#[derive(Debug, Deserialize)]
+pub struct BackMatter {
+    pub path: PathBuf,
+    /// Bookmark title, the file name without its extension if not set
+    pub title: Option<String>,
+    /// Add a top level bookmark pointing at the first page
+    #[serde(default = "default_back_matter_bookmark")]
+    pub bookmark: bool,
+}
+
+fn default_back_matter_bookmark() -> bool {
+    true
+}
+
+impl BackMatter {
+    /// Title of the bookmark pointing at this PDF
+    pub fn title(&self) -> String {
+        self.title.clone().unwrap_or_else(|| {
+            self.path
+                .file_stem()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .to_string()
+        })
+    }
+}
+
+/// What fills the gap between a table of contents entry and its page number
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Leader {
+    #[default]
+    Dots,
+    Dashes,
+    None,
+}
+
+/// Document information of the merged PDF
+#[derive(Debug, Default, Deserialize)]
+pub struct Metadata {
+    /// Document title, may contain variables like `output_pdf`
+    pub title: Option<String>,
+    /// Document author, may contain variables like `output_pdf`
+    pub author: Option<String>,
+}
+
+/// Downsampling of images in the merged PDF
+#[derive(Debug, Deserialize)]
+pub struct OptimizeImages {
+    /// Images drawn at a higher resolution are downsampled to this
+    #[serde(default = "default_optimize_images_max_dpi")]
+    pub max_dpi: f64,
+    /// JPEG quality between 1 and 100
+    #[serde(default = "default_optimize_images_quality")]
+    pub quality: u8,
+    /// Also downsample images with a soft mask, the mask itself is left as it is
+    #[serde(default)]
+    pub transparent: bool,
+}
+
+fn default_optimize_images_max_dpi() -> f64 {
+    150.0
+}
+
+fn default_optimize_images_quality() -> u8 {
+    80
+}
+
+impl OptimizeImages {
+    fn valid(&self) -> Result<()> {
+        if self.max_dpi <= 0.0 {
+            return Err(anyhow!("optimize_images.max_dpi must be greater than 0"));
+        }
+        if !(1..=100).contains(&self.quality) {
+            return Err(anyhow!("optimize_images.quality must be between 1 and 100"));
         }
         Ok(())
     }
 }
 
-/// We expect `vitepress-pdf-export` to be run as part of a CI actions so all options
-/// are handled by a TOML configuration file.
+/// Text or image stamped on every page of the merged PDF
 #[derive(Debug, Deserialize)]
-pub struct Config {
-    /// Where to download Chrome builds to
-    #[serde(default = "default_cache_path")]
-    pub chrome_cache: PathBuf,
-    /// Pin Chrome to a specific revision, e.g. `1336641`. If unset we use that latest known good build.
-    pub chrome_version: Option<String>,
-    /// The merged PDF file  
-    pub output_pdf: PathBuf,
-    /// `VitePress` Dev URL e.g., `http://localhost:5173``.
-    pub url: String,
-    /// The list of URLS generated from `url` and `vitepress_links`.
+pub struct Watermark {
+    /// Text centred on each page, e.g. `DRAFT`
+    pub text: Option<String>,
+    /// Font Name of `text`, one of the standard Type 1 fonts
+    #[serde(default = "default_page_number_font")]
+    pub font: String,
+    /// Font size of `text`
+    #[serde(default = "default_watermark_size")]
+    pub size: f64,
+    /// Colour of `text`
+    #[serde(default = "default_watermark_color")]
+    pub color: Color,
+    /// Counterclockwise rotation of `text` in degrees
+    #[serde(default = "default_watermark_rotation")]
+    pub rotation: f64,
+    /// PNG or JPEG image stamped instead of `text`, e.g. a logo
+    pub image: Option<PathBuf>,
+    /// Width of `image` as a fraction of each page's width
+    #[serde(default = "default_watermark_scale")]
+    pub scale: f64,
+    /// Where `image` goes on each page
+    #[serde(default)]
+    pub position: WatermarkPosition,
+    /// Opacity between 0 and 1
+    #[serde(default = "default_watermark_opacity")]
+    pub opacity: f64,
+    /// Stamp the watermark under the page content instead of over it
+    #[serde(default)]
+    pub under: bool,
+}
+
+/// Where an image watermark is placed, the corners are half an inch in from the edges
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum WatermarkPosition {
+    #[default]
+    Center,
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+}
+
+fn default_watermark_size() -> f64 {
+    72.0
+}
+
+fn default_watermark_color() -> Color {
+    Color {
+        r: 0.5,
+        g: 0.5,
+        b: 0.5,
+    }
+}
+
+fn default_watermark_rotation() -> f64 {
+    45.0
+}
+
+fn default_watermark_scale() -> f64 {
+    0.5
+}
+
+fn default_watermark_opacity() -> f64 {
+    0.3
+}
+
+impl Watermark {
+    fn valid(&self) -> Result<()> {
+        match (&self.text, &self.image) {
+            (Some(_), None) => {
+                self.color.valid()?;
+                check_type1_font(&self.font)?;
+                if self.size <= 0.0 {
+                    return Err(anyhow!("watermark.size must be greater than 0"));
+                }
+            }
+            (None, Some(image)) => {
+                if !image.is_file() {
+                    return Err(anyhow!("watermark.image {} doesn't exist", image.display()));
+                }
+                if self.scale <= 0.0 || self.scale > 1.0 {
+                    return Err(anyhow!(
+                        "watermark.scale must be greater than 0 and at most 1"
+                    ));
+                }
+            }
+            _ => {
+                return Err(anyhow!(
+                    "Exactly one of watermark.text or watermark.image must be set"
+                ))
+            }
+        }
+        if !(0.0..=1.0).contains(&self.opacity) {
+            return Err(anyhow!("watermark.opacity must be between 0 and 1"));
+        }
+        Ok(())
+    }
+}
+
+/// Password protection of the merged PDF
+#[derive(Debug, Deserialize)]
+pub struct Encryption {
+    /// Password needed to open the PDF, empty opens it without one
+    #[serde(default)]
+    pub user_password: String,
+    /// Password lifting the restrictions below
+    pub owner_password: String,
+    #[serde(default = "default_encryption_allow")]
+    pub allow_print: bool,
+    #[serde(default = "default_encryption_allow")]
+    pub allow_copy: bool,
+    /// Editing, annotating, filling forms and assembling pages
+    #[serde(default = "default_encryption_allow")]
+    pub allow_modify: bool,
+}
+
+fn default_encryption_allow() -> bool {
+    true
+}
+
+impl Encryption {
+    fn valid(&self) -> Result<()> {
+        if self.owner_password.is_empty() {
+            return Err(anyhow!("encryption.owner_password must not be empty"));
+        }
+        // The standard security handler pads or truncates passwords to 32 bytes of PDFDocEncoding
+        for (name, password) in [
+            ("user_password", &self.user_password),
+            ("owner_password", &self.owner_password),
+        ] {
+            if !password.is_ascii() || password.len() > 32 {
+                return Err(anyhow!(
+                    "encryption.{name} must be at most 32 ASCII characters"
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Table of contents pages generated from the merged outline
+#[derive(Debug, Deserialize)]
+pub struct Toc {
+    /// Heading printed above the entries
+    #[serde(default = "default_toc_title")]
+    pub title: String,
+    /// Deepest outline level listed, top level bookmarks are level 1
+    #[serde(default = "default_toc_depth")]
+    pub depth: usize,
+    /// Font Name
+    #[serde(default = "default_toc_font")]
+    pub font: String,
+    /// Font size of the entries, the title is 1.5 times larger
+    #[serde(default = "default_toc_size")]
+    pub size: f64,
+    #[serde(default)]
+    pub leader: Leader,
+}
+
+fn default_toc_title() -> String {
+    "Contents".to_string()
+}
+
+fn default_toc_depth() -> usize {
+    2
+}
+
+fn default_toc_font() -> String {
+    "Helvetica".to_string()
+}
+
+fn default_toc_size() -> f64 {
+    11.0
+}
+
+impl Toc {
+    fn valid(&self) -> Result<()> {
+        if self.depth == 0 {
+            return Err(anyhow!("toc.depth must be greater than 0"));
+        }
+        if self.size <= 0.0 {
+            return Err(anyhow!("toc.size must be greater than 0"));
+        }
+        check_type1_font(&self.font)
+    }
+}
+
+/// Render matching URLs at the first scale that doesn't overflow the page width
+#[derive(Debug, Deserialize)]
+pub struct AutoScale {
+    /// Regular expressions matched against each URL
+    pub patterns: Vec<String>,
+    /// Scales to try, in descending order
+    pub scales: Vec<f64>,
     #[serde(skip)]
-    pub urls: IndexSet<String>,
-    /// List of paths to JSON files that define the `VitePress` site.
-    pub vitepress_links: Vec<PathBuf>,
-    /// Page Number Style - if not defined page numbers will not be inserted.
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub page_number: Option<PageNumber>,
-    /// PDF Generation options see [Chrome DevTool Protocol](https://chromedevtools.github.io/devtools-protocol/tot/Page/#method-printToPDF) for documentation.
-    pub print_to_pdf: PrintToPdfOptions,
+    regexes: Vec<Regex>,
 }
 
-fn default_cache_path() -> PathBuf {
-    PathBuf::from("/tmp")
+impl AutoScale {
+    fn compile(&mut self) -> Result<()> {
+        if self.scales.is_empty() {
+            return Err(anyhow!("auto_scale.scales must not be empty"));
+        }
+        // Chrome only accepts scales between 0.1 and 2
+        if let Some(scale) = self.scales.iter().find(|s| !(0.1..=2.0).contains(*s)) {
+            return Err(anyhow!(
+                "Invalid auto_scale scale {scale}. Scales must be between 0.1 and 2.0"
+            ));
+        }
+        if self.scales.windows(2).any(|w| w[0] <= w[1]) {
+            return Err(anyhow!("auto_scale.scales must be in descending order"));
+        }
+        self.regexes = self
+            .patterns
+            .iter()
+            .map(|p| Regex::new(p).map_err(|e| anyhow!("Invalid auto_scale pattern {p}: {e}")))
+            .collect::<Result<_>>()?;
+        Ok(())
+    }
+
+    /// Whether `url` should be auto scaled
+    pub fn matches(&self, url: &str) -> bool {
+        self.regexes.iter().any(|r| r.is_match(url))
+    }
 }
 
-// VitePress defines the struct of the site in JSON files
+/// Cookie set on every tab before navigation, e.g. an SSO session cookie
 #[derive(Debug, Deserialize)]
-struct VitePressLinks {
-    link: String,
+pub struct Cookie {
+    pub name: String,
+    pub value: String,
+    pub domain: Option<String>,
+    #[serde(default = "default_cookie_path")]
+    pub path: String,
     #[serde(default)]
-    items: Vec<VitePressLinks>,
+    pub secure: bool,
 }
 
-// Converts relative URLs into absoute URLs.
-fn build_links(vp: &VitePressLinks, url: &String, links: &mut IndexSet<String>) {
-    let mut link = url.clone();
-    link.push_str(&vp.link);
+fn default_cookie_path() -> String {
+    "/".to_string()
+}
 
-    if link.ends_with('/') {
-        link.push_str("index.html");
-    } else if !link.ends_with(".html") {
-        link.push_str(".html");
+impl Cookie {
+    fn valid(&self) -> Result<()> {
+        if self.name.is_empty() {
+            return Err(anyhow!("Cookie names must not be empty"));
+        }
+        if self.domain.as_deref().unwrap_or("").is_empty() {
+            return Err(anyhow!("Cookie {} is missing a domain", self.name));
+        }
+        Ok(())
     }
-    links.insert(link);
+}
 
-    for item in &vp.items {
-        build_links(item, url, links)
+/// HTTP basic auth credentials sent with every navigation
+#[derive(Debug, Deserialize)]
+pub struct HttpAuth {
+    pub username: String,
+    /// The password, prefer `password_env` so it doesn't live in the committed config
+    pub password: Option<String>,
+    /// Name of the environment variable holding the password
+    pub password_env: Option<String>,
+}
+
+impl HttpAuth {
+    // Resolve `password_env` into `password`
+    fn resolve(&mut self) -> Result<()> {
+        match (&self.password, &self.password_env) {
+            (Some(_), None) => Ok(()),
+            (None, Some(var)) => {
+                self.password =
+                    Some(std::env::var(var).map_err(|e| {
+                        anyhow!("Unable to read http_auth password from ${var}: {e}")
+                    })?);
+                Ok(())
+            }
+            _ => Err(anyhow!(
+                "Exactly one of http_auth.password or http_auth.password_env must be set"
+            )),
+        }
     }
 }
 
-impl Config {
-    /// Loads the TOML file and generates the list of URLS to render into PDFs
-    pub fn load(path: &PathBuf) -> Result<Self> {
-        let mut conf: Config = toml::from_str::<ConfigFile>(&fs::read_to_string(path)?)?.config;
+/// Viewport pages are laid out in before printing
+#[derive(Debug, Deserialize)]
+pub struct Viewport {
+    /// Width in CSS pixels
+    pub width: u32,
+    /// Height in CSS pixels
+    pub height: u32,
+    #[serde(default = "default_device_scale_factor")]
+    pub device_scale_factor: f64,
+}
 
-        if let Some(page_number) = &conf.page_number {
-            page_number.valid()?;
+fn default_device_scale_factor() -> f64 {
+    1.0
+}
+
+impl Viewport {
+    fn valid(&self) -> Result<()> {
+        if self.width == 0 || self.height == 0 {
+            return Err(anyhow!("viewport width and height must be greater than 0"));
+        }
+        if self.device_scale_factor <= 0.0 {
+            return Err(anyhow!(
+                "viewport.device_scale_factor must be greater than 0"
+            ));
         }
+        Ok(())
+    }
+}
 
-        let mut index = conf.url.clone();
-        index.push_str("/index.html");
+/// How long to wait after navigation for the page to finish loading images and fonts
+#[derive(Debug, Deserialize)]
+pub struct Settle {
+    /// Fixed delay in milliseconds
+    pub delay_ms: Option<u64>,
+    /// Milliseconds without in flight requests
+    pub network_idle_ms: Option<u64>,
+}
+
+impl Settle {
+    fn valid(&self, timeout: Duration) -> Result<()> {
+        let ms = match (self.delay_ms, self.network_idle_ms) {
+            (Some(ms), None) | (None, Some(ms)) => ms,
+            _ => {
+                return Err(anyhow!(
+                    "Exactly one of settle.delay_ms or settle.network_idle_ms must be set"
+                ))
+            }
+        };
+        if ms == 0 {
+            return Err(anyhow!("settle delay must be greater than 0"));
+        }
+        if Duration::from_millis(ms) >= timeout {
+            return Err(anyhow!(
+                "settle delay of {ms} ms must be shorter than the navigation timeout of {} s",
+                timeout.as_secs()
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Dev server started before rendering and stopped once the export is done
+#[derive(Debug, Deserialize)]
+pub struct DevServer {
+    /// Shell command, e.g. `npx vitepress dev docs --port 0`
+    pub command: String,
+    /// Directory the command runs in. If unset the current directory is used.
+    pub working_dir: Option<PathBuf>,
+    /// Port the server listens on. If unset the first localhost URL the command prints is used.
+    pub port: Option<u16>,
+    /// Time allowed for the server to answer `url` with 200 OK
+    #[serde(default = "default_dev_server_startup_timeout_secs")]
+    pub startup_timeout_secs: u64,
+    /// Time allowed to exit after SIGTERM before the server is killed
+    #[serde(default = "default_dev_server_shutdown_timeout_secs")]
+    pub shutdown_timeout_secs: u64,
+}
 
-        let mut links = indexset! {index};
+fn default_dev_server_startup_timeout_secs() -> u64 {
+    60
+}
 
-        for path in &conf.vitepress_links {
-            let vp: VitePressLinks =
-                serde_json::from_str::<VitePressLinks>(&fs::read_to_string(path)?)?;
-            build_links(&vp, &conf.url, &mut links);
+fn default_dev_server_shutdown_timeout_secs() -> u64 {
+    10
+}
+
+impl DevServer {
+    fn valid(&self) -> Result<()> {
+        if self.command.trim().is_empty() {
+            return Err(anyhow!("dev_server.command must not be empty"));
+        }
+        if self.port == Some(0) {
+            return Err(anyhow!(
+                "dev_server.port must be greater than 0, leave it unset to detect the port"
+            ));
+        }
+        if self.startup_timeout_secs == 0 {
+            return Err(anyhow!(
+                "dev_server.startup_timeout_secs must be greater than 0"
+            ));
+        }
+        if let Some(dir) = self.working_dir.as_ref().filter(|d| !d.is_dir()) {
+            return Err(anyhow!(
+                "dev_server.working_dir {} is not a directory",
+                dir.display()
+            ));
         }
+        Ok(())
+    }
+}
 
-        conf.urls = links;
-        Ok(conf)
+/// Chrome's headless implementation
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum HeadlessMode {
+    /// The legacy `--headless`
+    #[default]
+    Old,
+    /// `--headless=new`, which shares its rendering with headful Chrome
+    New,
+}
+
+/// Paper size presets for `print_to_pdf`
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Paper {
+    A4,
+    A5,
+    Letter,
+    Legal,
+}
+
+impl Paper {
+    /// Portrait width and height in inches
+    pub fn inches(self) -> (f64, f64) {
+        match self {
+            Paper::A4 => (8.27, 11.69),
+            Paper::A5 => (5.83, 8.27),
+            Paper::Letter => (8.5, 11.0),
+            Paper::Legal => (8.5, 14.0),
+        }
+    }
+}
+
+/// Value emulated for the `prefers-color-scheme` media feature
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ColorScheme {
+    Light,
+    Dark,
+    NoPreference,
+}
+
+impl ColorScheme {
+    /// The CSS media feature value
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ColorScheme::Light => "light",
+            ColorScheme::Dark => "dark",
+            ColorScheme::NoPreference => "no-preference",
+        }
+    }
+}
+
+/// What happens to links to pages of the site which aren't in the PDF
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum BrokenLinks {
+    /// Leave them pointing at `url`
+    #[default]
+    Keep,
+    /// Remove their annotations, the text is no longer a link
+    Remove,
+    /// Point them at the same page under `public_url`
+    PointToSite,
+}
+
+/// Where chapters, the top-level sidebar entries, start
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ChapterBreaks {
+    /// Straight after the previous chapter
+    #[default]
+    None,
+    /// On an odd, right hand, page, after a blank page if need be
+    Odd,
+}
+
+/// Settings for a single URL which take precedence over the global ones
+#[derive(Debug, Default, Deserialize)]
+pub struct UrlOverride {
+    /// CSS selector to wait for before printing
+    pub wait_for_selector: Option<String>,
+    /// JavaScript file run on this page instead of the global `inject_js`
+    pub inject_js: Option<PathBuf>,
+    /// The contents of `inject_js`, read when the config is loaded.
+    #[serde(skip)]
+    pub injected_js: Option<String>,
+    /// Pages of this URL to print, e.g. `1-3`, instead of `print_to_pdf.pageRanges`
+    pub page_ranges: Option<String>,
+    /// printToPDF options of this URL which replace the ones of `print_to_pdf`
+    pub print_to_pdf: Option<toml::Table>,
+    /// `print_to_pdf` with this URL's options merged in, made when the config is loaded.
+    #[cfg(feature = "render")]
+    #[serde(skip)]
+    pub print_options: Option<PrintToPdfOptions>,
+}
+
+// Whether `tag` is well-formed BCP 47, e.g. `de`, `en-US` or `zh-Hant-TW`: a 2-3 or 5-8
+// letter language followed by subtags of 1-8 letters or digits
+fn is_language_tag(tag: &str) -> bool {
+    let mut subtags = tag.split('-');
+    let language = subtags.next().unwrap_or_default();
+    matches!(language.len(), 2 | 3 | 5..=8)
+        && language.chars().all(|c| c.is_ascii_alphabetic())
+        && subtags
+            .all(|s| (1..=8).contains(&s.len()) && s.chars().all(|c| c.is_ascii_alphanumeric()))
+}
+
+// Check a printToPDF `pageRanges` value, e.g. `1-5, 8, 11-`
+fn check_page_ranges(ranges: &str) -> Result<()> {
+    for range in ranges.split(',').map(str::trim) {
+        let (first, last) = range.split_once('-').unwrap_or((range, range));
+        let page = |p: &str| -> Result<Option<u32>> {
+            match p.trim() {
+                "" => Ok(None),
+                p => match p.parse() {
+                    Ok(0) | Err(_) => Err(anyhow!("{p:?} is not a page number")),
+                    Ok(n) => Ok(Some(n)),
+                },
+            }
+        };
+        match (page(first)?, page(last)?) {
+            (None, None) => return Err(anyhow!("{range:?} is not a page range")),
+            (Some(first), Some(last)) if first > last => {
+                return Err(anyhow!("{range:?} ends before it starts"))
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+// URW base35 clones of the PDF standard 14 fonts which we can embed
+const URW_BASE35: [(&str, &str); 12] = [
+    ("Times-Roman", "NimbusRoman-Regular"),
+    ("Times-Bold", "NimbusRoman-Bold"),
+    ("Times-Italic", "NimbusRoman-Italic"),
+    ("Times-BoldItalic", "NimbusRoman-BoldItalic"),
+    ("Helvetica", "NimbusSans-Regular"),
+    ("Helvetica-Bold", "NimbusSans-Bold"),
+    ("Helvetica-Oblique", "NimbusSans-Italic"),
+    ("Helvetica-BoldOblique", "NimbusSans-BoldItalic"),
+    ("Courier", "NimbusMonoPS-Regular"),
+    ("Courier-Bold", "NimbusMonoPS-Bold"),
+    ("Courier-Oblique", "NimbusMonoPS-Italic"),
+    ("Courier-BoldOblique", "NimbusMonoPS-BoldItalic"),
+];
+
+/// We expect `vitepress-pdf-export` to be run as part of a CI actions so all options
+/// are handled by a TOML configuration file.
+#[derive(Debug, Default, Deserialize)]
+pub struct Config {
+    /// Where to download Chrome builds to
+    #[serde(default = "default_cache_path")]
+    pub chrome_cache: PathBuf,
+    /// Pin Chrome to a specific revision, e.g. `1336641`. If unset we use that latest known good build.
+    pub chrome_version: Option<String>,
+    /// Launch this Chrome or Chromium binary instead of downloading one. Wins over `chrome_version`.
+    pub chrome_path: Option<PathBuf>,
+    /// Known good versions JSON used to find the latest Chrome revision, e.g. a mirror of chrome-for-testing.
+    pub chrome_versions_url: Option<String>,
+    /// Download Chrome from this URL instead of the fetcher's default, `{revision}` and `{platform}` are replaced.
+    pub chrome_download_url_template: Option<String>,
+    /// Never look up or download Chrome, `chrome_version` must be in `chrome_cache` or `chrome_path` set.
+    #[serde(default)]
+    pub offline: bool,
+    /// Fail the render of pages logging console errors, uncaught exceptions or failed requests.
+    #[serde(default)]
+    pub fail_on_console_error: bool,
+    /// Regular expressions of console messages and failed requests to ignore, e.g. `favicon.ico`.
+    #[serde(default)]
+    pub console_ignore: Vec<String>,
+    #[serde(skip)]
+    console_ignore_regexes: Vec<Regex>,
+    /// Where to write a JSON map of URLs to their console messages and failed requests.
+    #[serde(skip)]
+    pub console_report: Option<PathBuf>,
+    /// Request every URL before launching Chrome and stop if any doesn't return 200 OK.
+    #[serde(default)]
+    pub preflight: bool,
+    /// Fail without writing `output_pdf` if any link can't be remapped.
+    #[serde(default)]
+    pub strict_links: bool,
+    /// Request every external link left in the merged PDF and report those which are dead.
+    #[serde(default)]
+    pub check_external_links: bool,
+    /// Only check external links to these hosts and their subdomains, all hosts if empty.
+    #[serde(default)]
+    pub external_link_hosts: Vec<String>,
+    /// Never check external links to these hosts and their subdomains, e.g. flaky ones.
+    #[serde(default)]
+    pub skip_external_link_hosts: Vec<String>,
+    /// What happens to links to pages of the site which aren't in the PDF.
+    #[serde(default)]
+    pub broken_links: BrokenLinks,
+    /// Published URL of the site, e.g. `https://docs.example.com`, used by `broken_links = "point-to-site"`.
+    pub public_url: Option<String>,
+    /// Delete and download the cached `chrome_version` again.
+    #[serde(skip)]
+    pub force_chrome_download: bool,
+    /// Extra command line arguments passed verbatim to Chrome, e.g. `--no-sandbox`.
+    #[serde(default)]
+    pub chrome_args: Vec<String>,
+    /// The merged PDF file. May contain variables, e.g. `dist/manual-{date}.pdf`. Missing parent directories are created.
+    pub output_pdf: PathBuf,
+    /// Values for `{name}` variables used in templated options such as `output_pdf`.
+    ///
+    /// `date` and `git_sha` are built in but can be overridden here.
+    #[serde(default)]
+    pub variables: IndexMap<String, String>,
+    /// `VitePress` Dev URL e.g., `http://localhost:5173``.
+    ///
+    /// Optional with `serve_dir`, where only its path is used as the base of the site.
+    #[serde(default)]
+    pub url: String,
+    /// Directory of a built site served on a random localhost port instead of using a dev server at `url`.
+    pub serve_dir: Option<PathBuf>,
+    /// Start this dev server before rendering, `url` is moved to the port it listens on.
+    pub dev_server: Option<DevServer>,
+    /// The list of URLS generated from `url`, `sitemap` and `vitepress_links`.
+    #[serde(skip)]
+    pub urls: IndexSet<String>,
+    /// Sidebar text of the URLs from `vitepress_links`.
+    #[serde(skip)]
+    pub page_titles: IndexMap<String, String>,
+    /// First URL of each top-level entry of `vitepress_links`.
+    #[serde(skip)]
+    pub chapter_starts: IndexSet<String>,
+    /// URL or path of a `sitemap.xml` listing the pages, rendered before the `vitepress_links` pages.
+    pub sitemap: Option<String>,
+    /// List of paths to JSON files that define the `VitePress` site.
+    #[serde(default)]
+    pub vitepress_links: Vec<PathBuf>,
+    /// Page Number Style - if not defined page numbers will not be inserted.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub page_number: Option<PageNumber>,
+    /// Section title printed on each rendered page - if not defined there is no header.
+    pub running_header: Option<RunningHeader>,
+    /// PDF whose pages are placed in front of the rendered ones, without page numbers.
+    pub cover_pdf: Option<PathBuf>,
+    /// URL or path rendered and placed in front of the other pages like `cover_pdf`.
+    pub cover_url: Option<String>,
+    /// PDFs whose pages are appended after the rendered ones, e.g. legal notices.
+    #[serde(default)]
+    pub back_matter: Vec<BackMatter>,
+    /// Continue page numbers across `back_matter`.
+    #[serde(default = "default_number_back_matter")]
+    pub number_back_matter: bool,
+    /// Start each top-level sidebar entry of `vitepress_links` on an odd page for duplex printing.
+    #[serde(default)]
+    pub chapter_breaks: ChapterBreaks,
+    /// Stamp page numbers on the blank pages `chapter_breaks` inserts instead of leaving them empty.
+    #[serde(default)]
+    pub number_blank_pages: bool,
+    /// Deepest bookmark level kept in the merged outline, top level bookmarks are level 1. 0 removes the outline.
+    pub outline_depth: Option<usize>,
+    /// Add a top level bookmark for each rendered page with the page's own bookmarks under it.
+    #[serde(default)]
+    pub page_bookmarks: bool,
+    /// Table of contents pages inserted after any cover - if not defined none are generated.
+    pub toc: Option<Toc>,
+    /// Document information written to the merged PDF's Info dictionary and XMP metadata.
+    #[serde(default)]
+    pub metadata: Metadata,
+    /// PDF Generation options see [Chrome DevTool Protocol](https://chromedevtools.github.io/devtools-protocol/tot/Page/#method-printToPDF) for documentation.
+    #[cfg(feature = "render")]
+    pub print_to_pdf: PrintToPdfOptions,
+    /// Unused without the `render` feature.
+    #[cfg(not(feature = "render"))]
+    #[serde(default)]
+    pub print_to_pdf: toml::Table,
+    /// Paper size preset filling in `print_to_pdf.paperWidth` and `paperHeight`.
+    pub paper: Option<Paper>,
+    /// HTML file used as Chrome's `headerTemplate`, wins over `print_to_pdf.headerTemplate`.
+    pub header_template_file: Option<PathBuf>,
+    /// HTML file used as Chrome's `footerTemplate`, wins over `print_to_pdf.footerTemplate`.
+    pub footer_template_file: Option<PathBuf>,
+    /// Embed a metrically compatible clone of standard Type 1 fonts instead of referencing them.
+    #[serde(default)]
+    pub embed_standard_fonts: bool,
+    /// Directory holding the URW base35 OpenType fonts used by `embed_standard_fonts`.
+    #[serde(default = "default_standard_fonts_dir")]
+    pub standard_fonts_dir: PathBuf,
+    /// Best effort removal of transparency from the merged PDF for print shops.
+    #[serde(default)]
+    pub flatten_transparency: bool,
+    /// Alpha values at or above this are made opaque by `flatten_transparency`, lower ones are left alone and reported.
+    #[serde(default = "default_flatten_alpha_threshold")]
+    pub flatten_alpha_threshold: f64,
+    /// Keep a single copy of identical objects, e.g. the web fonts each page embeds, in the merged PDF.
+    #[serde(default)]
+    pub dedupe_resources: bool,
+    /// Write the catalog and the objects of the first page at the start of `output_pdf`.
+    #[serde(default)]
+    pub linearize: bool,
+    /// Downsample images drawn above `max_dpi` and re-encode them as JPEG.
+    pub optimize_images: Option<OptimizeImages>,
+    /// Encrypt `output_pdf` with a user and owner password.
+    pub encryption: Option<Encryption>,
+    /// Text or image stamped on every page.
+    pub watermark: Option<Watermark>,
+    /// Number of times a failed page render is retried with a fresh tab. 0 disables retries.
+    #[serde(default)]
+    pub render_retries: u32,
+    /// Delay before the first retry, doubled after every failed attempt.
+    #[serde(default = "default_render_retry_delay_ms")]
+    pub render_retry_delay_ms: u64,
+    /// Minimum delay between starting page navigations, across all `browser_instances`. 0 disables it.
+    #[serde(default)]
+    pub min_interval_ms: u64,
+    /// Number of times Chrome is relaunched after dying mid render, e.g. when the OOM killer picks it.
+    #[serde(default = "default_max_chrome_relaunches")]
+    pub max_chrome_relaunches: u32,
+    /// Stop rendering once this many seconds have passed since start up, including downloading Chrome.
+    pub render_deadline_secs: Option<u64>,
+    /// HTTP statuses of 400 and above which are rendered anyway instead of failing the page.
+    #[serde(default)]
+    pub allow_status: Vec<u16>,
+    /// Skip URLs which still fail after `render_retries` and report them at the end instead of stopping.
+    #[serde(default)]
+    pub continue_on_error: bool,
+    /// Pick the scale for matching URLs that avoids horizontal overflow.
+    pub auto_scale: Option<AutoScale>,
+    /// HTTP basic auth credentials for the site.
+    pub http_auth: Option<HttpAuth>,
+    /// Cookies set on every tab before navigation.
+    #[serde(default)]
+    pub cookies: Vec<Cookie>,
+    /// Extra HTTP headers sent with every request, e.g. a preview token or `Accept-Language`.
+    #[serde(default)]
+    pub extra_headers: IndexMap<String, String>,
+    /// Viewport size and device scale factor used to lay out each page. If unset Chrome's default is used.
+    pub viewport: Option<Viewport>,
+    /// Render every page in a fresh incognito browser context, so localStorage and cookies
+    /// set by one page don't affect the next.
+    #[serde(default)]
+    pub isolated_contexts: bool,
+    /// Accept invalid TLS certificates, e.g. a self-signed preview server. Only for trusted networks.
+    #[serde(default)]
+    pub insecure_tls: bool,
+    /// Proxy used by Chrome and the Chrome revision lookup. Defaults to `HTTPS_PROXY` or `HTTP_PROXY`.
+    pub proxy: Option<String>,
+    /// Hosts which bypass `proxy`, e.g. a local dev server. Defaults to `NO_PROXY`.
+    #[serde(default)]
+    pub no_proxy: Vec<String>,
+    /// User agent sent by every tab instead of headless Chrome's default.
+    pub user_agent: Option<String>,
+    /// IANA time zone pages are rendered in, e.g. `Europe/Berlin`, instead of the machine's.
+    pub timezone: Option<String>,
+    /// Locale pages are rendered in, e.g. `en-US`, for dates and numbers formatted by the page.
+    pub locale: Option<String>,
+    /// Emulate the print media type so `@media print` rules apply while rendering.
+    #[serde(default)]
+    pub emulate_media: bool,
+    /// Headless implementation Chrome is launched with.
+    #[serde(default)]
+    pub headless_mode: HeadlessMode,
+    /// Emulated `prefers-color-scheme`. If unset Chrome's default is used.
+    pub color_scheme: Option<ColorScheme>,
+    /// Also store `color_scheme` as the VitePress theme appearance in localStorage.
+    #[serde(default)]
+    pub color_scheme_local_storage: bool,
+    /// Class added to the `html` element right after navigation, so the theme can style the export.
+    pub export_class: Option<String>,
+    /// Query parameter appended to every URL before navigation, e.g. `pdf-export=1`.
+    pub export_query: Option<String>,
+    /// CSS selector to wait for after navigation and before printing each page.
+    pub wait_for_selector: Option<String>,
+    /// CSS files injected into every page before printing.
+    #[serde(default)]
+    pub inject_css: Vec<PathBuf>,
+    /// The contents of the `inject_css` files, read when the config is loaded.
+    #[serde(skip)]
+    pub injected_css: String,
+    /// JavaScript file run on every page after navigation and before printing.
+    pub inject_js: Option<PathBuf>,
+    /// The contents of `inject_js`, read when the config is loaded.
+    #[serde(skip)]
+    pub injected_js: Option<String>,
+    /// Remove scripts, the local search index and hidden overlays before printing.
+    #[serde(default)]
+    pub strip_hidden_dom: bool,
+    /// Open every `details` element before printing so collapsed content is included.
+    #[serde(default)]
+    pub expand_details: bool,
+    /// CSS selector of elements clicked when `expand_details` is set, e.g. accordion toggles.
+    pub expand_selector: Option<String>,
+    /// Load `loading="lazy"` images straight away and wait for them before printing.
+    #[serde(default)]
+    pub force_lazy_images: bool,
+    /// Wait for `document.fonts.ready` before settling, so pages aren't printed with fallback fonts.
+    #[serde(default)]
+    pub wait_for_fonts: bool,
+    /// Extra CSS selectors removed when `strip_hidden_dom` is set, even if the layout changes.
+    #[serde(default)]
+    pub strip_selectors: Vec<String>,
+    /// Regular expressions moving matching URLs to the front of the merged PDF, in pattern order.
+    ///
+    /// Unmatched URLs keep their sidebar order after them.
+    #[serde(default)]
+    pub order: Vec<String>,
+    /// Regular expressions removing matching URLs before rendering, e.g. the changelog.
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    /// The URLs removed by `exclude`, so links to them can be reported.
+    #[serde(skip)]
+    pub excluded: IndexSet<String>,
+    #[serde(skip)]
+    exclude_regexes: Vec<Regex>,
+    /// Render pages with `<meta name="pdf-export" content="false">` instead of skipping them.
+    #[serde(default)]
+    pub ignore_pdf_export_meta: bool,
+    /// The URLs skipped for their `pdf-export` meta tag, so links to them can be reported.
+    #[serde(skip)]
+    pub opted_out: IndexSet<String>,
+    /// Discover pages by following same origin links from the rendered pages, breadth first from the index.
+    #[serde(default)]
+    pub crawl: bool,
+    /// Stop adding crawled URLs once there are this many.
+    #[serde(default = "default_crawl_max_pages")]
+    pub crawl_max_pages: usize,
+    /// Per URL settings keyed by the path of the page, e.g. `/guide/setup.html`.
+    ///
+    /// After loading the keys are the absolute URLs.
+    #[serde(default)]
+    pub overrides: IndexMap<String, UrlOverride>,
+    /// Per page timeout for navigation and other tab operations. If unset headless_chrome's default is used.
+    pub navigation_timeout_secs: Option<u64>,
+    /// Wait for a fixed delay or for the network to go idle after navigation, bounded by the navigation timeout.
+    pub settle: Option<Settle>,
+    /// Number of Chrome processes rendering side by side, each uses its own memory and CPU.
+    #[serde(default = "default_browser_instances")]
+    pub browser_instances: usize,
+    /// Restart Chrome after rendering this many pages, Chrome's memory use grows over a long run.
+    pub browser_recycle_every: Option<usize>,
+    /// Memory ceiling in MiB for the exporter and Chrome. Fewer pages are rendered at once when use gets close to it.
+    pub max_memory_mb: Option<u64>,
+    /// CPU ceiling for the exporter and Chrome in cores, e.g. `2.5`. Fewer pages are rendered at once when use gets close to it.
+    pub max_cpu: Option<f64>,
+    /// Directory the per run temporary directory for rendered PDFs is created in. If unset the system default is used.
+    pub temp_dir: Option<PathBuf>,
+    /// Fail early if the file system holding `temp_dir` has less free space than this.
+    #[serde(default = "default_temp_dir_min_free_mb")]
+    pub temp_dir_min_free_mb: u64,
+    /// Reuse the PDF of a page whose HTML and print options are unchanged since the previous run.
+    #[serde(default)]
+    pub render_cache: bool,
+    /// The `render_cache` file, `cache.json` in `--keep_pdfs`.
+    #[serde(skip)]
+    pub render_cache_file: Option<PathBuf>,
+    /// Render every page and start `render_cache_file` over.
+    #[serde(skip)]
+    pub no_cache: bool,
+    /// Directory used to cache parsed PDFs between `--merge_only` runs.
+    #[serde(skip)]
+    pub merge_cache: Option<PathBuf>,
+    /// How render and merge progress is reported.
+    #[serde(skip)]
+    pub progress: ProgressMode,
+    /// Directory a full page PNG of every URL is written to, named like its PDF.
+    #[serde(skip)]
+    pub debug_screenshots: Option<PathBuf>,
+    /// Where to write the JSON map of URLs to pages and headings in the merged PDF.
+    #[serde(skip)]
+    pub page_map: Option<PathBuf>,
+    /// Where to write a JSON line for every Link annotation examined while rewriting links.
+    #[serde(skip)]
+    pub trace_links: Option<PathBuf>,
+    /// Where to write the JSON report of links which couldn't be remapped, see `merge::MergeReport`.
+    #[serde(skip)]
+    pub report: Option<PathBuf>,
+    /// PDFs rendered by a previous run which are reused instead of rendering their URL again.
+    #[serde(skip)]
+    pub resume: IndexMap<String, PathBuf>,
+    /// Resolve links and report without assembling page contents or writing `output_pdf`.
+    #[serde(skip)]
+    pub no_output: bool,
+}
+
+// Read a header or footer template file, warning if it replaces an inline template
+#[cfg(feature = "render")]
+fn read_template(
+    path: &Option<PathBuf>,
+    template: &mut Option<String>,
+    name: &str,
+) -> Result<bool> {
+    let Some(path) = path else {
+        return Ok(false);
+    };
+    let content = fs::read_to_string(path)
+        .map_err(|e| anyhow!("Unable to read {name}_file {}: {e}", path.display()))?;
+    if template.is_some() {
+        eprintln!(
+            "Warning: {name}_file {} overrides the inline print_to_pdf template",
+            path.display()
+        );
+    }
+    *template = Some(content);
+    Ok(true)
+}
+
+// Fill in the header and footer template files and the `paper` preset, and check `pageRanges`
+#[cfg(feature = "render")]
+fn prepare_print_to_pdf(
+    options: &mut PrintToPdfOptions,
+    header_template_file: &Option<PathBuf>,
+    footer_template_file: &Option<PathBuf>,
+    paper: Option<Paper>,
+) -> Result<()> {
+    let header = read_template(
+        header_template_file,
+        &mut options.header_template,
+        "header_template",
+    )?;
+    let footer = read_template(
+        footer_template_file,
+        &mut options.footer_template,
+        "footer_template",
+    )?;
+    if header || footer {
+        options.display_header_footer = Some(true);
+    }
+    if let Some(paper) = paper {
+        apply_paper(paper, options);
+    }
+    if let Some(ranges) = &options.page_ranges {
+        check_page_ranges(ranges)
+            .map_err(|e| anyhow!("print_to_pdf.pageRanges {ranges:?} is invalid: {e}"))?;
+    }
+    Ok(())
+}
+
+// Fill in the paper size from `paper`. An explicit `paperWidth` or `paperHeight` wins with a
+// warning. Otherwise with `landscape` the preset is swapped here and `landscape` cleared,
+// so the width `auto_scale` sees is the printed one and Chrome doesn't rotate it again.
+#[cfg(feature = "render")]
+fn apply_paper(paper: Paper, options: &mut PrintToPdfOptions) {
+    let (width, height) = paper.inches();
+    if options.paper_width.is_some() || options.paper_height.is_some() {
+        eprintln!("Warning: print_to_pdf.paperWidth and paperHeight override paper {paper:?}");
+        options.paper_width.get_or_insert(width);
+        options.paper_height.get_or_insert(height);
+        return;
+    }
+    let landscape = options.landscape.take() == Some(true);
+    let (width, height) = if landscape {
+        (height, width)
+    } else {
+        (width, height)
+    };
+    options.paper_width = Some(width);
+    options.paper_height = Some(height);
+}
+
+// headless_chrome's default timeout for tab operations
+const DEFAULT_NAVIGATION_TIMEOUT: Duration = Duration::from_secs(20);
+
+// Chrome switches set by us or headless_chrome, with the option to use instead
+const RESERVED_CHROME_ARGS: [(&str, &str); 7] = [
+    ("--headless", "use headless_mode instead"),
+    ("--generate-pdf-document-outline", "it is always set"),
+    ("--remote-debugging-port", "it is set by headless_chrome"),
+    ("--user-data-dir", "it is set by headless_chrome"),
+    ("--proxy-server", "use proxy instead"),
+    ("--proxy-bypass-list", "use no_proxy instead"),
+    ("--ignore-certificate-errors", "use insecure_tls instead"),
+];
+
+// First non empty environment variable of `names`
+fn env_var(names: &[&str]) -> Option<String> {
+    names
+        .iter()
+        .find_map(|n| std::env::var(n).ok().filter(|v| !v.trim().is_empty()))
+}
+
+fn read_inject_js(path: &Option<PathBuf>) -> Result<Option<String>> {
+    path.as_ref()
+        .map(|p| {
+            fs::read_to_string(p)
+                .map_err(|e| anyhow!("Unable to read inject_js {}: {e}", p.display()))
+        })
+        .transpose()
+}
+
+#[cfg(not(windows))]
+fn default_cache_path() -> PathBuf {
+    PathBuf::from("/tmp")
+}
+
+#[cfg(windows)]
+fn default_cache_path() -> PathBuf {
+    std::env::temp_dir()
+}
+
+fn default_number_back_matter() -> bool {
+    true
+}
+
+fn default_standard_fonts_dir() -> PathBuf {
+    PathBuf::from("/usr/share/fonts/opentype/urw-base35")
+}
+
+fn default_temp_dir_min_free_mb() -> u64 {
+    1024
+}
+
+fn default_crawl_max_pages() -> usize {
+    1000
+}
+
+fn default_flatten_alpha_threshold() -> f64 {
+    0.95
+}
+
+fn default_render_retry_delay_ms() -> u64 {
+    1000
+}
+
+fn default_browser_instances() -> usize {
+    1
+}
+
+fn default_max_chrome_relaunches() -> u32 {
+    3
+}
+
+// VitePress defines the struct of the site in JSON files
+#[derive(Debug, Deserialize)]
+struct VitePressLinks {
+    link: String,
+    /// Sidebar text of the page
+    #[serde(default)]
+    text: Option<String>,
+    #[serde(default)]
+    items: Vec<VitePressLinks>,
+}
+
+// Converts a relative VitePress link into an absolute URL of the rendered page.
+pub fn normalize_link(url: &str, link: &str) -> String {
+    let mut link = format!("{url}{link}");
+
+    if link.ends_with('/') {
+        link.push_str("index.html");
+    } else if !link.ends_with(".html") {
+        link.push_str(".html");
+    }
+    link
+}
+
+/// Split `url` into its origin and path, e.g. `http://localhost:5173` and `/docs`
+pub fn split_origin(url: &str) -> (&str, &str) {
+    let host = url.find("://").map_or(0, |i| i + 3);
+    url.split_at(url[host..].find('/').map_or(url.len(), |i| host + i))
+}
+
+// Converts relative URLs into absoute URLs, recording the sidebar text of each in `titles`.
+fn build_links(
+    vp: &VitePressLinks,
+    url: &String,
+    links: &mut IndexSet<String>,
+    titles: &mut IndexMap<String, String>,
+) {
+    let link = normalize_link(url, &vp.link);
+    if let Some(text) = &vp.text {
+        titles.entry(link.clone()).or_insert_with(|| text.clone());
+    }
+    links.insert(link);
+
+    for item in &vp.items {
+        build_links(item, url, links, titles)
+    }
+}
+
+// The first page of `vp` and its descendants, groups without a page of their own are skipped
+fn first_link(vp: &VitePressLinks, url: &str) -> Option<String> {
+    match vp.link.is_empty() {
+        false => Some(normalize_link(url, &vp.link)),
+        true => vp.items.iter().find_map(|item| first_link(item, url)),
+    }
+}
+
+// Record where the chapters of a sidebar start, its root page and each of its top-level entries
+fn build_chapter_starts(vp: &VitePressLinks, url: &str, starts: &mut IndexSet<String>) {
+    for entry in std::iter::once(vp).chain(&vp.items) {
+        if let Some(link) = first_link(entry, url) {
+            starts.insert(link);
+        }
+    }
+}
+
+// Compile the regular expressions of the `option` config key
+fn compile_patterns(option: &str, patterns: &[String]) -> Result<Vec<Regex>> {
+    patterns
+        .iter()
+        .map(|p| Regex::new(p).map_err(|e| anyhow!("Invalid {option} pattern {p}: {e}")))
+        .collect()
+}
+
+// Fetch or read the sitemap at `source`. It is fetched like the pages, with `proxy`,
+// `insecure_tls`, `extra_headers`, `user_agent` and `http_auth`.
+#[cfg_attr(not(feature = "render"), allow(unused_variables))]
+fn read_sitemap(conf: &Config, source: &str) -> Result<String> {
+    if source.starts_with("http://") || source.starts_with("https://") {
+        #[cfg(feature = "render")]
+        return block_on(crate::render::fetch_text(conf, source))?
+            .map_err(|e| anyhow!("Unable to fetch sitemap {source}: {e}"));
+        #[cfg(not(feature = "render"))]
+        return Err(anyhow!(
+            "Fetching sitemap {source} requires the render feature, use a local path"
+        ));
+    }
+    fs::read_to_string(source).map_err(|e| anyhow!("Unable to read sitemap {source}: {e}"))
+}
+
+// `Config::load` is synchronous, but may be called from async code. Runs `future` on a runtime
+// of its own in another thread, blocking a worker of the caller's runtime panics on a
+// current_thread runtime.
+#[cfg(feature = "render")]
+fn block_on<F>(future: F) -> Result<F::Output>
+where
+    F: std::future::Future + Send,
+    F::Output: Send,
+{
+    std::thread::scope(|s| {
+        s.spawn(|| {
+            Ok(tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()?
+                .block_on(future))
+        })
+        .join()
+        .map_err(|_| anyhow!("Fetching the sitemap panicked"))?
+    })
+}
+
+// The `<loc>` of every `<url>` in a sitemap `<urlset>`, in document order
+fn parse_sitemap(xml: &str) -> Result<Vec<String>> {
+    let mut reader = Reader::from_str(xml);
+    // Local names of the open elements
+    let mut open: Vec<Vec<u8>> = vec![];
+    let mut urlset = false;
+    let mut loc: Option<String> = None;
+    let mut locs = vec![];
+    loop {
+        let event = reader
+            .read_event()
+            .map_err(|e| anyhow!("{e} at byte {}", reader.buffer_position()))?;
+        match event {
+            XmlEvent::Start(e) => {
+                let name = e.local_name().as_ref().to_vec();
+                match name.as_slice() {
+                    b"urlset" if open.is_empty() => urlset = true,
+                    b"url" if in_urlset(&open) => loc = None,
+                    _ => {}
+                }
+                open.push(name);
+            }
+            XmlEvent::Empty(e) if in_urlset(&open) && e.local_name().as_ref() == b"url" => {
+                return Err(anyhow!("<url> without <loc>"));
+            }
+            XmlEvent::Text(text) if in_loc(&open) => loc
+                .get_or_insert_with(String::new)
+                .push_str(&text.unescape()?),
+            XmlEvent::CData(data) if in_loc(&open) => loc
+                .get_or_insert_with(String::new)
+                .push_str(&String::from_utf8_lossy(&data)),
+            XmlEvent::End(_) => {
+                if matches!(open.as_slice(), [root, url] if root == b"urlset" && url == b"url") {
+                    let l = loc.take().ok_or(anyhow!("<url> without <loc>"))?;
+                    locs.push(l.trim().to_string());
+                }
+                open.pop();
+            }
+            XmlEvent::Eof => break,
+            _ => {}
+        }
+    }
+    if let Some(name) = open.last() {
+        return Err(anyhow!("<{}> isn't closed", String::from_utf8_lossy(name)));
+    }
+    if !urlset {
+        return Err(anyhow!("no <urlset> element"));
+    }
+    Ok(locs)
+}
+
+// Directly inside the `<urlset>` root
+fn in_urlset(open: &[Vec<u8>]) -> bool {
+    matches!(open, [urlset] if urlset == b"urlset")
+}
+
+// Inside `<urlset><url><loc>`
+fn in_loc(open: &[Vec<u8>]) -> bool {
+    matches!(open, [urlset, url, loc] if urlset == b"urlset" && url == b"url" && loc == b"loc")
+}
+
+// Sitemaps hold absolute URLs of the deployed site, render the same path below `url`
+fn sitemap_link(url: &str, loc: &str) -> String {
+    let path = match loc.split_once("://") {
+        Some((_, rest)) => rest.find('/').map_or("/", |i| &rest[i..]),
+        None => loc,
+    };
+    normalize_link(url, path.split(['?', '#']).next().unwrap_or_default())
+}
+
+// Split off URLs matching any of `patterns`, returns the kept and the excluded URLs
+fn exclude_urls(
+    patterns: &[String],
+    urls: IndexSet<String>,
+) -> Result<(IndexSet<String>, IndexSet<String>)> {
+    let res = compile_patterns("exclude", patterns)?;
+    for (p, re) in patterns.iter().zip(&res) {
+        if !urls.iter().any(|u| re.is_match(u)) {
+            eprintln!("Warning: exclude pattern {p} doesn't match any URL");
+        }
+    }
+    Ok(urls
+        .into_iter()
+        .partition(|u| !res.iter().any(|re| re.is_match(u))))
+}
+
+// Move URLs matching each of `patterns` to the front, in pattern order
+fn order_urls(patterns: &[String], urls: IndexSet<String>) -> Result<IndexSet<String>> {
+    let mut ordered = IndexSet::new();
+    for p in patterns {
+        let re = Regex::new(p).map_err(|e| anyhow!("Invalid order pattern {p}: {e}"))?;
+        let matched: Vec<&String> = urls.iter().filter(|u| re.is_match(u)).collect();
+        if matched.is_empty() {
+            return Err(anyhow!("order pattern {p} doesn't match any URL"));
+        }
+        ordered.extend(matched.into_iter().cloned());
+    }
+    ordered.extend(urls);
+    Ok(ordered)
+}
+
+impl Config {
+    // Value of a template variable, user defined variables win over built in ones
+    fn variable(&self, name: &str) -> Option<String> {
+        if let Some(value) = self.variables.get(name) {
+            return Some(value.clone());
+        }
+        match name {
+            "date" => Some(chrono::Local::now().format("%Y-%m-%d").to_string()),
+            "git_sha" => Command::new("git")
+                .args(["rev-parse", "--short", "HEAD"])
+                .output()
+                .ok()
+                .filter(|o| o.status.success())
+                .map(|o| String::from_utf8_lossy(&o.stdout).trim().to_string()),
+            _ => None,
+        }
+    }
+
+    /// Move all URLs from `url` to `new_url`, e.g. to the address of the `serve_dir` server
+    pub fn rebase_url(&mut self, new_url: &str) {
+        let old_url = std::mem::replace(&mut self.url, new_url.to_string());
+        let rebase = |u: String| match u.strip_prefix(&old_url) {
+            Some(rest) => format!("{new_url}{rest}"),
+            None => u,
+        };
+        self.urls = std::mem::take(&mut self.urls)
+            .into_iter()
+            .map(rebase)
+            .collect();
+        self.excluded = std::mem::take(&mut self.excluded)
+            .into_iter()
+            .map(rebase)
+            .collect();
+        self.overrides = std::mem::take(&mut self.overrides)
+            .into_iter()
+            .map(|(u, o)| (rebase(u), o))
+            .collect();
+        self.page_titles = std::mem::take(&mut self.page_titles)
+            .into_iter()
+            .map(|(u, t)| (rebase(u), t))
+            .collect();
+        self.chapter_starts = std::mem::take(&mut self.chapter_starts)
+            .into_iter()
+            .map(rebase)
+            .collect();
+        self.cover_url = self.cover_url.take().map(rebase);
+    }
+
+    /// Render only the URLs in `list`, one per line, instead of the ones from the config.
+    ///
+    /// Paths are normalized against `url` like `vitepress_links`, e.g. `/guide/setup` is
+    /// `{url}/guide/setup.html`. Empty lines and lines starting with `#` are ignored.
+    pub fn replace_urls(&mut self, list: &str) {
+        self.urls = list
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+            .map(|l| self.resolve_link(l))
+            .collect();
+    }
+
+    // A URL as is, or a path normalized against `url` like `vitepress_links`
+    fn resolve_link(&self, link: &str) -> String {
+        match link {
+            l if l.starts_with("http://") || l.starts_with("https://") => l.to_string(),
+            l if l.starts_with('/') => normalize_link(&self.url, l),
+            l => normalize_link(&self.url, &format!("/{l}")),
+        }
+    }
+
+    /// Whether `url` matches one of the `exclude` patterns
+    pub fn is_excluded(&self, url: &str) -> bool {
+        self.exclude_regexes.iter().any(|re| re.is_match(url))
+    }
+
+    /// Whether a console message or failed request matches one of the `console_ignore` patterns
+    pub fn is_console_ignored(&self, message: &str) -> bool {
+        self.console_ignore_regexes
+            .iter()
+            .any(|re| re.is_match(message))
+    }
+
+    /// Substitute `{name}` variables in `template`. Unresolved variables are an error.
+    pub fn expand(&self, template: &str) -> Result<String> {
+        let re = Regex::new(r"\{([A-Za-z0-9_]+)\}")?;
+        let mut unresolved = vec![];
+        let expanded = re.replace_all(template, |caps: &regex::Captures| {
+            self.variable(&caps[1]).unwrap_or_else(|| {
+                unresolved.push(caps[1].to_string());
+                String::new()
+            })
+        });
+        if !unresolved.is_empty() {
+            return Err(anyhow!(
+                "Unresolved variable(s) {} in {template}",
+                unresolved.join(",")
+            ));
+        }
+        Ok(expanded.to_string())
+    }
+
+    fn valid(&self) -> Result<()> {
+        if let Some(page_number) = &self.page_number {
+            page_number.valid()?;
+        }
+        if let Some(running_header) = &self.running_header {
+            running_header.valid()?;
+        }
+        if let Some(toc) = &self.toc {
+            toc.valid()?;
+        }
+        if let Some(optimize_images) = &self.optimize_images {
+            optimize_images.valid()?;
+        }
+        if let Some(encryption) = &self.encryption {
+            encryption.valid()?;
+        }
+        if let Some(watermark) = &self.watermark {
+            watermark.valid()?;
+        }
+        if let Some(cover) = self.cover_pdf.as_ref().filter(|c| !c.is_file()) {
+            return Err(anyhow!("cover_pdf {} doesn't exist", cover.display()));
+        }
+        if let Some(b) = self.back_matter.iter().find(|b| !b.path.is_file()) {
+            return Err(anyhow!("back_matter {} doesn't exist", b.path.display()));
+        }
+        if self.cover_pdf.is_some() && self.cover_url.is_some() {
+            return Err(anyhow!("Only one of cover_pdf and cover_url can be set"));
+        }
+        let fonts = self
+            .page_number
+            .iter()
+            .filter(|p| p.font_file.is_none())
+            .map(|p| &p.font)
+            .chain(self.toc.iter().map(|t| &t.font));
+        for font in fonts.filter(|_| self.embed_standard_fonts) {
+            let path = self.standard_font_path(font)?;
+            if !path.exists() {
+                return Err(anyhow!(
+                    "embed_standard_fonts is set but {} doesn't exist, install the URW base35 fonts or set standard_fonts_dir",
+                    path.display()
+                ));
+            }
+        }
+        if let Some(viewport) = &self.viewport {
+            viewport.valid()?;
+        }
+        if self.chapter_breaks != ChapterBreaks::None && self.vitepress_links.is_empty() {
+            return Err(anyhow!(
+                "chapter_breaks requires vitepress_links, whose top-level entries are the chapters"
+            ));
+        }
+        if self.broken_links == BrokenLinks::PointToSite && self.public_url.is_none() {
+            return Err(anyhow!(
+                "broken_links = \"point-to-site\" requires public_url"
+            ));
+        }
+        #[cfg(not(feature = "render"))]
+        if self.check_external_links {
+            return Err(anyhow!("check_external_links requires the render feature"));
+        }
+        for host in self
+            .external_link_hosts
+            .iter()
+            .chain(&self.skip_external_link_hosts)
+        {
+            if host.is_empty() || host.contains(['/', ':']) {
+                return Err(anyhow!(
+                    "External link hosts are host names, e.g. github.com, not {host:?}"
+                ));
+            }
+        }
+        if let Some(selector) = &self.expand_selector {
+            if !self.expand_details {
+                return Err(anyhow!("expand_selector requires expand_details"));
+            }
+            if selector.trim().is_empty() {
+                return Err(anyhow!("expand_selector must not be empty"));
+            }
+        }
+        if let Some(settle) = &self.settle {
+            settle.valid(self.navigation_timeout())?;
+        }
+        for cookie in &self.cookies {
+            cookie.valid()?;
+        }
+        for (name, value) in &self.extra_headers {
+            // Header names are RFC 9110 tokens
+            if name.is_empty()
+                || !name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c))
+            {
+                return Err(anyhow!("Invalid extra_headers name {name:?}"));
+            }
+            if value.chars().any(|c| c.is_control() && c != '\t') {
+                return Err(anyhow!(
+                    "extra_headers value for {name} must not contain control characters"
+                ));
+            }
+        }
+        if !(0.0..=1.0).contains(&self.flatten_alpha_threshold) {
+            return Err(anyhow!(
+                "flatten_alpha_threshold must be in range 0.0 to 1.0"
+            ));
+        }
+        if self.color_scheme_local_storage && self.color_scheme.is_none() {
+            return Err(anyhow!(
+                "color_scheme_local_storage requires color_scheme to be set"
+            ));
+        }
+        if self.navigation_timeout_secs == Some(0) {
+            return Err(anyhow!("navigation_timeout_secs must be greater than 0"));
+        }
+        if self
+            .user_agent
+            .as_ref()
+            .is_some_and(|ua| ua.trim().is_empty() || ua.chars().any(char::is_control))
+        {
+            return Err(anyhow!(
+                "user_agent must not be empty or contain control characters"
+            ));
+        }
+        for arg in &self.chrome_args {
+            if arg.trim().is_empty() {
+                return Err(anyhow!("chrome_args must not contain empty arguments"));
+            }
+            let switch = arg.split('=').next().unwrap_or_default();
+            if let Some((_, hint)) = RESERVED_CHROME_ARGS.iter().find(|(s, _)| *s == switch) {
+                return Err(anyhow!("chrome_args can't contain {switch}, {hint}"));
+            }
+        }
+        if let Some(url) = &self.chrome_versions_url {
+            if !url.starts_with("http://") && !url.starts_with("https://") {
+                return Err(anyhow!("chrome_versions_url {url} must be an http(s) URL"));
+            }
+        }
+        if let Some(template) = &self.chrome_download_url_template {
+            if !template.starts_with("http://") && !template.starts_with("https://") {
+                return Err(anyhow!(
+                    "chrome_download_url_template {template} must be an http(s) URL"
+                ));
+            }
+            if !template.contains("{revision}") {
+                return Err(anyhow!(
+                    "chrome_download_url_template {template} must contain {{revision}}"
+                ));
+            }
+        }
+        if let Some(timezone) = &self.timezone {
+            if timezone.parse::<chrono_tz::Tz>().is_err() {
+                return Err(anyhow!(
+                    "timezone {timezone:?} is not an IANA time zone, e.g. Europe/Berlin"
+                ));
+            }
+        }
+        if let Some(locale) = &self.locale {
+            if !is_language_tag(locale) {
+                return Err(anyhow!(
+                    "locale {locale:?} is not a BCP 47 language tag, e.g. en-US"
+                ));
+            }
+        }
+        if let Some(class) = &self.export_class {
+            if class.is_empty() || class.contains(char::is_whitespace) {
+                return Err(anyhow!(
+                    "export_class {class:?} must be a single class name"
+                ));
+            }
+        }
+        if let Some(query) = &self.export_query {
+            if query.is_empty() || query.contains(['?', '&', '#', ' ']) {
+                return Err(anyhow!(
+                    "export_query {query:?} must be a single parameter, e.g. pdf-export=1"
+                ));
+            }
+        }
+        if self.proxy.as_ref().is_some_and(|p| p.trim().is_empty()) {
+            return Err(anyhow!("proxy must not be empty"));
+        }
+        if self.url.is_empty() && self.serve_dir.is_none() {
+            return Err(anyhow!("url must be set unless serve_dir is"));
+        }
+        if let Some(dev_server) = &self.dev_server {
+            if self.serve_dir.is_some() {
+                return Err(anyhow!("Only one of dev_server or serve_dir can be set"));
+            }
+            dev_server.valid()?;
+        }
+        if let Some(dir) = self.serve_dir.as_ref().filter(|d| !d.is_dir()) {
+            return Err(anyhow!("serve_dir {} is not a directory", dir.display()));
+        }
+        if self.render_cache && self.crawl {
+            return Err(anyhow!(
+                "render_cache can't be combined with crawl, the links of cached pages aren't followed"
+            ));
+        }
+        if self.crawl_max_pages == 0 {
+            return Err(anyhow!("crawl_max_pages must be greater than 0"));
+        }
+        if self.max_memory_mb == Some(0) {
+            return Err(anyhow!("max_memory_mb must be greater than 0"));
+        }
+        if self.max_cpu.is_some_and(|c| c.is_nan() || c <= 0.0) {
+            return Err(anyhow!("max_cpu must be greater than 0"));
+        }
+        if let Some(status) = self.allow_status.iter().find(|s| !(400..=599).contains(*s)) {
+            return Err(anyhow!(
+                "allow_status {status} is not an HTTP error status, use 400 through 599"
+            ));
+        }
+        if self.render_deadline_secs == Some(0) {
+            return Err(anyhow!("render_deadline_secs must be greater than 0"));
+        }
+        if self.browser_instances == 0 {
+            return Err(anyhow!("browser_instances must be greater than 0"));
+        }
+        if self.browser_recycle_every == Some(0) {
+            return Err(anyhow!("browser_recycle_every must be greater than 0"));
+        }
+        Ok(())
+    }
+
+    /// Path of the URW clone used to embed standard Type 1 `font`
+    pub fn standard_font_path(&self, font: &str) -> Result<PathBuf> {
+        // Accept the unicode minus used in the PDF Reference's font table
+        let font = font.replace('−', "-");
+        URW_BASE35
+            .iter()
+            .find(|(name, _)| *name == font)
+            .map(|(_, file)| self.standard_fonts_dir.join(format!("{file}.otf")))
+            .ok_or(anyhow!("No embeddable clone of {font}"))
+    }
+
+    /// Timeout for navigation and other tab operations
+    pub fn navigation_timeout(&self) -> Duration {
+        self.navigation_timeout_secs
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_NAVIGATION_TIMEOUT)
+    }
+
+    /// `url` with `export_query` added, before any fragment
+    pub fn export_url(&self, url: &str) -> String {
+        let Some(query) = &self.export_query else {
+            return url.to_string();
+        };
+        let (page, fragment) = url.split_at(url.find('#').unwrap_or(url.len()));
+        let separator = if page.contains('?') { '&' } else { '?' };
+        format!("{page}{separator}{query}{fragment}")
+    }
+
+    /// `url` without `export_query`, links on exported pages may carry it
+    pub fn strip_export_query(&self, url: &str) -> String {
+        let Some(query) = &self.export_query else {
+            return url.to_string();
+        };
+        let (page, fragment) = url.split_at(url.find('#').unwrap_or(url.len()));
+        let Some((path, params)) = page.split_once('?') else {
+            return url.to_string();
+        };
+        let params: Vec<&str> = params.split('&').filter(|p| p != query).collect();
+        match params.is_empty() {
+            true => format!("{path}{fragment}"),
+            false => format!("{path}?{}{fragment}", params.join("&")),
+        }
+    }
+
+    /// Whether `check_external_links` requests `url`, an http(s) link off the site to a host
+    /// in `external_link_hosts` and not in `skip_external_link_hosts`
+    pub fn checks_external_link(&self, url: &str) -> bool {
+        let Some(rest) = url
+            .strip_prefix("https://")
+            .or_else(|| url.strip_prefix("http://"))
+        else {
+            return false;
+        };
+        if !self.check_external_links || url.starts_with(&self.url) {
+            return false;
+        }
+        let authority = rest.split(['/', '?', '#']).next().unwrap_or_default();
+        let host = authority.rsplit('@').next().unwrap_or_default();
+        let host = host.split(':').next().unwrap_or_default().to_lowercase();
+        let listed = |hosts: &[String]| {
+            hosts.iter().any(|h| {
+                let h = h.to_lowercase();
+                host == h || host.ends_with(&format!(".{h}"))
+            })
+        };
+        (self.external_link_hosts.is_empty() || listed(&self.external_link_hosts))
+            && !listed(&self.skip_external_link_hosts)
+    }
+
+    /// `url`, a page of the site, under `public_url` instead
+    pub fn public_link(&self, url: &str) -> Option<String> {
+        let public_url = self.public_url.as_deref()?.trim_end_matches('/');
+        let path = url.strip_prefix(self.url.trim_end_matches('/'))?;
+        Some(format!("{public_url}{path}"))
+    }
+
+    /// Settings overriding the global ones for `url`
+    pub fn url_override(&self, url: &str) -> Option<&UrlOverride> {
+        self.overrides.get(url)
+    }
+
+    /// Loads the TOML file and generates the list of URLS to render into PDFs
+    pub fn load(path: &PathBuf) -> Result<Self> {
+        let content = fs::read_to_string(path)?;
+        let mut conf: Config = toml::from_str::<ConfigFile>(&content)?.config;
+
+        conf.valid()?;
+
+        conf.output_pdf = PathBuf::from(conf.expand(&conf.output_pdf.to_string_lossy())?);
+        conf.metadata.title = conf
+            .metadata
+            .title
+            .as_deref()
+            .map(|t| conf.expand(t))
+            .transpose()?;
+        conf.metadata.author = conf
+            .metadata
+            .author
+            .as_deref()
+            .map(|a| conf.expand(a))
+            .transpose()?;
+
+        if conf.proxy.is_none() {
+            conf.proxy = env_var(&["HTTPS_PROXY", "https_proxy", "HTTP_PROXY", "http_proxy"]);
+        }
+        if conf.no_proxy.is_empty() {
+            conf.no_proxy = env_var(&["NO_PROXY", "no_proxy"])
+                .map(|v| v.split(',').map(|h| h.trim().to_string()).collect())
+                .unwrap_or_default();
+        }
+
+        for css in &conf.inject_css {
+            let content = fs::read_to_string(css)
+                .map_err(|e| anyhow!("Unable to read inject_css {}: {e}", css.display()))?;
+            conf.injected_css.push_str(&content);
+            conf.injected_css.push('\n');
+        }
+
+        if let Some(http_auth) = conf.http_auth.as_mut() {
+            http_auth.resolve()?;
+        }
+
+        conf.injected_js = read_inject_js(&conf.inject_js)?;
+
+        #[cfg(feature = "render")]
+        {
+            prepare_print_to_pdf(
+                &mut conf.print_to_pdf,
+                &conf.header_template_file,
+                &conf.footer_template_file,
+                conf.paper,
+            )?;
+            // Merged as written, so the override gets the same template files and paper
+            let global = toml::from_str::<PrintToPdfFile>(&content)?
+                .config
+                .print_to_pdf;
+            for (path, o) in conf.overrides.iter_mut() {
+                let Some(table) = &o.print_to_pdf else {
+                    continue;
+                };
+                let mut merged = global.clone();
+                merged.extend(table.clone());
+                let mut options: PrintToPdfOptions = toml::Value::Table(merged)
+                    .try_into()
+                    .map_err(|e| anyhow!("print_to_pdf of {path} is invalid: {e}"))?;
+                prepare_print_to_pdf(
+                    &mut options,
+                    &conf.header_template_file,
+                    &conf.footer_template_file,
+                    conf.paper,
+                )
+                .map_err(|e| anyhow!("print_to_pdf of {path} is invalid: {e}"))?;
+                o.print_options = Some(options);
+            }
+        }
+        for (path, o) in conf.overrides.iter_mut() {
+            o.injected_js = read_inject_js(&o.inject_js)?;
+            if let Some(ranges) = &o.page_ranges {
+                check_page_ranges(ranges)
+                    .map_err(|e| anyhow!("page_ranges {ranges:?} of {path} is invalid: {e}"))?;
+            }
+        }
+
+        if let Some(auto_scale) = conf.auto_scale.as_mut() {
+            auto_scale.compile()?;
+        }
+
+        let mut links = IndexSet::new();
+
+        if let Some(sitemap) = &conf.sitemap {
+            let locs = parse_sitemap(&read_sitemap(&conf, sitemap)?)
+                .map_err(|e| anyhow!("Malformed sitemap {sitemap}: {e}"))?;
+            links.extend(locs.iter().map(|loc| sitemap_link(&conf.url, loc)));
+        }
+
+        let mut index = conf.url.clone();
+        index.push_str("/index.html");
+        links.insert(index);
+
+        for path in &conf.vitepress_links {
+            let vp: VitePressLinks =
+                serde_json::from_str::<VitePressLinks>(&fs::read_to_string(path)?)?;
+            build_links(&vp, &conf.url, &mut links, &mut conf.page_titles);
+            build_chapter_starts(&vp, &conf.url, &mut conf.chapter_starts);
+        }
+
+        let total = links.len();
+        conf.exclude_regexes = compile_patterns("exclude", &conf.exclude)?;
+        conf.console_ignore_regexes = compile_patterns("console_ignore", &conf.console_ignore)?;
+        let (links, excluded) = exclude_urls(&conf.exclude, links)?;
+        if !conf.exclude.is_empty() {
+            eprintln!("Excluded {} of {total} URLs", excluded.len());
+        }
+        conf.excluded = excluded;
+
+        conf.urls = order_urls(&conf.order, links)?;
+
+        // The cover is rendered ahead of the other pages, not as one of them
+        if let Some(cover) = &conf.cover_url {
+            let cover = conf.resolve_link(cover);
+            conf.urls.shift_remove(&cover);
+            conf.cover_url = Some(cover);
+        }
+
+        conf.overrides = std::mem::take(&mut conf.overrides)
+            .into_iter()
+            .map(|(path, o)| (normalize_link(&conf.url, &path), o))
+            .collect();
+
+        Ok(conf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use indexmap::indexset;
+    use std::io::Write as _;
+
+    const BASE: &str = r#"
+[config]
+output_pdf = "out.pdf"
+url = "http://localhost:5173"
+vitepress_links = []
+"#;
+
+    // Write `extra` config keys after the base config and load it
+    fn load(extra: &str) -> Result<Config> {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        write!(file, "{BASE}{extra}\n[config.print_to_pdf]\n").unwrap();
+        Config::load(&file.path().to_path_buf())
+    }
+
+    #[test]
+    fn test_navigation_timeout() {
+        assert_eq!(load("").unwrap().navigation_timeout_secs, None);
+        assert_eq!(
+            load("navigation_timeout_secs = 90")
+                .unwrap()
+                .navigation_timeout_secs,
+            Some(90)
+        );
+        assert!(load("navigation_timeout_secs = 0").is_err());
+    }
+
+    #[test]
+    fn test_expand_details() {
+        let conf = load("").unwrap();
+        assert!(!conf.expand_details);
+        assert!(load("expand_selector = '.accordion > button'").is_err());
+        let conf = load("expand_details = true\nexpand_selector = '.accordion > button'").unwrap();
+        assert_eq!(conf.expand_selector.as_deref(), Some(".accordion > button"));
+        assert!(load("expand_details = true\nexpand_selector = ' '").is_err());
+    }
+
+    #[test]
+    fn test_settle() {
+        assert!(load("").unwrap().settle.is_none());
+        let conf = load("[config.settle]\nnetwork_idle_ms = 500").unwrap();
+        assert_eq!(conf.settle.unwrap().network_idle_ms, Some(500));
+        assert!(load("[config.settle]\ndelay_ms = 1000").is_ok());
+        assert!(load("[config.settle]").is_err());
+        assert!(load("[config.settle]\ndelay_ms = 1000\nnetwork_idle_ms = 500").is_err());
+        assert!(load("[config.settle]\ndelay_ms = 0").is_err());
+        // Bounded by the navigation timeout
+        assert!(load("[config.settle]\ndelay_ms = 20000").is_err());
+        assert!(load("navigation_timeout_secs = 60\n[config.settle]\ndelay_ms = 20000").is_ok());
+    }
+
+    #[test]
+    fn test_sitemap() {
+        let xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+<urlset xmlns="http://www.sitemaps.org/schemas/sitemap/0.9">
+  <url><loc>https://docs.example.com/guide/setup</loc><lastmod>2024-08-01</lastmod></url>
+  <url>
+    <loc>https://docs.example.com/api/</loc>
+  </url>
+  <url><loc>https://docs.example.com/search?q=a&amp;b</loc></url>
+</urlset>"#;
+        assert_eq!(
+            parse_sitemap(xml).unwrap(),
+            vec![
+                "https://docs.example.com/guide/setup",
+                "https://docs.example.com/api/",
+                "https://docs.example.com/search?q=a&b",
+            ]
+        );
+        assert_eq!(
+            sitemap_link("http://localhost:5173", "https://docs.example.com/api/"),
+            "http://localhost:5173/api/index.html"
+        );
+        assert_eq!(
+            sitemap_link("http://localhost:5173", "https://docs.example.com"),
+            "http://localhost:5173/index.html"
+        );
+
+        // Commented out entries and CDATA are XML, not text
+        assert_eq!(
+            parse_sitemap(
+                "<urlset><!-- <url><loc>/old</loc></url> --><url><loc><![CDATA[/a&b]]></loc></url></urlset>"
+            )
+            .unwrap(),
+            vec!["/a&b"]
+        );
+        assert!(parse_sitemap("<html></html>").is_err());
+        assert!(parse_sitemap("<urlset><url><loc>/a</loc></urlset>").is_err());
+        assert!(parse_sitemap("<urlset><url><lastmod/></url></urlset>").is_err());
+
+        let mut sitemap = tempfile::NamedTempFile::new().unwrap();
+        write!(sitemap, "{xml}").unwrap();
+        let conf = load(&format!("sitemap = {:?}", sitemap.path())).unwrap();
+        assert_eq!(
+            conf.urls.into_iter().collect::<Vec<_>>(),
+            vec![
+                "http://localhost:5173/guide/setup.html",
+                "http://localhost:5173/api/index.html",
+                "http://localhost:5173/search.html",
+                "http://localhost:5173/index.html",
+            ]
+        );
+
+        let mut malformed = tempfile::NamedTempFile::new().unwrap();
+        write!(malformed, "<urlset><url>").unwrap();
+        let err = load(&format!("sitemap = {:?}", malformed.path()))
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains(&*malformed.path().to_string_lossy()));
+    }
+
+    // `Config::load` fetches a sitemap URL without blocking the caller's runtime, which panics
+    // on the current_thread runtime `tokio::test` uses
+    #[cfg(feature = "render")]
+    #[tokio::test]
+    async fn test_sitemap_url() {
+        let site = tempfile::tempdir().unwrap();
+        fs::write(
+            site.path().join("sitemap.xml"),
+            "<urlset><url><loc>https://docs.example.com/guide/setup</loc></url></urlset>",
+        )
+        .unwrap();
+        let server = crate::serve::StaticServer::start(site.path(), "").unwrap();
+        let conf = load(&format!("sitemap = '{}/sitemap.xml'", server.url())).unwrap();
+        assert!(conf.urls.contains("http://localhost:5173/guide/setup.html"));
+    }
+
+    #[cfg(feature = "render")]
+    #[test]
+    fn test_template_files() {
+        let mut footer = tempfile::NamedTempFile::new().unwrap();
+        write!(
+            footer,
+            "<div style=\"font-size: 8px\">\n  <span class=\"title\"></span> <span class=\"date\"></span>\n</div>"
+        )
+        .unwrap();
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        write!(
+            file,
+            "{BASE}footer_template_file = {:?}\n[config.print_to_pdf]\nfooterTemplate = \"<span></span>\"\n",
+            footer.path()
+        )
+        .unwrap();
+        let conf = Config::load(&file.path().to_path_buf()).unwrap();
+        let options = conf.print_to_pdf;
+        assert!(options
+            .footer_template
+            .unwrap()
+            .contains("<span class=\"title\"></span>"));
+        assert_eq!(options.header_template, None);
+        assert_eq!(options.display_header_footer, Some(true));
+
+        assert!(load("header_template_file = \"missing.html\"").is_err());
+        assert_eq!(load("").unwrap().print_to_pdf.display_header_footer, None);
+    }
+
+    #[test]
+    fn test_offline() {
+        assert!(!load("").unwrap().offline);
+        assert!(
+            load("offline = true\nchrome_version = '1336641'")
+                .unwrap()
+                .offline
+        );
+    }
+
+    #[test]
+    fn test_chrome_mirror() {
+        let conf = load(
+            "chrome_versions_url = 'https://mirror.corp/cft/known-good-versions.json'\n\
+             chrome_download_url_template = 'https://mirror.corp/snapshots/{platform}/{revision}/chrome.zip'",
+        )
+        .unwrap();
+        assert_eq!(
+            conf.chrome_versions_url.as_deref(),
+            Some("https://mirror.corp/cft/known-good-versions.json")
+        );
+        assert!(load("chrome_versions_url = 'mirror.corp/versions.json'").is_err());
+        assert!(load("chrome_download_url_template = 'https://mirror.corp/chrome.zip'").is_err());
+        assert!(load("chrome_download_url_template = 'ftp://mirror.corp/{revision}.zip'").is_err());
+    }
+
+    #[test]
+    fn test_chrome_args() {
+        assert!(load("").unwrap().chrome_args.is_empty());
+        assert_eq!(
+            load("chrome_args = ['--no-sandbox', '--window-size=1280,800']")
+                .unwrap()
+                .chrome_args,
+            vec!["--no-sandbox", "--window-size=1280,800"]
+        );
+        assert!(load("chrome_args = ['--headless=new']").is_err());
+        assert!(load("chrome_args = ['--proxy-server=http://proxy:3128']").is_err());
+        assert!(load("chrome_args = ['--ignore-certificate-errors']").is_err());
+        assert!(load("chrome_args = ['']").is_err());
+    }
+
+    #[test]
+    fn test_proxy() {
+        let conf =
+            load("proxy = 'http://proxy.corp:3128'\nno_proxy = ['localhost', '.corp.example.com']")
+                .unwrap();
+        assert_eq!(conf.proxy.as_deref(), Some("http://proxy.corp:3128"));
+        assert_eq!(conf.no_proxy, vec!["localhost", ".corp.example.com"]);
+        assert!(load("proxy = ''").is_err());
+    }
+
+    #[test]
+    fn test_user_agent() {
+        assert_eq!(load("").unwrap().user_agent, None);
+        assert_eq!(
+            load("user_agent = 'Mozilla/5.0 (X11; Linux x86_64) DocsExport/1.0'")
+                .unwrap()
+                .user_agent
+                .as_deref(),
+            Some("Mozilla/5.0 (X11; Linux x86_64) DocsExport/1.0")
+        );
+        assert!(load("user_agent = ' '").is_err());
+        assert!(load(r#"user_agent = "a\nb""#).is_err());
+    }
+
+    #[test]
+    fn test_dev_server() {
+        assert!(load("").unwrap().dev_server.is_none());
+        let conf =
+            load("[config.dev_server]\ncommand = 'npx vitepress dev docs --port 0'").unwrap();
+        let dev_server = conf.dev_server.unwrap();
+        assert_eq!(dev_server.port, None);
+        assert_eq!(dev_server.startup_timeout_secs, 60);
+        assert_eq!(dev_server.shutdown_timeout_secs, 10);
+
+        assert!(load("[config.dev_server]\ncommand = ' '").is_err());
+        assert!(load("[config.dev_server]\ncommand = 'vitepress dev'\nport = 0").is_err());
+        assert!(
+            load("[config.dev_server]\ncommand = 'vitepress dev'\nworking_dir = 'missing'")
+                .is_err()
+        );
+        assert!(load("serve_dir = '.'\n[config.dev_server]\ncommand = 'vitepress dev'").is_err());
+    }
+
+    #[test]
+    fn test_serve_dir() {
+        let dist = tempfile::tempdir().unwrap();
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        write!(
+            file,
+            "[config]\noutput_pdf = 'out.pdf'\nserve_dir = {:?}\n[config.print_to_pdf]\n[config.overrides.'/guide']\n",
+            dist.path()
+        )
+        .unwrap();
+        let mut conf = Config::load(&file.path().to_path_buf()).unwrap();
+        assert_eq!(conf.url, "");
+
+        conf.rebase_url("http://127.0.0.1:40123");
+        assert_eq!(
+            conf.urls.iter().collect::<Vec<_>>(),
+            vec!["http://127.0.0.1:40123/index.html"]
+        );
+        assert!(conf
+            .url_override("http://127.0.0.1:40123/guide.html")
+            .is_some());
+
+        assert_eq!(
+            split_origin("http://localhost:5173/docs/guide.html"),
+            ("http://localhost:5173", "/docs/guide.html")
+        );
+        assert_eq!(
+            split_origin("http://localhost:5173"),
+            ("http://localhost:5173", "")
+        );
+        assert_eq!(split_origin("/docs"), ("", "/docs"));
+
+        assert!(load("serve_dir = 'missing'").is_err());
+    }
+
+    #[test]
+    fn test_console_ignore() {
+        let conf = load("console_ignore = ['favicon\\.ico', '^warning: ']").unwrap();
+        assert!(conf.is_console_ignored(
+            "error: Failed to load resource: 404 (http://localhost:5173/favicon.ico)"
+        ));
+        assert!(conf.is_console_ignored("warning: deprecated API"));
+        assert!(!conf.is_console_ignored("error: Uncaught TypeError"));
+        assert!(load("console_ignore = ['(']").is_err());
+    }
+
+    #[test]
+    fn test_render_cache() {
+        assert!(!load("").unwrap().render_cache);
+        assert!(load("render_cache = true").unwrap().render_cache);
+        assert!(load("render_cache = true\ncrawl = true").is_err());
+    }
+
+    #[test]
+    fn test_ignore_pdf_export_meta() {
+        assert!(!load("").unwrap().ignore_pdf_export_meta);
+        assert!(
+            load("ignore_pdf_export_meta = true")
+                .unwrap()
+                .ignore_pdf_export_meta
+        );
+    }
+
+    #[test]
+    fn test_export_query() {
+        let conf = load("export_query = 'pdf-export=1'\nexport_class = 'pdf-export'").unwrap();
+        assert_eq!(conf.export_class.as_deref(), Some("pdf-export"));
+        assert_eq!(
+            conf.export_url("http://localhost:5173/guide.html"),
+            "http://localhost:5173/guide.html?pdf-export=1"
+        );
+        assert_eq!(
+            conf.export_url("http://localhost:5173/guide.html?tab=npm#install"),
+            "http://localhost:5173/guide.html?tab=npm&pdf-export=1#install"
+        );
+        assert_eq!(
+            conf.strip_export_query("http://localhost:5173/guide.html?pdf-export=1#install"),
+            "http://localhost:5173/guide.html#install"
+        );
+        assert_eq!(
+            conf.strip_export_query("http://localhost:5173/guide.html?tab=npm&pdf-export=1"),
+            "http://localhost:5173/guide.html?tab=npm"
+        );
+        assert_eq!(
+            conf.strip_export_query("http://localhost:5173/guide.html?pdf-export=10"),
+            "http://localhost:5173/guide.html?pdf-export=10"
+        );
+
+        let conf = load("").unwrap();
+        assert_eq!(
+            conf.export_url("http://localhost:5173/"),
+            "http://localhost:5173/"
+        );
+        assert!(load("export_query = 'a=1&b=2'").is_err());
+        assert!(load("export_class = 'pdf export'").is_err());
+    }
+
+    #[test]
+    fn test_checks_external_link() {
+        let conf =
+            load("check_external_links = true\nskip_external_link_hosts = ['flaky.example.org']")
+                .unwrap();
+        assert!(conf.checks_external_link("https://github.com/whamcloud"));
+        assert!(conf.checks_external_link("http://user@GitHub.com:8080/x"));
+        assert!(conf.checks_external_link("https://example.org/"));
+        assert!(!conf.checks_external_link("https://flaky.example.org/page"));
+        assert!(!conf.checks_external_link("https://api.flaky.example.org"));
+        assert!(!conf.checks_external_link("mailto:support@ddn.com"));
+        assert!(!conf.checks_external_link(&format!("{}/guide.html", conf.url)));
+
+        let conf =
+            load("check_external_links = true\nexternal_link_hosts = ['github.com']").unwrap();
+        assert!(conf.checks_external_link("https://docs.github.com/en"));
+        assert!(!conf.checks_external_link("https://notgithub.com"));
+        assert!(!conf.checks_external_link("https://example.org/"));
+
+        assert!(!load("").unwrap().checks_external_link("https://github.com"));
+        assert!(load("external_link_hosts = ['https://github.com']").is_err());
+        assert!(load("skip_external_link_hosts = ['']").is_err());
+    }
+
+    #[test]
+    fn test_chapter_starts() {
+        // Groups without a page start at their first page
+        let vp: VitePressLinks = serde_json::from_str(
+            r#"{"link": "", "items": [
+                {"link": "", "text": "Guide", "items": [{"link": "/guide/setup"}, {"link": "/guide/use"}]},
+                {"link": "/reference/", "items": [{"link": "/reference/api"}]}
+            ]}"#,
+        )
+        .unwrap();
+        let mut starts = IndexSet::new();
+        build_chapter_starts(&vp, "http://localhost", &mut starts);
+        assert_eq!(
+            starts,
+            indexset! {
+                "http://localhost/guide/setup.html".to_string(),
+                "http://localhost/reference/index.html".to_string(),
+            }
+        );
+
+        assert_eq!(load("").unwrap().chapter_breaks, ChapterBreaks::None);
+        assert!(load("chapter_breaks = 'odd'").is_err());
+        assert!(load("chapter_breaks = 'even'").is_err());
+    }
+
+    #[test]
+    fn test_broken_links() {
+        assert_eq!(load("").unwrap().broken_links, BrokenLinks::Keep);
+        assert_eq!(
+            load("broken_links = 'remove'").unwrap().broken_links,
+            BrokenLinks::Remove
+        );
+        assert!(load("broken_links = 'point-to-site'").is_err());
+
+        let conf = load("broken_links = 'point-to-site'\npublic_url = 'https://docs.example.com/'")
+            .unwrap();
+        assert_eq!(
+            conf.public_link("http://localhost:5173/guide/setup.html#install")
+                .as_deref(),
+            Some("https://docs.example.com/guide/setup.html#install")
+        );
+        assert_eq!(conf.public_link("https://github.com/whamcloud"), None);
+    }
+
+    #[test]
+    fn test_watermark() {
+        assert!(load("").unwrap().watermark.is_none());
+        let watermark = load("[config.watermark]\ntext = 'DRAFT'")
+            .unwrap()
+            .watermark
+            .unwrap();
+        assert_eq!(watermark.font, "Helvetica");
+        assert_eq!((watermark.rotation, watermark.opacity), (45.0, 0.3));
+        assert!(!watermark.under);
+        assert!(load("[config.watermark]\ntext = 'DRAFT'\nopacity = 1.5").is_err());
+        assert!(load("[config.watermark]\ntext = 'DRAFT'\nfont = 'Arial'").is_err());
+        assert!(load("[config.watermark]\nopacity = 0.5").is_err());
+
+        let image = tempfile::NamedTempFile::new().unwrap();
+        let both = format!(
+            "[config.watermark]\ntext = 'DRAFT'\nimage = {:?}",
+            image.path()
+        );
+        assert!(load(&both).is_err());
+        let watermark = load(&format!(
+            "[config.watermark]\nimage = {:?}\nposition = 'bottom-right'",
+            image.path()
+        ))
+        .unwrap()
+        .watermark
+        .unwrap();
+        assert_eq!(watermark.position, WatermarkPosition::BottomRight);
+        assert!(load("[config.watermark]\nimage = '/nonexistent.png'").is_err());
+    }
+
+    #[test]
+    fn test_encryption() {
+        assert!(load("").unwrap().encryption.is_none());
+        let conf =
+            load("[config.encryption]\nowner_password = \"owner\"\nallow_copy = false").unwrap();
+        let encryption = conf.encryption.unwrap();
+        assert_eq!(encryption.user_password, "");
+        assert!(encryption.allow_print && encryption.allow_modify);
+        assert!(!encryption.allow_copy);
+        assert!(load("[config.encryption]\nuser_password = \"user\"").is_err());
+        assert!(load("[config.encryption]\nowner_password = \"\"").is_err());
+        assert!(load("[config.encryption]\nowner_password = \"pässword\"").is_err());
+        let long = "x".repeat(33);
+        assert!(load(&format!("[config.encryption]\nowner_password = \"{long}\"")).is_err());
+    }
+
+    #[test]
+    fn test_optimize_images() {
+        assert!(load("").unwrap().optimize_images.is_none());
+        let conf = load("[config.optimize_images]").unwrap();
+        let options = conf.optimize_images.unwrap();
+        assert_eq!((options.max_dpi, options.quality), (150.0, 80));
+        assert!(!options.transparent);
+        assert!(load("[config.optimize_images]\nmax_dpi = 0").is_err());
+        assert!(load("[config.optimize_images]\nquality = 0").is_err());
+        assert!(load("[config.optimize_images]\nquality = 101").is_err());
+    }
+
+    #[test]
+    fn test_crawl() {
+        let conf = load("crawl = true").unwrap();
+        assert!(conf.crawl);
+        assert_eq!(conf.crawl_max_pages, 1000);
+        assert!(load("crawl = true\ncrawl_max_pages = 0").is_err());
+    }
+
+    #[test]
+    fn test_browser_instances() {
+        assert_eq!(load("").unwrap().browser_instances, 1);
+        assert_eq!(load("browser_instances = 4").unwrap().browser_instances, 4);
+        assert!(load("browser_instances = 0").is_err());
+    }
+
+    #[test]
+    fn test_browser_recycle_every() {
+        assert_eq!(load("").unwrap().browser_recycle_every, None);
+        assert_eq!(
+            load("browser_recycle_every = 100")
+                .unwrap()
+                .browser_recycle_every,
+            Some(100)
+        );
+        assert!(load("browser_recycle_every = 0").is_err());
+    }
+
+    #[test]
+    fn test_render_deadline() {
+        assert_eq!(load("").unwrap().render_deadline_secs, None);
+        assert_eq!(
+            load("render_deadline_secs = 1800")
+                .unwrap()
+                .render_deadline_secs,
+            Some(1800)
+        );
+        assert!(load("render_deadline_secs = 0").is_err());
+    }
+
+    #[test]
+    fn test_min_interval_ms() {
+        assert_eq!(load("").unwrap().min_interval_ms, 0);
+        assert_eq!(load("min_interval_ms = 500").unwrap().min_interval_ms, 500);
+    }
+
+    #[test]
+    fn test_max_chrome_relaunches() {
+        assert_eq!(load("").unwrap().max_chrome_relaunches, 3);
+        assert_eq!(
+            load("max_chrome_relaunches = 0")
+                .unwrap()
+                .max_chrome_relaunches,
+            0
+        );
+    }
+
+    #[test]
+    fn test_max_memory() {
+        assert_eq!(load("").unwrap().max_memory_mb, None);
+        assert_eq!(
+            load("max_memory_mb = 3072").unwrap().max_memory_mb,
+            Some(3072)
+        );
+        assert!(load("max_memory_mb = 0").is_err());
+    }
+
+    #[test]
+    fn test_max_cpu() {
+        assert_eq!(load("").unwrap().max_cpu, None);
+        assert_eq!(load("max_cpu = 2.5").unwrap().max_cpu, Some(2.5));
+        assert_eq!(load("max_cpu = 2").unwrap().max_cpu, Some(2.0));
+        assert!(load("max_cpu = 0").is_err());
+        assert!(load("max_cpu = -1.0").is_err());
+    }
+
+    #[test]
+    fn test_output_pdf_template() {
+        let conf = load("[config.variables]\nversion = '1.2'\nlocale = 'de'").unwrap();
+        assert_eq!(
+            conf.expand("dist/manual-{version}-{locale}.pdf").unwrap(),
+            "dist/manual-1.2-de.pdf"
+        );
+        assert_eq!(
+            conf.expand("{date}.pdf").unwrap(),
+            format!("{}.pdf", chrono::Local::now().format("%Y-%m-%d"))
+        );
+        assert!(conf.expand("manual-{profile}.pdf").is_err());
+        assert!(load("output_pdf = 'manual-{nope}.pdf'").is_err());
+    }
+
+    #[test]
+    fn test_cookies() {
+        let conf = load(
+            "[[config.cookies]]\nname = 'session'\nvalue = 'abc'\ndomain = 'docs.example.com'",
+        )
+        .unwrap();
+        assert_eq!(conf.cookies[0].path, "/");
+
+        assert!(
+            load("[[config.cookies]]\nname = ''\nvalue = 'abc'\ndomain = 'example.com'").is_err()
+        );
+        assert!(load("[[config.cookies]]\nname = 'session'\nvalue = 'abc'").is_err());
+    }
+
+    #[test]
+    fn test_extra_headers() {
+        let conf =
+            load("[config.extra_headers]\nX-Preview-Token = 'abc'\nAccept-Language = 'de-DE'")
+                .unwrap();
+        assert_eq!(conf.extra_headers["X-Preview-Token"], "abc");
+
+        assert!(load("[config.extra_headers]\n'X-Preview Token' = 'abc'").is_err());
+        assert!(load(
+            r#"[config.extra_headers]
+"X-Token\u0007" = 'abc'"#
+        )
+        .is_err());
+        assert!(load(
+            r#"[config.extra_headers]
+X-Token = "abc\r\nX-Evil: 1""#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_allow_status() {
+        assert!(load("").unwrap().allow_status.is_empty());
+        assert_eq!(
+            load("allow_status = [404, 503]").unwrap().allow_status,
+            vec![404, 503]
+        );
+        assert!(load("allow_status = [200]").is_err());
+        assert!(load("allow_status = [600]").is_err());
+    }
+
+    #[test]
+    fn test_http_auth() {
+        std::env::set_var("VITEPRESS_PDF_TEST_PASSWORD", "hunter2");
+        let conf = load(
+            "[config.http_auth]\nusername = 'docs'\npassword_env = 'VITEPRESS_PDF_TEST_PASSWORD'",
+        )
+        .unwrap();
+        assert_eq!(conf.http_auth.unwrap().password.as_deref(), Some("hunter2"));
+
+        assert!(load("[config.http_auth]\nusername = 'docs'").is_err());
+        assert!(load(
+            "[config.http_auth]\nusername = 'docs'\npassword_env = 'VITEPRESS_PDF_TEST_UNSET'"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_overrides() {
+        let conf = load(
+            "wait_for_selector = '.content'\n[config.overrides.'/guide/setup']\nwait_for_selector = '.vp-doc'",
+        )
+        .unwrap();
+        assert_eq!(conf.wait_for_selector.as_deref(), Some(".content"));
+        let o = conf
+            .url_override("http://localhost:5173/guide/setup.html")
+            .unwrap();
+        assert_eq!(o.wait_for_selector.as_deref(), Some(".vp-doc"));
+        assert!(conf
+            .url_override("http://localhost:5173/index.html")
+            .is_none());
+    }
+
+    #[cfg(feature = "render")]
+    #[test]
+    fn test_print_to_pdf_override() {
+        let conf = load(
+            "paper = 'a4'\n[config.overrides.'/api/huge'.print_to_pdf]\nlandscape = true\nscale = 0.8",
+        )
+        .unwrap();
+        assert_eq!(conf.print_to_pdf.scale, None);
+        let options = conf
+            .url_override("http://localhost:5173/api/huge.html")
+            .and_then(|o| o.print_options.as_ref())
+            .unwrap();
+        assert_eq!(options.scale, Some(0.8));
+        // The paper preset is swapped for landscape like the global options
+        assert_eq!(
+            (options.paper_width, options.paper_height, options.landscape),
+            (Some(11.69), Some(8.27), None)
+        );
+
+        assert!(load("[config.overrides.'/api/huge'.print_to_pdf]\npageRanges = '3-1'").is_err());
+        assert!(load("[config.overrides.'/api/huge'.print_to_pdf]\nscale = 'big'").is_err());
+    }
+
+    #[test]
+    fn test_page_ranges() {
+        for ranges in ["1", "1-3", "1-5, 8, 11-13", "-3", "4-"] {
+            assert!(check_page_ranges(ranges).is_ok(), "{ranges}");
+        }
+        for ranges in ["", "0", "3-1", "a-b", "1,,2", "-"] {
+            assert!(check_page_ranges(ranges).is_err(), "{ranges}");
+        }
+
+        let conf = load("[config.overrides.'/api/huge']\npage_ranges = '1-3'").unwrap();
+        assert_eq!(
+            conf.url_override("http://localhost:5173/api/huge.html")
+                .unwrap()
+                .page_ranges
+                .as_deref(),
+            Some("1-3")
+        );
+        let err = load("[config.overrides.'/api/huge']\npage_ranges = '3-1'")
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("/api/huge"), "{err}");
+    }
+
+    #[test]
+    fn test_timezone_locale() {
+        let conf = load("timezone = 'Europe/Berlin'\nlocale = 'de-DE'").unwrap();
+        assert_eq!(conf.timezone.as_deref(), Some("Europe/Berlin"));
+        assert_eq!(conf.locale.as_deref(), Some("de-DE"));
+        assert!(load("timezone = ''").is_err());
+        assert!(load("locale = 'en US'").is_err());
+        assert!(load("timezone = 'Europe/Atlantis'").is_err());
+        assert!(load("locale = 'en_US'").is_err());
+        assert!(load("locale = 'e'").is_err());
+        assert!(load("locale = 'en-'").is_err());
+        assert!(load("locale = 'zh-Hant-TW'").is_ok());
+        assert!(load("timezone = 'UTC'").is_ok());
+    }
+
+    #[test]
+    fn test_cover_pdf() {
+        assert!(load("").unwrap().cover_pdf.is_none());
+        let cover = tempfile::NamedTempFile::new().unwrap();
+        let conf = load(&format!("cover_pdf = {:?}", cover.path())).unwrap();
+        assert_eq!(conf.cover_pdf.as_deref(), Some(cover.path()));
+        assert!(load("cover_pdf = 'missing.pdf'").is_err());
+    }
+
+    #[test]
+    fn test_cover_url() {
+        let conf = load("cover_url = '/pdf-cover'").unwrap();
+        assert_eq!(
+            conf.cover_url.as_deref(),
+            Some("http://localhost:5173/pdf-cover.html")
+        );
+        assert!(!conf.urls.contains("http://localhost:5173/pdf-cover.html"));
+
+        let cover = tempfile::NamedTempFile::new().unwrap();
+        assert!(load(&format!(
+            "cover_url = '/pdf-cover'\ncover_pdf = {:?}",
+            cover.path()
+        ))
+        .is_err());
+    }
+
+    #[test]
+    fn test_back_matter() {
+        let conf = load("").unwrap();
+        assert!(conf.back_matter.is_empty());
+        assert!(conf.number_back_matter);
+
+        let legal = tempfile::Builder::new().suffix(".pdf").tempfile().unwrap();
+        let index = tempfile::NamedTempFile::new().unwrap();
+        let conf = load(&format!(
+            "number_back_matter = false\nback_matter = [{{ path = {:?} }}, {{ path = {:?}, title = 'Index', bookmark = false }}]",
+            legal.path(),
+            index.path()
+        ))
+        .unwrap();
+        assert!(!conf.number_back_matter);
+        let stem = legal.path().file_stem().unwrap().to_string_lossy();
+        assert_eq!(conf.back_matter[0].title(), stem);
+        assert!(conf.back_matter[0].bookmark);
+        assert_eq!(conf.back_matter[1].title(), "Index");
+        assert!(!conf.back_matter[1].bookmark);
+
+        assert!(load("back_matter = [{ path = 'missing.pdf' }]").is_err());
+    }
+
+    #[test]
+    fn test_page_number_format() {
+        let page_number = "[config.page_number]\ncolor = { r = 0.0, g = 0.0, b = 0.0 }\nfont = 'Helvetica'\nsize = 12\nx = 1.0\ny = 1.0\n";
+        let style = load(page_number).unwrap().page_number.unwrap();
+        assert_eq!(style.text(3, 10).as_deref(), Some("Page 3"));
+        let style = load(&format!(
+            "{page_number}format = 'Seite {{page}} von {{total}}'"
+        ))
+        .unwrap()
+        .page_number
+        .unwrap();
+        assert_eq!(style.text(3, 10).as_deref(), Some("Seite 3 von 10"));
+        assert!(load(&format!("{page_number}format = '{{page}} / {{pages}}'")).is_err());
+        assert!(load(&format!("{page_number}format = '{{}}'")).is_err());
+
+        assert_eq!(style.align, Align::Left);
+        assert_eq!(style.x, 1.0);
+        let style = load(&format!("{page_number}align = 'center'"))
+            .unwrap()
+            .page_number
+            .unwrap();
+        assert_eq!(style.align, Align::Center);
+        assert!(load(&format!("{page_number}align = 'middle'")).is_err());
+        assert_eq!(style.y_from, YFrom::Top);
+        let style = load(&format!("{page_number}y_from = 'bottom'"))
+            .unwrap()
+            .page_number
+            .unwrap();
+        assert_eq!(style.y_from, YFrom::Bottom);
+        assert!(load(&format!("{page_number}y_from = 'left'")).is_err());
+    }
+
+    #[test]
+    fn test_page_titles() {
+        let vp: VitePressLinks = serde_json::from_str(
+            r#"{"link": "/", "text": "Home", "items": [
+                {"link": "/guide/", "text": "Guide", "items": [{"link": "/guide/setup"}]}
+            ]}"#,
+        )
+        .unwrap();
+        let mut links = IndexSet::new();
+        let mut titles = IndexMap::new();
+        build_links(
+            &vp,
+            &"http://localhost".to_string(),
+            &mut links,
+            &mut titles,
+        );
+        assert_eq!(
+            links,
+            indexset! {
+                "http://localhost/index.html".to_string(),
+                "http://localhost/guide/index.html".to_string(),
+                "http://localhost/guide/setup.html".to_string(),
+            }
+        );
+        assert_eq!(
+            titles,
+            IndexMap::from([
+                (
+                    "http://localhost/index.html".to_string(),
+                    "Home".to_string()
+                ),
+                (
+                    "http://localhost/guide/index.html".to_string(),
+                    "Guide".to_string()
+                ),
+            ])
+        );
+
+        let mut starts = IndexSet::new();
+        build_chapter_starts(&vp, "http://localhost", &mut starts);
+        assert_eq!(
+            starts,
+            indexset! {
+                "http://localhost/index.html".to_string(),
+                "http://localhost/guide/index.html".to_string(),
+            }
+        );
+
+        let mut conf = load("").unwrap();
+        conf.page_titles = titles;
+        conf.rebase_url("http://127.0.0.1:8080");
+        assert_eq!(
+            conf.page_titles
+                .get("http://127.0.0.1:8080/guide/index.html"),
+            Some(&"Guide".to_string())
+        );
+    }
+
+    #[test]
+    fn test_metadata() {
+        let conf = load("").unwrap();
+        assert!(conf.metadata.title.is_none());
+        assert!(conf.metadata.author.is_none());
+        let conf = load(
+            "[config.variables]\nversion = '2.15'\n[config.metadata]\ntitle = 'Manual {version}'\nauthor = 'Docs Team'\n",
+        )
+        .unwrap();
+        assert_eq!(conf.metadata.title.as_deref(), Some("Manual 2.15"));
+        assert_eq!(conf.metadata.author.as_deref(), Some("Docs Team"));
+        assert!(load("[config.metadata]\ntitle = 'Manual {missing}'\n").is_err());
+    }
+
+    #[test]
+    fn test_running_header() {
+        let header =
+            "[config.running_header]\ncolor = { r = 0.0, g = 0.0, b = 0.0 }\nsize = 10\ny = 0.5\n";
+        let style = load(header).unwrap().running_header.unwrap();
+        assert_eq!(style.font, "Helvetica");
+        assert_eq!(style.text("Installation", "Guide"), "Installation");
+        assert_eq!((style.align(1), style.align(2)), (Align::Left, Align::Left));
+
+        let style = load(&format!(
+            "{header}format = '{{chapter}} - {{title}}'\nalign = 'right'\nmirror = true"
+        ))
+        .unwrap()
+        .running_header
+        .unwrap();
+        assert_eq!(style.text("Installation", "Guide"), "Guide - Installation");
+        assert_eq!(
+            style.text("Using {chapter}", "Guide — Part 1"),
+            "Guide — Part 1 - Using {chapter}"
+        );
+        assert_eq!(
+            (style.align(1), style.align(2)),
+            (Align::Right, Align::Left)
+        );
+
+        assert!(load(&format!("{header}format = '{{page}}'")).is_err());
+        assert!(load(&format!("{header}font = 'Comic Sans'")).is_err());
+    }
+
+    #[test]
+    fn test_page_number_font_file() {
+        let page_number =
+            "[config.page_number]\ncolor = { r = 0.0, g = 0.0, b = 0.0 }\nsize = 12\ny = 1.0\n";
+        let style = load(page_number).unwrap().page_number.unwrap();
+        assert_eq!(style.font, "Helvetica");
+        assert!(style.font_file.is_none());
+
+        let mut font = tempfile::NamedTempFile::new().unwrap();
+        font.write_all(&crate::font::test_font()).unwrap();
+        let style = load(&format!("{page_number}font_file = {:?}", font.path()))
+            .unwrap()
+            .page_number
+            .unwrap();
+        assert_eq!(style.font_file.as_deref(), Some(font.path()));
+
+        let mut not_a_font = tempfile::NamedTempFile::new().unwrap();
+        not_a_font.write_all(b"not a font").unwrap();
+        assert!(load(&format!("{page_number}font_file = {:?}", not_a_font.path())).is_err());
+        assert!(load(&format!("{page_number}font_file = 'missing.ttf'")).is_err());
+    }
+
+    #[test]
+    fn test_front_matter() {
+        assert_eq!(
+            [1, 2, 3, 4, 9, 14, 40, 1994].map(roman),
+            ["i", "ii", "iii", "iv", "ix", "xiv", "xl", "mcmxciv"]
+        );
+
+        let page_number = "[config.page_number]\ncolor = { r = 0.0, g = 0.0, b = 0.0 }\nfont = 'Helvetica'\nsize = 12\nx = 1.0\ny = 1.0\nfront_matter_pages = 2\n";
+        let style = load(page_number).unwrap().page_number.unwrap();
+        assert_eq!(style.front_matter_style, FrontMatterStyle::Roman);
+        assert_eq!(
+            (1..=4).map(|n| style.label(n)).collect::<Vec<_>>(),
+            [Some("i"), Some("ii"), Some("1"), Some("2")].map(|l| l.map(str::to_string))
+        );
+        let style = load(&format!("{page_number}front_matter_style = 'none'"))
+            .unwrap()
+            .page_number
+            .unwrap();
+        assert_eq!(style.text(2, 5), None);
+        assert_eq!(style.text(3, 5).as_deref(), Some("Page 1"));
+        assert!(load(&format!("{page_number}front_matter_style = 'alpha'")).is_err());
+    }
+
+    #[test]
+    fn test_toc() {
+        assert!(load("").unwrap().toc.is_none());
+        let toc = load("[config.toc]").unwrap().toc.unwrap();
+        assert_eq!(
+            (
+                toc.title.as_str(),
+                toc.depth,
+                toc.font.as_str(),
+                toc.size,
+                toc.leader
+            ),
+            ("Contents", 2, "Helvetica", 11.0, Leader::Dots)
+        );
+        let toc = load("[config.toc]\ntitle = 'Table of Contents'\ndepth = 1\nleader = 'none'")
+            .unwrap()
+            .toc
+            .unwrap();
+        assert_eq!(
+            (toc.title.as_str(), toc.depth, toc.leader),
+            ("Table of Contents", 1, Leader::None)
+        );
+        assert!(load("[config.toc]\ndepth = 0").is_err());
+        assert!(load("[config.toc]\nsize = 0.0").is_err());
+        assert!(load("[config.toc]\nfont = 'Arial'").is_err());
+        assert!(load("[config.toc]\nleader = 'stars'").is_err());
+    }
+
+    #[cfg(feature = "render")]
+    #[test]
+    fn test_paper() {
+        for (paper, inches) in [
+            ("a4", (8.27, 11.69)),
+            ("a5", (5.83, 8.27)),
+            ("letter", (8.5, 11.0)),
+            ("legal", (8.5, 14.0)),
+        ] {
+            let options = load(&format!("paper = '{paper}'")).unwrap().print_to_pdf;
+            assert_eq!(
+                (options.paper_width, options.paper_height),
+                (Some(inches.0), Some(inches.1))
+            );
+        }
+        assert!(load("paper = 'a3'").is_err());
+
+        let mut options = PrintToPdfOptions {
+            landscape: Some(true),
+            ..Default::default()
+        };
+        apply_paper(Paper::A4, &mut options);
+        assert_eq!(
+            (options.paper_width, options.paper_height),
+            (Some(11.69), Some(8.27))
+        );
+        assert_eq!(options.landscape, None);
+
+        // Explicit sizes win and Chrome still handles landscape
+        let mut options = PrintToPdfOptions {
+            paper_width: Some(7.0),
+            landscape: Some(true),
+            ..Default::default()
+        };
+        apply_paper(Paper::A4, &mut options);
+        assert_eq!(
+            (options.paper_width, options.paper_height),
+            (Some(7.0), Some(11.69))
+        );
+        assert_eq!(options.landscape, Some(true));
+    }
+
+    #[test]
+    fn test_order() {
+        let urls = indexset! {
+            "http://localhost:5173/index.html".to_string(),
+            "http://localhost:5173/guide/install.html".to_string(),
+            "http://localhost:5173/guide/quick-start.html".to_string(),
+            "http://localhost:5173/api/index.html".to_string(),
+        };
+        let ordered = order_urls(
+            &["/quick-start".to_string(), "/api/".to_string()],
+            urls.clone(),
+        )
+        .unwrap();
+        assert_eq!(
+            ordered.into_iter().collect::<Vec<_>>(),
+            vec![
+                "http://localhost:5173/guide/quick-start.html",
+                "http://localhost:5173/api/index.html",
+                "http://localhost:5173/index.html",
+                "http://localhost:5173/guide/install.html",
+            ]
+        );
+
+        assert!(order_urls(&["/missing".to_string()], urls.clone()).is_err());
+        assert!(order_urls(&["(".to_string()], urls).is_err());
+        assert!(load("order = ['/missing']").is_err());
+    }
+
+    #[test]
+    fn test_replace_urls() {
+        let mut conf = load("").unwrap();
+        conf.replace_urls(
+            "# print CSS\n/guide/setup\n\n  guide/  \nhttp://localhost:5173/api.html\n/guide/setup.html\n",
+        );
+        assert_eq!(
+            conf.urls.into_iter().collect::<Vec<_>>(),
+            vec![
+                "http://localhost:5173/guide/setup.html",
+                "http://localhost:5173/guide/index.html",
+                "http://localhost:5173/api.html",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_exclude() {
+        let urls = indexset! {
+            "http://localhost:5173/index.html".to_string(),
+            "http://localhost:5173/changelog.html".to_string(),
+            "http://localhost:5173/drafts/internal.html".to_string(),
+            "http://localhost:5173/guide/install.html".to_string(),
+        };
+        let (kept, excluded) = exclude_urls(
+            &[
+                "/changelog".to_string(),
+                "/drafts/".to_string(),
+                "/typo".to_string(),
+            ],
+            urls.clone(),
+        )
+        .unwrap();
+        assert_eq!(
+            kept.into_iter().collect::<Vec<_>>(),
+            vec![
+                "http://localhost:5173/index.html",
+                "http://localhost:5173/guide/install.html",
+            ]
+        );
+        assert_eq!(
+            excluded.into_iter().collect::<Vec<_>>(),
+            vec![
+                "http://localhost:5173/changelog.html",
+                "http://localhost:5173/drafts/internal.html",
+            ]
+        );
+
+        assert!(exclude_urls(&["(".to_string()], urls).is_err());
+
+        let conf = load("exclude = ['/index.html$']").unwrap();
+        assert!(conf.urls.is_empty());
+        assert!(conf.excluded.contains("http://localhost:5173/index.html"));
+        assert!(conf.is_excluded("http://localhost:5173/guide/index.html"));
+        assert!(!conf.is_excluded("http://localhost:5173/guide/install.html"));
+    }
+
+    #[test]
+    fn test_viewport() {
+        let conf = load("[config.viewport]\nwidth = 1600\nheight = 900").unwrap();
+        assert_eq!(conf.viewport.unwrap().device_scale_factor, 1.0);
+
+        assert!(load("[config.viewport]\nwidth = 0\nheight = 900").is_err());
+        assert!(
+            load("[config.viewport]\nwidth = 1600\nheight = 900\ndevice_scale_factor = 0.0")
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_headless_mode() {
+        assert_eq!(load("").unwrap().headless_mode, HeadlessMode::Old);
+        assert_eq!(
+            load("headless_mode = 'new'").unwrap().headless_mode,
+            HeadlessMode::New
+        );
+        assert!(load("headless_mode = 'shell'").is_err());
+    }
+
+    #[test]
+    fn test_color_scheme() {
+        assert_eq!(load("").unwrap().color_scheme, None);
+        assert_eq!(
+            load("color_scheme = 'no-preference'").unwrap().color_scheme,
+            Some(ColorScheme::NoPreference)
+        );
+        assert!(load("color_scheme = 'sepia'").is_err());
+        assert!(load("color_scheme_local_storage = true").is_err());
+    }
+
+    #[test]
+    fn test_auto_scale() {
+        let conf =
+            load("[config.auto_scale]\npatterns = ['/api/']\nscales = [1.0, 0.9, 0.8]").unwrap();
+        let auto_scale = conf.auto_scale.unwrap();
+        assert!(auto_scale.matches("http://localhost:5173/api/index.html"));
+        assert!(!auto_scale.matches("http://localhost:5173/guide.html"));
+
+        assert!(load("[config.auto_scale]\npatterns = ['(']\nscales = [1.0]").is_err());
+        assert!(load("[config.auto_scale]\npatterns = []\nscales = [0.8, 0.9]").is_err());
+        assert!(load("[config.auto_scale]\npatterns = []\nscales = [3.0]").is_err());
+        assert!(load("[config.auto_scale]\npatterns = []\nscales = []").is_err());
     }
 }
@@ -0,0 +1,253 @@
+// Copyright (c) 2024 DDN. All rights reserved.
+// Use of this source code is governed by a MIT-style
+// license that can be found in the LICENSE file.
+
+use lopdf::{Dictionary, Document, Object, ObjectId};
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+};
+
+// Objects whose identity matters, sharing them would merge pages, annotations or structure
+const UNIQUE_TYPES: [&str; 11] = [
+    "Catalog",
+    "Pages",
+    "Page",
+    "Annot",
+    "Outlines",
+    "StructTreeRoot",
+    "StructElem",
+    "OBJR",
+    "MCR",
+    "ObjStm",
+    "XRef",
+];
+
+fn dict(object: &Object) -> Option<&Dictionary> {
+    match object {
+        Object::Dictionary(dict) => Some(dict),
+        Object::Stream(stream) => Some(&stream.dict),
+        _ => None,
+    }
+}
+
+// Whether `object` may be replaced by an identical one
+fn candidate(object: &Object) -> bool {
+    let Some(dict) = dict(object) else {
+        return true;
+    };
+    let unique_type = dict
+        .get(b"Type")
+        .and_then(Object::as_name_str)
+        .is_ok_and(|t| UNIQUE_TYPES.contains(&t));
+    // Annotations don't always have a Type, but always have a Rect
+    !unique_type && !dict.has(b"Rect")
+}
+
+// Hash `object` so that equal objects, with dictionary keys in any order, hash the same
+fn hash_object<H: Hasher>(object: &Object, h: &mut H) {
+    std::mem::discriminant(object).hash(h);
+    match object {
+        Object::Null => {}
+        Object::Boolean(b) => b.hash(h),
+        Object::Integer(i) => i.hash(h),
+        Object::Real(r) => r.to_bits().hash(h),
+        Object::Name(name) => name.hash(h),
+        Object::String(s, _) => s.hash(h),
+        Object::Array(array) => {
+            array.len().hash(h);
+            array.iter().for_each(|o| hash_object(o, h));
+        }
+        Object::Dictionary(dict) => hash_dict(dict, h),
+        Object::Stream(stream) => {
+            hash_dict(&stream.dict, h);
+            stream.content.hash(h);
+        }
+        Object::Reference(id) => id.hash(h),
+    }
+}
+
+fn hash_dict<H: Hasher>(dict: &Dictionary, h: &mut H) {
+    let mut entries: Vec<_> = dict.iter().collect();
+    entries.sort_by(|a, b| a.0.cmp(b.0));
+    entries.len().hash(h);
+    for (key, value) in entries {
+        key.hash(h);
+        hash_object(value, h);
+    }
+}
+
+fn same_object(a: &Object, b: &Object) -> bool {
+    match (a, b) {
+        (Object::Null, Object::Null) => true,
+        (Object::Boolean(a), Object::Boolean(b)) => a == b,
+        (Object::Integer(a), Object::Integer(b)) => a == b,
+        (Object::Real(a), Object::Real(b)) => a.to_bits() == b.to_bits(),
+        (Object::Name(a), Object::Name(b)) => a == b,
+        (Object::String(a, _), Object::String(b, _)) => a == b,
+        (Object::Reference(a), Object::Reference(b)) => a == b,
+        (Object::Array(a), Object::Array(b)) => {
+            a.len() == b.len() && a.iter().zip(b).all(|(a, b)| same_object(a, b))
+        }
+        (Object::Dictionary(a), Object::Dictionary(b)) => same_dict(a, b),
+        (Object::Stream(a), Object::Stream(b)) => {
+            a.content == b.content && same_dict(&a.dict, &b.dict)
+        }
+        _ => false,
+    }
+}
+
+fn same_dict(a: &Dictionary, b: &Dictionary) -> bool {
+    a.len() == b.len()
+        && a.iter()
+            .all(|(key, value)| b.get(key).is_ok_and(|other| same_object(value, other)))
+}
+
+// Point references to the IDs in `kept` at the IDs they map to
+pub(crate) fn replace_references(object: &mut Object, kept: &HashMap<ObjectId, ObjectId>) {
+    match object {
+        Object::Reference(id) => {
+            if let Some(kept_id) = kept.get(id) {
+                *id = *kept_id;
+            }
+        }
+        Object::Array(array) => array.iter_mut().for_each(|o| replace_references(o, kept)),
+        Object::Dictionary(dict) => dict
+            .iter_mut()
+            .for_each(|(_, o)| replace_references(o, kept)),
+        Object::Stream(stream) => stream
+            .dict
+            .iter_mut()
+            .for_each(|(_, o)| replace_references(o, kept)),
+        _ => {}
+    }
+}
+
+/// Replace objects which are identical to another, such as the web fonts every page embeds,
+/// with a single copy and return how many were deleted.
+///
+/// References are compared by object ID, so objects only become identical once the objects
+/// they refer to have been merged. Passes repeat until nothing changes, which merges trees
+/// from the leaves up and never merges objects in reference cycles.
+pub(crate) fn dedupe_objects(doc: &mut Document) -> usize {
+    let mut deleted = 0;
+    loop {
+        // The lowest ID of a set of identical objects is kept
+        let mut kept: HashMap<ObjectId, ObjectId> = HashMap::new();
+        let mut groups: HashMap<u64, Vec<ObjectId>> = HashMap::new();
+        for (id, object) in doc.objects.iter().filter(|(_, o)| candidate(o)) {
+            let mut h = DefaultHasher::new();
+            hash_object(object, &mut h);
+            let group = groups.entry(h.finish()).or_default();
+            match group
+                .iter()
+                .find(|other| same_object(&doc.objects[other], object))
+            {
+                Some(other) => {
+                    kept.insert(*id, *other);
+                }
+                None => group.push(*id),
+            }
+        }
+        if kept.is_empty() {
+            return deleted;
+        }
+
+        for id in kept.keys() {
+            doc.objects.remove(id);
+        }
+        for object in doc.objects.values_mut() {
+            replace_references(object, &kept);
+        }
+        for (_, object) in doc.trailer.iter_mut() {
+            replace_references(object, &kept);
+        }
+        deleted += kept.len();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lopdf::{dictionary, Stream};
+
+    #[test]
+    fn test_dedupe_objects() {
+        let mut doc = Document::with_version("1.7");
+        let pages_id = doc.new_object_id();
+        let mut kids = vec![];
+        let mut fonts = vec![];
+        let mut cycles = vec![];
+        for i in 0..3 {
+            let file_id = doc.add_object(Stream::new(dictionary! {}, vec![7; 64]));
+            let descriptor_id = doc.add_object(dictionary! {
+                "Type" => "FontDescriptor",
+                "FontFile2" => file_id,
+            });
+            // Keys in another order are the same dictionary
+            let font = match i {
+                1 => dictionary! {
+                    "FontDescriptor" => descriptor_id,
+                    "Subtype" => "TrueType",
+                    "Type" => "Font",
+                },
+                _ => dictionary! {
+                    "Type" => "Font",
+                    "Subtype" => "TrueType",
+                    "FontDescriptor" => descriptor_id,
+                },
+            };
+            let font_id = doc.add_object(font);
+            fonts.push(font_id);
+
+            // Identical pages stay separate pages
+            let page_id = doc.add_object(dictionary! {
+                "Type" => "Page",
+                "Parent" => pages_id,
+                "Resources" => dictionary! {
+                    "Font" => dictionary! { "F1" => font_id },
+                },
+            });
+            kids.push(page_id.into());
+
+            // Objects referring to each other are left alone
+            let a_id = doc.new_object_id();
+            let b_id = doc.add_object(dictionary! { "Next" => a_id });
+            doc.objects
+                .insert(a_id, Object::Dictionary(dictionary! { "Next" => b_id }));
+            cycles.push(a_id);
+        }
+        doc.objects.insert(
+            pages_id,
+            Object::Dictionary(dictionary! {
+                "Type" => "Pages",
+                "Kids" => kids,
+                "Count" => 3,
+            }),
+        );
+        let catalog_id = doc.add_object(dictionary! {
+            "Type" => "Catalog",
+            "Pages" => pages_id,
+        });
+        doc.trailer.set("Root", catalog_id);
+
+        // Each of the two fonts after the first has a file, descriptor and font dictionary
+        assert_eq!(dedupe_objects(&mut doc), 6);
+        assert_eq!(doc.get_pages().len(), 3);
+        for page_id in doc.page_iter() {
+            let font = doc
+                .get_dictionary(page_id)
+                .unwrap()
+                .get(b"Resources")
+                .and_then(Object::as_dict)
+                .and_then(|r| r.get(b"Font"))
+                .and_then(Object::as_dict)
+                .and_then(|f| f.get(b"F1"))
+                .and_then(Object::as_reference)
+                .unwrap();
+            assert_eq!(font, fonts[0]);
+        }
+        assert!(cycles.iter().all(|id| doc.objects.contains_key(id)));
+        assert_eq!(dedupe_objects(&mut doc), 0);
+    }
+}
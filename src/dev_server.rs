@@ -0,0 +1,251 @@
+// Copyright (c) 2024 DDN. All rights reserved.
+// Use of this source code is governed by a MIT-style
+// license that can be found in the LICENSE file.
+
+use crate::config::{split_origin, DevServer};
+use anyhow::{anyhow, Result};
+use regex::Regex;
+use std::{
+    io::{BufRead as _, BufReader},
+    process::{Child, Command, Stdio},
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
+};
+
+/// The `dev_server` command, running until dropped.
+///
+/// The command runs in its own process group on unix, dropping sends the group SIGTERM
+/// and SIGKILL once `shutdown_timeout_secs` has passed, so servers started by `npx`
+/// or a shell go away too.
+pub struct DevServerProcess {
+    child: Child,
+    shutdown_timeout: Duration,
+    url: String,
+}
+
+impl DevServerProcess {
+    /// Start `conf.command` and wait until `url`, moved to the server's port, returns 200 OK
+    pub fn start(conf: &DevServer, url: &str) -> Result<Self> {
+        let timeout = Duration::from_secs(conf.startup_timeout_secs);
+        let start = Instant::now();
+
+        #[cfg(unix)]
+        let mut command = {
+            use std::os::unix::process::CommandExt as _;
+            let mut command = Command::new("sh");
+            command.arg("-c").arg(&conf.command).process_group(0);
+            command
+        };
+        #[cfg(not(unix))]
+        let mut command = {
+            let mut command = Command::new("cmd");
+            command.arg("/C").arg(&conf.command);
+            command
+        };
+        if let Some(dir) = &conf.working_dir {
+            command.current_dir(dir);
+        }
+        let mut child = command
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|e| anyhow!("Unable to start dev_server {}: {e}", conf.command))?;
+
+        // Keep reading so the server never blocks on a full pipe
+        let stdout = child.stdout.take();
+        let (port_tx, port_rx) = mpsc::channel();
+        thread::spawn(move || {
+            let Some(stdout) = stdout else {
+                return;
+            };
+            let mut port_tx = Some(port_tx);
+            for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+                if let (Some(tx), Some(port)) = (&port_tx, detect_port(&line)) {
+                    let _ = tx.send(port);
+                    port_tx = None;
+                }
+            }
+        });
+
+        let mut server = DevServerProcess {
+            child,
+            shutdown_timeout: Duration::from_secs(conf.shutdown_timeout_secs),
+            url: url.to_string(),
+        };
+
+        let port = match conf.port {
+            Some(port) => port,
+            None => loop {
+                server.check_running()?;
+                match port_rx.recv_timeout(Duration::from_millis(100)) {
+                    Ok(port) => break port,
+                    Err(_) if start.elapsed() >= timeout => {
+                        return Err(anyhow!(
+                        "dev_server didn't print a localhost URL within {} s, set dev_server.port",
+                        timeout.as_secs()
+                    ))
+                    }
+                    Err(_) => {}
+                }
+            },
+        };
+        server.url = with_port(url, port);
+
+        // Any response but 200 means the server isn't serving the site yet
+        loop {
+            server.check_running()?;
+            let ready = ureq::get(&server.url)
+                .timeout(Duration::from_secs(2))
+                .call()
+                .is_ok_and(|r| r.status() == 200);
+            if ready {
+                return Ok(server);
+            }
+            if start.elapsed() >= timeout {
+                return Err(anyhow!(
+                    "dev_server didn't answer {} with 200 OK within {} s",
+                    server.url,
+                    timeout.as_secs()
+                ));
+            }
+            thread::sleep(Duration::from_millis(250));
+        }
+    }
+
+    /// `url` on the port the server listens on
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    fn check_running(&mut self) -> Result<()> {
+        match self.child.try_wait()? {
+            Some(status) => Err(anyhow!("dev_server exited before it was ready: {status}")),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for DevServerProcess {
+    #[cfg(unix)]
+    fn drop(&mut self) {
+        // The child leads its process group, a negative pid signals the whole group
+        let group = -(self.child.id() as libc::pid_t);
+        // SAFETY: kill has no memory safety requirements
+        unsafe { libc::kill(group, libc::SIGTERM) };
+        let start = Instant::now();
+        while start.elapsed() < self.shutdown_timeout {
+            if !matches!(self.child.try_wait(), Ok(None)) {
+                break;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        // Also reaps whatever is left of the group
+        unsafe { libc::kill(group, libc::SIGKILL) };
+        let _ = self.child.wait();
+    }
+
+    #[cfg(not(unix))]
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+// The port of the first localhost URL in a line of server output, e.g. Vite's
+// `➜  Local:   http://localhost:5173/`, which is printed with the port in bold.
+fn detect_port(line: &str) -> Option<u16> {
+    let ansi = Regex::new(r"\x1b\[[0-9;]*[A-Za-z]").ok()?;
+    let local = Regex::new(r"https?://(?:localhost|127\.0\.0\.1|\[::1\]):(\d+)").ok()?;
+    let line = ansi.replace_all(line, "");
+    local.captures(&line)?.get(1)?.as_str().parse().ok()
+}
+
+// `url` with its port replaced by `port`
+fn with_port(url: &str, port: u16) -> String {
+    let (origin, path) = split_origin(url);
+    let host_start = origin.find("://").map_or(0, |i| i + 3);
+    // The port follows the last ':' which isn't inside an IPv6 address
+    let host = match origin[host_start..].rfind(':') {
+        Some(i) if !origin[host_start + i..].contains(']') => &origin[..host_start + i],
+        _ => origin,
+    };
+    format!("{host}:{port}{path}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect_port() {
+        assert_eq!(
+            detect_port("  \u{1b}[32m➜\u{1b}[39m  \u{1b}[1mLocal\u{1b}[22m:   \u{1b}[36mhttp://localhost:\u{1b}[1m5174\u{1b}[22m/docs/\u{1b}[39m"),
+            Some(5174)
+        );
+        assert_eq!(detect_port("Local: http://127.0.0.1:4173/"), Some(4173));
+        assert_eq!(detect_port("Network: http://192.168.1.2:5173/"), None);
+        assert_eq!(detect_port("vitepress v1.3.4"), None);
+    }
+
+    #[test]
+    fn test_with_port() {
+        assert_eq!(
+            with_port("http://localhost:5173/docs", 4000),
+            "http://localhost:4000/docs"
+        );
+        assert_eq!(with_port("http://localhost", 4000), "http://localhost:4000");
+        assert_eq!(with_port("http://[::1]:5173", 4000), "http://[::1]:4000");
+        assert_eq!(
+            with_port("http://[::1]/docs", 4000),
+            "http://[::1]:4000/docs"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_dev_server_process() {
+        use crate::serve::StaticServer;
+
+        let dist = tempfile::tempdir().unwrap();
+        std::fs::write(dist.path().join("index.html"), "home").unwrap();
+        let site = StaticServer::start(dist.path(), "").unwrap();
+        let port = site.url().rsplit(':').next().unwrap().to_string();
+
+        let pid_file = dist.path().join("pid");
+        // Prints the URL like Vite, then a grandchild keeps the group alive
+        let conf = DevServer {
+            command: format!(
+                "echo 'Local: http://localhost:{port}/'; sleep 60 & echo $! > {}; wait",
+                pid_file.display()
+            ),
+            working_dir: None,
+            port: None,
+            startup_timeout_secs: 10,
+            shutdown_timeout_secs: 1,
+        };
+        let server = DevServerProcess::start(&conf, "http://localhost:5173").unwrap();
+        assert_eq!(server.url(), format!("http://localhost:{port}"));
+
+        let sleep_pid: u32 = std::fs::read_to_string(&pid_file)
+            .unwrap()
+            .trim()
+            .parse()
+            .unwrap();
+        drop(server);
+        // The grandchild was killed with the group, it may linger as a zombie until reaped
+        #[cfg(target_os = "linux")]
+        assert!(std::fs::read_to_string(format!("/proc/{sleep_pid}/stat"))
+            .map_or(true, |stat| stat.contains(") Z ")));
+
+        let conf = DevServer {
+            command: "exit 3".to_string(),
+            ..conf
+        };
+        let err = DevServerProcess::start(&conf, "http://localhost:5173").unwrap_err();
+        assert!(
+            err.to_string().contains("exited before it was ready"),
+            "{err}"
+        );
+    }
+}
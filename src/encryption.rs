@@ -0,0 +1,372 @@
+// Copyright (c) 2024 DDN. All rights reserved.
+// Use of this source code is governed by a MIT-style
+// license that can be found in the LICENSE file.
+
+use crate::config::Encryption;
+use aes::{
+    cipher::{generic_array::GenericArray, BlockEncrypt, KeyInit},
+    Aes128,
+};
+use anyhow::Result;
+use lopdf::{dictionary, Document, Object, ObjectId, StringFormat};
+use md5::{Digest, Md5};
+use std::time::SystemTime;
+
+// Pads passwords to 32 bytes, from the standard security handler
+const PADDING: [u8; 32] = [
+    0x28, 0xBF, 0x4E, 0x5E, 0x4E, 0x75, 0x8A, 0x41, 0x64, 0x00, 0x4E, 0x56, 0xFF, 0xFA, 0x01, 0x08,
+    0x2E, 0x2E, 0x00, 0xB6, 0xD0, 0x68, 0x3E, 0x80, 0x2F, 0x0C, 0xA9, 0xFE, 0x64, 0x53, 0x69, 0x7A,
+];
+
+// Permission bits of P, the reserved bits 7, 8 and 13 to 32 must be set
+const RESERVED: u32 = 0xFFFF_F0C0;
+const PRINT: u32 = 1 << 2;
+const MODIFY: u32 = 1 << 3;
+const COPY: u32 = 1 << 4;
+const ANNOTATE: u32 = 1 << 5;
+const FILL_FORMS: u32 = 1 << 8;
+const ACCESSIBILITY: u32 = 1 << 9;
+const ASSEMBLE: u32 = 1 << 10;
+const PRINT_HIGH_QUALITY: u32 = 1 << 11;
+
+fn md5(parts: &[&[u8]]) -> [u8; 16] {
+    let mut hasher = Md5::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+fn pad(password: &str) -> [u8; 32] {
+    let mut padded = PADDING;
+    let bytes = password.as_bytes();
+    let len = bytes.len().min(32);
+    padded[..len].copy_from_slice(&bytes[..len]);
+    padded[len..].copy_from_slice(&PADDING[..32 - len]);
+    padded
+}
+
+fn rc4(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut s: Vec<u8> = (0..=255).collect();
+    let mut j = 0u8;
+    for i in 0..256 {
+        j = j.wrapping_add(s[i]).wrapping_add(key[i % key.len()]);
+        s.swap(i, j as usize);
+    }
+    let (mut i, mut j) = (0u8, 0u8);
+    data.iter()
+        .map(|byte| {
+            i = i.wrapping_add(1);
+            j = j.wrapping_add(s[i as usize]);
+            s.swap(i as usize, j as usize);
+            byte ^ s[s[i as usize].wrapping_add(s[j as usize]) as usize]
+        })
+        .collect()
+}
+
+// RC4 with `key`, then 19 more times with each byte of the key XORed with the round number
+fn rc4_rounds(key: &[u8; 16], data: &[u8]) -> Vec<u8> {
+    (1..=19u8).fold(rc4(key, data), |data, round| {
+        rc4(&key.map(|k| k ^ round), &data)
+    })
+}
+
+// The owner password hashed 51 times keys the RC4 encryption of the padded user password
+fn owner_value(owner_password: &str, user_password: &str) -> Vec<u8> {
+    let mut key = md5(&[&pad(owner_password)]);
+    for _ in 0..50 {
+        key = md5(&[&key]);
+    }
+    rc4_rounds(&key, &pad(user_password))
+}
+
+// The file encryption key, from the user password, O, P and the first file identifier
+fn file_key(user_password: &str, owner: &[u8], permissions: i32, id: &[u8]) -> [u8; 16] {
+    let mut key = md5(&[&pad(user_password), owner, &permissions.to_le_bytes(), id]);
+    for _ in 0..50 {
+        key = md5(&[&key]);
+    }
+    key
+}
+
+// Hash of the padding and file identifier, encrypted so the user password can be checked
+fn user_value(key: &[u8; 16], id: &[u8]) -> Vec<u8> {
+    let mut value = rc4_rounds(key, &md5(&[&PADDING, id]));
+    // The last 16 bytes are arbitrary
+    value.extend_from_slice(&PADDING[..16]);
+    value
+}
+
+// The AES key of the strings and streams of the object `id`
+fn object_key(key: &[u8; 16], id: ObjectId) -> [u8; 16] {
+    md5(&[
+        key,
+        &id.0.to_le_bytes()[..3],
+        &id.1.to_le_bytes()[..2],
+        b"sAlT",
+    ])
+}
+
+// `data` encrypted with AES-128 in CBC mode, PKCS#5 padded and prefixed with the IV
+fn aes_cbc(key: &[u8; 16], iv: [u8; 16], data: &[u8]) -> Vec<u8> {
+    let cipher = Aes128::new(GenericArray::from_slice(key));
+    let pad = 16 - data.len() % 16;
+    let mut padded = data.to_vec();
+    padded.resize(data.len() + pad, pad as u8);
+
+    let mut encrypted = iv.to_vec();
+    let mut previous = iv;
+    for chunk in padded.chunks(16) {
+        let mut block = GenericArray::clone_from_slice(chunk);
+        block.iter_mut().zip(previous).for_each(|(b, p)| *b ^= p);
+        cipher.encrypt_block(&mut block);
+        previous = block.into();
+        encrypted.extend_from_slice(&previous);
+    }
+    encrypted
+}
+
+// Encrypts the strings and streams of one object, each with its own IV
+struct ObjectCipher {
+    key: [u8; 16],
+    count: u32,
+}
+
+impl ObjectCipher {
+    // IVs only have to differ, they are derived from the object key and a counter
+    fn encrypt(&mut self, data: &[u8]) -> Vec<u8> {
+        self.count += 1;
+        let iv = md5(&[&self.key, &self.count.to_le_bytes()]);
+        aes_cbc(&self.key, iv, data)
+    }
+
+    fn encrypt_strings(&mut self, object: &mut Object) {
+        match object {
+            Object::String(s, format) => {
+                *s = self.encrypt(s);
+                *format = StringFormat::Hexadecimal;
+            }
+            Object::Array(array) => array.iter_mut().for_each(|o| self.encrypt_strings(o)),
+            Object::Dictionary(dict) => dict.iter_mut().for_each(|(_, o)| self.encrypt_strings(o)),
+            Object::Stream(stream) => stream
+                .dict
+                .iter_mut()
+                .for_each(|(_, o)| self.encrypt_strings(o)),
+            _ => {}
+        }
+    }
+}
+
+fn permissions(encryption: &Encryption) -> i32 {
+    let mut p = RESERVED | ACCESSIBILITY;
+    if encryption.allow_print {
+        p |= PRINT | PRINT_HIGH_QUALITY;
+    }
+    if encryption.allow_modify {
+        p |= MODIFY | ANNOTATE | FILL_FORMS | ASSEMBLE;
+    }
+    if encryption.allow_copy {
+        p |= COPY;
+    }
+    p as i32
+}
+
+/// Encrypt every string and stream of `doc` with the standard security handler, revision 4
+/// with AES-128.
+///
+/// Streams are encrypted as they are, so `doc` must already be compressed and nothing may
+/// change it before it is saved.
+pub(crate) fn encrypt(doc: &mut Document, encryption: &Encryption) -> Result<()> {
+    let id = match doc
+        .trailer
+        .get(b"ID")
+        .and_then(Object::as_array)
+        .ok()
+        .and_then(|ids| ids.first()?.as_str().ok())
+    {
+        Some(id) => id.to_vec(),
+        None => {
+            let now = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)?
+                .as_nanos();
+            md5(&[&now.to_le_bytes(), &doc.max_id.to_le_bytes()]).to_vec()
+        }
+    };
+    doc.trailer.set(
+        "ID",
+        vec![
+            Object::String(id.clone(), StringFormat::Hexadecimal),
+            Object::String(id.clone(), StringFormat::Hexadecimal),
+        ],
+    );
+
+    // The AESV2 crypt filter needs PDF 1.6
+    if doc.version.as_str() < "1.6" {
+        doc.version = "1.6".to_string();
+    }
+
+    let p = permissions(encryption);
+    let owner = owner_value(&encryption.owner_password, &encryption.user_password);
+    let key = file_key(&encryption.user_password, &owner, p, &id);
+    let user = user_value(&key, &id);
+
+    for (object_id, object) in doc.objects.iter_mut() {
+        let is_xref = object
+            .as_stream()
+            .ok()
+            .and_then(|s| s.dict.get(b"Type").ok())
+            .and_then(|t| t.as_name_str().ok())
+            == Some("XRef");
+        if is_xref {
+            continue;
+        }
+        let mut cipher = ObjectCipher {
+            key: object_key(&key, *object_id),
+            count: 0,
+        };
+        cipher.encrypt_strings(object);
+        if let Object::Stream(stream) = object {
+            let content = cipher.encrypt(&stream.content);
+            stream.set_content(content);
+            stream.allows_compression = false;
+        }
+    }
+
+    let encrypt_id = doc.add_object(dictionary! {
+        "Filter" => "Standard",
+        "V" => 4,
+        "R" => 4,
+        "Length" => 128,
+        "CF" => dictionary! {
+            "StdCF" => dictionary! {
+                "Type" => "CryptFilter",
+                "CFM" => "AESV2",
+                "AuthEvent" => "DocOpen",
+                "Length" => 16,
+            },
+        },
+        "StmF" => "StdCF",
+        "StrF" => "StdCF",
+        "O" => Object::String(owner, StringFormat::Hexadecimal),
+        "U" => Object::String(user, StringFormat::Hexadecimal),
+        "P" => p,
+        "EncryptMetadata" => true,
+    });
+    doc.trailer.set("Encrypt", encrypt_id);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aes::cipher::BlockDecrypt;
+    use lopdf::Stream;
+
+    fn aes_cbc_decrypt(key: &[u8; 16], data: &[u8]) -> Vec<u8> {
+        let cipher = Aes128::new(GenericArray::from_slice(key));
+        let mut previous = GenericArray::clone_from_slice(&data[..16]);
+        let mut decrypted = vec![];
+        for chunk in data[16..].chunks(16) {
+            let mut block = GenericArray::clone_from_slice(chunk);
+            cipher.decrypt_block(&mut block);
+            block.iter_mut().zip(previous).for_each(|(b, p)| *b ^= p);
+            decrypted.extend_from_slice(&block);
+            previous = GenericArray::clone_from_slice(chunk);
+        }
+        let pad = *decrypted.last().unwrap() as usize;
+        decrypted.truncate(decrypted.len() - pad);
+        decrypted
+    }
+
+    #[test]
+    fn test_rc4() {
+        // Test vectors from RFC 6229 and the original RC4 posting
+        assert_eq!(
+            rc4(b"Key", b"Plaintext"),
+            [0xBB, 0xF3, 0x16, 0xE8, 0xD9, 0x40, 0xAF, 0x0A, 0xD3]
+        );
+        assert_eq!(
+            rc4(b"Secret", b"Attack at dawn"),
+            [0x45, 0xA0, 0x1F, 0x64, 0x5F, 0xC3, 0x5B, 0x38, 0x35, 0x52, 0x54, 0x4B, 0x9B, 0xF5]
+        );
+    }
+
+    #[test]
+    fn test_permissions() {
+        let encryption = |print, copy, modify| Encryption {
+            user_password: String::new(),
+            owner_password: "owner".to_string(),
+            allow_print: print,
+            allow_copy: copy,
+            allow_modify: modify,
+        };
+        assert_eq!(permissions(&encryption(true, true, true)), -4);
+        assert_eq!(
+            permissions(&encryption(false, false, false)) as u32,
+            0xFFFF_F2C0
+        );
+    }
+
+    #[test]
+    fn test_encrypt() {
+        let encryption = Encryption {
+            user_password: "reader".to_string(),
+            owner_password: "owner".to_string(),
+            allow_print: false,
+            allow_copy: true,
+            allow_modify: false,
+        };
+        let mut doc = Document::with_version("1.7");
+        let stream_id = doc.add_object(Stream::new(
+            dictionary! { "Title" => Object::string_literal("Confidential") },
+            b"BT (Hello) Tj ET".to_vec(),
+        ));
+        encrypt(&mut doc, &encryption).unwrap();
+
+        let encrypt_id = doc.trailer.get(b"Encrypt").unwrap().as_reference().unwrap();
+        let dict = doc.get_dictionary(encrypt_id).unwrap();
+        let get = |key: &[u8]| dict.get(key).unwrap().as_str().unwrap().to_vec();
+        let (owner, user) = (get(b"O"), get(b"U"));
+        let p = dict.get(b"P").unwrap().as_i64().unwrap() as i32;
+        assert_eq!(p & PRINT as i32, 0);
+        let id = doc.trailer.get(b"ID").unwrap().as_array().unwrap()[0]
+            .as_str()
+            .unwrap()
+            .to_vec();
+
+        // The user password opens the document, another doesn't
+        let key = file_key("reader", &owner, p, &id);
+        assert_eq!(user_value(&key, &id)[..16], user[..16]);
+        let wrong_key = file_key("", &owner, p, &id);
+        assert_ne!(user_value(&wrong_key, &id)[..16], user[..16]);
+
+        // The owner password recovers the user password
+        let mut owner_key = md5(&[&pad("owner")]);
+        for _ in 0..50 {
+            owner_key = md5(&[&owner_key]);
+        }
+        let user_password = (0..=19u8).rev().fold(owner.clone(), |data, round| {
+            rc4(&owner_key.map(|k| k ^ round), &data)
+        });
+        assert_eq!(user_password, pad("reader"));
+
+        // Streams and strings decrypt with the object key
+        let object_key = object_key(&key, stream_id);
+        let stream = doc.get_object(stream_id).unwrap().as_stream().unwrap();
+        assert_eq!(
+            aes_cbc_decrypt(&object_key, &stream.content),
+            b"BT (Hello) Tj ET"
+        );
+        assert_eq!(
+            stream.dict.get(b"Length").unwrap().as_i64().unwrap() as usize,
+            stream.content.len()
+        );
+        let title = stream.dict.get(b"Title").unwrap().as_str().unwrap();
+        assert_eq!(aes_cbc_decrypt(&object_key, title), b"Confidential");
+        // The Encrypt dictionary itself isn't encrypted
+        assert_eq!(
+            dict.get(b"Filter").unwrap().as_name_str().unwrap(),
+            "Standard"
+        );
+    }
+}
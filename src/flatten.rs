@@ -0,0 +1,293 @@
+// Copyright (c) 2024 DDN. All rights reserved.
+// Use of this source code is governed by a MIT-style
+// license that can be found in the LICENSE file.
+
+use anyhow::Result;
+use lopdf::{Dictionary, Document, Object, ObjectId};
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+/// What `flatten_transparency` changed and what it left alone
+#[derive(Debug, Default)]
+pub struct FlattenReport {
+    /// Number of entries made opaque, keyed by page number
+    pub altered: BTreeMap<u32, usize>,
+    /// Transparency which would visibly change if flattened, e.g. `Page No. 3: ExtGState ca 0.5`
+    pub untouched: Vec<String>,
+}
+
+// Transparency found while walking a page's resources
+#[derive(Default)]
+struct Found {
+    ext_g_states: Vec<ObjectId>,
+    images: Vec<ObjectId>,
+}
+
+fn deref<'a>(doc: &'a Document, object: &'a Object) -> Option<&'a Object> {
+    match object {
+        Object::Reference(id) => doc.get_object(*id).ok(),
+        o => Some(o),
+    }
+}
+
+// Collect ExtGState and Image XObject ids from a resource dictionary, recursing into Form XObjects
+fn walk_resources(
+    doc: &Document,
+    resources: &Dictionary,
+    visited: &mut HashSet<ObjectId>,
+    found: &mut Found,
+) {
+    if let Some(Object::Dictionary(states)) =
+        resources.get(b"ExtGState").ok().and_then(|o| deref(doc, o))
+    {
+        for (_, state) in states.iter() {
+            if let Ok(id) = state.as_reference() {
+                if visited.insert(id) {
+                    found.ext_g_states.push(id);
+                }
+            }
+        }
+    }
+
+    if let Some(Object::Dictionary(xobjects)) =
+        resources.get(b"XObject").ok().and_then(|o| deref(doc, o))
+    {
+        for (_, xobject) in xobjects.iter() {
+            let Ok(id) = xobject.as_reference() else {
+                continue;
+            };
+            if !visited.insert(id) {
+                continue;
+            }
+            let Ok(Object::Stream(stream)) = doc.get_object(id) else {
+                continue;
+            };
+            match stream.dict.get(b"Subtype").and_then(Object::as_name_str) {
+                Ok("Image") => found.images.push(id),
+                Ok("Form") => {
+                    if let Some(Object::Dictionary(form_resources)) = stream
+                        .dict
+                        .get(b"Resources")
+                        .ok()
+                        .and_then(|o| deref(doc, o))
+                    {
+                        walk_resources(doc, form_resources, visited, found);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+// Smallest alpha value of an 8 bit soft mask, `None` if we don't understand the mask
+fn min_mask_alpha(doc: &Document, smask: &Object) -> Option<f64> {
+    let Some(Object::Stream(mask)) = deref(doc, smask) else {
+        return None;
+    };
+    if mask
+        .dict
+        .get(b"BitsPerComponent")
+        .and_then(Object::as_i64)
+        .ok()
+        != Some(8)
+    {
+        return None;
+    }
+    let content = if mask.dict.has(b"Filter") {
+        mask.decompressed_content().ok()?
+    } else {
+        mask.content.clone()
+    };
+    content.iter().min().map(|min| *min as f64 / 255.0)
+}
+
+/// Best effort removal of transparency for print shops whose RIPs can't handle it.
+///
+/// Alpha values at or above `threshold` are made opaque, opaque or nearly opaque image
+/// soft masks are removed and transparency groups are dropped from pages left without
+/// transparency. Anything that would change visibly is left alone and reported.
+pub fn flatten_transparency(doc: &mut Document, threshold: f64) -> Result<FlattenReport> {
+    let mut report = FlattenReport::default();
+    // Resources are shared between pages, remember whether each one still has transparency
+    let mut processed: HashMap<ObjectId, bool> = HashMap::new();
+
+    for (page_num, page_id) in doc.get_pages() {
+        let mut visited = HashSet::new();
+        let mut found = Found::default();
+        if let Some(Object::Dictionary(resources)) = doc
+            .get_dictionary(page_id)?
+            .get(b"Resources")
+            .ok()
+            .and_then(|o| deref(doc, o))
+        {
+            walk_resources(doc, resources, &mut visited, &mut found);
+        }
+
+        let mut altered = 0;
+        let mut untouched = 0;
+
+        for id in found.ext_g_states {
+            if let Some(transparent) = processed.get(&id) {
+                untouched += *transparent as usize;
+                continue;
+            }
+            let untouched_before = untouched;
+            let state = doc.get_dictionary_mut(id)?;
+            for key in [b"CA".as_slice(), b"ca".as_slice()] {
+                let Ok(alpha) = state.get(key).and_then(Object::as_float) else {
+                    continue;
+                };
+                let alpha = alpha as f64;
+                if alpha >= 1.0 {
+                    continue;
+                } else if alpha >= threshold {
+                    state.set(key, 1.0);
+                    altered += 1;
+                } else {
+                    report.untouched.push(format!(
+                        "Page No. {page_num}: ExtGState {} {alpha:.2}",
+                        String::from_utf8_lossy(key)
+                    ));
+                    untouched += 1;
+                }
+            }
+            if let Ok(mode) = state.get(b"BM").and_then(Object::as_name_str) {
+                if mode != "Normal" && mode != "Compatible" {
+                    report
+                        .untouched
+                        .push(format!("Page No. {page_num}: ExtGState blend mode {mode}"));
+                    untouched += 1;
+                }
+            }
+            if let Ok(smask) = state.get(b"SMask") {
+                if smask.as_name_str().ok() != Some("None") {
+                    report
+                        .untouched
+                        .push(format!("Page No. {page_num}: ExtGState soft mask"));
+                    untouched += 1;
+                }
+            }
+            processed.insert(id, untouched > untouched_before);
+        }
+
+        for id in found.images {
+            if let Some(transparent) = processed.get(&id) {
+                untouched += *transparent as usize;
+                continue;
+            }
+            processed.insert(id, false);
+            let Ok(smask) = doc.get_object(id)?.as_stream()?.dict.get(b"SMask") else {
+                continue;
+            };
+            match min_mask_alpha(doc, smask) {
+                Some(alpha) if alpha >= threshold => {
+                    doc.get_object_mut(id)?
+                        .as_stream_mut()?
+                        .dict
+                        .remove(b"SMask");
+                    altered += 1;
+                }
+                Some(alpha) => {
+                    report.untouched.push(format!(
+                        "Page No. {page_num}: image soft mask with alpha {alpha:.2}"
+                    ));
+                    processed.insert(id, true);
+                    untouched += 1;
+                }
+                None => {
+                    report
+                        .untouched
+                        .push(format!("Page No. {page_num}: unsupported image soft mask"));
+                    processed.insert(id, true);
+                    untouched += 1;
+                }
+            }
+        }
+
+        // The page group only matters while there is transparency left on the page
+        if untouched == 0 {
+            let page = doc.get_dictionary_mut(page_id)?;
+            let transparency_group = page
+                .get(b"Group")
+                .and_then(Object::as_dict)
+                .and_then(|g| g.get(b"S"))
+                .and_then(Object::as_name_str)
+                .is_ok_and(|s| s == "Transparency");
+            if transparency_group {
+                page.remove(b"Group");
+                altered += 1;
+            }
+        }
+
+        if altered > 0 {
+            report.altered.insert(page_num, altered);
+        }
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lopdf::{dictionary, Stream};
+
+    #[test]
+    fn test_flatten_transparency() {
+        let mut doc = Document::with_version("1.7");
+        let pages_id = doc.new_object_id();
+        let nearly_opaque = doc.add_object(dictionary! { "ca" => 0.98 });
+        let translucent = doc.add_object(dictionary! { "CA" => 0.5 });
+        let opaque_mask = doc.add_object(Stream::new(
+            dictionary! { "BitsPerComponent" => 8 },
+            vec![255, 254, 255],
+        ));
+        let image = doc.add_object(Stream::new(
+            dictionary! { "Subtype" => "Image", "SMask" => opaque_mask },
+            vec![0, 0, 0],
+        ));
+        let flat_page = doc.add_object(dictionary! {
+            "Type" => "Page",
+            "Parent" => pages_id,
+            "Group" => dictionary! { "S" => "Transparency" },
+            "Resources" => dictionary! {
+                "ExtGState" => dictionary! { "G1" => nearly_opaque },
+                "XObject" => dictionary! { "I1" => image },
+            },
+        });
+        let translucent_page = doc.add_object(dictionary! {
+            "Type" => "Page",
+            "Parent" => pages_id,
+            "Group" => dictionary! { "S" => "Transparency" },
+            "Resources" => dictionary! {
+                "ExtGState" => dictionary! { "G1" => translucent },
+            },
+        });
+        doc.objects.insert(
+            pages_id,
+            Object::Dictionary(dictionary! {
+                "Type" => "Pages",
+                "Kids" => vec![flat_page.into(), translucent_page.into()],
+                "Count" => 2,
+            }),
+        );
+        let catalog_id = doc.add_object(dictionary! {
+            "Type" => "Catalog",
+            "Pages" => pages_id,
+        });
+        doc.trailer.set("Root", catalog_id);
+
+        let report = flatten_transparency(&mut doc, 0.95).unwrap();
+
+        // ca, image soft mask and page group
+        assert_eq!(report.altered, BTreeMap::from([(1, 3)]));
+        assert_eq!(report.untouched, vec!["Page No. 2: ExtGState CA 0.50"]);
+
+        let state = doc.get_dictionary(nearly_opaque).unwrap();
+        assert_eq!(state.get(b"ca").unwrap().as_float().unwrap(), 1.0);
+        let image = doc.get_object(image).unwrap().as_stream().unwrap();
+        assert!(!image.dict.has(b"SMask"));
+        assert!(!doc.get_dictionary(flat_page).unwrap().has(b"Group"));
+        assert!(doc.get_dictionary(translucent_page).unwrap().has(b"Group"));
+    }
+}
@@ -0,0 +1,810 @@
+// Copyright (c) 2024 DDN. All rights reserved.
+// Use of this source code is governed by a MIT-style
+// license that can be found in the LICENSE file.
+
+use crate::text::{char_to_win_ansi, win_ansi_to_char};
+use anyhow::{anyhow, Result};
+use lopdf::{dictionary, Document, Object, ObjectId, Stream, StringFormat};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    ops::Range,
+};
+
+/// Just enough of a TrueType/OpenType (sfnt) parser to embed a font into a PDF.
+///
+/// See the [OpenType spec](https://learn.microsoft.com/en-us/typography/opentype/spec/otff)
+/// for the table layouts.
+pub struct Sfnt {
+    data: Vec<u8>,
+    /// CFF based OpenType font rather than TrueType outlines
+    pub is_cff: bool,
+    pub units_per_em: u16,
+    pub bbox: [i16; 4],
+    pub ascent: i16,
+    pub descent: i16,
+    pub cap_height: i16,
+    pub italic_angle: f64,
+    hmtx: usize,
+    num_h_metrics: u16,
+    cmap: Option<usize>,
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Result<u16> {
+    data.get(offset..offset + 2)
+        .map(|b| u16::from_be_bytes([b[0], b[1]]))
+        .ok_or(anyhow!("Font truncated at offset {offset}"))
+}
+
+fn read_i16(data: &[u8], offset: usize) -> Result<i16> {
+    Ok(read_u16(data, offset)? as i16)
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32> {
+    data.get(offset..offset + 4)
+        .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or(anyhow!("Font truncated at offset {offset}"))
+}
+
+// Offset and length of the `tag` table in the font
+fn find_table(data: &[u8], tag: &[u8; 4]) -> Result<Option<(usize, usize)>> {
+    for i in 0..read_u16(data, 4)? as usize {
+        let record = 12 + i * 16;
+        if data.get(record..record + 4) == Some(tag.as_slice()) {
+            let offset = read_u32(data, record + 8)? as usize;
+            let length = read_u32(data, record + 12)? as usize;
+            if offset + length > data.len() {
+                return Err(anyhow!(
+                    "Font table {} is truncated",
+                    String::from_utf8_lossy(tag)
+                ));
+            }
+            return Ok(Some((offset, length)));
+        }
+    }
+    Ok(None)
+}
+
+// Assemble a font from its tables, in the order given
+fn write_sfnt(version: u32, tables: &[([u8; 4], Vec<u8>)]) -> Vec<u8> {
+    let num_tables = tables.len() as u16;
+    let entry_selector = num_tables.max(1).ilog2() as u16;
+    let search_range = 16 << entry_selector;
+    let mut font = vec![];
+    font.extend_from_slice(&version.to_be_bytes());
+    font.extend_from_slice(&num_tables.to_be_bytes());
+    font.extend_from_slice(&search_range.to_be_bytes());
+    font.extend_from_slice(&entry_selector.to_be_bytes());
+    font.extend_from_slice(&(num_tables * 16 - search_range).to_be_bytes());
+
+    let mut offset = 12 + tables.len() * 16;
+    let mut body = vec![];
+    for (tag, data) in tables {
+        let mut padded = data.clone();
+        padded.resize(data.len().next_multiple_of(4), 0);
+        let checksum = padded
+            .chunks(4)
+            .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+            .fold(0u32, u32::wrapping_add);
+        font.extend_from_slice(tag);
+        font.extend_from_slice(&checksum.to_be_bytes());
+        font.extend_from_slice(&(offset as u32).to_be_bytes());
+        font.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        offset += padded.len();
+        body.extend_from_slice(&padded);
+    }
+    font.extend_from_slice(&body);
+    font
+}
+
+impl Sfnt {
+    /// Parse a TrueType or OpenType font, failing if any table we need is missing
+    pub fn parse(data: Vec<u8>) -> Result<Self> {
+        let version = read_u32(&data, 0)?;
+        let is_cff = match version {
+            0x0001_0000 | 0x7472_7565 => false, // 1.0 or 'true'
+            0x4F54_544F => true,                // 'OTTO'
+            _ => return Err(anyhow!("Not a TrueType or OpenType font")),
+        };
+
+        let table = |tag: &[u8; 4]| -> Result<Option<usize>> {
+            Ok(find_table(&data, tag)?.map(|(offset, _)| offset))
+        };
+        let required = |tag: &[u8; 4]| -> Result<usize> {
+            table(tag)?.ok_or(anyhow!(
+                "Font is missing the {} table",
+                String::from_utf8_lossy(tag)
+            ))
+        };
+
+        let head = required(b"head")?;
+        let hhea = required(b"hhea")?;
+        let hmtx = required(b"hmtx")?;
+        let cmap = required(b"cmap")?;
+
+        let ascent = read_i16(&data, hhea + 4)?;
+        let cap_height = match table(b"OS/2")? {
+            Some(os2) if read_u16(&data, os2)? >= 2 => read_i16(&data, os2 + 88)?,
+            _ => ascent,
+        };
+        let italic_angle = match table(b"post")? {
+            Some(post) => read_u32(&data, post + 4)? as i32 as f64 / 65536.0,
+            None => 0.0,
+        };
+
+        let mut font = Sfnt {
+            is_cff,
+            units_per_em: read_u16(&data, head + 18)?,
+            bbox: [
+                read_i16(&data, head + 36)?,
+                read_i16(&data, head + 38)?,
+                read_i16(&data, head + 40)?,
+                read_i16(&data, head + 42)?,
+            ],
+            ascent,
+            descent: read_i16(&data, hhea + 6)?,
+            cap_height,
+            italic_angle,
+            hmtx,
+            num_h_metrics: read_u16(&data, hhea + 34)?,
+            cmap: None,
+            data: vec![],
+        };
+        if font.units_per_em == 0 || font.num_h_metrics == 0 {
+            return Err(anyhow!("Font has invalid metrics"));
+        }
+
+        // Find a Unicode BMP subtable in format 4
+        for i in 0..read_u16(&data, cmap + 2)? as usize {
+            let record = cmap + 4 + i * 8;
+            let platform = read_u16(&data, record)?;
+            let encoding = read_u16(&data, record + 2)?;
+            let subtable = cmap + read_u32(&data, record + 4)? as usize;
+            if (platform == 0 || (platform == 3 && encoding == 1))
+                && read_u16(&data, subtable)? == 4
+            {
+                font.cmap = Some(subtable);
+                break;
+            }
+        }
+        if font.cmap.is_none() {
+            return Err(anyhow!("Font has no Unicode cmap"));
+        }
+
+        font.data = data;
+        Ok(font)
+    }
+
+    /// The raw font program
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    // Glyph index for `c` from the format 4 cmap subtable
+    fn glyph(&self, c: char) -> Result<u16> {
+        let c = match u16::try_from(c as u32) {
+            Ok(c) => c,
+            Err(_) => return Ok(0),
+        };
+        let cmap = self.cmap.ok_or(anyhow!("Font has no Unicode cmap"))?;
+        let seg_count = read_u16(&self.data, cmap + 6)? as usize / 2;
+        let end_codes = cmap + 14;
+        let start_codes = end_codes + seg_count * 2 + 2;
+        let id_deltas = start_codes + seg_count * 2;
+        let id_range_offsets = id_deltas + seg_count * 2;
+
+        for i in 0..seg_count {
+            if read_u16(&self.data, end_codes + i * 2)? < c {
+                continue;
+            }
+            let start = read_u16(&self.data, start_codes + i * 2)?;
+            if start > c {
+                return Ok(0);
+            }
+            let delta = read_u16(&self.data, id_deltas + i * 2)?;
+            let range_offset = read_u16(&self.data, id_range_offsets + i * 2)? as usize;
+            if range_offset == 0 {
+                return Ok(c.wrapping_add(delta));
+            }
+            let glyph_offset = id_range_offsets + i * 2 + range_offset + (c - start) as usize * 2;
+            let glyph = read_u16(&self.data, glyph_offset)?;
+            return Ok(if glyph == 0 {
+                0
+            } else {
+                glyph.wrapping_add(delta)
+            });
+        }
+        Ok(0)
+    }
+
+    /// Advance width of `c` in PDF text space units (1/1000 em)
+    pub fn advance(&self, c: char) -> Result<f64> {
+        self.glyph_advance(self.glyph(c)?)
+    }
+
+    // Advance width of `glyph` in PDF text space units, glyphs past the last horizontal metric
+    // share its advance
+    fn glyph_advance(&self, glyph: u16) -> Result<f64> {
+        let glyph = glyph.min(self.num_h_metrics - 1) as usize;
+        let advance = read_u16(&self.data, self.hmtx + glyph * 4)?;
+        Ok(self.scale(advance as i16))
+    }
+
+    /// The font program with the outlines of glyphs not needed to show `text` removed.
+    ///
+    /// Glyph IDs are kept so the cmap and metrics still apply, unused glyphs are just left
+    /// empty. CFF fonts and fonts without `glyf` outlines are returned whole.
+    pub fn subset(&self, text: &str) -> Result<Vec<u8>> {
+        let data = &self.data;
+        let [head, maxp, loca, glyf] =
+            [b"head", b"maxp", b"loca", b"glyf"].map(|tag| find_table(data, tag));
+        let (Some((head, head_len)), Some((maxp, _)), Some((loca, _)), Some((glyf, glyf_len))) =
+            (head?, maxp?, loca?, glyf?)
+        else {
+            return Ok(data.clone());
+        };
+        if self.is_cff {
+            return Ok(data.clone());
+        }
+        if head_len < 54 {
+            return Err(anyhow!("Font head table is truncated"));
+        }
+
+        let long_offsets = read_i16(data, head + 50)? == 1;
+        let num_glyphs = read_u16(data, maxp + 4)?;
+        // Range of `glyph`'s outline in the font
+        let outline = |glyph: u16| -> Result<Range<usize>> {
+            let glyph = glyph as usize;
+            let (start, end) = if long_offsets {
+                (
+                    read_u32(data, loca + glyph * 4)? as usize,
+                    read_u32(data, loca + glyph * 4 + 4)? as usize,
+                )
+            } else {
+                (
+                    read_u16(data, loca + glyph * 2)? as usize * 2,
+                    read_u16(data, loca + glyph * 2 + 2)? as usize * 2,
+                )
+            };
+            if start > end || end > glyf_len {
+                return Err(anyhow!("Font glyph {glyph} is out of bounds"));
+            }
+            Ok(glyf + start..glyf + end)
+        };
+
+        // .notdef, the glyphs in `text` and the components of composite glyphs
+        let mut keep = BTreeSet::from([0]);
+        for c in text.chars() {
+            keep.insert(self.glyph(c)?);
+        }
+        let mut pending: Vec<u16> = keep.iter().copied().collect();
+        while let Some(glyph) = pending.pop() {
+            if glyph >= num_glyphs {
+                continue;
+            }
+            let range = outline(glyph)?;
+            if range.is_empty() || read_i16(data, range.start)? >= 0 {
+                continue;
+            }
+            let mut offset = range.start + 10;
+            loop {
+                let flags = read_u16(data, offset)?;
+                let component = read_u16(data, offset + 2)?;
+                if keep.insert(component) {
+                    pending.push(component);
+                }
+                // Arguments, then a scale, an x and y scale or a 2x2 matrix
+                offset += 4 + if flags & 0x1 != 0 { 4 } else { 2 };
+                offset += match flags {
+                    f if f & 0x8 != 0 => 2,
+                    f if f & 0x40 != 0 => 4,
+                    f if f & 0x80 != 0 => 8,
+                    _ => 0,
+                };
+                if flags & 0x20 == 0 {
+                    break;
+                }
+            }
+        }
+
+        let mut new_glyf = vec![];
+        let mut new_loca = vec![];
+        for glyph in 0..num_glyphs {
+            new_loca.extend_from_slice(&(new_glyf.len() as u32).to_be_bytes());
+            if keep.contains(&glyph) {
+                new_glyf.extend_from_slice(&data[outline(glyph)?]);
+                new_glyf.resize(new_glyf.len().next_multiple_of(4), 0);
+            }
+        }
+        new_loca.extend_from_slice(&(new_glyf.len() as u32).to_be_bytes());
+        let mut new_head = data[head..head + head_len].to_vec();
+        // Clear checkSumAdjustment, it no longer applies, and switch to long loca offsets
+        new_head[8..12].fill(0);
+        new_head[50..52].copy_from_slice(&1i16.to_be_bytes());
+
+        let mut tables = vec![];
+        for i in 0..read_u16(data, 4)? as usize {
+            let record = 12 + i * 16;
+            let tag: [u8; 4] = data
+                .get(record..record + 4)
+                .and_then(|t| t.try_into().ok())
+                .ok_or(anyhow!("Font truncated at offset {record}"))?;
+            let table = match &tag {
+                b"head" => new_head.clone(),
+                b"loca" => new_loca.clone(),
+                b"glyf" => new_glyf.clone(),
+                _ => {
+                    let offset = read_u32(data, record + 8)? as usize;
+                    let length = read_u32(data, record + 12)? as usize;
+                    data.get(offset..offset + length)
+                        .ok_or(anyhow!(
+                            "Font table {} is truncated",
+                            String::from_utf8_lossy(&tag)
+                        ))?
+                        .to_vec()
+                }
+            };
+            tables.push((tag, table));
+        }
+        Ok(write_sfnt(read_u32(data, 0)?, &tables))
+    }
+
+    /// Convert font units into PDF text space units (1/1000 em)
+    pub fn scale(&self, v: i16) -> f64 {
+        v as f64 * 1000.0 / self.units_per_em as f64
+    }
+}
+
+/// A font added to a document for the text we draw ourselves, such as page numbers
+pub struct TextFont {
+    pub id: ObjectId,
+    encoding: TextEncoding,
+}
+
+// How text is shown in a `TextFont`
+enum TextEncoding {
+    // WinAnsiEncoding in standard Type 1 `font`, or in `sfnt` embedded in its place
+    WinAnsi { font: String, sfnt: Option<Sfnt> },
+    // Two byte glyph IDs of the embedded font through Identity-H
+    Identity(Sfnt),
+}
+
+impl TextFont {
+    /// Standard Type 1 `font` added as `id` with WinAnsiEncoding, `sfnt` is the clone of it
+    /// embedded instead if there is one
+    pub fn win_ansi(id: ObjectId, font: &str, sfnt: Option<Sfnt>) -> Self {
+        TextFont {
+            id,
+            encoding: TextEncoding::WinAnsi {
+                font: font.to_string(),
+                sfnt,
+            },
+        }
+    }
+
+    // `text` as it's shown, characters WinAnsiEncoding doesn't have become a question mark
+    fn shown(&self, text: &str) -> String {
+        match &self.encoding {
+            TextEncoding::WinAnsi { .. } => text
+                .chars()
+                .map(|c| {
+                    if char_to_win_ansi(c).is_some() {
+                        c
+                    } else {
+                        '?'
+                    }
+                })
+                .collect(),
+            TextEncoding::Identity(_) => text.to_string(),
+        }
+    }
+
+    /// `text` as the string operand of a `Tj` operator
+    pub fn encode(&self, text: &str) -> Object {
+        match &self.encoding {
+            TextEncoding::WinAnsi { .. } => Object::String(
+                self.shown(text)
+                    .chars()
+                    .filter_map(char_to_win_ansi)
+                    .collect(),
+                StringFormat::Literal,
+            ),
+            TextEncoding::Identity(sfnt) => Object::String(
+                text.chars()
+                    .flat_map(|c| sfnt.glyph(c).unwrap_or(0).to_be_bytes())
+                    .collect(),
+                StringFormat::Hexadecimal,
+            ),
+        }
+    }
+
+    /// Width of `text` at font `size`, in the units of the size
+    pub fn width(&self, text: &str, size: f64) -> f64 {
+        self.shown(text)
+            .chars()
+            .map(|c| match &self.encoding {
+                TextEncoding::WinAnsi { font, sfnt: None } => standard_advance(font, c),
+                TextEncoding::WinAnsi {
+                    sfnt: Some(sfnt), ..
+                }
+                | TextEncoding::Identity(sfnt) => sfnt.advance(c).unwrap_or(500.0),
+            })
+            .sum::<f64>()
+            * size
+            / 1000.0
+    }
+}
+
+/// Embed `font` as a simple WinAnsi encoded font with widths for every code.
+///
+/// The whole font program is embedded, we don't subset it.
+pub fn embed_font(doc: &mut Document, base_font: &str, font: &Sfnt) -> Result<ObjectId> {
+    let widths = (32..=255)
+        .map(|b| match win_ansi_to_char(b) {
+            Some(c) => font.advance(c).map(|w| Object::Real(w as _)),
+            None => Ok(Object::Integer(0)),
+        })
+        .collect::<Result<Vec<Object>>>()?;
+    let descriptor_id = add_descriptor(doc, base_font, font, NONSYMBOLIC, font.data().to_vec());
+
+    Ok(doc.add_object(dictionary! {
+        "Type" => "Font",
+        "Subtype" => if font.is_cff { "Type1" } else { "TrueType" },
+        "BaseFont" => Object::Name(base_font.as_bytes().to_vec()),
+        "FirstChar" => 32,
+        "LastChar" => 255,
+        "Widths" => widths,
+        "Encoding" => "WinAnsiEncoding",
+        "FontDescriptor" => descriptor_id,
+    }))
+}
+
+/// Embed `font` as a composite font, subset to the glyphs needed to show `text`.
+///
+/// Text is shown as two byte glyph IDs through the Identity-H encoding, so any character the
+/// font has can be shown. A ToUnicode CMap maps the glyphs back for copying and searching.
+pub fn embed_font_subset(
+    doc: &mut Document,
+    base_font: &str,
+    font: Sfnt,
+    text: &str,
+) -> Result<TextFont> {
+    let mut used = BTreeMap::new();
+    for c in text.chars() {
+        used.entry(font.glyph(c)?).or_insert(c);
+    }
+    // Subset fonts are named with a tag of six capital letters derived from their glyphs
+    let tag: String = used
+        .keys()
+        .fold(0xcbf2_9ce4_8422_2325u64, |hash, glyph| {
+            (hash ^ *glyph as u64).wrapping_mul(0x0100_0000_01b3)
+        })
+        .to_be_bytes()[..6]
+        .iter()
+        .map(|b| (b'A' + b % 26) as char)
+        .collect();
+    let base_font = format!("{tag}+{base_font}");
+
+    let mut widths = vec![];
+    for glyph in used.keys() {
+        widths.push(Object::Integer(*glyph as i64));
+        widths.push(vec![Object::Real(font.glyph_advance(*glyph)? as _)].into());
+    }
+    let descriptor_id = add_descriptor(doc, &base_font, &font, SYMBOLIC, font.subset(text)?);
+    let mut cid_font = dictionary! {
+        "Type" => "Font",
+        "Subtype" => if font.is_cff { "CIDFontType0" } else { "CIDFontType2" },
+        "BaseFont" => Object::Name(base_font.as_bytes().to_vec()),
+        "CIDSystemInfo" => dictionary! {
+            "Registry" => Object::string_literal("Adobe"),
+            "Ordering" => Object::string_literal("Identity"),
+            "Supplement" => 0,
+        },
+        "FontDescriptor" => descriptor_id,
+        "W" => widths,
+    };
+    if !font.is_cff {
+        cid_font.set("CIDToGIDMap", "Identity");
+    }
+    let cid_font_id = doc.add_object(cid_font);
+    let to_unicode_id = doc.add_object(Stream::new(dictionary! {}, to_unicode(&used)));
+
+    let id = doc.add_object(dictionary! {
+        "Type" => "Font",
+        "Subtype" => "Type0",
+        "BaseFont" => Object::Name(base_font.as_bytes().to_vec()),
+        "Encoding" => "Identity-H",
+        "DescendantFonts" => vec![cid_font_id.into()],
+        "ToUnicode" => to_unicode_id,
+    });
+    Ok(TextFont {
+        id,
+        encoding: TextEncoding::Identity(font),
+    })
+}
+
+// A ToUnicode CMap mapping two byte glyph IDs to the characters they show
+fn to_unicode(glyphs: &BTreeMap<u16, char>) -> Vec<u8> {
+    let mut cmap = String::from(
+        "/CIDInit /ProcSet findresource begin\n12 dict begin\nbegincmap\n\
+         /CIDSystemInfo << /Registry (Adobe) /Ordering (UCS) /Supplement 0 >> def\n\
+         /CMapName /Adobe-Identity-UCS def\n/CMapType 2 def\n\
+         1 begincodespacerange\n<0000> <FFFF>\nendcodespacerange\n",
+    );
+    let glyphs: Vec<(&u16, &char)> = glyphs.iter().collect();
+    // At most 100 mappings to a block
+    for block in glyphs.chunks(100) {
+        cmap.push_str(&format!("{} beginbfchar\n", block.len()));
+        for (glyph, c) in block {
+            let unicode: String = c
+                .encode_utf16(&mut [0; 2])
+                .iter()
+                .map(|unit| format!("{unit:04X}"))
+                .collect();
+            cmap.push_str(&format!("<{glyph:04X}> <{unicode}>\n"));
+        }
+        cmap.push_str("endbfchar\n");
+    }
+    cmap.push_str("endcmap\nCMapName currentdict /CMap defineresource pop\nend\nend\n");
+    cmap.into_bytes()
+}
+
+// Font descriptor flags for fonts using the standard Latin character set, or not
+const NONSYMBOLIC: i64 = 32;
+const SYMBOLIC: i64 = 4;
+
+fn add_descriptor(
+    doc: &mut Document,
+    base_font: &str,
+    font: &Sfnt,
+    flags: i64,
+    program: Vec<u8>,
+) -> ObjectId {
+    let font_file = if font.is_cff {
+        doc.add_object(Stream::new(
+            dictionary! { "Subtype" => "OpenType" },
+            program,
+        ))
+    } else {
+        doc.add_object(Stream::new(
+            dictionary! { "Length1" => program.len() as i64 },
+            program,
+        ))
+    };
+
+    let mut descriptor = dictionary! {
+        "Type" => "FontDescriptor",
+        "FontName" => Object::Name(base_font.as_bytes().to_vec()),
+        "Flags" => flags,
+        "FontBBox" => font.bbox.iter().map(|v| Object::Real(font.scale(*v) as _)).collect::<Vec<Object>>(),
+        "ItalicAngle" => font.italic_angle,
+        "Ascent" => font.scale(font.ascent),
+        "Descent" => font.scale(font.descent),
+        "CapHeight" => font.scale(font.cap_height),
+        "StemV" => 80,
+    };
+    descriptor.set(
+        if font.is_cff {
+            "FontFile3"
+        } else {
+            "FontFile2"
+        },
+        font_file,
+    );
+    doc.add_object(descriptor)
+}
+
+// Advance widths of printable ASCII, space to tilde, from the Adobe Core14 AFM files
+const HELVETICA_WIDTHS: [u16; 95] = [
+    278, 278, 355, 556, 556, 889, 667, 191, 333, 333, 389, 584, 278, 333, 278, 278, 556, 556, 556,
+    556, 556, 556, 556, 556, 556, 556, 278, 278, 584, 584, 584, 556, 1015, 667, 667, 722, 722, 667,
+    611, 778, 722, 278, 500, 667, 556, 833, 722, 778, 667, 778, 722, 667, 611, 722, 667, 944, 667,
+    667, 611, 278, 278, 278, 469, 556, 333, 556, 556, 500, 556, 556, 278, 556, 556, 222, 222, 500,
+    222, 833, 556, 556, 556, 556, 333, 500, 278, 556, 500, 722, 500, 500, 500, 334, 260, 334, 584,
+];
+const TIMES_WIDTHS: [u16; 95] = [
+    250, 333, 408, 500, 500, 833, 778, 180, 333, 333, 500, 564, 250, 333, 250, 278, 500, 500, 500,
+    500, 500, 500, 500, 500, 500, 500, 278, 278, 564, 564, 564, 444, 921, 722, 667, 667, 722, 611,
+    556, 722, 722, 333, 389, 722, 611, 889, 722, 722, 556, 722, 667, 556, 611, 722, 722, 944, 722,
+    722, 611, 333, 278, 333, 469, 500, 333, 444, 500, 444, 500, 444, 333, 500, 500, 278, 278, 500,
+    278, 778, 500, 500, 500, 500, 333, 389, 278, 500, 500, 722, 500, 500, 444, 480, 200, 480, 541,
+];
+
+/// Advance width of `c` in standard Type 1 `font`, in PDF text space units (1/1000 em).
+///
+/// Bold and italic styles use their family's regular widths so are approximate, characters
+/// outside printable ASCII are given an average width.
+pub fn standard_advance(font: &str, c: char) -> f64 {
+    let widths = if font.starts_with("Courier") {
+        return 600.0;
+    } else if font.starts_with("Times") {
+        &TIMES_WIDTHS
+    } else {
+        &HELVETICA_WIDTHS
+    };
+    match c {
+        ' '..='~' => widths[c as usize - 32] as f64,
+        _ => 500.0,
+    }
+}
+
+/// Build a tiny TrueType font mapping space, digits and ASCII letters.
+#[cfg(test)]
+pub fn test_font() -> Vec<u8> {
+    fn table(tables: &mut Vec<([u8; 4], Vec<u8>)>, tag: &[u8; 4], data: Vec<u8>) {
+        tables.push((*tag, data));
+    }
+    let mut tables = vec![];
+
+    let mut head = vec![0u8; 54];
+    head[18..20].copy_from_slice(&1000u16.to_be_bytes());
+    head[40..42].copy_from_slice(&600i16.to_be_bytes());
+    head[42..44].copy_from_slice(&800i16.to_be_bytes());
+    table(&mut tables, b"head", head);
+
+    let mut hhea = vec![0u8; 36];
+    hhea[4..6].copy_from_slice(&800i16.to_be_bytes());
+    hhea[6..8].copy_from_slice(&(-200i16).to_be_bytes());
+    hhea[34..36].copy_from_slice(&3u16.to_be_bytes());
+    table(&mut tables, b"hhea", hhea);
+
+    // Glyph 0 .notdef, 1 space and letters, 2 digits
+    let mut hmtx = vec![];
+    for advance in [500u16, 250, 556] {
+        hmtx.extend_from_slice(&advance.to_be_bytes());
+        hmtx.extend_from_slice(&0u16.to_be_bytes());
+    }
+    table(&mut tables, b"hmtx", hmtx);
+
+    // Format 4 with segments for space, digits, letters and the required 0xFFFF
+    let segments: [(u16, u16, u16); 4] = [
+        (32, 32, 1u16.wrapping_sub(32)),
+        (48, 57, 2u16.wrapping_sub(48)),
+        (65, 122, 1u16.wrapping_sub(65)),
+        (0xFFFF, 0xFFFF, 1),
+    ];
+    let mut cmap = vec![];
+    cmap.extend_from_slice(&0u16.to_be_bytes()); // version
+    cmap.extend_from_slice(&1u16.to_be_bytes()); // numTables
+    cmap.extend_from_slice(&3u16.to_be_bytes()); // platform
+    cmap.extend_from_slice(&1u16.to_be_bytes()); // encoding
+    cmap.extend_from_slice(&12u32.to_be_bytes()); // offset
+    let mut subtable = vec![];
+    subtable.extend_from_slice(&4u16.to_be_bytes());
+    subtable.extend_from_slice(&0u16.to_be_bytes()); // length, unused
+    subtable.extend_from_slice(&0u16.to_be_bytes()); // language
+    subtable.extend_from_slice(&(segments.len() as u16 * 2).to_be_bytes());
+    subtable.extend_from_slice(&[0u8; 6]); // searchRange, entrySelector, rangeShift
+    segments
+        .iter()
+        .for_each(|s| subtable.extend_from_slice(&s.1.to_be_bytes()));
+    subtable.extend_from_slice(&0u16.to_be_bytes());
+    segments
+        .iter()
+        .for_each(|s| subtable.extend_from_slice(&s.0.to_be_bytes()));
+    segments
+        .iter()
+        .for_each(|s| subtable.extend_from_slice(&s.2.to_be_bytes()));
+    segments
+        .iter()
+        .for_each(|_| subtable.extend_from_slice(&0u16.to_be_bytes()));
+    cmap.extend_from_slice(&subtable);
+    table(&mut tables, b"cmap", cmap);
+
+    // Letters map up to glyph 58, only the first three have outlines. Glyph 2 is a
+    // composite made from glyph 1.
+    let num_glyphs = 59u16;
+    let mut maxp = vec![0u8; 6];
+    maxp[0..4].copy_from_slice(&0x0000_5000u32.to_be_bytes());
+    maxp[4..6].copy_from_slice(&num_glyphs.to_be_bytes());
+    table(&mut tables, b"maxp", maxp);
+
+    let mut glyf = vec![0u8; 12];
+    glyf.extend_from_slice(&[0, 0, 0, 1, 0, 1, 0, 1, 0, 1, 0, 0]);
+    glyf.extend_from_slice(&(-1i16).to_be_bytes());
+    glyf.extend_from_slice(&[0u8; 8]);
+    glyf.extend_from_slice(&0u16.to_be_bytes()); // flags, byte arguments and no more components
+    glyf.extend_from_slice(&1u16.to_be_bytes()); // glyphIndex
+    glyf.extend_from_slice(&[0, 0]); // arguments
+    let mut loca = vec![];
+    for offset in [0u16, 12, 24].into_iter().chain([40; 57]) {
+        loca.extend_from_slice(&(offset / 2).to_be_bytes());
+    }
+    table(&mut tables, b"loca", loca);
+    table(&mut tables, b"glyf", glyf);
+
+    write_sfnt(0x0001_0000, &tables)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_font() {
+        let font = Sfnt::parse(test_font()).unwrap();
+        assert!(!font.is_cff);
+        assert_eq!(font.advance(' ').unwrap(), 250.0);
+        assert_eq!(font.advance('7').unwrap(), 556.0);
+        assert_eq!(font.advance('A').unwrap(), 250.0);
+        // Glyphs past the last horizontal metric share its advance
+        assert_eq!(font.advance('P').unwrap(), 556.0);
+        // Unmapped characters use .notdef
+        assert_eq!(font.advance('~').unwrap(), 500.0);
+
+        assert!(Sfnt::parse(b"not a font".to_vec()).is_err());
+    }
+
+    #[test]
+    fn test_subset() {
+        let font = Sfnt::parse(test_font()).unwrap();
+        let glyf_len = |text: &str| {
+            let subset = font.subset(text).unwrap();
+            assert!(Sfnt::parse(subset.clone()).is_ok());
+            find_table(&subset, b"glyf").unwrap().unwrap().1
+        };
+        assert_eq!(glyf_len(""), 12);
+        assert_eq!(glyf_len(" "), 24);
+        // The composite digit glyph brings in its component
+        assert_eq!(glyf_len("7"), 40);
+        assert_eq!(glyf_len("Page 7"), 40);
+
+        let mut doc = Document::with_version("1.7");
+        let font = embed_font_subset(&mut doc, "Test", font, "Page 7").unwrap();
+        let dict = doc.get_dictionary(font.id).unwrap();
+        assert_eq!(
+            dict.get(b"Subtype").unwrap().as_name_str().unwrap(),
+            "Type0"
+        );
+        assert!(dict
+            .get(b"BaseFont")
+            .unwrap()
+            .as_name_str()
+            .unwrap()
+            .ends_with("+Test"));
+        // Letters and space share glyph 1, digits are glyph 2
+        let cid_font_id = dict.get(b"DescendantFonts").unwrap().as_array().unwrap()[0]
+            .as_reference()
+            .unwrap();
+        let widths = doc
+            .get_dictionary(cid_font_id)
+            .unwrap()
+            .get(b"W")
+            .unwrap()
+            .as_array()
+            .unwrap();
+        assert_eq!(widths.len(), 4);
+        let Object::String(shown, _) = font.encode("a7") else {
+            panic!("Not a string");
+        };
+        assert_eq!(shown, [0, 1, 0, 2]);
+
+        let to_unicode_id = dict.get(b"ToUnicode").unwrap().as_reference().unwrap();
+        let cmap = &doc
+            .get_object(to_unicode_id)
+            .unwrap()
+            .as_stream()
+            .unwrap()
+            .content;
+        assert!(String::from_utf8_lossy(cmap).contains("<0002> <0037>"));
+    }
+
+    #[test]
+    fn test_win_ansi_font() {
+        let font = TextFont::win_ansi((1, 0), "Helvetica", None);
+        let Object::String(shown, _) = font.encode("Chapter 3 — Café 日") else {
+            panic!("Not a string");
+        };
+        assert_eq!(shown, b"Chapter 3 \x97 Caf\xe9 ?");
+        assert_eq!(font.width("A?", 10.0), font.width("A日", 10.0));
+    }
+
+    #[test]
+    fn test_standard_advance() {
+        assert_eq!(standard_advance("Helvetica", ' '), 278.0);
+        assert_eq!(standard_advance("Helvetica−Bold", '7'), 556.0);
+        assert_eq!(standard_advance("Times−Roman", 'W'), 944.0);
+        assert_eq!(standard_advance("Times-Italic", '~'), 541.0);
+        assert_eq!(standard_advance("Courier−Oblique", 'i'), 600.0);
+        assert_eq!(standard_advance("Helvetica", 'é'), 500.0);
+    }
+}
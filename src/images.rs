@@ -0,0 +1,527 @@
+// Copyright (c) 2024 DDN. All rights reserved.
+// Use of this source code is governed by a MIT-style
+// license that can be found in the LICENSE file.
+
+use crate::config::OptimizeImages;
+use anyhow::Result;
+use jpeg_encoder::{ColorType, Encoder};
+use lopdf::{content::Content, Dictionary, Document, Object, ObjectId};
+use std::collections::{HashMap, HashSet};
+
+/// What `optimize_images` changed
+#[derive(Debug, Default)]
+pub struct ImageReport {
+    /// Number of images downsampled and re-encoded
+    pub images: usize,
+    /// Encoded size of those images before, in bytes
+    pub before: usize,
+    /// Encoded size of those images after, in bytes
+    pub after: usize,
+}
+
+// Form XObjects nested deeper than this aren't followed
+const MAX_FORM_DEPTH: usize = 16;
+
+type Matrix = [f64; 6];
+
+const IDENTITY: Matrix = [1.0, 0.0, 0.0, 1.0, 0.0, 0.0];
+
+// `m` applied before `n`, as `cm` applies a matrix before the current transformation matrix
+fn multiply(m: Matrix, n: Matrix) -> Matrix {
+    [
+        m[0] * n[0] + m[1] * n[2],
+        m[0] * n[1] + m[1] * n[3],
+        m[2] * n[0] + m[3] * n[2],
+        m[2] * n[1] + m[3] * n[3],
+        m[4] * n[0] + m[5] * n[2] + n[4],
+        m[4] * n[1] + m[5] * n[3] + n[5],
+    ]
+}
+
+fn number(object: &Object) -> Option<f64> {
+    match object {
+        Object::Integer(i) => Some(*i as f64),
+        Object::Real(r) => Some(*r as f64),
+        _ => None,
+    }
+}
+
+fn matrix(operands: &[Object]) -> Option<Matrix> {
+    let numbers: Vec<f64> = operands.iter().map(number).collect::<Option<_>>()?;
+    numbers.try_into().ok()
+}
+
+fn deref<'a>(doc: &'a Document, object: &'a Object) -> Option<&'a Object> {
+    match object {
+        Object::Reference(id) => doc.get_object(*id).ok(),
+        o => Some(o),
+    }
+}
+
+// Record the largest size, in points, each image is drawn at by `content`, following Form XObjects
+fn collect_uses(
+    doc: &Document,
+    content: &[u8],
+    resources: Option<&Dictionary>,
+    ctm: Matrix,
+    depth: usize,
+    uses: &mut HashMap<ObjectId, (f64, f64)>,
+) {
+    let Ok(content) = Content::decode(content) else {
+        return;
+    };
+    let xobjects = resources
+        .and_then(|r| r.get(b"XObject").ok())
+        .and_then(|o| deref(doc, o))
+        .and_then(|o| o.as_dict().ok());
+
+    let mut stack = vec![];
+    let mut ctm = ctm;
+    for op in content.operations {
+        match op.operator.as_str() {
+            "q" => stack.push(ctm),
+            "Q" => ctm = stack.pop().unwrap_or(ctm),
+            "cm" => {
+                if let Some(m) = matrix(&op.operands) {
+                    ctm = multiply(m, ctm);
+                }
+            }
+            "Do" => {
+                let Some(id) = op
+                    .operands
+                    .first()
+                    .and_then(|name| name.as_name().ok())
+                    .and_then(|name| xobjects?.get(name).ok())
+                    .and_then(|o| o.as_reference().ok())
+                else {
+                    continue;
+                };
+                let Ok(Object::Stream(stream)) = doc.get_object(id) else {
+                    continue;
+                };
+                match stream.dict.get(b"Subtype").and_then(Object::as_name_str) {
+                    // Images are drawn into the unit square
+                    Ok("Image") => {
+                        let width = ctm[0].hypot(ctm[1]);
+                        let height = ctm[2].hypot(ctm[3]);
+                        let size = uses.entry(id).or_default();
+                        *size = (size.0.max(width), size.1.max(height));
+                    }
+                    Ok("Form") if depth < MAX_FORM_DEPTH => {
+                        let form_matrix = stream
+                            .dict
+                            .get(b"Matrix")
+                            .and_then(Object::as_array)
+                            .ok()
+                            .and_then(|m| matrix(m))
+                            .unwrap_or(IDENTITY);
+                        let form_resources = stream
+                            .dict
+                            .get(b"Resources")
+                            .ok()
+                            .and_then(|o| deref(doc, o))
+                            .and_then(|o| o.as_dict().ok())
+                            .or(resources);
+                        let form_content = match stream.dict.has(b"Filter") {
+                            true => stream.decompressed_content().unwrap_or_default(),
+                            false => stream.content.clone(),
+                        };
+                        collect_uses(
+                            doc,
+                            &form_content,
+                            form_resources,
+                            multiply(form_matrix, ctm),
+                            depth + 1,
+                            uses,
+                        );
+                    }
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+// Images used as a mask of another, changing them would change what shows through
+fn collect_masks(object: &Object, masks: &mut HashSet<ObjectId>) {
+    let dict = match object {
+        Object::Dictionary(dict) => dict,
+        Object::Stream(stream) => &stream.dict,
+        Object::Array(array) => {
+            array.iter().for_each(|o| collect_masks(o, masks));
+            return;
+        }
+        _ => return,
+    };
+    for (key, value) in dict.iter() {
+        match (key.as_slice(), value) {
+            (b"SMask" | b"Mask", Object::Reference(id)) => {
+                masks.insert(*id);
+            }
+            _ => collect_masks(value, masks),
+        }
+    }
+}
+
+// The JPEG color type of an image `dict`, `None` for anything but plain 8 bit gray or RGB
+fn color_type(doc: &Document, dict: &Dictionary, transparent: bool) -> Option<ColorType> {
+    let int = |key: &[u8]| dict.get(key).and_then(Object::as_i64).ok();
+    if int(b"BitsPerComponent") != Some(8)
+        || dict.has(b"ImageMask")
+        || dict.has(b"Mask")
+        || dict.has(b"Decode")
+        || dict.has(b"DecodeParms")
+        || (dict.has(b"SMask") && !transparent)
+    {
+        return None;
+    }
+    // A soft mask with `Matte` must have the image's size, the colors are premultiplied with it
+    let smask = dict.get(b"SMask").ok().and_then(|o| deref(doc, o));
+    if let Some(Object::Stream(smask)) = smask {
+        if smask.dict.has(b"Matte") {
+            return None;
+        }
+    }
+    match dict.get(b"Filter").ok().and_then(|o| deref(doc, o)) {
+        None => {}
+        Some(Object::Name(name)) if name == b"FlateDecode" => {}
+        Some(Object::Array(filters))
+            if filters.len() == 1
+                && filters[0].as_name().ok() == Some(b"FlateDecode".as_slice()) => {}
+        _ => return None,
+    }
+
+    let components = match deref(doc, dict.get(b"ColorSpace").ok()?)? {
+        Object::Name(name) => match name.as_slice() {
+            b"DeviceGray" => 1,
+            b"DeviceRGB" => 3,
+            _ => return None,
+        },
+        // ICC profiles are kept, the samples stay in the same color space
+        Object::Array(cs) if cs.first()?.as_name().ok()? == b"ICCBased" => {
+            match deref(doc, cs.get(1)?)? {
+                Object::Stream(profile) => profile.dict.get(b"N").and_then(Object::as_i64).ok()?,
+                _ => return None,
+            }
+        }
+        _ => return None,
+    };
+    match components {
+        1 => Some(ColorType::Luma),
+        3 => Some(ColorType::Rgb),
+        _ => None,
+    }
+}
+
+// Average the `components` channel `pixels` of a `width` by `height` image down to `new_width` by `new_height`
+fn downsample(
+    pixels: &[u8],
+    (width, height): (usize, usize),
+    (new_width, new_height): (usize, usize),
+    components: usize,
+) -> Vec<u8> {
+    let mut out = Vec::with_capacity(new_width * new_height * components);
+    let mut sums = vec![0u64; components];
+    for y in 0..new_height {
+        let y0 = y * height / new_height;
+        let y1 = ((y + 1) * height / new_height).max(y0 + 1);
+        for x in 0..new_width {
+            let x0 = x * width / new_width;
+            let x1 = ((x + 1) * width / new_width).max(x0 + 1);
+            sums.iter_mut().for_each(|s| *s = 0);
+            for row in y0..y1 {
+                let start = (row * width + x0) * components;
+                let end = (row * width + x1) * components;
+                for (i, sample) in pixels[start..end].iter().enumerate() {
+                    sums[i % components] += *sample as u64;
+                }
+            }
+            let count = ((y1 - y0) * (x1 - x0)) as u64;
+            out.extend(sums.iter().map(|s| ((s + count / 2) / count) as u8));
+        }
+    }
+    out
+}
+
+/// Downsample images drawn above `options.max_dpi` and re-encode them as JPEG.
+///
+/// Only 8 bit gray, RGB and ICC based images are changed. Images used as masks, with
+/// color key masks, decode arrays, predictors or filters other than Flate are skipped, as
+/// are those with soft masks unless `options.transparent` is set. Images whose soft mask has
+/// `Matte` are always skipped, as the mask would need resampling too. An image is only
+/// replaced when the JPEG is smaller.
+pub fn optimize_images(doc: &mut Document, options: &OptimizeImages) -> Result<ImageReport> {
+    let mut uses = HashMap::new();
+    for page_id in doc.page_iter() {
+        let content = doc.get_page_content(page_id).unwrap_or_default();
+        let resources = doc
+            .get_dictionary(page_id)?
+            .get(b"Resources")
+            .ok()
+            .and_then(|o| deref(doc, o))
+            .and_then(|o| o.as_dict().ok());
+        collect_uses(doc, &content, resources, IDENTITY, 0, &mut uses);
+    }
+
+    let mut masks = HashSet::new();
+    for object in doc.objects.values() {
+        collect_masks(object, &mut masks);
+    }
+
+    let mut report = ImageReport::default();
+    for (id, (drawn_width, drawn_height)) in uses {
+        if masks.contains(&id) || drawn_width <= 0.0 || drawn_height <= 0.0 {
+            continue;
+        }
+        let stream = doc.get_object(id)?.as_stream()?;
+        let Some(color_type) = color_type(doc, &stream.dict, options.transparent) else {
+            continue;
+        };
+        let size = |key: &[u8]| {
+            stream
+                .dict
+                .get(key)
+                .and_then(Object::as_i64)
+                .ok()
+                .filter(|s| *s > 0)
+                .map(|s| s as usize)
+        };
+        let (Some(width), Some(height)) = (size(b"Width"), size(b"Height")) else {
+            continue;
+        };
+
+        // The lower of the horizontal and vertical resolution the image is drawn at
+        let dpi = (width as f64 * 72.0 / drawn_width).min(height as f64 * 72.0 / drawn_height);
+        if dpi <= options.max_dpi {
+            continue;
+        }
+        let scale = options.max_dpi / dpi;
+        let new_size = (
+            ((width as f64 * scale).round() as usize).max(1),
+            ((height as f64 * scale).round() as usize).max(1),
+        );
+        let (Ok(new_width), Ok(new_height)) =
+            (u16::try_from(new_size.0), u16::try_from(new_size.1))
+        else {
+            continue;
+        };
+
+        let components = color_type.get_bytes_per_pixel();
+        let pixels = match stream.dict.has(b"Filter") {
+            true => match stream.decompressed_content() {
+                Ok(pixels) => pixels,
+                Err(_) => continue,
+            },
+            false => stream.content.clone(),
+        };
+        if pixels.len() != width * height * components {
+            continue;
+        }
+        let resized = downsample(&pixels, (width, height), new_size, components);
+        let mut jpeg = vec![];
+        Encoder::new(&mut jpeg, options.quality)
+            .encode(&resized, new_width, new_height, color_type)?;
+        if jpeg.len() >= stream.content.len() {
+            continue;
+        }
+
+        report.images += 1;
+        report.before += stream.content.len();
+        report.after += jpeg.len();
+        let stream = doc.get_object_mut(id)?.as_stream_mut()?;
+        stream.dict.set("Filter", "DCTDecode");
+        stream.dict.set("Width", new_size.0 as i64);
+        stream.dict.set("Height", new_size.1 as i64);
+        stream.set_content(jpeg);
+        stream.allows_compression = false;
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lopdf::{dictionary, Stream};
+
+    fn options() -> OptimizeImages {
+        toml::from_str("").unwrap()
+    }
+
+    // A `width` by `height` RGB image with a gradient, Flate compressed
+    fn image(doc: &mut Document, width: usize, height: usize, extra: Dictionary) -> ObjectId {
+        let pixels = (0..width * height)
+            .flat_map(|i| {
+                let (x, y) = (i % width, i / width);
+                [(x * 255 / width) as u8, (y * 255 / height) as u8, 128]
+            })
+            .collect();
+        let mut dict = dictionary! {
+            "Type" => "XObject",
+            "Subtype" => "Image",
+            "Width" => width as i64,
+            "Height" => height as i64,
+            "ColorSpace" => "DeviceRGB",
+            "BitsPerComponent" => 8,
+        };
+        for (key, value) in extra.iter() {
+            dict.set(key.clone(), value.clone());
+        }
+        let mut stream = Stream::new(dict, pixels);
+        stream.compress().unwrap();
+        doc.add_object(stream)
+    }
+
+    #[test]
+    fn test_optimize_images() {
+        let mut doc = Document::with_version("1.7");
+        let pages_id = doc.new_object_id();
+        let large = image(&mut doc, 400, 200, dictionary! {});
+        let small = image(&mut doc, 100, 50, dictionary! {});
+        let mask = doc.add_object(Stream::new(
+            dictionary! {
+                "Type" => "XObject",
+                "Subtype" => "Image",
+                "Width" => 400,
+                "Height" => 200,
+                "ColorSpace" => "DeviceGray",
+                "BitsPerComponent" => 8,
+            },
+            vec![255; 400 * 200],
+        ));
+        let masked = image(&mut doc, 400, 200, dictionary! { "SMask" => mask });
+        let matte_mask = doc.add_object(Stream::new(
+            dictionary! {
+                "Type" => "XObject",
+                "Subtype" => "Image",
+                "Width" => 400,
+                "Height" => 200,
+                "ColorSpace" => "DeviceGray",
+                "BitsPerComponent" => 8,
+                "Matte" => vec![0.into(), 0.into(), 0.into()],
+            },
+            vec![128; 400 * 200],
+        ));
+        let premultiplied = image(&mut doc, 400, 200, dictionary! { "SMask" => matte_mask });
+        let indexed = image(
+            &mut doc,
+            400,
+            200,
+            dictionary! {
+                "ColorSpace" => vec![
+                    "Indexed".into(),
+                    "DeviceRGB".into(),
+                    0.into(),
+                    Object::string_literal(vec![0, 0, 0]),
+                ],
+            },
+        );
+
+        // Every image is drawn 100 by 50 points inside a form scaled by 2
+        let form = doc.add_object(Stream::new(
+            dictionary! {
+                "Type" => "XObject",
+                "Subtype" => "Form",
+                "BBox" => vec![0.into(), 0.into(), 100.into(), 100.into()],
+                "Matrix" => vec![2.into(), 0.into(), 0.into(), 2.into(), 0.into(), 0.into()],
+                "Resources" => dictionary! {
+                    "XObject" => dictionary! {
+                        "Large" => large,
+                        "Small" => small,
+                        "Masked" => masked,
+                        "Indexed" => indexed,
+                        "Premultiplied" => premultiplied,
+                    },
+                },
+            },
+            b"q 50 0 0 25 0 0 cm /Large Do /Small Do /Masked Do /Indexed Do /Premultiplied Do Q"
+                .to_vec(),
+        ));
+        let content = doc.add_object(Stream::new(
+            dictionary! {},
+            b"q 1 0 0 1 10 10 cm /Fm1 Do Q".to_vec(),
+        ));
+        let page_id = doc.add_object(dictionary! {
+            "Type" => "Page",
+            "Parent" => pages_id,
+            "Contents" => content,
+            "Resources" => dictionary! { "XObject" => dictionary! { "Fm1" => form } },
+        });
+        doc.objects.insert(
+            pages_id,
+            Object::Dictionary(dictionary! {
+                "Type" => "Pages",
+                "Kids" => vec![page_id.into()],
+                "Count" => 1,
+            }),
+        );
+        let catalog_id = doc.add_object(dictionary! {
+            "Type" => "Catalog",
+            "Pages" => pages_id,
+        });
+        doc.trailer.set("Root", catalog_id);
+
+        let before = |doc: &Document, id| doc.get_object(id).unwrap().as_stream().unwrap().clone();
+        let untouched = [small, mask, masked, indexed].map(|id| before(&doc, id));
+        let premultiplied_before = before(&doc, premultiplied);
+
+        // 400 pixels across 100 points is 288 dpi, halved to 144 by max_dpi 144
+        let report = optimize_images(
+            &mut doc,
+            &OptimizeImages {
+                max_dpi: 144.0,
+                ..options()
+            },
+        )
+        .unwrap();
+        assert_eq!(report.images, 1);
+        assert!(report.after < report.before);
+
+        let stream = doc.get_object(large).unwrap().as_stream().unwrap();
+        let int = |key: &[u8]| stream.dict.get(key).unwrap().as_i64().unwrap();
+        assert_eq!((int(b"Width"), int(b"Height")), (200, 100));
+        assert_eq!(
+            stream.dict.get(b"Filter").unwrap().as_name_str().unwrap(),
+            "DCTDecode"
+        );
+        assert!(stream.content.starts_with(&[0xFF, 0xD8]));
+        assert_eq!(report.after, stream.content.len());
+
+        for (id, before) in [small, mask, masked, indexed].into_iter().zip(untouched) {
+            let after = doc.get_object(id).unwrap().as_stream().unwrap();
+            assert_eq!(after.content, before.content);
+        }
+
+        // Soft masked images are only changed when asked to, the mask stays as it was
+        let report = optimize_images(
+            &mut doc,
+            &OptimizeImages {
+                max_dpi: 144.0,
+                transparent: true,
+                ..options()
+            },
+        )
+        .unwrap();
+        assert_eq!(report.images, 1);
+        let stream = doc.get_object(masked).unwrap().as_stream().unwrap();
+        assert_eq!(
+            stream.dict.get(b"SMask").unwrap().as_reference().unwrap(),
+            mask
+        );
+        assert_eq!(
+            doc.get_object(mask).unwrap().as_stream().unwrap().content,
+            vec![255; 400 * 200]
+        );
+        // Unless the mask has Matte, it would no longer match the image's size
+        let stream = doc.get_object(premultiplied).unwrap().as_stream().unwrap();
+        assert_eq!(stream.content, premultiplied_before.content);
+    }
+
+    #[test]
+    fn test_downsample() {
+        let pixels = [0, 10, 20, 30, 40, 50, 60, 70];
+        assert_eq!(downsample(&pixels, (4, 2), (2, 1), 1), [25, 45]);
+        assert_eq!(downsample(&pixels, (2, 2), (1, 1), 2), [30, 40]);
+    }
+}
@@ -0,0 +1,30 @@
+// Copyright (c) 2024 DDN. All rights reserved.
+// Use of this source code is governed by a MIT-style
+// license that can be found in the LICENSE file.
+
+//! Export a `VitePress` site as a single PDF with working links and page numbers.
+//!
+//! The `vitepress-pdf-export` binary is a thin wrapper around this crate.
+
+mod cache;
+pub mod config;
+mod dedupe;
+#[cfg(feature = "render")]
+pub mod dev_server;
+mod encryption;
+mod flatten;
+mod font;
+mod images;
+pub mod merge;
+mod metadata;
+pub mod progress;
+#[cfg(feature = "render")]
+pub mod render;
+#[cfg(feature = "render")]
+mod render_cache;
+pub mod serve;
+mod text;
+mod toc;
+mod watermark;
+
+pub use config::Config;
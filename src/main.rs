@@ -4,19 +4,79 @@
 
 use anyhow::{anyhow, Result};
 use clap::Parser;
-use std::{fs, fs::File, io::Write, path::PathBuf, process::ExitCode};
+use std::{
+    fs,
+    fs::File,
+    io::Write,
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
 use tempfile::tempdir;
 
-mod config;
-use config::Config;
-mod merge;
-use merge::merge_pdfs;
-mod render;
-use render::render_urls;
+use serde::{Deserialize, Serialize};
+use vitepress_pdf_export::{
+    config::split_origin,
+    merge::merge_pdfs,
+    progress::{PageTimings, ProgressMode, RenderOutcome},
+    serve::StaticServer,
+    Config,
+};
+#[cfg(feature = "render")]
+use vitepress_pdf_export::{
+    dev_server::DevServerProcess,
+    render::{render_urls, RenderError},
+};
+
+// Merge only builds can still merge PDFs rendered elsewhere
+#[cfg(not(feature = "render"))]
+async fn render_urls(_config: &Config, _pdf_temp_dir: &Path) -> Result<RenderOutcome> {
+    Err(anyhow!(
+        "This build of vitepress-pdf-export doesn't include the render feature, use --merge_only with a --map of rendered PDFs"
+    ))
+}
+
+// A `--map` value, the PDF or a record which also holds how long rendering took with
+// `--map-timings` and the scale `auto_scale` picked
+#[derive(Deserialize, Serialize)]
+#[serde(untagged)]
+enum MapEntry {
+    Pdf(PathBuf),
+    Record {
+        pdf: PathBuf,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        timings: Option<PageTimings>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        scale: Option<f64>,
+    },
+}
+
+// Read a `--map` written with or without `--map-timings`
+fn read_map(path: &Path) -> Result<indexmap::IndexMap<String, PathBuf>> {
+    let map: indexmap::IndexMap<String, MapEntry> =
+        serde_json::from_str(&fs::read_to_string(path)?)?;
+    Ok(map
+        .into_iter()
+        .map(|(url, entry)| match entry {
+            MapEntry::Pdf(pdf) | MapEntry::Record { pdf, .. } => (url, pdf),
+        })
+        .collect())
+}
+
+// Exit code when render_deadline_secs passes, ordinary failures exit with 1
+const DEADLINE_EXIT_CODE: u8 = 3;
+
+// Exit code after Ctrl-C, like a shell reports a process killed by SIGINT
+const INTERRUPTED_EXIT_CODE: u8 = 130;
 
 /// A program to convert a `VitePress` web site into a single PDF
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[command(
+    version,
+    about,
+    long_about = None,
+    after_help = "Command line options take precedence over the config file, which takes \
+        precedence over environment variables such as HTTPS_PROXY and NO_PROXY."
+)]
 struct Args {
     /// Configuration File
     #[arg(short = 'c', long)]
@@ -26,9 +86,18 @@ struct Args {
     #[arg(short = 'o', long)]
     output_pdf: Option<PathBuf>,
 
+    /// Overwrite the `chrome_path` defined in the config file
+    #[arg(long)]
+    chrome_path: Option<PathBuf>,
+
+    /// Overwrite the `temp_dir` defined in the config file
+    #[arg(long)]
+    temp_dir: Option<PathBuf>,
+
     /// Directory to save individual PDFs into.
     ///
-    /// If this option is not defined individual PDFs will be removed.
+    /// If this option is not defined individual PDFs will be removed. PDFs are named
+    /// after the page path, e.g. `guide-setup.html.pdf`.
     #[arg(short = 'k', long)]
     keep_pdfs: Option<PathBuf>,
 
@@ -38,6 +107,64 @@ struct Args {
     #[arg(short = 'm', long)]
     map: Option<PathBuf>,
 
+    /// Write a JSON map of each URL to its first page and headings in the merged PDF.
+    ///
+    /// Headings are nested by level and include the named destination pointing at them.
+    #[arg(long)]
+    page_map: Option<PathBuf>,
+
+    /// Write each URL's render timings into `--map` next to its PDF.
+    ///
+    /// `--merge_only` and `--resume` read maps with or without timings.
+    #[arg(long, action)]
+    map_timings: bool,
+
+    /// Render only the URLs or paths listed in this file, one per line, `-` reads stdin.
+    ///
+    /// Paths are resolved against `url` like `vitepress_links`. Empty lines and lines
+    /// starting with `#` are ignored.
+    #[arg(long)]
+    urls_file: Option<PathBuf>,
+
+    /// Save a full page PNG of every URL, taken just before printing, into this directory.
+    ///
+    /// The PNGs are named like the PDFs in `--keep_pdfs`. Failed screenshots only warn.
+    #[arg(long)]
+    debug_screenshots: Option<PathBuf>,
+
+    /// Write a JSON line per Link annotation recording how it was rewritten.
+    ///
+    /// Used to diagnose links pointing at the wrong page, output is unchanged.
+    #[arg(long)]
+    trace_links: Option<PathBuf>,
+
+    /// Write a JSON report of the URLs and anchors which couldn't be remapped.
+    ///
+    /// Includes the page, target and source URL of each, their counts, the page count
+    /// and the size of the merged PDF. Written even if `--strict` fails the merge.
+    #[arg(long)]
+    report: Option<PathBuf>,
+
+    /// Write a JSON map of each URL to its console errors, warnings and failed requests.
+    #[arg(long)]
+    console_report: Option<PathBuf>,
+
+    /// How progress is reported: `bar`, `plain` with a line per URL or `json` with an object per event.
+    ///
+    /// `bar` falls back to `plain` when stdout isn't a terminal. In `json` mode everything
+    /// else is printed to stderr.
+    #[arg(long)]
+    progress: Option<ProgressMode>,
+
+    /// Merge, resolve links and report without writing the merged PDF.
+    ///
+    /// Stream contents such as pages and images are neither loaded nor copied, and
+    /// passes which only change the written PDF, e.g. page numbers, metadata,
+    /// `flatten_transparency`, `optimize_images` and compression, are skipped. This
+    /// makes it faster and lighter than a full merge. The merge cache isn't used.
+    #[arg(long, action)]
+    no_output: bool,
+
     /// Used to speed up merge development.
     ///
     /// This option skips PDF rendering and uses the saved PDFs and map.
@@ -46,6 +173,83 @@ struct Args {
     /// will render out the pdfs then run `vitepress --merge-onlys --map map.json`
     #[arg(long, action)]
     merge_only: bool,
+
+    /// Resume a previous run, only rendering URLs without a PDF in `--keep_pdfs`.
+    ///
+    /// Requires `--keep_pdfs` and `--map`. The map is updated with the new PDFs and
+    /// URLs no longer in the config are dropped from it.
+    #[arg(long, action)]
+    resume: bool,
+
+    /// Never look up or download Chrome, see `offline` in the config.
+    #[arg(long, action)]
+    offline: bool,
+
+    /// Request every URL before rendering, see `preflight` in the config.
+    #[arg(long, action)]
+    preflight: bool,
+
+    /// Fail without writing the merged PDF if any link can't be remapped, see `strict_links` in the config.
+    #[arg(long, action)]
+    strict: bool,
+
+    /// Delete the cached Chrome revision and download it again.
+    #[arg(long, action)]
+    force_chrome_download: bool,
+
+    /// Render every page even if `render_cache` has an unchanged PDF for it.
+    ///
+    /// The cache is written again from this run's PDFs.
+    #[arg(long, action)]
+    no_cache: bool,
+
+    /// Disable the parsed PDF cache used by `--merge_only`.
+    ///
+    /// The cache is stored in `.merge-cache` next to the map file.
+    #[arg(long, action)]
+    no_merge_cache: bool,
+}
+
+// The serve_dir server and dev_server can get a new port every run, move URLs saved by a previous run to `origin`
+fn move_to_origin(
+    map: indexmap::IndexMap<String, PathBuf>,
+    origin: &str,
+) -> indexmap::IndexMap<String, PathBuf> {
+    map.into_iter()
+        .map(|(url, pdf)| (format!("{origin}{}", split_origin(&url).1), pdf))
+        .collect()
+}
+
+// Fail if the file system holding `dir` has less than `min_free_mb` available
+#[cfg(unix)]
+fn check_free_space(dir: &Path, min_free_mb: u64) -> Result<()> {
+    use std::{ffi::CString, os::unix::ffi::OsStrExt as _};
+
+    let c_dir = CString::new(dir.as_os_str().as_bytes())?;
+    // SAFETY: statvfs only writes into `stat`, which is plain old data
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_dir.as_ptr(), &mut stat) } != 0 {
+        return Err(anyhow!(
+            "Unable to check free space of {}: {}",
+            dir.display(),
+            std::io::Error::last_os_error()
+        ));
+    }
+    // The field types differ between platforms
+    #[allow(clippy::unnecessary_cast)]
+    let free_mb = stat.f_bavail as u64 * stat.f_frsize as u64 / 1024 / 1024;
+    if free_mb < min_free_mb {
+        return Err(anyhow!(
+            "Only {free_mb} MiB free in temp_dir {}, temp_dir_min_free_mb is {min_free_mb}",
+            dir.display()
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_free_space(_dir: &Path, _min_free_mb: u64) -> Result<()> {
+    Ok(())
 }
 
 #[tokio::main]
@@ -53,15 +257,129 @@ async fn main() -> Result<ExitCode> {
     let args = Args::parse();
 
     if args.merge_only && args.map.is_none() {
-        println!("--map must defined when --merge_only")
+        eprintln!("--map must defined when --merge_only")
     }
     let mut config = Config::load(&args.config)?;
 
+    if let Some(urls_file) = &args.urls_file {
+        let list = match urls_file.to_str() {
+            Some("-") => std::io::read_to_string(std::io::stdin())?,
+            _ => fs::read_to_string(urls_file)
+                .map_err(|e| anyhow!("Unable to read {}: {e}", urls_file.display()))?,
+        };
+        config.replace_urls(&list);
+        if config.urls.is_empty() {
+            return Err(anyhow!("{} doesn't list any URLs", urls_file.display()));
+        }
+    }
+
     if let Some(output_pdf) = args.output_pdf {
-        config.output_pdf = output_pdf;
+        config.output_pdf = PathBuf::from(config.expand(&output_pdf.to_string_lossy())?);
+    }
+
+    if args.temp_dir.is_some() {
+        config.temp_dir = args.temp_dir;
     }
 
-    let temp_dir = tempdir()?;
+    if args.chrome_path.is_some() {
+        config.chrome_path = args.chrome_path;
+    }
+
+    if args.offline {
+        config.offline = true;
+    }
+    config.force_chrome_download = args.force_chrome_download;
+
+    if args.preflight {
+        config.preflight = true;
+    }
+
+    if args.strict {
+        config.strict_links = true;
+    }
+
+    config.progress = args.progress.unwrap_or_default().detect();
+    config.debug_screenshots = args.debug_screenshots;
+    config.page_map = args.page_map;
+    config.trace_links = args.trace_links;
+    config.report = args.report;
+    config.console_report = args.console_report;
+    config.no_output = args.no_output;
+
+    if args.merge_only && !args.no_merge_cache {
+        if let Some(map) = args.map.as_ref() {
+            config.merge_cache = Some(map.parent().unwrap_or(Path::new("")).join(".merge-cache"));
+        }
+    }
+
+    if config.render_cache && !args.merge_only {
+        let Some(dir) = &args.keep_pdfs else {
+            return Err(anyhow!(
+                "render_cache requires --keep_pdfs, the cached PDFs are kept there"
+            ));
+        };
+        config.render_cache_file = Some(dir.join("cache.json"));
+        config.no_cache = args.no_cache;
+    }
+
+    // Serves the built site until the PDFs are rendered
+    let server = match (&config.serve_dir, args.merge_only) {
+        (Some(dir), false) => {
+            let base = split_origin(&config.url).1.to_string();
+            let server = StaticServer::start(dir, &base)?;
+            config.progress.println(format_args!(
+                "Serving {} at {}{base}",
+                dir.display(),
+                server.url()
+            ));
+            config.rebase_url(&format!("{}{base}", server.url()));
+            Some(server)
+        }
+        _ => None,
+    };
+
+    // Stopped when main returns, after merging or on any error
+    #[cfg(feature = "render")]
+    let _dev_server = match (&config.dev_server, args.merge_only) {
+        (Some(dev_server), false) => {
+            let process = DevServerProcess::start(dev_server, &config.url)?;
+            config
+                .progress
+                .println(format_args!("Started dev_server at {}", process.url()));
+            config.rebase_url(process.url());
+            Some(process)
+        }
+        _ => None,
+    };
+
+    if args.resume {
+        let (Some(_), Some(map)) = (&args.keep_pdfs, &args.map) else {
+            return Err(anyhow!("--resume requires --keep_pdfs and --map"));
+        };
+        if map.exists() {
+            config.resume = read_map(map)?;
+            if config.serve_dir.is_some() || config.dev_server.is_some() {
+                let origin = split_origin(&config.url).0.to_string();
+                config.resume = move_to_origin(std::mem::take(&mut config.resume), &origin);
+            }
+        }
+    }
+
+    let temp_dir = match &config.temp_dir {
+        Some(dir) => {
+            fs::create_dir_all(dir)?;
+            check_free_space(dir, config.temp_dir_min_free_mb)?;
+            let temp_dir = tempfile::Builder::new()
+                .prefix("vitepress-pdf-export-")
+                .tempdir_in(dir)?;
+            config.progress.println(format_args!(
+                "Using temporary directory {}",
+                temp_dir.path().display()
+            ));
+            temp_dir
+        }
+        None => tempdir()?,
+    };
 
     let path = match &args.keep_pdfs {
         None => {
@@ -71,23 +389,105 @@ async fn main() -> Result<ExitCode> {
         Some(dir) => dir.as_path(),
     };
 
-    let url_to_pdf: indexmap::IndexMap<String, PathBuf> = match args.merge_only {
-        false => render_urls(&config, path).await?,
-        true => serde_json::from_str::<indexmap::IndexMap<String, PathBuf>>(&fs::read_to_string(
-            args.map
-                .as_ref()
-                .ok_or(anyhow!("Map must be defined when using merge_only"))?,
-        )?)?,
+    let RenderOutcome {
+        pdfs: mut url_to_pdf,
+        failed: failed_urls,
+        deadline_exceeded,
+        timings,
+        opted_out,
+        scales,
+    } = match args.merge_only {
+        false => match render_urls(&config, path).await {
+            Ok(outcome) => outcome,
+            #[cfg(feature = "render")]
+            Err(e) if e.downcast_ref::<RenderError>().is_some() => {
+                eprintln!("{e}");
+                return Ok(ExitCode::from(INTERRUPTED_EXIT_CODE));
+            }
+            Err(e) => return Err(e),
+        },
+        true => RenderOutcome {
+            pdfs: read_map(
+                args.map
+                    .as_ref()
+                    .ok_or(anyhow!("Map must be defined when using merge_only"))?,
+            )?,
+            ..Default::default()
+        },
     };
 
+    drop(server);
+    config.opted_out.extend(opted_out);
+
+    // Rendering stops on Ctrl-C by itself, merging is just ended
+    tokio::spawn(async {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            std::process::exit(INTERRUPTED_EXIT_CODE.into());
+        }
+    });
+
+    // Links are rewritten against the address the saved map was rendered from
+    if args.merge_only && (config.serve_dir.is_some() || config.dev_server.is_some()) {
+        if let Some(url) = url_to_pdf.keys().next() {
+            let url = format!("{}{}", split_origin(url).0, split_origin(&config.url).1);
+            config.rebase_url(&url);
+        }
+    }
+
+    // Saved maps predate the current `order`, URLs missing from the config go last
+    if args.merge_only && !config.order.is_empty() {
+        url_to_pdf.sort_by_cached_key(|url, _| config.urls.get_index_of(url).unwrap_or(usize::MAX));
+    }
+
     if let Some(map) = args.map.as_ref() {
         if !args.merge_only {
+            let entries: indexmap::IndexMap<&String, MapEntry> = url_to_pdf
+                .iter()
+                .map(|(url, pdf)| {
+                    let timings = timings.get(url).filter(|_| args.map_timings).copied();
+                    let scale = scales.get(url).copied();
+                    let entry = match timings.is_some() || scale.is_some() {
+                        true => MapEntry::Record {
+                            pdf: pdf.clone(),
+                            timings,
+                            scale,
+                        },
+                        false => MapEntry::Pdf(pdf.clone()),
+                    };
+                    (url, entry)
+                })
+                .collect();
             let mut output = File::create(map)?;
-            write!(output, "{}", serde_json::to_string_pretty(&url_to_pdf)?)?;
+            write!(output, "{}", serde_json::to_string_pretty(&entries)?)?;
         }
     }
 
-    merge_pdfs(&config, url_to_pdf)
+    // The map of what was rendered is kept so the run can be resumed
+    if deadline_exceeded && (!config.continue_on_error || url_to_pdf.is_empty()) {
+        config.progress.println(format_args!(
+            "Rendering didn't finish within render_deadline_secs, not merging"
+        ));
+        return Ok(ExitCode::from(DEADLINE_EXIT_CODE));
+    }
+
+    let retcode = merge_pdfs(&config, url_to_pdf)?;
+
+    if !failed_urls.is_empty() {
+        config.progress.println(format_args!(
+            "Failed to render these URLS, they are missing from the merged PDF.\n{}",
+            failed_urls
+                .iter()
+                .map(|s| format!("  * {s}"))
+                .collect::<Vec<String>>()
+                .join("\n")
+        ));
+        return Ok(match deadline_exceeded {
+            true => ExitCode::from(DEADLINE_EXIT_CODE),
+            false => ExitCode::FAILURE,
+        });
+    }
+
+    Ok(retcode)
 }
 
 #[cfg(test)]
@@ -99,4 +499,48 @@ mod tests {
     fn verify_cli() {
         Args::command().debug_assert()
     }
+
+    #[test]
+    fn test_read_map() {
+        let dir = tempfile::tempdir().unwrap();
+        let map = dir.path().join("map.json");
+        std::fs::write(
+            &map,
+            r#"{
+                "http://localhost:5173/index.html": "pdfs/index.html.pdf",
+                "http://localhost:5173/guide.html": {
+                    "pdf": "pdfs/guide.html.pdf",
+                    "timings": {"navigation_secs": 0.4, "settle_secs": 0.1, "print_secs": 1.2, "total_secs": 1.8}
+                },
+                "http://localhost:5173/api.html": {"pdf": "pdfs/api.html.pdf", "scale": 0.8}
+            }"#,
+        )
+        .unwrap();
+        let map = super::read_map(&map).unwrap();
+        assert_eq!(
+            map.into_values().collect::<Vec<_>>(),
+            [
+                std::path::PathBuf::from("pdfs/index.html.pdf"),
+                std::path::PathBuf::from("pdfs/guide.html.pdf"),
+                std::path::PathBuf::from("pdfs/api.html.pdf")
+            ]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_check_free_space() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(super::check_free_space(dir.path(), 0).is_ok());
+        assert!(super::check_free_space(dir.path(), u64::MAX).is_err());
+    }
+
+    #[cfg(not(feature = "render"))]
+    #[tokio::test]
+    async fn test_merge_only_build() {
+        let err = super::render_urls(&super::Config::default(), std::path::Path::new(""))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("--merge_only"));
+    }
 }
@@ -2,39 +2,457 @@
 // Use of this source code is governed by a MIT-style
 // license that can be found in the LICENSE file.
 
-use crate::Config;
+#[cfg(feature = "render")]
+use crate::render::check_external_links;
+use crate::{
+    cache::MergeCache,
+    config::{
+        normalize_link, split_origin, Align, BrokenLinks, ChapterBreaks, Color, Encryption,
+        FrontMatterStyle, YFrom,
+    },
+    dedupe::{dedupe_objects, replace_references},
+    encryption::encrypt,
+    flatten::{flatten_transparency, FlattenReport},
+    font::{embed_font, embed_font_subset, Sfnt, TextFont},
+    images::{optimize_images, ImageReport},
+    metadata::add_metadata,
+    progress::ProgressEvent,
+    text::{decode_text_string, encode_text_string, one_line},
+    toc::insert_toc,
+    watermark::{
+        add_resource, add_save_restore, add_watermark, draw_over, own_resources, resource_name,
+    },
+    Config,
+};
 use anyhow::{anyhow, Result};
 use indexmap::IndexMap;
 use lopdf::{
     content::{Content, Operation},
-    dictionary, Dictionary, Document, Object, ObjectId,
+    dictionary, Dictionary, Document, Object, ObjectId, Stream, StringFormat,
+};
+use percent_encoding::percent_decode_str;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    fmt, fs,
+    ops::Range,
+    path::{Path, PathBuf},
+    process::ExitCode,
+    sync::Arc,
 };
-use std::{collections::BTreeMap, path::PathBuf, process::ExitCode};
+use tokio_util::sync::CancellationToken;
 
 struct PdfParts {
     objects: BTreeMap<ObjectId, Object>,
     pages: BTreeMap<ObjectId, Object>,
+    /// Number of cover pages in front of the rendered ones
+    cover_pages: usize,
+    /// Index of the first page of each back matter PDF
+    back_matter_starts: Vec<usize>,
 }
 
 pub fn get_named_dests(doc: &Document) -> Result<IndexMap<Vec<u8>, lopdf::Object>> {
     let catalog = doc.catalog()?;
-    let mut tree = doc.get_dict_in_dict(catalog, b"Dests");
-
-    if tree.is_err() {
-        let names = doc.get_dict_in_dict(catalog, b"Names");
-        if let Ok(names) = names {
-            let dests = doc.get_dict_in_dict(names, b"Dests");
-            if dests.is_ok() {
-                tree = dests;
+
+    // PDF 1.1 style flat dictionary
+    if let Ok(dests) = doc.get_dict_in_dict(catalog, b"Dests") {
+        return Ok(dests.as_hashmap().clone());
+    }
+
+    get_name_tree(doc, b"Dests")
+}
+
+// The entries of the catalog's `Names` → `key` name tree, e.g. `Dests` or `EmbeddedFiles`
+fn get_name_tree(doc: &Document, key: &[u8]) -> Result<IndexMap<Vec<u8>, Object>> {
+    let names = doc.get_dict_in_dict(doc.catalog()?, b"Names")?;
+    let root = doc.get_dict_in_dict(names, key)?;
+    let mut entries = IndexMap::new();
+    read_name_tree(doc, root, &mut entries, &mut HashSet::new())?;
+    Ok(entries)
+}
+
+// Collect the key/value pairs of a name tree, see PDF 32000-1:2008 7.9.6
+fn read_name_tree(
+    doc: &Document,
+    node: &Dictionary,
+    entries: &mut IndexMap<Vec<u8>, Object>,
+    visited: &mut HashSet<ObjectId>,
+) -> Result<()> {
+    let limits = node
+        .get_deref(b"Limits", doc)
+        .and_then(Object::as_array)
+        .ok()
+        .and_then(|l| match l.as_slice() {
+            [first, last] => Some((first.as_str().ok()?, last.as_str().ok()?)),
+            _ => None,
+        });
+
+    if let Ok(names) = node.get_deref(b"Names", doc).and_then(Object::as_array) {
+        for pair in names.chunks_exact(2) {
+            let name = pair[0].as_str()?;
+            if let Some((first, last)) = limits {
+                if name < first || name > last {
+                    eprintln!(
+                        "Name {} is outside of its name tree node Limits",
+                        String::from_utf8_lossy(name)
+                    );
+                }
+            }
+            // Keys are text strings, store them as UTF-8 to match URL anchors
+            entries.insert(decode_text_string(name).into_bytes(), pair[1].clone());
+        }
+    }
+
+    if let Ok(kids) = node.get_deref(b"Kids", doc).and_then(Object::as_array) {
+        for kid in kids {
+            // Kids should be indirect but some producers write them inline
+            let kid = match kid {
+                Object::Reference(id) => {
+                    if !visited.insert(*id) {
+                        return Err(anyhow!(
+                            "Name tree node {id:?} is referenced more than once"
+                        ));
+                    }
+                    doc.get_dictionary(*id)?
+                }
+                kid => kid.as_dict()?,
+            };
+            read_name_tree(doc, kid, entries, visited)?;
+        }
+    }
+    Ok(())
+}
+
+// Replace a `Names` → `Dests` name tree with a flat catalog `Dests` dictionary,
+// which is the form `build_pdf_from_objects` merges.
+fn flatten_named_dests(doc: &mut Document) -> Result<()> {
+    if doc.catalog()?.has(b"Dests") {
+        return Ok(());
+    }
+    let Ok(dests) = get_named_dests(doc) else {
+        return Ok(());
+    };
+
+    let mut dictionary = Dictionary::new();
+    for (name, dest) in dests {
+        dictionary.set(name, dest);
+    }
+    let dests_id = doc.add_object(dictionary);
+
+    let catalog_id = doc.trailer.get(b"Root")?.as_reference()?;
+    let catalog = doc.get_dictionary_mut(catalog_id)?;
+    catalog.set("Dests", dests_id);
+    if let Ok(Object::Dictionary(names)) = catalog.get_mut(b"Names") {
+        names.remove(b"Dests");
+    }
+    Ok(())
+}
+
+// Replace the catalog's name trees, e.g. `EmbeddedFiles` or `JavaScript`, with a single node
+// each, which `build_pdf_from_objects` merges. `Dests` is left to `flatten_named_dests`. The
+// attachments of the page rendered from `url` are namespaced like its destinations.
+fn flatten_name_trees(doc: &mut Document, url: Option<&str>) -> Result<()> {
+    // The catalog's own copy of `Names`, which may be shared
+    let Ok(mut names) = doc.get_dict_in_dict(doc.catalog()?, b"Names").cloned() else {
+        return Ok(());
+    };
+    let keys: Vec<_> = names
+        .iter()
+        .map(|(key, _)| key.clone())
+        .filter(|key| key != b"Dests")
+        .collect();
+    for key in keys {
+        let tree = match get_name_tree(doc, &key) {
+            Ok(tree) => tree,
+            Err(e) => {
+                eprintln!(
+                    "Dropping unreadable {} name tree: {e}",
+                    String::from_utf8_lossy(&key)
+                );
+                names.remove(&key);
+                continue;
+            }
+        };
+        let mut entries = vec![];
+        for (name, value) in tree {
+            let name = String::from_utf8_lossy(&name);
+            let name = match url {
+                Some(url) if key == b"EmbeddedFiles" => dest_key(url, &name),
+                _ => name.to_string(),
+            };
+            entries.push(encode_text_string(&name));
+            entries.push(value);
+        }
+        names.set(key, doc.add_object(dictionary! { "Names" => entries }));
+    }
+    let catalog_id = doc.trailer.get(b"Root")?.as_reference()?;
+    doc.get_dictionary_mut(catalog_id)?.set("Names", names);
+    Ok(())
+}
+
+// `name`, or with the lowest ` (n)` suffix not in `entries` when it's taken
+fn unique_name(entries: &BTreeMap<Vec<u8>, Object>, name: &[u8]) -> Vec<u8> {
+    std::iter::once(name.to_vec())
+        .chain((2..).map(|n| [name, format!(" ({n})").as_bytes()].concat()))
+        .find(|name| !entries.contains_key(name))
+        .unwrap()
+}
+
+// Name of the destination for `anchor` on the page rendered from `url`, its path and the
+// anchor, e.g. `/guide/setup#intro`
+fn dest_key(url: &str, anchor: &str) -> String {
+    let (_, path) = split_origin(url);
+    format!("{}#{anchor}", path.strip_suffix(".html").unwrap_or(path))
+}
+
+// The anchor of a destination name from `dest_key`
+fn dest_anchor(name: &str) -> &str {
+    name.split_once('#').map_or(name, |(_, anchor)| anchor)
+}
+
+// Point `Dest` and GoTo `D` entries naming a destination in `renamed` at its new name
+fn rename_dest_refs(object: &mut Object, renamed: &HashMap<Vec<u8>, Vec<u8>>) {
+    let dict = match object {
+        Object::Dictionary(dict) => dict,
+        Object::Stream(stream) => &mut stream.dict,
+        Object::Array(array) => {
+            array.iter_mut().for_each(|o| rename_dest_refs(o, renamed));
+            return;
+        }
+        _ => return,
+    };
+    for (key, value) in dict.iter_mut() {
+        if key == b"Dest" || key == b"D" {
+            let name = match value {
+                Object::Name(name) => Some(name.clone()),
+                Object::String(name, _) => Some(decode_text_string(name).into_bytes()),
+                _ => None,
+            };
+            if let Some(new_name) = name.and_then(|n| renamed.get(&n)) {
+                *value = Object::Name(new_name.clone());
+                continue;
+            }
+        }
+        rename_dest_refs(value, renamed);
+    }
+}
+
+// Prefix the named destinations of the PDF rendered from `url` with its path so pages with
+// the same anchors, e.g. `#introduction`, keep their own destinations when merged
+fn namespace_dests(doc: &mut Document, url: &str) -> Result<()> {
+    let catalog_id = doc.trailer.get(b"Root")?.as_reference()?;
+    let (dests_id, dests) = match doc.get_dictionary(catalog_id)?.get(b"Dests") {
+        Ok(Object::Reference(id)) => (Some(*id), doc.get_dictionary(*id)?.clone()),
+        Ok(Object::Dictionary(dests)) => (None, dests.clone()),
+        _ => return Ok(()),
+    };
+
+    let mut renamed = HashMap::new();
+    let mut namespaced = Dictionary::new();
+    for (name, dest) in dests.iter() {
+        let new_name = dest_key(url, &String::from_utf8_lossy(name)).into_bytes();
+        namespaced.set(new_name.clone(), dest.clone());
+        renamed.insert(name.clone(), new_name);
+    }
+    match dests_id {
+        Some(id) => {
+            doc.objects.insert(id, Object::Dictionary(namespaced));
+        }
+        None => {
+            let id = doc.add_object(namespaced);
+            doc.get_dictionary_mut(catalog_id)?.set("Dests", id);
+        }
+    }
+
+    for object in doc.objects.values_mut() {
+        rename_dest_refs(object, &renamed);
+    }
+    Ok(())
+}
+
+// `page_ranges` leaves pages out, drop the named destinations pointing at them so links
+// to their anchors are reported instead of pointing nowhere
+fn drop_dangling_dests(doc: &mut Document) -> Result<()> {
+    let Ok(dests_id) = doc.catalog()?.get(b"Dests").and_then(Object::as_reference) else {
+        return Ok(());
+    };
+    let pages: HashSet<ObjectId> = doc.get_pages().into_values().collect();
+    let dangling: Vec<Vec<u8>> = doc
+        .get_dictionary(dests_id)?
+        .iter()
+        .filter(|(_, dest)| {
+            let dest = match dest {
+                Object::Reference(id) => doc.get_object(*id).ok(),
+                dest => Some(*dest),
+            };
+            dest.and_then(|d| d.as_array().ok())
+                .and_then(|d| d.first())
+                .and_then(|page| page.as_reference().ok())
+                .is_some_and(|page| !pages.contains(&page))
+        })
+        .map(|(name, _)| name.clone())
+        .collect();
+    let dests = doc.get_dictionary_mut(dests_id)?;
+    for name in dangling {
+        dests.remove(&name);
+    }
+    Ok(())
+}
+
+// Some post processors omit `/Type`, which `build_pdf_from_objects` uses to find the
+// catalog and page tree. Fill it in from the trailer `Root` and the page tree structure.
+fn add_missing_types(doc: &mut Document) -> Result<()> {
+    let catalog_id = doc.trailer.get(b"Root")?.as_reference()?;
+    let catalog = doc.get_dictionary_mut(catalog_id)?;
+    if !catalog.has(b"Type") {
+        catalog.set("Type", "Catalog");
+    }
+
+    let mut nodes = vec![catalog.get(b"Pages")?.as_reference()?];
+    let mut visited = HashSet::new();
+    while let Some(id) = nodes.pop() {
+        if !visited.insert(id) {
+            continue;
+        }
+        let node = doc.get_dictionary_mut(id)?;
+        let kids: Option<Vec<ObjectId>> = node
+            .get(b"Kids")
+            .and_then(Object::as_array)
+            .ok()
+            .map(|kids| kids.iter().flat_map(Object::as_reference).collect());
+        if !node.has(b"Type") {
+            node.set("Type", if kids.is_some() { "Pages" } else { "Page" });
+        }
+        nodes.extend(kids.unwrap_or_default());
+    }
+    Ok(())
+}
+
+// Walk up the page tree from `page_id` to the nearest node setting `key`
+fn inherited(doc: &Document, page_id: ObjectId, key: &[u8]) -> Option<Object> {
+    let mut node = doc.get_dictionary(page_id).ok();
+    while let Some(dict) = node {
+        if let Ok(value) = dict.get(key) {
+            return Some(value.clone());
+        }
+        node = dict
+            .get(b"Parent")
+            .and_then(Object::as_reference)
+            .and_then(|id| doc.get_dictionary(id))
+            .ok();
+    }
+    None
+}
+
+// US Letter, used when a page has no MediaBox
+pub(crate) const DEFAULT_MEDIA_BOX: [f64; 4] = [0.0, 0.0, 612.0, 792.0];
+
+// MediaBox of `page_id`, which may be inherited from the page tree
+pub(crate) fn media_box(doc: &Document, page_id: ObjectId) -> [f64; 4] {
+    let media_box = inherited(doc, page_id, b"MediaBox")
+        .and_then(|o| doc.dereference(&o).ok().map(|(_, o)| o.clone()))
+        .and_then(|o| {
+            o.as_array()
+                .ok()?
+                .iter()
+                .map(|v| v.as_float().map(f64::from).ok())
+                .collect::<Option<Vec<f64>>>()
+        });
+    match media_box.as_deref() {
+        Some([x0, y0, x1, y1]) => [*x0, *y0, *x1, *y1],
+        _ => DEFAULT_MEDIA_BOX,
+    }
+}
+
+// Put the pages of a PDF which didn't come from Chrome directly under its root `Pages`,
+// with what they inherited copied onto them, and in object ID order
+fn flatten_page_tree(doc: &mut Document) -> Result<()> {
+    let pages_id = doc.catalog()?.get(b"Pages")?.as_reference()?;
+    let mut page_ids: Vec<ObjectId> = doc.get_pages().into_values().collect();
+
+    // Merged pages are ordered by object ID, give the pages new ones in page order if need be
+    if !page_ids.windows(2).all(|w| w[0] < w[1]) {
+        let mut ids = HashMap::new();
+        for page_id in page_ids.iter_mut() {
+            let page = doc.objects.remove(page_id).unwrap();
+            let new_id = doc.add_object(page);
+            ids.insert(*page_id, new_id);
+            *page_id = new_id;
+        }
+        for object in doc.objects.values_mut() {
+            replace_references(object, &ids);
+        }
+    }
+
+    for page_id in &page_ids {
+        for key in [b"Resources".as_slice(), b"MediaBox", b"CropBox", b"Rotate"] {
+            if let Some(value) = inherited(doc, *page_id, key) {
+                doc.get_dictionary_mut(*page_id)?.set(key, value);
             }
         }
+        doc.get_dictionary_mut(*page_id)?.set("Parent", pages_id);
+    }
+    doc.objects.insert(
+        pages_id,
+        Object::Dictionary(dictionary! {
+            "Type" => "Pages",
+            "Kids" => page_ids.iter().map(|id| Object::Reference(*id)).collect::<Vec<_>>(),
+            "Count" => page_ids.len() as i64,
+        }),
+    );
+    Ok(())
+}
+
+// Reduce `cover` to its pages so its catalog, outline and destinations don't end up in the
+// merged PDF
+fn prepare_cover(cover: &mut Document) -> Result<()> {
+    add_missing_types(cover)?;
+    flatten_page_tree(cover)?;
+
+    let catalog_id = cover.trailer.get(b"Root")?.as_reference()?;
+    let pages_id = cover.catalog()?.get(b"Pages")?.as_reference()?;
+    cover.objects.insert(
+        catalog_id,
+        Object::Dictionary(dictionary! {
+            "Type" => "Catalog",
+            "Pages" => pages_id,
+        }),
+    );
+    cover.trailer.remove(b"Info");
+    cover.prune_objects();
+    Ok(())
+}
+
+// Drop the outline of back matter, which gets a bookmark of its own, keeping its destinations
+// so its internal links still work
+fn prepare_back_matter(doc: &mut Document) -> Result<()> {
+    add_missing_types(doc)?;
+    flatten_page_tree(doc)?;
+    flatten_name_trees(doc, None)?;
+    flatten_named_dests(doc)?;
+
+    let catalog_id = doc.trailer.get(b"Root")?.as_reference()?;
+    let catalog = doc.get_dictionary_mut(catalog_id)?;
+    catalog.remove(b"Outlines");
+    // `build_pdf_from_objects` expects the catalog to reference its destinations
+    let dests = match catalog.remove(b"Dests") {
+        Some(Object::Dictionary(dests)) => Some(Object::Reference(doc.add_object(dests))),
+        dests => dests,
+    };
+    if let Some(dests) = dests {
+        doc.get_dictionary_mut(catalog_id)?.set("Dests", dests);
     }
-    Ok(tree.map(|d| d.as_hashmap().clone())?)
+    doc.trailer.remove(b"Info");
+    doc.prune_objects();
+    Ok(())
 }
 
-/// Loads PDFs into memory as PDF Objects and merges the PDF Objects
+/// Loads PDFs into memory as PDF Objects and merges the PDF Objects, with the pages of `cover`
+/// first and those of `back_matter` last
 fn merge_pdf_objects(
+    cover: Option<Document>,
     url_to_pdf_doc: IndexMap<String, Document>,
+    back_matter: Vec<Document>,
 ) -> Result<(PdfParts, IndexMap<String, usize>)> {
     // Used remap links internal to the VitePress site to internal PDF links
     let mut url_to_page_num = IndexMap::new();
@@ -44,11 +462,36 @@ fn merge_pdf_objects(
     let mut pages = BTreeMap::new();
     let mut starting_id = 1;
 
+    // Lowest object IDs so the cover's pages sort first
+    if let Some(mut cover) = cover {
+        prepare_cover(&mut cover)?;
+        cover.renumber_objects_with(starting_id);
+        starting_id = cover.max_id + 1;
+        pages.extend(
+            cover
+                .get_pages()
+                .into_values()
+                .map(|object_id| (object_id, cover.get_object(object_id).unwrap().to_owned()))
+                .collect::<BTreeMap<ObjectId, Object>>(),
+        );
+        // The first rendered PDF's catalog is used
+        let catalog_id = cover.trailer.get(b"Root")?.as_reference()?;
+        cover.objects.remove(&catalog_id);
+        objects.extend(cover.objects);
+    }
+    let cover_pages = pages.len();
+
     for (url, mut doc) in url_to_pdf_doc {
         // Record the page where a PDF generate from `url` are inserted into the merged PDF.
         // Used by `rewrite_vitepress_links`.
         url_to_page_num.insert(url.clone(), pages.len());
 
+        add_missing_types(&mut doc)?;
+        flatten_name_trees(&mut doc, Some(&url))?;
+        flatten_named_dests(&mut doc)?;
+        drop_dangling_dests(&mut doc)?;
+        namespace_dests(&mut doc, &url)?;
+
         // Object IDs are indexes not UUIDs so we need to renumber them
         // before inserting them into a unified collection.
         doc.renumber_objects_with(starting_id);
@@ -63,13 +506,40 @@ fn merge_pdf_objects(
         objects.extend(doc.objects);
     }
 
-    Ok((PdfParts { objects, pages }, url_to_page_num))
+    let mut back_matter_starts = vec![];
+    for mut doc in back_matter {
+        back_matter_starts.push(pages.len());
+        prepare_back_matter(&mut doc)?;
+        doc.renumber_objects_with(starting_id);
+        starting_id = doc.max_id + 1;
+        pages.extend(
+            doc.get_pages()
+                .into_values()
+                .map(|object_id| (object_id, doc.get_object(object_id).unwrap().to_owned()))
+                .collect::<BTreeMap<ObjectId, Object>>(),
+        );
+        objects.extend(doc.objects);
+    }
+
+    Ok((
+        PdfParts {
+            objects,
+            pages,
+            cover_pages,
+            back_matter_starts,
+        },
+        url_to_page_num,
+    ))
 }
 
-fn build_pdf_from_objects(parts: &PdfParts) -> Result<Document> {
+/// Assemble the merged document from `parts`.
+///
+/// With `skip_streams` stream contents aren't copied, for runs which only report on the result.
+fn build_pdf_from_objects(parts: &PdfParts, skip_streams: bool) -> Result<Document> {
     // Catalog and Pages are mandatory
     let mut catalog_object: Option<(ObjectId, Object)> = None;
     let mut destination_ids: Vec<ObjectId> = vec![];
+    let mut name_tree_ids: Vec<(Vec<u8>, ObjectId)> = vec![];
     let mut outlines: Vec<((u32, u16), Dictionary)> = vec![];
     let mut pages_object: Option<(ObjectId, Object)> = None;
 
@@ -87,6 +557,11 @@ fn build_pdf_from_objects(parts: &PdfParts) -> Result<Document> {
                     if let Ok(dests) = dict.get(b"Dests") {
                         destination_ids.push(dests.as_reference()?);
                     }
+                    if let Ok(names) = dict.get(b"Names").and_then(Object::as_dict) {
+                        for (key, tree) in names.iter().filter(|(key, _)| *key != b"Dests") {
+                            name_tree_ids.push((key.clone(), tree.as_reference()?));
+                        }
+                    }
                 }
             }
             "Pages" => {
@@ -116,7 +591,13 @@ fn build_pdf_from_objects(parts: &PdfParts) -> Result<Document> {
                 outlines.push((*object_id, object.as_dict()?.clone()));
             }
             _ => {
-                document.objects.insert(*object_id, object.clone());
+                let object = match object {
+                    Object::Stream(stream) if skip_streams => {
+                        Object::Stream(Stream::new(stream.dict.clone(), vec![]))
+                    }
+                    object => object.clone(),
+                };
+                document.objects.insert(*object_id, object);
             }
         }
     }
@@ -134,6 +615,24 @@ fn build_pdf_from_objects(parts: &PdfParts) -> Result<Document> {
         document.delete_object(destination_id);
     }
 
+    // Likewise the name trees, which `flatten_name_trees` left in a single node each. Name
+    // tree keys are sorted, a name already taken by another PDF, e.g. an attachment of two back
+    // matter PDFs, gets a suffix rather than replacing it.
+    let mut name_trees: BTreeMap<Vec<u8>, BTreeMap<Vec<u8>, Object>> = BTreeMap::new();
+    for (key, tree_id) in name_tree_ids {
+        let names = document
+            .get_dictionary(tree_id)?
+            .get(b"Names")?
+            .as_array()?
+            .clone();
+        let entries = name_trees.entry(key).or_default();
+        for pair in names.chunks_exact(2) {
+            let name = unique_name(entries, pair[0].as_str()?);
+            entries.insert(name, pair[1].clone());
+        }
+        document.delete_object(tree_id);
+    }
+
     // If no "Pages" found abort
     if pages_object.is_none() {
         return Err(anyhow!("No Pages found."));
@@ -198,6 +697,23 @@ fn build_pdf_from_objects(parts: &PdfParts) -> Result<Document> {
 
         dictionary.set(b"Dests", Object::Dictionary(destinations));
 
+        let mut names = match dictionary.remove(b"Names") {
+            Some(Object::Dictionary(names)) => names,
+            _ => Dictionary::new(),
+        };
+        // Only the first catalog's `Dests` name tree, if `flatten_named_dests` left one
+        names.as_hashmap_mut().retain(|key, _| key == b"Dests");
+        for (key, entries) in name_trees {
+            let entries: Vec<Object> = entries
+                .into_iter()
+                .flat_map(|(name, value)| [Object::String(name, StringFormat::Literal), value])
+                .collect();
+            names.set(key, dictionary! { "Names" => entries });
+        }
+        if !names.as_hashmap().is_empty() {
+            dictionary.set("Names", names);
+        }
+
         document
             .objects
             .insert(catalog_object.0, Object::Dictionary(dictionary));
@@ -214,11 +730,41 @@ fn build_pdf_from_objects(parts: &PdfParts) -> Result<Document> {
     //Set any Bookmarks to the First child if they are not set to a page
     document.adjust_zero_pages();
 
-    document.compress();
+    normalize_outline_titles(&mut document)?;
 
     Ok(document)
 }
 
+// Re-encode outline titles per the PDF text string rules, some producers write UTF-8
+fn normalize_outline_titles(document: &mut Document) -> Result<()> {
+    let mut items: Vec<ObjectId> = document
+        .catalog()?
+        .get(b"Outlines")
+        .and_then(Object::as_reference)
+        .and_then(|id| document.get_dictionary(id))
+        .and_then(|outlines| outlines.get(b"First"))
+        .and_then(Object::as_reference)
+        .into_iter()
+        .collect();
+    let mut visited = HashSet::new();
+    while let Some(id) = items.pop() {
+        if !visited.insert(id) {
+            continue;
+        }
+        let item = document.get_dictionary_mut(id)?;
+        if let Ok(title) = item.get(b"Title").and_then(Object::as_str) {
+            let title = encode_text_string(&decode_text_string(title));
+            item.set("Title", title);
+        }
+        for key in [b"First".as_slice(), b"Next".as_slice()] {
+            if let Ok(next) = item.get(key).and_then(Object::as_reference) {
+                items.push(next);
+            }
+        }
+    }
+    Ok(())
+}
+
 fn merge_outlines(
     document: &mut Document,
     outlines: Vec<((u32, u16), Dictionary)>,
@@ -269,28 +815,383 @@ fn merge_outlines(
     Ok(None)
 }
 
+// Title Chrome gives a rendered PDF, the page's `<title>`
+fn info_title(doc: &Document) -> Option<String> {
+    let info = doc.trailer.get(b"Info").ok()?;
+    let (_, info) = doc.dereference(info).ok()?;
+    let title = info.as_dict().ok()?.get(b"Title").ok()?.as_str().ok()?;
+    Some(decode_text_string(title)).filter(|t| !t.trim().is_empty())
+}
+
+// Put the bookmarks of `doc` under a single top level bookmark titled `title` pointing at its first page
+fn add_page_bookmark(doc: &mut Document, title: &str) -> Result<()> {
+    let Some(first_page) = doc.page_iter().next() else {
+        return Ok(());
+    };
+    let catalog_id = doc.trailer.get(b"Root")?.as_reference()?;
+    let outlines_id = match doc
+        .get_dictionary(catalog_id)?
+        .get(b"Outlines")
+        .and_then(Object::as_reference)
+    {
+        Ok(id) => id,
+        Err(_) => {
+            let id = doc.add_object(dictionary! {
+                "Type" => "Outlines",
+                "Count" => 0,
+            });
+            doc.get_dictionary_mut(catalog_id)?.set("Outlines", id);
+            id
+        }
+    };
+    let outlines = doc.get_dictionary(outlines_id)?.clone();
+
+    let item_id = doc.new_object_id();
+    let mut item = dictionary! {
+        "Title" => encode_text_string(title),
+        "Parent" => outlines_id,
+        "Dest" => vec![first_page.into(), "Fit".into()],
+    };
+    // Open with the page's bookmarks as its children
+    let count = outlines.get(b"Count").and_then(Object::as_i64).unwrap_or(0);
+    if let (Ok(first), Ok(last)) = (outlines.get(b"First"), outlines.get(b"Last")) {
+        item.set("First", first.clone());
+        item.set("Last", last.clone());
+        item.set("Count", count);
+        let mut child = first.as_reference().ok();
+        while let Some(child_id) = child {
+            let dict = doc.get_dictionary_mut(child_id)?;
+            dict.set("Parent", item_id);
+            child = dict.get(b"Next").and_then(Object::as_reference).ok();
+        }
+    }
+    doc.objects.insert(item_id, Object::Dictionary(item));
+
+    let outlines = doc.get_dictionary_mut(outlines_id)?;
+    outlines.set("Type", "Outlines");
+    outlines.set("First", item_id);
+    outlines.set("Last", item_id);
+    outlines.set("Count", 1 + count.max(0));
+    Ok(())
+}
+
+// Children of outline item `id`, in order
+fn outline_children(doc: &Document, id: ObjectId) -> Vec<ObjectId> {
+    let mut children = vec![];
+    let mut seen = HashSet::new();
+    let mut child = doc
+        .get_dictionary(id)
+        .and_then(|d| d.get(b"First"))
+        .and_then(Object::as_reference)
+        .ok();
+    while let Some(child_id) = child.filter(|c| seen.insert(*c)) {
+        children.push(child_id);
+        child = doc
+            .get_dictionary(child_id)
+            .and_then(|d| d.get(b"Next"))
+            .and_then(Object::as_reference)
+            .ok();
+    }
+    children
+}
+
+// Delete the descendants of outline item `id`
+fn remove_outline_children(doc: &mut Document, id: ObjectId) {
+    for child in outline_children(doc, id) {
+        remove_outline_children(doc, child);
+        doc.objects.remove(&child);
+    }
+    if let Ok(dict) = doc.get_dictionary_mut(id) {
+        dict.remove(b"First");
+        dict.remove(b"Last");
+        dict.remove(b"Count");
+    }
+}
+
+// Keep `levels` levels of items below outline item `id`, returning how many are visible when it's open
+fn prune_outline(doc: &mut Document, id: ObjectId, levels: usize) -> Result<i64> {
+    if levels == 0 {
+        remove_outline_children(doc, id);
+        return Ok(0);
+    }
+    let mut visible = 0;
+    for child in outline_children(doc, id) {
+        let below = prune_outline(doc, child, levels - 1)?;
+        let dict = doc.get_dictionary_mut(child)?;
+        // A negative count means the item is closed
+        let open = dict
+            .get(b"Count")
+            .and_then(Object::as_i64)
+            .map_or(true, |c| c >= 0);
+        if below == 0 {
+            dict.remove(b"Count");
+        } else {
+            dict.set("Count", if open { below } else { -below });
+        }
+        visible += 1 + if open { below } else { 0 };
+    }
+    Ok(visible)
+}
+
+// Drop outline items more than `depth` levels deep, 0 removes the outline
+fn limit_outline_depth(doc: &mut Document, depth: usize) -> Result<()> {
+    let catalog_id = doc.trailer.get(b"Root")?.as_reference()?;
+    let Ok(outlines_id) = doc
+        .get_dictionary(catalog_id)?
+        .get(b"Outlines")
+        .and_then(Object::as_reference)
+    else {
+        return Ok(());
+    };
+    if depth == 0 {
+        remove_outline_children(doc, outlines_id);
+        doc.objects.remove(&outlines_id);
+        doc.get_dictionary_mut(catalog_id)?.remove(b"Outlines");
+        return Ok(());
+    }
+    let visible = prune_outline(doc, outlines_id, depth)?;
+    doc.get_dictionary_mut(outlines_id)?.set("Count", visible);
+    Ok(())
+}
+
+// Append top level bookmarks pointing at pages, creating the outline if there isn't one
+fn append_bookmarks(doc: &mut Document, bookmarks: Vec<(String, ObjectId)>) -> Result<()> {
+    if bookmarks.is_empty() {
+        return Ok(());
+    }
+    let outlines_id = match doc
+        .catalog()?
+        .get(b"Outlines")
+        .and_then(Object::as_reference)
+    {
+        Ok(id) => id,
+        Err(_) => {
+            let id = doc.add_object(dictionary! {
+                "Type" => "Outlines",
+                "Count" => 0,
+            });
+            let catalog_id = doc.trailer.get(b"Root")?.as_reference()?;
+            doc.get_dictionary_mut(catalog_id)?.set("Outlines", id);
+            id
+        }
+    };
+
+    for (title, page_id) in bookmarks {
+        let last = doc
+            .get_dictionary(outlines_id)?
+            .get(b"Last")
+            .and_then(Object::as_reference)
+            .ok();
+        let mut item = dictionary! {
+            "Title" => encode_text_string(&title),
+            "Parent" => outlines_id,
+            "Dest" => vec![page_id.into(), "Fit".into()],
+        };
+        if let Some(last) = last {
+            item.set("Prev", last);
+        }
+        let item_id = doc.add_object(item);
+        if let Some(last) = last {
+            doc.get_dictionary_mut(last)?.set("Next", item_id);
+        }
+
+        let outlines = doc.get_dictionary_mut(outlines_id)?;
+        if last.is_none() {
+            outlines.set("First", item_id);
+        }
+        outlines.set("Last", item_id);
+        let count = outlines.get(b"Count").and_then(Object::as_i64).unwrap_or(0);
+        outlines.set("Count", count + 1);
+    }
+    Ok(())
+}
+
+// Longest URI or destination name recorded by `--trace-links`
+const TRACE_MAX_LEN: usize = 512;
+
+/// Lookup table consulted for a link
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LinkTable {
+    Urls,
+    Dests,
+}
+
+/// What happened to a link
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LinkAction {
+    RewrittenToPage,
+    RewrittenToDest,
+    LeftExternal,
+    Problem,
+}
+
+/// The decision `rewrite_vitepress_links` made for a Link annotation, written by `--trace-links`
+#[derive(Debug, Serialize)]
+pub struct LinkTrace {
+    /// Page number in the merged PDF, starting at 1
+    pub page: usize,
+    /// The URL the page was rendered from
+    pub source_url: Option<String>,
+    /// The raw URI or destination name of the annotation
+    pub found: String,
+    /// What was looked up in `table`, e.g. the URL with `index.html` appended or the anchor
+    pub canonical: Option<String>,
+    pub table: Option<LinkTable>,
+    pub action: LinkAction,
+}
+
+fn truncate(s: &str) -> String {
+    if s.len() <= TRACE_MAX_LEN {
+        return s.to_string();
+    }
+    let mut end = TRACE_MAX_LEN;
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}...", &s[..end])
+}
+
+impl LinkTrace {
+    fn new(
+        page_num: usize,
+        source_url: Option<&String>,
+        found: &str,
+        canonical: Option<&str>,
+        table: Option<LinkTable>,
+        action: LinkAction,
+    ) -> Self {
+        LinkTrace {
+            page: page_num + 1, // +1 because enumerate is zero indexed but humans are one indexed.
+            source_url: source_url.map(|u| truncate(u)),
+            found: truncate(found),
+            canonical: canonical.map(truncate),
+            table,
+            action,
+        }
+    }
+}
+
+/// A link `rewrite_vitepress_links` couldn't point at a page of the merged PDF
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LinkProblem {
+    /// Page number in the merged PDF, starting at 1
+    pub page: usize,
+    /// The URL, or destination name, the link points at
+    pub target: String,
+    /// The URL the page was rendered from
+    pub source_url: Option<String>,
+    /// Why it couldn't be remapped, e.g. `excluded`
+    pub reason: Option<String>,
+}
+
+impl LinkProblem {
+    fn new(
+        page_num: usize,
+        target: &str,
+        source_url: Option<&String>,
+        reason: Option<String>,
+    ) -> Self {
+        LinkProblem {
+            page: page_num + 1, // +1 because enumerate is zero indexed but humans are one indexed.
+            target: target.to_string(),
+            source_url: source_url.cloned(),
+            reason,
+        }
+    }
+}
+
+impl fmt::Display for LinkProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Page No. {}: {}", self.page, self.target)?;
+        if let Some(reason) = &self.reason {
+            write!(f, " ({reason})")?;
+        }
+        Ok(())
+    }
+}
+
+/// The JSON document written by `--report`, the field names are stable
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct MergeReport {
+    pub problem_urls: Vec<LinkProblem>,
+    pub problem_anchors: Vec<LinkProblem>,
+    pub counts: ReportCounts,
+    /// Pages in the merged PDF
+    pub pages: usize,
+    /// Size of `output_pdf` in bytes, `None` if it wasn't written
+    pub output_size: Option<u64>,
+}
+
+/// Number of each kind of problem in a `MergeReport`
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct ReportCounts {
+    pub problem_urls: usize,
+    pub problem_anchors: usize,
+    pub dead_links: usize,
+}
+
+// The http(s) links left in `doc` which `check_external_links` requests, with the pages
+// linking to each
+fn external_links(conf: &Config, doc: &Document) -> Result<IndexMap<String, Vec<usize>>> {
+    let mut links: IndexMap<String, Vec<usize>> = IndexMap::new();
+    if !conf.check_external_links {
+        return Ok(links);
+    }
+    for (page_num, page_id) in doc.page_iter().enumerate() {
+        for annotation in doc.get_page_annotations(page_id).unwrap_or_default() {
+            let Ok(uri) = annotation
+                .get_deref(b"A", doc)
+                .and_then(Object::as_dict)
+                .and_then(|a| a.get_deref(b"URI", doc))
+                .and_then(Object::as_string)
+            else {
+                continue;
+            };
+            if !conf.checks_external_link(&uri) {
+                continue;
+            }
+            let pages = links.entry(uri.to_string()).or_default();
+            if pages.last() != Some(&(page_num + 1)) {
+                pages.push(page_num + 1);
+            }
+        }
+    }
+    Ok(links)
+}
+
 fn rewrite_vitepress_links(
     conf: &Config,
     doc: &mut Document,
-    url_to_page_num: IndexMap<String, usize>,
-) -> Result<(Vec<String>, Vec<String>)> {
+    url_to_page_num: &IndexMap<String, usize>,
+    trace: &mut Vec<LinkTrace>,
+) -> Result<(Vec<LinkProblem>, Vec<LinkProblem>)> {
     // Build a maping from URL to Page ID
     let page_num_to_id = doc.get_pages();
     let mut url_to_page_id = IndexMap::new();
     for (url, page_num) in url_to_page_num {
-        let page_num: u32 = page_num as u32 + 1; // Get Pages starts indexing at 1
-        url_to_page_id.insert(url, page_num_to_id.get(&page_num).unwrap());
+        let page_num: u32 = *page_num as u32 + 1; // Get Pages starts indexing at 1
+        url_to_page_id.insert(url.clone(), page_num_to_id.get(&page_num).unwrap());
     }
 
-    let mut problem_anchors: Vec<String> = vec![];
-    let mut problem_urls: Vec<String> = vec![];
+    let mut problem_anchors: Vec<LinkProblem> = vec![];
+    let mut problem_urls: Vec<LinkProblem> = vec![];
     let mut anchors_to_rewrite: Vec<(ObjectId, Object)> = vec![];
     let mut urls_to_rewrite: Vec<(ObjectId, ObjectId)> = vec![];
+    // Page, annotation and URL, if there is one, of each link which couldn't be remapped
+    let mut broken: Vec<(ObjectId, ObjectId, Option<String>)> = vec![];
 
     let dests = get_named_dests(doc)?;
 
     // Go through the pages
     for (page_num, page_id) in doc.page_iter().enumerate() {
+        let source_url = url_to_page_num
+            .iter()
+            .rev()
+            .find(|(_, start)| **start <= page_num)
+            .map(|(url, _)| url);
+
         // Get the Annoation ID and Object
         let mut annotations: Vec<(ObjectId, &Dictionary)> = vec![];
         if let Ok(page) = doc.get_dictionary(page_id) {
@@ -324,10 +1225,20 @@ fn rewrite_vitepress_links(
             if subtype.eq("Link") {
                 // We've found a Annotation Link with an URL
                 if let Ok(ahref) = annotation.get_deref(b"A", doc).and_then(Object::as_dict) {
-                    let mut url = ahref.get(b"URI")?.as_string()?.to_string();
+                    let raw = ahref.get(b"URI")?.as_string()?.to_string();
+                    // Chrome writes links within the page against the URL it navigated to
+                    let mut url = conf.strip_export_query(&raw);
 
                     // We only care URLs that are part of our VitePress site.
                     if !url.starts_with(&conf.url) {
+                        trace.push(LinkTrace::new(
+                            page_num,
+                            source_url,
+                            &raw,
+                            None,
+                            None,
+                            LinkAction::LeftExternal,
+                        ));
                         continue;
                     }
 
@@ -348,32 +1259,103 @@ fn rewrite_vitepress_links(
                             .split('#')
                             .last()
                             .ok_or(anyhow!("Error extracting anchor from URI {url}"))?;
-                        match dests.get(anchor.as_bytes()) {
-                            Some(dest) => anchors_to_rewrite.push((annotation_id, dest.clone())),
+                        // Find the page linked to first, then the anchor among its own
+                        // destinations so a heading of the same name elsewhere isn't used
+                        let target = normalize_link(url.split('#').next().unwrap_or_default(), "");
+                        let key = dest_key(&target, anchor);
+                        // Anchors of non-ASCII headings are percent encoded
+                        let decoded = percent_decode_str(anchor).decode_utf8_lossy();
+                        let dest = url_to_page_id.get(&target).and_then(|_| {
+                            dests
+                                .get(key.as_bytes())
+                                .or_else(|| dests.get(dest_key(&target, &decoded).as_bytes()))
+                        });
+                        let action = match dest {
+                            Some(dest) => {
+                                anchors_to_rewrite.push((annotation_id, dest.clone()));
+                                LinkAction::RewrittenToDest
+                            }
                             None => {
-                                problem_anchors.push(format!("Page No. {}: {url}", page_num + 1))
-                            } // +1 because enumerate is zero indexed but humans are one indexed.
-                        }
+                                let missing = if url_to_page_id.contains_key(&target) {
+                                    format!("no #{decoded} on {target}")
+                                } else {
+                                    format!("{target} is not in the PDF")
+                                };
+                                problem_anchors.push(LinkProblem::new(
+                                    page_num,
+                                    &url,
+                                    source_url,
+                                    Some(missing),
+                                ));
+                                broken.push((page_id, annotation_id, Some(url.clone())));
+                                LinkAction::Problem
+                            }
+                        };
+                        trace.push(LinkTrace::new(
+                            page_num,
+                            source_url,
+                            &raw,
+                            Some(&key),
+                            Some(LinkTable::Dests),
+                            action,
+                        ));
                     // Hande Plain URLS
                     } else {
-                        match url_to_page_id.get(&url) {
-                            Some(page_id) => urls_to_rewrite.push((annotation_id, **page_id)),
+                        let action = match url_to_page_id.get(&url) {
+                            Some(page_id) => {
+                                urls_to_rewrite.push((annotation_id, **page_id));
+                                LinkAction::RewrittenToPage
+                            }
                             None => {
-                                problem_urls.push(format!("Page No. {}: {url}", page_num + 1));
-                                // +1 because enumerate is zero indexed but humans are one indexed.
+                                let excluded = if conf.excluded.contains(&url) {
+                                    Some("excluded")
+                                } else if conf.opted_out.contains(&url) {
+                                    Some("opted out")
+                                } else {
+                                    None
+                                };
+                                problem_urls.push(LinkProblem::new(
+                                    page_num,
+                                    &url,
+                                    source_url,
+                                    excluded.map(str::to_string),
+                                ));
+                                broken.push((page_id, annotation_id, Some(url.clone())));
+                                LinkAction::Problem
                             }
-                        }
+                        };
+                        trace.push(LinkTrace::new(
+                            page_num,
+                            source_url,
+                            &raw,
+                            Some(url.as_str()),
+                            Some(LinkTable::Urls),
+                            action,
+                        ));
                     }
                 // Dest conflicts with "A" and indicates an internal link that needs to be updated
                 } else if let Ok(anchor) = annotation.get(b"Dest").and_then(Object::as_name) {
-                    match dests.get(anchor) {
-                        Some(dest) => anchors_to_rewrite.push((annotation_id, dest.clone())),
-                        None => problem_anchors.push(format!(
-                            "Page No. {}: {}",
-                            page_num + 1,
-                            String::from_utf8_lossy(anchor)
-                        )),
-                    }
+                    let name = String::from_utf8_lossy(anchor);
+                    let action = match dests.get(anchor) {
+                        Some(dest) => {
+                            anchors_to_rewrite.push((annotation_id, dest.clone()));
+                            LinkAction::RewrittenToDest
+                        }
+                        None => {
+                            problem_anchors
+                                .push(LinkProblem::new(page_num, &name, source_url, None));
+                            broken.push((page_id, annotation_id, None));
+                            LinkAction::Problem
+                        }
+                    };
+                    trace.push(LinkTrace::new(
+                        page_num,
+                        source_url,
+                        &name,
+                        Some(&*name),
+                        Some(LinkTable::Dests),
+                        action,
+                    ));
                 }
             }
         }
@@ -393,400 +1375,3565 @@ fn rewrite_vitepress_links(
         annot.set("Dest", Object::from(vec![page_id.into(), "Fit".into()]));
     }
 
+    match conf.broken_links {
+        BrokenLinks::Keep => {}
+        BrokenLinks::Remove => {
+            let mut page_to_annotations: HashMap<ObjectId, HashSet<ObjectId>> = HashMap::new();
+            for (page_id, annotation_id, _) in broken {
+                page_to_annotations
+                    .entry(page_id)
+                    .or_default()
+                    .insert(annotation_id);
+            }
+            for (page_id, annotation_ids) in page_to_annotations {
+                remove_annotations(doc, page_id, &annotation_ids)?;
+            }
+        }
+        // Links without a URL, to a missing named destination, have nothing to point at
+        BrokenLinks::PointToSite => {
+            for (_, annotation_id, url) in broken {
+                if let Some(link) = url.and_then(|u| conf.public_link(&u)) {
+                    set_link_uri(doc, annotation_id, link)?;
+                }
+            }
+        }
+    }
+
     Ok((problem_urls, problem_anchors))
 }
 
-fn add_page_numbers(doc: &mut Document, conf: &Config) -> Result<()> {
-    if let Some(style) = &conf.page_number {
-        // Add the font for each page to reference
-        let font_id = doc.add_object(dictionary! {
-            "Type" => "Font",
-            "Subtype" => "Type1",
-            "BaseFont" => style.font.to_string(),
-        });
-
-        // Go through each page
-        let pages: BTreeMap<u32, (u32, u16)> = doc.get_pages();
-        for (page_num, page_id) in pages {
-            let mut font_num = 1;
-            // Get pages Resouces
-            if let Ok(page) = doc.get_dictionary_mut(page_id) {
-                if let Ok(resource_dict) =
-                    page.get_mut(b"Resources").map(|o| o.as_dict_mut().unwrap())
-                {
-                    // Get the pages fonts
-                    if let Ok(fonts) = resource_dict
-                        .get_mut(b"Font")
-                        .map(|o| o.as_dict_mut().unwrap())
-                    {
-                        // Find the first unused font index - this is normally F1
-                        while fonts.has(format!("F{font_num}").as_bytes()) {
-                            font_num += 1;
-                        }
-                        fonts.set(format!("F{font_num}").as_bytes(), font_id);
-                    }
-                }
-            }
-
-            let content: Content = Content {
-                operations: vec![
-                    // Begin Text Element
-                    Operation::new("BT", vec![]),
-                    // Font Color
-                    Operation::new(
-                        "rg",
-                        vec![
-                            style.color.r.into(),
-                            style.color.g.into(),
-                            style.color.b.into(),
-                        ],
-                    ),
-                    // Font and Size
-                    Operation::new("Tf", vec![format!("F{font_num}").into(), style.size.into()]),
-                    // Set the text matrix, this is an affine transformation matrix which is used to veritically filp the text
-                    // and position it at the bottom of the page. The Vertical filp is required by due to how chrome renders the PDFs.
-                    // See section 4.2.2 in PDF Reference for more details.
-                    Operation::new(
-                        "Tm",
-                        vec![
-                            (1).into(),
-                            0.into(),
-                            0.into(),
-                            (-1).into(),
-                            (style.x * 300.0).into(), // Convert x from inches into dots by multplying by the standard 300 DPI
-                            (style.y * 300.0).into(),
-                        ],
-                    ),
-                    // Set the page number text
-                    Operation::new(
-                        "Tj",
-                        vec![Object::string_literal(format!("Page {}", page_num))],
-                    ),
-                    // End Text
-                    Operation::new("ET", vec![]),
-                ],
-            };
-            doc.add_to_page_content(page_id, content)?;
-        }
+// Take the annotations `annotation_ids` off the page `page_id` and delete them
+fn remove_annotations(
+    doc: &mut Document,
+    page_id: ObjectId,
+    annotation_ids: &HashSet<ObjectId>,
+) -> Result<()> {
+    let annots_id = match doc.get_dictionary(page_id)?.get(b"Annots") {
+        Ok(Object::Reference(id)) => Some(*id),
+        Ok(_) => None,
+        Err(_) => return Ok(()),
+    };
+    let annots = match annots_id {
+        Some(id) => doc.get_object_mut(id)?,
+        None => doc.get_dictionary_mut(page_id)?.get_mut(b"Annots")?,
+    };
+    annots.as_array_mut()?.retain(|a| {
+        a.as_reference()
+            .map_or(true, |id| !annotation_ids.contains(&id))
+    });
+    for id in annotation_ids {
+        doc.objects.remove(id);
     }
+    Ok(())
+}
 
+// Point the URI action of the Link annotation `annotation_id` at `uri`
+fn set_link_uri(doc: &mut Document, annotation_id: ObjectId, uri: String) -> Result<()> {
+    let action = match doc.get_dictionary(annotation_id)?.get(b"A")?.as_reference() {
+        Ok(id) => doc.get_dictionary_mut(id)?,
+        Err(_) => doc
+            .get_dictionary_mut(annotation_id)?
+            .get_mut(b"A")?
+            .as_dict_mut()?,
+    };
+    action.set("URI", Object::string_literal(uri));
     Ok(())
 }
 
-pub fn merge_pdfs(conf: &Config, url_to_pdf_path: IndexMap<String, PathBuf>) -> Result<ExitCode> {
-    let mut url_to_pdf_doc = IndexMap::new();
-    for (url, path) in url_to_pdf_path {
-        url_to_pdf_doc.insert(url.clone(), Document::load(path)?);
+/// A heading of a rendered page, resolved against the merged PDF
+#[derive(Debug, Serialize)]
+pub struct Heading {
+    pub title: String,
+    /// Outline depth, top level headings are level 1
+    pub level: usize,
+    /// Anchor of the named destination pointing at the heading, if there is one
+    pub dest: Option<String>,
+    /// Page number in the merged PDF, starting at 1
+    pub page: u32,
+    pub headings: Vec<Heading>,
+    // Explicit destination of the heading, used by links to it
+    #[serde(skip)]
+    pub(crate) target: Vec<Object>,
+}
+
+/// Where a URL and its headings ended up in the merged PDF
+#[derive(Debug, Serialize)]
+pub struct PageMapEntry {
+    /// Page number in the merged PDF where the URL starts, starting at 1
+    pub page: u32,
+    pub headings: Vec<Heading>,
+}
+
+// Follow named destinations, references and GoTo dictionaries down to an explicit
+// destination, e.g. `[page_id /XYZ left top zoom]`
+fn explicit_dest<'a>(
+    doc: &'a Document,
+    dests: &'a IndexMap<Vec<u8>, Object>,
+    dest: &'a Object,
+) -> Option<&'a Vec<Object>> {
+    match dest {
+        Object::Reference(id) => explicit_dest(doc, dests, doc.get_object(*id).ok()?),
+        Object::Name(name) => explicit_dest(doc, dests, dests.get(name.as_slice())?),
+        // Name tree keys are stored as UTF-8 by `read_name_tree`
+        Object::String(name, _) => explicit_dest(
+            doc,
+            dests,
+            dests
+                .get(name.as_slice())
+                .or_else(|| dests.get(decode_text_string(name).as_bytes()))?,
+        ),
+        Object::Dictionary(dict) => explicit_dest(doc, dests, dict.get(b"D").ok()?),
+        Object::Array(array) => Some(array),
+        _ => None,
     }
+}
 
-    let (parts, url_to_page_num) = merge_pdf_objects(url_to_pdf_doc)?;
+// Walk the outline items starting at `first` and all their siblings
+fn outline_headings(
+    doc: &Document,
+    dests: &IndexMap<Vec<u8>, Object>,
+    dest_names: &HashMap<String, String>,
+    page_id_to_num: &BTreeMap<ObjectId, u32>,
+    first: Option<ObjectId>,
+    level: usize,
+) -> Result<Vec<Heading>> {
+    let mut headings = vec![];
+    let mut next = first;
+    while let Some(item_id) = next {
+        let item = doc.get_dictionary(item_id)?;
+        let dest = item
+            .get(b"Dest")
+            .ok()
+            .or_else(|| {
+                item.get_deref(b"A", doc)
+                    .and_then(Object::as_dict)
+                    .and_then(|a| a.get(b"D"))
+                    .ok()
+            })
+            .and_then(|d| explicit_dest(doc, dests, d));
 
-    let mut pdf = build_pdf_from_objects(&parts)?;
+        // Outline items that don't point at a page can't be mapped
+        let page = dest
+            .and_then(|d| d.first())
+            .and_then(|p| p.as_reference().ok())
+            .and_then(|id| page_id_to_num.get(&id));
 
-    let (problem_urls, problem_anchors) = rewrite_vitepress_links(conf, &mut pdf, url_to_page_num)?;
+        if let (Some(dest), Some(page)) = (dest, page) {
+            headings.push(Heading {
+                title: item
+                    .get(b"Title")
+                    .and_then(Object::as_str)
+                    .map(decode_text_string)
+                    .unwrap_or_default(),
+                level,
+                dest: dest_names.get(&format!("{dest:?}")).cloned(),
+                page: *page,
+                target: dest.clone(),
+                headings: outline_headings(
+                    doc,
+                    dests,
+                    dest_names,
+                    page_id_to_num,
+                    item.get(b"First").and_then(Object::as_reference).ok(),
+                    level + 1,
+                )?,
+            });
+        }
+        next = item.get(b"Next").and_then(Object::as_reference).ok();
+    }
+    Ok(headings)
+}
 
-    add_page_numbers(&mut pdf, conf)?;
+/// Every heading in the outline of the merged PDF
+pub(crate) fn document_headings(doc: &Document) -> Result<Vec<Heading>> {
+    let page_id_to_num: BTreeMap<ObjectId, u32> =
+        doc.get_pages().into_iter().map(|(n, id)| (id, n)).collect();
 
-    pdf.save(&conf.output_pdf)?;
+    let dests = get_named_dests(doc).unwrap_or_default();
+    // Reverse lookup so headings can report the named destination which points at them
+    let dest_names: HashMap<String, String> = dests
+        .iter()
+        .filter_map(|(name, dest)| {
+            explicit_dest(doc, &dests, dest).map(|d| {
+                let name = String::from_utf8_lossy(name);
+                (format!("{d:?}"), dest_anchor(&name).to_string())
+            })
+        })
+        .collect();
 
-    println!("Merged PDF is avalible here {}", conf.output_pdf.display());
+    let first = doc
+        .catalog()?
+        .get(b"Outlines")
+        .and_then(Object::as_reference)
+        .and_then(|id| doc.get_dictionary(id))
+        .and_then(|outlines| outlines.get(b"First"))
+        .and_then(Object::as_reference)
+        .ok();
 
-    let mut retcode = ExitCode::SUCCESS;
-    if !problem_urls.is_empty() {
-        println!(
-            "Unable to remap these URLS.\n{}",
-            problem_urls
-                .iter()
-                .map(|s| format!("  * {s}"))
-                .collect::<Vec<String>>()
-                .join("\n")
-        );
-        retcode = ExitCode::FAILURE;
-    }
+    outline_headings(doc, &dests, &dest_names, &page_id_to_num, first, 1)
+}
 
-    if !problem_anchors.is_empty() {
-        println!(
-            "Unable to remap these Anchors.\n{}",
-            problem_anchors
-                .iter()
-                .map(|s| format!("  * {s}"))
-                .collect::<Vec<String>>()
-                .join("\n")
-        );
-        retcode = ExitCode::FAILURE;
+/// Map each URL to its first page and the headings Chrome generated for it
+///
+/// Headings from `back_matter_start`, the index of the first back matter page, on are left out.
+fn build_page_map(
+    doc: &Document,
+    url_to_page_num: &IndexMap<String, usize>,
+    back_matter_start: Option<usize>,
+) -> Result<IndexMap<String, PageMapEntry>> {
+    let mut map: IndexMap<String, PageMapEntry> = url_to_page_num
+        .iter()
+        .map(|(url, page_num)| {
+            (
+                url.clone(),
+                PageMapEntry {
+                    page: *page_num as u32 + 1,
+                    headings: vec![],
+                },
+            )
+        })
+        .collect();
+
+    let rendered = |h: &Heading| back_matter_start.map_or(true, |start| h.page as usize <= start);
+    for heading in document_headings(doc)?.into_iter().filter(rendered) {
+        // A heading belongs to the last URL which starts on or before its page
+        if let Some(entry) = map.values_mut().rev().find(|e| e.page <= heading.page) {
+            entry.headings.push(heading);
+        }
     }
 
-    Ok(retcode)
+    Ok(map)
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use headless_chrome::types::PrintToPdfOptions;
-    use indexmap::IndexSet;
-    use lopdf::{
-        content::{Content, Operation},
-        dictionary, Stream,
-    };
-
-    pub fn generate_pdf_with_link(url: String) -> Document {
-        let mut doc = Document::with_version("1.5");
-        let pages_id = doc.new_object_id();
+/// Add standard Type 1 `font` to `doc` with WinAnsiEncoding, embedding its clone with
+/// `embed_standard_fonts`.
+pub(crate) fn add_standard_font(
+    doc: &mut Document,
+    conf: &Config,
+    font: &str,
+    used_for: &str,
+) -> Result<TextFont> {
+    // The standard font names in the config are spelt with U+2212, PostScript names are ASCII
+    let base_font = font.replace('−', "-");
+    if conf.embed_standard_fonts {
+        let path = conf.standard_font_path(font)?;
+        let sfnt = Sfnt::parse(fs::read(&path)?)
+            .map_err(|e| anyhow!("Unable to embed {}: {e}", path.display()))?;
+        let font_id = embed_font(doc, &base_font, &sfnt)?;
+        Ok(TextFont::win_ansi(font_id, font, Some(sfnt)))
+    } else {
+        conf.progress.println(format_args!(
+            "{used_for} font {font} is not embedded, set embed_standard_fonts for PDF/A"
+        ));
         let font_id = doc.add_object(dictionary! {
             "Type" => "Font",
             "Subtype" => "Type1",
-            "BaseFont" => "Courier",
-        });
-        let resources_id = doc.add_object(dictionary! {
-            "Font" => dictionary! {
-                "F1" => font_id,
-            },
-        });
-        let content: Content = Content {
-            operations: vec![
-                Operation::new("BT", vec![]),
-                Operation::new("Tf", vec!["F1".into(), 48.into()]),
-                Operation::new("Td", vec![100.into(), 600.into()]),
-                Operation::new("Tj", vec![Object::string_literal("Hello World!")]),
-                Operation::new("ET", vec![]),
-            ],
-        };
-        let content_id = doc.add_object(Stream::new(dictionary! {}, content.encode().unwrap()));
-        let a_id = doc.add_object(dictionary! {
-            "Type" => "Action",
-            "S" => "URI",
-            "URI" => Object::string_literal(url),
-        });
-        let annot_id = doc.add_object(dictionary! {
-            "Type" => "Annot",
-            "Subtype" => "Link",
-            "Rect" => vec![0.into(), 0.into(), 595.into(), 842.into()],
-            "F" => 4,
-            "Border" => vec![1.into(), 1.into(), 1.into()],
-            "A" => a_id,
+            "BaseFont" => base_font.as_str(),
+            "Encoding" => "WinAnsiEncoding",
         });
+        Ok(TextFont::win_ansi(font_id, font, None))
+    }
+}
+
+// Label pages in viewers the way `add_page_numbers` numbers them, pages before `numbered_start`
+// have no label. Unnumbered back matter continues the decimal labels so every page has its own.
+fn add_page_labels(doc: &mut Document, conf: &Config, numbered_start: usize) -> Result<()> {
+    let Some(style) = &conf.page_number else {
+        return Ok(());
+    };
+    let front_matter = match style.front_matter_style {
+        FrontMatterStyle::Roman => dictionary! { "S" => "r" },
+        FrontMatterStyle::None => dictionary! {},
+    };
+    let ranges = [
+        (0, dictionary! {}),
+        (numbered_start, front_matter),
+        (
+            numbered_start + style.front_matter_pages,
+            dictionary! { "S" => "D" },
+        ),
+    ];
+    // Ranges start on distinct pages, empty ones are replaced by the range after them and
+    // unlabelled ones run on into the next
+    let mut nums: Vec<Object> = vec![];
+    let mut unlabelled = false;
+    for (i, (start, label)) in ranges.iter().enumerate() {
+        let empty = ranges[i + 1..].iter().any(|(next, _)| next <= start);
+        if !empty && !(unlabelled && label.is_empty()) {
+            nums.push((*start as i64).into());
+            nums.push(Object::Dictionary(label.clone()));
+            unlabelled = label.is_empty();
+        }
+    }
+
+    let catalog_id = doc.trailer.get(b"Root")?.as_reference()?;
+    doc.get_dictionary_mut(catalog_id)?
+        .set("PageLabels", dictionary! { "Nums" => nums });
+    Ok(())
+}
 
+// Insert a blank page in front of each chapter which would start on an even page, so it starts
+// on a right hand page when printed duplex. Page indexes after them move down, the blank pages
+// are returned.
+fn insert_chapter_breaks(
+    doc: &mut Document,
+    conf: &Config,
+    url_to_page_num: &mut IndexMap<String, usize>,
+    back_matter_starts: &mut [usize],
+) -> Result<HashSet<ObjectId>> {
+    let pages_id = doc.catalog()?.get(b"Pages")?.as_reference()?;
+    let mut kids = doc
+        .get_dictionary(pages_id)?
+        .get(b"Kids")?
+        .as_array()?
+        .clone();
+    let mut blank_pages = HashSet::new();
+    for (url, page_num) in url_to_page_num.iter_mut() {
+        *page_num += blank_pages.len();
+        // Page indexes count from 0, so odd indexes are even pages
+        if *page_num % 2 == 0 || !conf.chapter_starts.contains(url) {
+            continue;
+        }
+        // The size and resources of the page before, which text stamped on it can use
+        let before = kids[*page_num - 1].as_reference()?;
+        let [x0, y0, x1, y1] = media_box(doc, before);
+        let resources = inherited(doc, before, b"Resources")
+            .unwrap_or_else(|| Object::Dictionary(Dictionary::new()));
         let page_id = doc.add_object(dictionary! {
             "Type" => "Page",
             "Parent" => pages_id,
-            "Contents" => content_id,
-            "Resources" => resources_id,
-            "MediaBox" => vec![0.into(), 0.into(), 595.into(), 842.into()],
-            "Annots" => vec![annot_id.into()],
+            "MediaBox" => vec![x0.into(), y0.into(), x1.into(), y1.into()],
+            "Resources" => resources,
         });
-        let pages = dictionary! {
-            "Type" => "Pages",
-            "Kids" => vec![page_id.into()],
-            "Count" => 1,
+        kids.insert(*page_num, page_id.into());
+        blank_pages.insert(page_id);
+        *page_num += 1;
+    }
+    for start in back_matter_starts {
+        *start += blank_pages.len();
+    }
+
+    let pages = doc.get_dictionary_mut(pages_id)?;
+    pages.set("Count", kids.len() as i64);
+    pages.set("Kids", kids);
+    Ok(blank_pages)
+}
+
+// Number the pages in the `numbered` range of page indexes, the front matter first then from 1,
+// except those in `unnumbered`. `{total}` is the number of pages after the front matter.
+fn add_page_numbers(
+    doc: &mut Document,
+    conf: &Config,
+    numbered: Range<usize>,
+    unnumbered: &HashSet<ObjectId>,
+) -> Result<()> {
+    if let Some(style) = &conf.page_number {
+        if style.front_matter_pages > numbered.len() {
+            return Err(anyhow!(
+                "page_number.front_matter_pages is {} but only {} pages are numbered",
+                style.front_matter_pages,
+                numbered.len()
+            ));
+        }
+        let total = numbered.len() - style.front_matter_pages;
+
+        // The text of each numbered page
+        let pages: BTreeMap<u32, (u32, u16)> = doc.get_pages();
+        let stamped: Vec<(ObjectId, String)> = pages
+            .into_iter()
+            .skip(numbered.start)
+            .take(numbered.len())
+            .filter(|(_, page_id)| !unnumbered.contains(page_id))
+            .filter_map(|(page_num, page_id)| {
+                let text = style.text(page_num as usize - numbered.start, total)?;
+                Some((page_id, text))
+            })
+            .collect();
+
+        let stamped = stamped
+            .into_iter()
+            .map(|(page_id, text)| (page_id, text, style.align))
+            .collect();
+        let stamp = Stamp {
+            color: &style.color,
+            font: &style.font,
+            font_file: style.font_file.as_ref(),
+            size: style.size,
+            x: style.x,
+            y: style.y,
+            y_from: style.y_from,
         };
-        doc.objects.insert(pages_id, Object::Dictionary(pages));
-        let catalog_id = doc.add_object(dictionary! {
-            "Type" => "Catalog",
-            "Pages" => pages_id,
-        });
-        doc.trailer.set("Root", catalog_id);
+        stamp_pages(doc, conf, &stamp, stamped, "Page number")?;
+    }
 
-        doc
+    Ok(())
+}
+
+// Print the section title on each page of the rendered `sections`, the URLs and their page
+// ranges, except on `blank_pages`
+fn add_running_headers(
+    doc: &mut Document,
+    conf: &Config,
+    sections: &[(String, Range<usize>)],
+    titles: &IndexMap<String, String>,
+    blank_pages: &HashSet<ObjectId>,
+) -> Result<()> {
+    let Some(style) = &conf.running_header else {
+        return Ok(());
+    };
+
+    let page_ids: Vec<ObjectId> = doc.page_iter().collect();
+    let mut chapter = None;
+    let mut stamped = vec![];
+    for (url, pages) in sections {
+        let title = titles.get(url);
+        if conf.chapter_starts.is_empty() || conf.chapter_starts.contains(url) {
+            chapter = title;
+        }
+        let Some(title) = title else {
+            continue;
+        };
+        let text = one_line(&style.text(title, chapter.map_or("", String::as_str)));
+        for i in pages.clone() {
+            if !blank_pages.contains(&page_ids[i]) {
+                stamped.push((page_ids[i], text.clone(), style.align(i + 1)));
+            }
+        }
     }
 
-    pub fn generate_pdf_with_outline() -> Document {
-        let mut doc = Document::with_version("1.7");
-        let pages_id = doc.new_object_id();
-        let font_id = doc.add_object(dictionary! {
-            "Type" => "Font",
-            "Subtype" => "Type1",
-            "BaseFont" => "Courier",
-        });
-        let resources_id = doc.add_object(dictionary! {
-            "Font" => dictionary! {
-                "F1" => font_id,
-            },
-        });
+    let stamp = Stamp {
+        color: &style.color,
+        font: &style.font,
+        font_file: style.font_file.as_ref(),
+        size: style.size,
+        x: style.x,
+        y: style.y,
+        y_from: style.y_from,
+    };
+    stamp_pages(doc, conf, &stamp, stamped, "Running header")
+}
+
+// How `stamp_pages` shows text, the settings page numbers and running headers share
+struct Stamp<'a> {
+    color: &'a Color,
+    font: &'a str,
+    font_file: Option<&'a PathBuf>,
+    size: i16,
+    x: f64,
+    y: f64,
+    y_from: YFrom,
+}
+
+// Show the text of each of `stamped` on its page, aligned against the page's own width
+fn stamp_pages(
+    doc: &mut Document,
+    conf: &Config,
+    style: &Stamp,
+    stamped: Vec<(ObjectId, String, Align)>,
+    used_for: &str,
+) -> Result<()> {
+    if stamped.is_empty() {
+        return Ok(());
+    }
+    // Add the font for each page to reference
+    let font = match style.font_file {
+        Some(path) => {
+            let sfnt = Sfnt::parse(fs::read(path)?)
+                .map_err(|e| anyhow!("Unable to embed {}: {e}", path.display()))?;
+            let name: String = path
+                .file_stem()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .chars()
+                .filter(|c| c.is_ascii_alphanumeric() || *c == '-')
+                .collect();
+            let used: String = stamped.iter().map(|(_, text, _)| text.as_str()).collect();
+            embed_font_subset(doc, &name, sfnt, &used)?
+        }
+        None => add_standard_font(doc, conf, style.font, used_for)?,
+    };
+    // Width of `text` in the 300 DPI units it's placed in
+    let width = |text: &str| font.width(text, style.size as f64);
+
+    let save_restore = add_save_restore(doc);
+
+    // Go through each page
+    for (page_id, text, align) in stamped {
+        // Convert from points into dots at the standard 300 DPI
+        let [x0, y0, x1, y1] = media_box(doc, page_id);
+        let page_width = (x1 - x0) * 300.0 / 72.0;
+        let offset = style.x * 300.0;
+        let x = match align {
+            Align::Left => offset,
+            Align::Center => (page_width - width(&text)) / 2.0 + offset,
+            Align::Right => page_width - width(&text) - offset,
+        };
+        let y = match style.y_from {
+            YFrom::Top => (y1 - y0) * 300.0 / 72.0 - style.y * 300.0,
+            YFrom::Bottom => style.y * 300.0,
+        };
+
+        // Register the font in the page's own resources, which may have been indirect or shared
+        let mut resources = own_resources(doc, page_id);
+        let font_name = resource_name(&resources, b"Font", "F");
+        add_resource(&mut resources, "Font", &font_name, font.id);
+
         let content: Content = Content {
             operations: vec![
+                Operation::new("q", vec![]),
+                // Scale default user space to dots, from the bottom left corner of the MediaBox.
+                // The page content is wrapped in q and Q, so the flipped matrix Chrome draws
+                // with doesn't apply here and pages we made ourselves are stamped the same way.
+                Operation::new(
+                    "cm",
+                    vec![
+                        (72.0 / 300.0).into(),
+                        0.into(),
+                        0.into(),
+                        (72.0 / 300.0).into(),
+                        x0.into(),
+                        y0.into(),
+                    ],
+                ),
+                // Begin Text Element
                 Operation::new("BT", vec![]),
-                Operation::new("Tf", vec!["F1".into(), 48.into()]),
-                Operation::new("Td", vec![100.into(), 600.into()]),
-                Operation::new("Tj", vec![Object::string_literal("Hello World!")]),
+                // Font Color
+                Operation::new(
+                    "rg",
+                    vec![
+                        style.color.r.into(),
+                        style.color.g.into(),
+                        style.color.b.into(),
+                    ],
+                ),
+                // Font and Size
+                Operation::new("Tf", vec![font_name.as_str().into(), style.size.into()]),
+                // Position the baseline
+                Operation::new(
+                    "Tm",
+                    vec![1.into(), 0.into(), 0.into(), 1.into(), x.into(), y.into()],
+                ),
+                // Set the text
+                Operation::new("Tj", vec![font.encode(&text)]),
+                // End Text
                 Operation::new("ET", vec![]),
+                Operation::new("Q", vec![]),
             ],
         };
-        let content_id = doc.add_object(Stream::new(dictionary! {}, content.encode().unwrap()));
+        let stamp_id = doc.add_object(Stream::new(dictionary! {}, content.encode()?));
+        draw_over(doc, page_id, save_restore, resources, stamp_id)?;
+    }
+    Ok(())
+}
 
-        let outline_dict = doc.new_object_id();
-        let node_1 = doc.new_object_id();
-        let node_2 = doc.new_object_id();
-        let node_3 = doc.new_object_id();
+// The merged document and what went wrong along the way
+struct Merged {
+    pdf: Document,
+    problem_urls: Vec<LinkProblem>,
+    problem_anchors: Vec<LinkProblem>,
+    flatten_report: Option<FlattenReport>,
+    image_report: Option<ImageReport>,
+    /// Number of duplicate objects deleted by `dedupe_resources`
+    deduped_objects: Option<usize>,
+    /// External links to check with `check_external_links` and the pages linking to them
+    external_links: IndexMap<String, Vec<usize>>,
+    /// External links the check found dead
+    dead_links: Vec<String>,
+}
 
-        // outline_dictionary
-        //  └► node 1
-        //    └► node 2
-        //    └► node 3
+impl Merged {
+    // The links that couldn't be remapped, a bullet list per kind
+    fn link_problems(&self) -> Vec<String> {
+        [
+            ("Unable to remap these URLS.", &self.problem_urls),
+            ("Unable to remap these Anchors.", &self.problem_anchors),
+        ]
+        .into_iter()
+        .filter(|(_, problems)| !problems.is_empty())
+        .map(|(heading, problems)| {
+            format!(
+                "{heading}\n{}",
+                problems
+                    .iter()
+                    .map(|s| format!("  * {s}"))
+                    .collect::<Vec<String>>()
+                    .join("\n")
+            )
+        })
+        .collect()
+    }
 
-        doc.objects.insert(
-            outline_dict,
-            Object::Dictionary(dictionary! {
-                "Type" => "Outlines",
-                "First" => node_1,
-                "Last" => node_1,
-                "Count" => 3,
-            }),
-        );
+    fn to_report(&self, output_size: Option<u64>) -> MergeReport {
+        MergeReport {
+            problem_urls: self.problem_urls.clone(),
+            problem_anchors: self.problem_anchors.clone(),
+            counts: ReportCounts {
+                problem_urls: self.problem_urls.len(),
+                problem_anchors: self.problem_anchors.len(),
+                dead_links: self.dead_links.len(),
+            },
+            pages: self.pdf.get_pages().len(),
+            output_size,
+        }
+    }
 
-        doc.objects.insert(
-            node_1,
-            Object::Dictionary(dictionary! {
-                "Title" => Object::string_literal("Node 1"),
-                "Parent" => outline_dict,
-                "First" => node_2,
-                "Last" => node_3,
-                "Count" => 2
-            }),
+    // Write the `--report` JSON, with the size of `output` if it was saved
+    fn write_report(&self, conf: &Config, output: Option<&Path>) -> Result<()> {
+        let Some(path) = &conf.report else {
+            return Ok(());
+        };
+        let output_size = output.map(fs::metadata).transpose()?.map(|m| m.len());
+        fs::write(
+            path,
+            serde_json::to_string_pretty(&self.to_report(output_size))?,
+        )?;
+        Ok(())
+    }
+
+    // Check the external links, on a runtime of its own in another thread as `merge_pdfs`
+    // may be called from async code
+    #[cfg(feature = "render")]
+    fn check_external_links(&mut self, conf: &Config) -> Result<()> {
+        if self.external_links.is_empty() {
+            return Ok(());
+        }
+        let links = &self.external_links;
+        self.dead_links = std::thread::scope(|s| {
+            s.spawn(|| {
+                tokio::runtime::Builder::new_current_thread()
+                    .enable_all()
+                    .build()?
+                    .block_on(check_external_links(conf, links))
+            })
+            .join()
+            .map_err(|_| anyhow!("Checking external links panicked"))?
+        })?;
+        Ok(())
+    }
+
+    // With `strict_links` fail before anything is written if links couldn't be remapped
+    fn check_strict_links(&self, conf: &Config) -> Result<()> {
+        let problems = self.link_problems();
+        if conf.strict_links && !problems.is_empty() {
+            return Err(anyhow!(
+                "{}\nNot writing {} because strict_links is set",
+                problems.join("\n"),
+                conf.output_pdf.display()
+            ));
+        }
+        Ok(())
+    }
+}
+
+// Keep an object as it is read, but without its stream contents. Object and cross-reference
+// streams are kept as the objects in them are read from them.
+fn drop_stream_content(id: ObjectId, object: &mut Object) -> Option<(ObjectId, Object)> {
+    if let Object::Stream(stream) = object {
+        let kind = stream.dict.get(b"Type").and_then(Object::as_name);
+        if !matches!(kind, Ok(b"ObjStm" | b"XRef")) {
+            stream.set_content(vec![]);
+        }
+    }
+    Some((id, std::mem::replace(object, Object::Null)))
+}
+
+// Parse `path`, without stream contents for `no_output` runs which never write them
+fn parse_pdf(path: &Path, no_output: bool) -> lopdf::Result<Document> {
+    match no_output {
+        true => Document::load_filtered(path, drop_stream_content),
+        false => Document::load(path),
+    }
+}
+
+// Load `path`, through the merge cache if there is one. Documents without stream contents
+// aren't cached.
+fn load_pdf(cache: Option<&mut MergeCache>, path: &Path, no_output: bool) -> Result<Document> {
+    let doc = match cache {
+        Some(cache) if !no_output => cache.load(path)?,
+        _ => parse_pdf(path, no_output)?,
+    };
+    if doc.is_encrypted() {
+        return Err(anyhow!(
+            "{} is encrypted, merge the unencrypted rendered PDFs and set encryption instead",
+            path.display()
+        ));
+    }
+    Ok(doc)
+}
+
+// Merge the loaded PDFs, rewrite links and write the side outputs such as the page map
+fn assemble(conf: &Config, mut url_to_pdf_doc: IndexMap<String, Document>) -> Result<Merged> {
+    // A rendered cover isn't a link target, numbered or in the outline
+    let cover = match (&conf.cover_pdf, &conf.cover_url) {
+        (Some(path), _) => {
+            let doc = parse_pdf(path, conf.no_output)
+                .map_err(|e| anyhow!("Unable to load cover_pdf {}: {e}", path.display()))?;
+            if doc.is_encrypted() {
+                return Err(anyhow!("cover_pdf {} is encrypted", path.display()));
+            }
+            Some(doc)
+        }
+        (None, Some(url)) => url_to_pdf_doc.shift_remove(url),
+        (None, None) => None,
+    };
+    let mut back_matter = vec![];
+    for b in &conf.back_matter {
+        let doc = parse_pdf(&b.path, conf.no_output)
+            .map_err(|e| anyhow!("Unable to load back_matter {}: {e}", b.path.display()))?;
+        if doc.is_encrypted() {
+            return Err(anyhow!("back_matter {} is encrypted", b.path.display()));
+        }
+        back_matter.push(doc);
+    }
+    if conf.page_bookmarks {
+        for (url, doc) in url_to_pdf_doc.iter_mut() {
+            let title = conf
+                .page_titles
+                .get(url)
+                .cloned()
+                .or_else(|| info_title(doc))
+                .unwrap_or_else(|| url.clone());
+            add_page_bookmark(doc, &title)?;
+        }
+    }
+    // Sidebar text or the page's <title>, before the PDFs are merged
+    let titles: IndexMap<String, String> = match conf.running_header {
+        Some(_) => url_to_pdf_doc
+            .iter()
+            .filter_map(|(url, doc)| {
+                let title = conf
+                    .page_titles
+                    .get(url)
+                    .cloned()
+                    .or_else(|| info_title(doc));
+                Some((url.clone(), title?))
+            })
+            .collect(),
+        None => IndexMap::new(),
+    };
+    let (mut parts, mut url_to_page_num) = merge_pdf_objects(cover, url_to_pdf_doc, back_matter)?;
+
+    let mut pdf = build_pdf_from_objects(&parts, conf.no_output)?;
+
+    let page_ids = pdf.get_pages();
+    let bookmarks = conf
+        .back_matter
+        .iter()
+        .zip(&parts.back_matter_starts)
+        .filter(|(b, _)| b.bookmark)
+        .filter_map(|(b, start)| Some((b.title(), *page_ids.get(&(*start as u32 + 1))?)))
+        .collect();
+    append_bookmarks(&mut pdf, bookmarks)?;
+    let mut blank_pages = match conf.chapter_breaks {
+        ChapterBreaks::Odd => insert_chapter_breaks(
+            &mut pdf,
+            conf,
+            &mut url_to_page_num,
+            &mut parts.back_matter_starts,
+        )?,
+        ChapterBreaks::None => HashSet::new(),
+    };
+    if !conf.no_output {
+        add_metadata(&mut pdf, &conf.metadata)?;
+    }
+
+    let mut trace = vec![];
+    let (mut problem_urls, mut problem_anchors) =
+        rewrite_vitepress_links(conf, &mut pdf, &url_to_page_num, &mut trace)?;
+
+    // Before page numbers so they're counted, page map entries move down past them
+    let (toc_pages, toc_blank_page) = insert_toc(&mut pdf, conf, parts.cover_pages)?;
+    blank_pages.extend(toc_blank_page);
+    // Pages after the cover moved down past the table of contents since links were rewritten
+    let after_toc = |page: usize| match page > parts.cover_pages {
+        true => page + toc_pages,
+        false => page,
+    };
+    for problem in problem_urls.iter_mut().chain(problem_anchors.iter_mut()) {
+        problem.page = after_toc(problem.page);
+    }
+    for t in trace.iter_mut() {
+        t.page = after_toc(t.page);
+    }
+    let external_links = external_links(conf, &pdf)?;
+
+    if let Some(trace_links) = &conf.trace_links {
+        let mut lines = String::new();
+        for t in &trace {
+            lines.push_str(&serde_json::to_string(t)?);
+            lines.push('\n');
+        }
+        fs::write(trace_links, lines)?;
+    }
+    // After the table of contents, which lists the whole outline down to its own depth
+    if let Some(depth) = conf.outline_depth {
+        limit_outline_depth(&mut pdf, depth)?;
+    }
+    let url_to_page_num: IndexMap<String, usize> = url_to_page_num
+        .into_iter()
+        .map(|(url, page_num)| (url, page_num + toc_pages))
+        .collect();
+
+    let back_matter_start = parts.back_matter_starts.first().map(|s| s + toc_pages);
+
+    if !conf.no_output {
+        let numbered_end = match back_matter_start {
+            Some(start) if !conf.number_back_matter => start,
+            _ => pdf.get_pages().len(),
+        };
+        let unnumbered = match conf.number_blank_pages {
+            true => HashSet::new(),
+            false => blank_pages.clone(),
+        };
+        add_page_numbers(&mut pdf, conf, parts.cover_pages..numbered_end, &unnumbered)?;
+        add_page_labels(&mut pdf, conf, parts.cover_pages)?;
+
+        // Each URL's pages run up to the next URL's, the last up to any back matter
+        let rendered_end = back_matter_start.unwrap_or(pdf.get_pages().len());
+        let starts: Vec<usize> = url_to_page_num.values().copied().collect();
+        let sections: Vec<(String, Range<usize>)> = url_to_page_num
+            .iter()
+            .enumerate()
+            .map(|(i, (url, start))| {
+                let end = starts.get(i + 1).copied().unwrap_or(rendered_end);
+                (url.clone(), *start..end)
+            })
+            .collect();
+        add_running_headers(&mut pdf, conf, &sections, &titles, &blank_pages)?;
+        // Over everything else, before flattening so its opacity is flattened or reported
+        add_watermark(&mut pdf, conf)?;
+    }
+
+    // Flattening inspects image soft masks, which `no_output` runs don't load
+    let flatten_report = if conf.flatten_transparency && !conf.no_output {
+        Some(flatten_transparency(
+            &mut pdf,
+            conf.flatten_alpha_threshold,
+        )?)
+    } else {
+        None
+    };
+
+    let image_report = match (&conf.optimize_images, conf.no_output) {
+        (Some(options), false) => Some(optimize_images(&mut pdf, options)?),
+        _ => None,
+    };
+
+    // Last so the objects added along the way are shared too
+    let deduped_objects =
+        (conf.dedupe_resources && !conf.no_output).then(|| dedupe_objects(&mut pdf));
+    if conf.linearize && !conf.no_output {
+        front_load_first_page(&mut pdf)?;
+    }
+
+    if let Some(page_map) = &conf.page_map {
+        fs::write(
+            page_map,
+            serde_json::to_string_pretty(&build_page_map(
+                &pdf,
+                &url_to_page_num,
+                back_matter_start,
+            )?)?,
+        )?;
+    }
+
+    Ok(Merged {
+        pdf,
+        problem_urls,
+        problem_anchors,
+        flatten_report,
+        image_report,
+        deduped_objects,
+        external_links,
+        dead_links: vec![],
+    })
+}
+
+// Renumber the objects so the catalog, the page tree root and the first page with
+// everything it draws come first. lopdf writes objects in ID order, so a reader going
+// through the file from the start has page 1 before the rest of the document.
+fn front_load_first_page(doc: &mut Document) -> Result<()> {
+    let catalog_id = doc.trailer.get(b"Root")?.as_reference()?;
+    let Some(first_page_id) = doc.page_iter().next() else {
+        return Ok(());
+    };
+
+    let mut order = vec![catalog_id];
+    if let Ok(pages_id) = doc
+        .get_dictionary(catalog_id)?
+        .get(b"Pages")
+        .and_then(Object::as_reference)
+    {
+        order.push(pages_id);
+    }
+    let mut seen: HashSet<ObjectId> = order.iter().copied().collect();
+    // Follow what the first page draws, not the rest of the page tree
+    let mut queue = VecDeque::from([first_page_id]);
+    while let Some(id) = queue.pop_front() {
+        if !seen.insert(id) {
+            continue;
+        }
+        order.push(id);
+        let Ok(object) = doc.get_object(id) else {
+            continue;
+        };
+        let mut references = vec![];
+        collect_page_references(object, &mut references);
+        queue.extend(references.into_iter().filter(|r| {
+            !doc.get_dictionary(*r)
+                .and_then(|d| d.get(b"Type"))
+                .and_then(Object::as_name_str)
+                .is_ok_and(|t| t == "Page" || t == "Pages")
+        }));
+    }
+    order.extend(doc.objects.keys().filter(|id| !seen.contains(id)));
+
+    let renumbered: HashMap<ObjectId, ObjectId> = order
+        .iter()
+        .enumerate()
+        .map(|(i, id)| (*id, (i as u32 + 1, 0)))
+        .collect();
+    let mut objects = BTreeMap::new();
+    for (id, mut object) in std::mem::take(&mut doc.objects) {
+        replace_references(&mut object, &renumbered);
+        objects.insert(renumbered[&id], object);
+    }
+    doc.objects = objects;
+    for (_, object) in doc.trailer.iter_mut() {
+        replace_references(object, &renumbered);
+    }
+    doc.max_id = order.len() as u32;
+    Ok(())
+}
+
+// References in `object` except back up the page tree
+fn collect_page_references(object: &Object, references: &mut Vec<ObjectId>) {
+    match object {
+        Object::Reference(id) => references.push(*id),
+        Object::Array(array) => array
+            .iter()
+            .for_each(|o| collect_page_references(o, references)),
+        Object::Dictionary(dict) => dict
+            .iter()
+            .filter(|(key, _)| key.as_slice() != b"Parent")
+            .for_each(|(_, o)| collect_page_references(o, references)),
+        Object::Stream(stream) => stream
+            .dict
+            .iter()
+            .for_each(|(_, o)| collect_page_references(o, references)),
+        _ => {}
+    }
+}
+
+fn save_pdf(pdf: &mut Document, path: &Path, encryption: Option<&Encryption>) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    pdf.compress();
+    // Last, encrypted streams can't be compressed and encrypted links can't be rewritten
+    if let Some(encryption) = encryption {
+        encrypt(pdf, encryption)?;
+    }
+    pdf.save(path)?;
+    Ok(())
+}
+
+// Print the summary, failing if any links couldn't be remapped
+fn report(conf: &Config, merged: Merged, cache: Option<MergeCache>) -> ExitCode {
+    let link_problems = merged.link_problems();
+    let Merged {
+        pdf,
+        flatten_report,
+        image_report,
+        deduped_objects,
+        dead_links,
+        ..
+    } = merged;
+
+    conf.progress.event(&ProgressEvent::Merged {
+        output: (!conf.no_output).then_some(conf.output_pdf.as_path()),
+        pages: pdf.get_pages().len(),
+    });
+
+    if conf.no_output {
+        conf.progress.println(format_args!(
+            "Merged {} pages, not writing the merged PDF",
+            pdf.get_pages().len()
+        ));
+    } else {
+        conf.progress.println(format_args!(
+            "Merged PDF is avalible here {}",
+            conf.output_pdf.display()
+        ));
+    }
+
+    if let Some(report) = flatten_report {
+        conf.progress.println(format_args!(
+            "Flattened {} transparency entries on {} pages",
+            report.altered.values().sum::<usize>(),
+            report.altered.len()
+        ));
+        if !report.untouched.is_empty() {
+            conf.progress.println(format_args!(
+                "Left this transparency untouched because it's below flatten_alpha_threshold.\n{}",
+                report
+                    .untouched
+                    .iter()
+                    .map(|s| format!("  * {s}"))
+                    .collect::<Vec<String>>()
+                    .join("\n")
+            ));
+        }
+    }
+
+    if let Some(report) = image_report {
+        let mib = |bytes: usize| bytes as f64 / (1024.0 * 1024.0);
+        conf.progress.println(format_args!(
+            "Optimized {} images from {:.1} MiB to {:.1} MiB",
+            report.images,
+            mib(report.before),
+            mib(report.after)
+        ));
+    }
+
+    if let Some(deduped) = deduped_objects {
+        conf.progress
+            .println(format_args!("Removed {deduped} duplicate objects"));
+    }
+
+    if let Some(cache) = cache {
+        conf.progress.println(format_args!(
+            "Merge cache: {} hits, {} misses",
+            cache.stats.hits, cache.stats.misses
+        ));
+    }
+
+    for problems in &link_problems {
+        conf.progress.println(format_args!("{problems}"));
+    }
+
+    if !dead_links.is_empty() {
+        conf.progress.println(format_args!(
+            "These external links are dead.\n{}",
+            dead_links
+                .iter()
+                .map(|s| format!("  * {s}"))
+                .collect::<Vec<String>>()
+                .join("\n")
+        ));
+    }
+
+    match link_problems.is_empty() && dead_links.is_empty() {
+        true => ExitCode::SUCCESS,
+        false => ExitCode::FAILURE,
+    }
+}
+
+pub fn merge_pdfs(conf: &Config, url_to_pdf_path: IndexMap<String, PathBuf>) -> Result<ExitCode> {
+    let mut cache = conf
+        .merge_cache
+        .as_deref()
+        .filter(|_| !conf.no_output)
+        .map(MergeCache::new)
+        .transpose()?;
+
+    let mut url_to_pdf_doc = IndexMap::new();
+    for (url, path) in url_to_pdf_path {
+        url_to_pdf_doc.insert(url, load_pdf(cache.as_mut(), &path, conf.no_output)?);
+    }
+
+    let mut merged = assemble(conf, url_to_pdf_doc)?;
+    if let Err(e) = merged.check_strict_links(conf) {
+        merged.write_report(conf, None)?;
+        return Err(e);
+    }
+    #[cfg(feature = "render")]
+    merged.check_external_links(conf)?;
+
+    if !conf.no_output {
+        save_pdf(&mut merged.pdf, &conf.output_pdf, conf.encryption.as_ref())?;
+    }
+    merged.write_report(conf, (!conf.no_output).then_some(conf.output_pdf.as_path()))?;
+
+    Ok(report(conf, merged, cache))
+}
+
+/// Errors specific to `merge_pdfs_async`
+#[derive(Debug, PartialEq)]
+pub enum MergeError {
+    /// The `CancellationToken` was cancelled before the merge finished
+    Cancelled,
+}
+
+impl fmt::Display for MergeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MergeError::Cancelled => write!(f, "Merge cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for MergeError {}
+
+// Number of PDFs loaded per blocking task by `merge_pdfs_async`
+const LOAD_CHUNK_SIZE: usize = 16;
+
+fn check_cancelled(cancel: &CancellationToken) -> Result<()> {
+    if cancel.is_cancelled() {
+        return Err(MergeError::Cancelled.into());
+    }
+    Ok(())
+}
+
+/// `merge_pdfs` for async callers which must not block the runtime.
+///
+/// Loading, assembly and saving run on blocking worker threads, PDFs are loaded in chunks
+/// and we yield between phases. Once `cancel` is cancelled the merge stops at the next
+/// chunk or phase with `MergeError::Cancelled`. The output is written to a `.partial` file
+/// which is only renamed to `output_pdf` if the merge wasn't cancelled.
+pub async fn merge_pdfs_async(
+    conf: Arc<Config>,
+    url_to_pdf_path: IndexMap<String, PathBuf>,
+    cancel: CancellationToken,
+) -> Result<ExitCode> {
+    let mut cache = conf
+        .merge_cache
+        .as_deref()
+        .filter(|_| !conf.no_output)
+        .map(MergeCache::new)
+        .transpose()?;
+
+    let paths: Vec<(String, PathBuf)> = url_to_pdf_path.into_iter().collect();
+    let no_output = conf.no_output;
+    let mut url_to_pdf_doc = IndexMap::new();
+    for chunk in paths.chunks(LOAD_CHUNK_SIZE) {
+        check_cancelled(&cancel)?;
+        let chunk = chunk.to_vec();
+        let (docs, returned_cache) = tokio::task::spawn_blocking(move || {
+            let docs = chunk
+                .into_iter()
+                .map(|(url, path)| Ok((url, load_pdf(cache.as_mut(), &path, no_output)?)))
+                .collect::<Result<Vec<_>>>();
+            (docs, cache)
+        })
+        .await?;
+        cache = returned_cache;
+        url_to_pdf_doc.extend(docs?);
+        tokio::task::yield_now().await;
+    }
+
+    check_cancelled(&cancel)?;
+    let assemble_conf = conf.clone();
+    let mut merged =
+        tokio::task::spawn_blocking(move || assemble(&assemble_conf, url_to_pdf_doc)).await??;
+    if let Err(e) = merged.check_strict_links(&conf) {
+        merged.write_report(&conf, None)?;
+        return Err(e);
+    }
+    tokio::task::yield_now().await;
+
+    #[cfg(feature = "render")]
+    if !merged.external_links.is_empty() {
+        check_cancelled(&cancel)?;
+        merged.dead_links = check_external_links(&conf, &merged.external_links).await?;
+    }
+
+    check_cancelled(&cancel)?;
+    if !conf.no_output {
+        let partial = conf.output_pdf.with_extension("pdf.partial");
+        let save_path = partial.clone();
+        let save_conf = conf.clone();
+        let (returned, saved) = tokio::task::spawn_blocking(move || {
+            let saved = save_pdf(&mut merged.pdf, &save_path, save_conf.encryption.as_ref());
+            (merged, saved)
+        })
+        .await?;
+        merged = returned;
+
+        if let Err(e) = saved.and_then(|_| check_cancelled(&cancel)) {
+            let _ = fs::remove_file(&partial);
+            return Err(e);
+        }
+        fs::rename(&partial, &conf.output_pdf)?;
+    }
+    merged.write_report(
+        &conf,
+        (!conf.no_output).then_some(conf.output_pdf.as_path()),
+    )?;
+
+    Ok(report(&conf, merged, cache))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lopdf::{
+        content::{Content, Operation},
+        dictionary, Stream,
+    };
+
+    pub fn generate_pdf_with_link(url: String) -> Document {
+        let mut doc = Document::with_version("1.5");
+        let pages_id = doc.new_object_id();
+        let font_id = doc.add_object(dictionary! {
+            "Type" => "Font",
+            "Subtype" => "Type1",
+            "BaseFont" => "Courier",
+        });
+        let resources_id = doc.add_object(dictionary! {
+            "Font" => dictionary! {
+                "F1" => font_id,
+            },
+        });
+        let content: Content = Content {
+            operations: vec![
+                Operation::new("BT", vec![]),
+                Operation::new("Tf", vec!["F1".into(), 48.into()]),
+                Operation::new("Td", vec![100.into(), 600.into()]),
+                Operation::new("Tj", vec![Object::string_literal("Hello World!")]),
+                Operation::new("ET", vec![]),
+            ],
+        };
+        let content_id = doc.add_object(Stream::new(dictionary! {}, content.encode().unwrap()));
+        let a_id = doc.add_object(dictionary! {
+            "Type" => "Action",
+            "S" => "URI",
+            "URI" => Object::string_literal(url),
+        });
+        let annot_id = doc.add_object(dictionary! {
+            "Type" => "Annot",
+            "Subtype" => "Link",
+            "Rect" => vec![0.into(), 0.into(), 595.into(), 842.into()],
+            "F" => 4,
+            "Border" => vec![1.into(), 1.into(), 1.into()],
+            "A" => a_id,
+        });
+
+        let page_id = doc.add_object(dictionary! {
+            "Type" => "Page",
+            "Parent" => pages_id,
+            "Contents" => content_id,
+            "Resources" => resources_id,
+            "MediaBox" => vec![0.into(), 0.into(), 595.into(), 842.into()],
+            "Annots" => vec![annot_id.into()],
+        });
+        let pages = dictionary! {
+            "Type" => "Pages",
+            "Kids" => vec![page_id.into()],
+            "Count" => 1,
+        };
+        doc.objects.insert(pages_id, Object::Dictionary(pages));
+        let catalog_id = doc.add_object(dictionary! {
+            "Type" => "Catalog",
+            "Pages" => pages_id,
+        });
+        doc.trailer.set("Root", catalog_id);
+
+        doc
+    }
+
+    pub fn generate_pdf_with_outline() -> Document {
+        let mut doc = Document::with_version("1.7");
+        let pages_id = doc.new_object_id();
+        let font_id = doc.add_object(dictionary! {
+            "Type" => "Font",
+            "Subtype" => "Type1",
+            "BaseFont" => "Courier",
+        });
+        let resources_id = doc.add_object(dictionary! {
+            "Font" => dictionary! {
+                "F1" => font_id,
+            },
+        });
+        let content: Content = Content {
+            operations: vec![
+                Operation::new("BT", vec![]),
+                Operation::new("Tf", vec!["F1".into(), 48.into()]),
+                Operation::new("Td", vec![100.into(), 600.into()]),
+                Operation::new("Tj", vec![Object::string_literal("Hello World!")]),
+                Operation::new("ET", vec![]),
+            ],
+        };
+        let content_id = doc.add_object(Stream::new(dictionary! {}, content.encode().unwrap()));
+
+        let outline_dict = doc.new_object_id();
+        let node_1 = doc.new_object_id();
+        let node_2 = doc.new_object_id();
+        let node_3 = doc.new_object_id();
+
+        // outline_dictionary
+        //  └► node 1
+        //    └► node 2
+        //    └► node 3
+
+        doc.objects.insert(
+            outline_dict,
+            Object::Dictionary(dictionary! {
+                "Type" => "Outlines",
+                "First" => node_1,
+                "Last" => node_1,
+                "Count" => 3,
+            }),
+        );
+
+        doc.objects.insert(
+            node_1,
+            Object::Dictionary(dictionary! {
+                "Title" => Object::string_literal("Node 1"),
+                "Parent" => outline_dict,
+                "First" => node_2,
+                "Last" => node_3,
+                "Count" => 2
+            }),
+        );
+
+        doc.objects.insert(
+            node_2,
+            Object::Dictionary(dictionary! {
+                "Title" => Object::string_literal("Node 2"),
+                "Parent" => node_1,
+                "Next" => node_3,
+                "Count" => 1,
+            }),
+        );
+
+        doc.objects.insert(
+            node_3,
+            Object::Dictionary(dictionary! {
+                "Title" => Object::string_literal("Node 3"),
+                "Parent" => node_1,
+                "Prev" => node_2,
+                "Count" => 1,
+            }),
+        );
+
+        let page_id = doc.add_object(dictionary! {
+            "Type" => "Page",
+            "Parent" => pages_id,
+            "Contents" => content_id,
+            "Resources" => resources_id,
+            "MediaBox" => vec![0.into(), 0.into(), 595.into(), 842.into()],
+        });
+
+        let pages = dictionary! {
+            "Type" => "Pages",
+            "Kids" => vec![page_id.into()],
+            "Count" => 1,
+        };
+        doc.objects.insert(pages_id, Object::Dictionary(pages));
+        let catalog_id = doc.add_object(dictionary! {
+            "Type" => "Catalog",
+            "Pages" => pages_id,
+            "Outlines" => outline_dict,
+        });
+        doc.trailer.set("Root", catalog_id);
+
+        doc
+    }
+
+    // A single page with two nested headings and named destinations for both
+    pub fn generate_pdf_with_headings() -> Document {
+        let mut doc = Document::with_version("1.7");
+        let pages_id = doc.new_object_id();
+        let page_id = doc.new_object_id();
+        let outline_dict = doc.new_object_id();
+        let intro = doc.new_object_id();
+        let details = doc.new_object_id();
+
+        let intro_dest = || vec![page_id.into(), "XYZ".into(), 0.into(), 800.into(), 0.into()];
+        let details_dest = || vec![page_id.into(), "XYZ".into(), 0.into(), 400.into(), 0.into()];
+
+        doc.objects.insert(
+            outline_dict,
+            Object::Dictionary(dictionary! {
+                "Type" => "Outlines",
+                "First" => intro,
+                "Last" => intro,
+                "Count" => 2,
+            }),
+        );
+        doc.objects.insert(
+            intro,
+            Object::Dictionary(dictionary! {
+                "Title" => Object::string_literal("Intro"),
+                "Parent" => outline_dict,
+                "First" => details,
+                "Last" => details,
+                "Count" => 1,
+                "Dest" => intro_dest(),
+            }),
+        );
+        doc.objects.insert(
+            details,
+            Object::Dictionary(dictionary! {
+                "Title" => Object::string_literal("Details"),
+                "Parent" => intro,
+                "Dest" => details_dest(),
+            }),
+        );
+        let dests_id = doc.add_object(dictionary! {
+            "intro" => intro_dest(),
+            "details" => details_dest(),
+        });
+        doc.objects.insert(
+            page_id,
+            Object::Dictionary(dictionary! {
+                "Type" => "Page",
+                "Parent" => pages_id,
+                "MediaBox" => vec![0.into(), 0.into(), 595.into(), 842.into()],
+            }),
+        );
+        doc.objects.insert(
+            pages_id,
+            Object::Dictionary(dictionary! {
+                "Type" => "Pages",
+                "Kids" => vec![page_id.into()],
+                "Count" => 1,
+            }),
+        );
+        let catalog_id = doc.add_object(dictionary! {
+            "Type" => "Catalog",
+            "Pages" => pages_id,
+            "Outlines" => outline_dict,
+            "Dests" => dests_id,
+        });
+        doc.trailer.set("Root", catalog_id);
+
+        doc
+    }
+
+    // Two page cover in a nested page tree which it inherits its MediaBox and Resources from,
+    // with a bookmark of its own
+    pub fn generate_cover() -> Document {
+        let mut doc = Document::with_version("1.7");
+        let root_id = doc.new_object_id();
+        let node_id = doc.new_object_id();
+        let font_id = doc.add_object(dictionary! {
+            "Type" => "Font",
+            "Subtype" => "Type1",
+            "BaseFont" => "Times-Roman",
+        });
+        let content_id = doc.add_object(Stream::new(
+            dictionary! {},
+            b"BT /F1 48 Tf 100 600 Td (Cover) Tj ET".to_vec(),
+        ));
+        let page_ids: Vec<ObjectId> = (0..2)
+            .map(|_| {
+                doc.add_object(dictionary! {
+                    "Type" => "Page",
+                    "Parent" => node_id,
+                    "Contents" => content_id,
+                })
+            })
+            .collect();
+        doc.objects.insert(
+            node_id,
+            Object::Dictionary(dictionary! {
+                "Type" => "Pages",
+                "Parent" => root_id,
+                "Kids" => page_ids.iter().map(|id| Object::Reference(*id)).collect::<Vec<_>>(),
+                "Count" => 2,
+                "Resources" => dictionary! {
+                    "Font" => dictionary! {
+                        "F1" => font_id,
+                    },
+                },
+            }),
+        );
+        doc.objects.insert(
+            root_id,
+            Object::Dictionary(dictionary! {
+                "Type" => "Pages",
+                "Kids" => vec![node_id.into()],
+                "Count" => 2,
+                "MediaBox" => vec![0.into(), 0.into(), 595.into(), 842.into()],
+            }),
+        );
+        let outline_id = doc.new_object_id();
+        let item_id = doc.add_object(dictionary! {
+            "Title" => Object::string_literal("Cover"),
+            "Parent" => outline_id,
+            "Dest" => vec![page_ids[0].into(), "Fit".into()],
+        });
+        doc.objects.insert(
+            outline_id,
+            Object::Dictionary(dictionary! {
+                "Type" => "Outlines",
+                "First" => item_id,
+                "Last" => item_id,
+                "Count" => 1,
+            }),
+        );
+        let catalog_id = doc.add_object(dictionary! {
+            "Type" => "Catalog",
+            "Pages" => root_id,
+            "Outlines" => outline_id,
+        });
+        doc.trailer.set("Root", catalog_id);
+
+        doc
+    }
+
+    // Single page PDF with named destinations `a`, `b` and `c` in a two level name tree
+    pub fn generate_pdf_with_name_tree() -> Document {
+        let mut doc = generate_pdf_with_link("http://example.com".to_string());
+        let page_id = doc.page_iter().next().unwrap();
+        let dest = |y: i64| -> Object {
+            vec![page_id.into(), "XYZ".into(), 0.into(), y.into(), 0.into()].into()
+        };
+        let first = doc.add_object(dictionary! {
+            "Limits" => vec![Object::string_literal("a"), Object::string_literal("b")],
+            "Names" => vec![Object::string_literal("a"), dest(800), Object::string_literal("b"), dest(600)],
+        });
+        let second = doc.add_object(dictionary! {
+            "Limits" => vec![Object::string_literal("c"), Object::string_literal("c")],
+            "Names" => vec![Object::string_literal("c"), dest(400)],
+        });
+        let root = doc.add_object(dictionary! {
+            "Kids" => vec![first.into(), second.into()],
+        });
+        let catalog_id = doc.trailer.get(b"Root").unwrap().as_reference().unwrap();
+        doc.get_dictionary_mut(catalog_id)
+            .unwrap()
+            .set("Names", dictionary! { "Dests" => root });
+        doc
+    }
+
+    #[test]
+    fn test_named_dests_name_tree() {
+        let mut doc = generate_pdf_with_name_tree();
+        let dests = get_named_dests(&doc).unwrap();
+        assert_eq!(
+            dests.keys().collect::<Vec<_>>(),
+            vec![b"a".as_slice(), b"b".as_slice(), b"c".as_slice()]
+        );
+        assert_eq!(
+            dests[b"c".as_slice()].as_array().unwrap()[3]
+                .as_i64()
+                .unwrap(),
+            400
+        );
+
+        // Name trees are flattened so they survive merging
+        flatten_named_dests(&mut doc).unwrap();
+        assert!(doc.catalog().unwrap().has(b"Dests"));
+        let mut map = IndexMap::new();
+        map.insert("http://example.com/1.html".to_string(), doc);
+        let (parts, _) = merge_pdf_objects(None, map, vec![]).unwrap();
+        let pdf = build_pdf_from_objects(&parts, false).unwrap();
+        assert_eq!(get_named_dests(&pdf).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_name_tree_kids() {
+        // Chrome writes kid structured trees for pages with many anchors, kids may be inline
+        let mut doc = generate_pdf_with_name_tree();
+        let page_id = doc.page_iter().next().unwrap();
+        let leaf = |names: &[&str]| -> Dictionary {
+            let entries = names
+                .iter()
+                .flat_map(|name| {
+                    let dest: Object = vec![page_id.into(), "Fit".into()].into();
+                    [Object::string_literal(*name), dest]
+                })
+                .collect::<Vec<_>>();
+            dictionary! {
+                "Limits" => vec![
+                    Object::string_literal(names[0]),
+                    Object::string_literal(names[names.len() - 1]),
+                ],
+                "Names" => entries,
+            }
+        };
+        let deep = doc.add_object(leaf(&["d", "e"]));
+        let middle = doc.add_object(dictionary! {
+            "Limits" => vec![Object::string_literal("d"), Object::string_literal("f")],
+            "Kids" => vec![deep.into(), Object::Dictionary(leaf(&["f"]))],
+        });
+        let catalog = doc.catalog().unwrap().clone();
+        let names = catalog.get(b"Names").unwrap().as_dict().unwrap();
+        let root = names.get(b"Dests").unwrap().as_reference().unwrap();
+        doc.get_dictionary_mut(root)
+            .unwrap()
+            .get_mut(b"Kids")
+            .unwrap()
+            .as_array_mut()
+            .unwrap()
+            .push(middle.into());
+
+        let dests = get_named_dests(&doc).unwrap();
+        assert_eq!(
+            dests.keys().collect::<Vec<_>>(),
+            ["a", "b", "c", "d", "e", "f"].map(|n| n.as_bytes())
+        );
+
+        // A node reached twice is an error rather than an endless loop
+        doc.get_dictionary_mut(deep)
+            .unwrap()
+            .set("Kids", vec![middle.into()]);
+        assert!(get_named_dests(&doc).is_err());
+    }
+
+    // Single page PDF with `name` attached in a two level EmbeddedFiles name tree
+    fn generate_pdf_with_attachment(name: &str) -> Document {
+        let mut doc = generate_pdf_with_link("http://example.com".to_string());
+        let file_id = doc.add_object(Stream::new(
+            dictionary! { "Type" => "EmbeddedFile" },
+            name.as_bytes().to_vec(),
+        ));
+        let filespec_id = doc.add_object(dictionary! {
+            "Type" => "Filespec",
+            "F" => Object::string_literal(name),
+            "EF" => dictionary! { "F" => file_id },
+        });
+        let leaf = doc.add_object(dictionary! {
+            "Limits" => vec![Object::string_literal(name), Object::string_literal(name)],
+            "Names" => vec![Object::string_literal(name), filespec_id.into()],
+        });
+        let catalog_id = doc.trailer.get(b"Root").unwrap().as_reference().unwrap();
+        doc.get_dictionary_mut(catalog_id).unwrap().set(
+            "Names",
+            dictionary! { "EmbeddedFiles" => dictionary! { "Kids" => vec![leaf.into()] } },
+        );
+        doc
+    }
+
+    #[test]
+    fn test_embedded_files() {
+        let mut map = IndexMap::new();
+        map.insert(
+            "http://example.com/b.html".to_string(),
+            generate_pdf_with_attachment("data.csv"),
+        );
+        map.insert(
+            "http://example.com/a.html".to_string(),
+            generate_pdf_with_attachment("data.csv"),
+        );
+        let (parts, _) = merge_pdf_objects(None, map, vec![]).unwrap();
+        let pdf = build_pdf_from_objects(&parts, false).unwrap();
+
+        // Both attachments are kept under their page's names, in sorted order
+        let files = get_name_tree(&pdf, b"EmbeddedFiles").unwrap();
+        assert_eq!(
+            files.keys().collect::<Vec<_>>(),
+            [b"/a#data.csv".as_slice(), b"/b#data.csv".as_slice()]
+        );
+        for filespec in files.values() {
+            let filespec = pdf
+                .get_dictionary(filespec.as_reference().unwrap())
+                .unwrap();
+            let file_id = filespec
+                .get(b"EF")
+                .and_then(Object::as_dict)
+                .and_then(|ef| ef.get(b"F"))
+                .and_then(Object::as_reference)
+                .unwrap();
+            assert_eq!(
+                pdf.get_object(file_id)
+                    .unwrap()
+                    .as_stream()
+                    .unwrap()
+                    .content,
+                b"data.csv"
+            );
+        }
+    }
+
+    // Back matter attachments with the same name are all kept, and the name trees of every PDF,
+    // not only the first, are merged
+    #[test]
+    fn test_merged_name_trees() {
+        let mut page = generate_pdf_with_attachment("data.csv");
+        let js_id = page.add_object(dictionary! {
+            "S" => "JavaScript",
+            "JS" => Object::string_literal("init()"),
+        });
+        let catalog_id = page.trailer.get(b"Root").unwrap().as_reference().unwrap();
+        page.get_dictionary_mut(catalog_id)
+            .unwrap()
+            .get_mut(b"Names")
+            .and_then(Object::as_dict_mut)
+            .unwrap()
+            .set(
+                "JavaScript",
+                dictionary! { "Names" => vec![Object::string_literal("init"), js_id.into()] },
+            );
+        let mut map = IndexMap::new();
+        map.insert(
+            "http://example.com/1.html".to_string(),
+            generate_pdf_with_link("http://example.com/2.html".to_string()),
+        );
+        map.insert("http://example.com/2.html".to_string(), page);
+        let back_matter = vec![
+            generate_pdf_with_attachment("data.csv"),
+            generate_pdf_with_attachment("data.csv"),
+        ];
+        let (parts, _) = merge_pdf_objects(None, map, back_matter).unwrap();
+        let pdf = build_pdf_from_objects(&parts, false).unwrap();
+
+        let files = get_name_tree(&pdf, b"EmbeddedFiles").unwrap();
+        assert_eq!(
+            files.keys().collect::<Vec<_>>(),
+            [
+                b"/2#data.csv".as_slice(),
+                b"data.csv".as_slice(),
+                b"data.csv (2)".as_slice()
+            ]
+        );
+        let scripts = get_name_tree(&pdf, b"JavaScript").unwrap();
+        let script = pdf
+            .get_dictionary(scripts[b"init".as_slice()].as_reference().unwrap())
+            .unwrap();
+        assert_eq!(script.get(b"JS").unwrap().as_str().unwrap(), b"init()");
+    }
+
+    #[test]
+    fn test_named_dests_flat() {
+        let mut doc = generate_pdf_with_link("http://example.com".to_string());
+        let page_id = doc.page_iter().next().unwrap();
+        let dests_id = doc.add_object(dictionary! {
+            "intro" => vec![page_id.into(), "Fit".into()],
+        });
+        let catalog_id = doc.trailer.get(b"Root").unwrap().as_reference().unwrap();
+        doc.get_dictionary_mut(catalog_id)
+            .unwrap()
+            .set("Dests", dests_id);
+
+        let dests = get_named_dests(&doc).unwrap();
+        assert_eq!(dests.keys().collect::<Vec<_>>(), vec![b"intro".as_slice()]);
+    }
+
+    #[test]
+    fn test_drop_dangling_dests() {
+        let mut doc = generate_pdf_with_link("http://example.com".to_string());
+        let page_id = doc.page_iter().next().unwrap();
+        let printed = doc.add_object(vec![page_id.into(), "Fit".into()]);
+        let dests_id = doc.add_object(dictionary! {
+            "intro" => vec![page_id.into(), "Fit".into()],
+            "summary" => printed,
+            // A heading on a page left out by page_ranges
+            "appendix" => vec![Object::Reference((9999, 0)), "Fit".into()],
+        });
+        let catalog_id = doc.trailer.get(b"Root").unwrap().as_reference().unwrap();
+        doc.get_dictionary_mut(catalog_id)
+            .unwrap()
+            .set("Dests", dests_id);
+
+        drop_dangling_dests(&mut doc).unwrap();
+        assert_eq!(
+            get_named_dests(&doc).unwrap().keys().collect::<Vec<_>>(),
+            vec![b"intro".as_slice(), b"summary".as_slice()]
+        );
+    }
+
+    #[test]
+    fn test_page_map_headings() {
+        let mut map = IndexMap::new();
+        map.insert(
+            "http://example.com/1.html".to_string(),
+            generate_pdf_with_outline(),
+        );
+        map.insert(
+            "http://example.com/2.html".to_string(),
+            generate_pdf_with_headings(),
+        );
+
+        let (parts, url_to_page_num) = merge_pdf_objects(None, map, vec![]).unwrap();
+        let mut pdf = build_pdf_from_objects(&parts, false).unwrap();
+        let page_map = build_page_map(&pdf, &url_to_page_num, None).unwrap();
+
+        let entry = page_map.get("http://example.com/2.html").unwrap();
+        assert_eq!(entry.page, 2);
+        assert_eq!(entry.headings.len(), 1);
+        let intro = &entry.headings[0];
+        assert_eq!(
+            (intro.title.as_str(), intro.level, intro.dest.as_deref()),
+            ("Intro", 1, Some("intro"))
+        );
+        let details = &intro.headings[0];
+        assert_eq!(
+            (
+                details.title.as_str(),
+                details.level,
+                details.dest.as_deref()
+            ),
+            ("Details", 2, Some("details"))
+        );
+
+        // Cross check against the destination stored in the saved document
+        let file = tempfile::NamedTempFile::new().unwrap();
+        pdf.save(file.path()).unwrap();
+        let saved = Document::load(file.path()).unwrap();
+        let dests = get_named_dests(&saved).unwrap();
+        let page_id = explicit_dest(&saved, &dests, dests.get(b"/2#details".as_slice()).unwrap())
+            .unwrap()[0]
+            .as_reference()
+            .unwrap();
+        let page_num = saved
+            .get_pages()
+            .into_iter()
+            .find(|(_, id)| *id == page_id)
+            .unwrap()
+            .0;
+        assert_eq!(details.page, page_num);
+    }
+
+    #[test]
+    fn test_namespaced_dests() {
+        // Both pages have an `intro` anchor, each links to the other's
+        let page = |link: &str| {
+            let mut doc = generate_pdf_with_link(link.to_string());
+            let page_id = doc.page_iter().next().unwrap();
+            let dests_id = doc.add_object(dictionary! {
+                "intro" => vec![page_id.into(), "Fit".into()],
+            });
+            let catalog_id = doc.trailer.get(b"Root").unwrap().as_reference().unwrap();
+            doc.get_dictionary_mut(catalog_id)
+                .unwrap()
+                .set("Dests", dests_id);
+            doc
+        };
+        let conf = Config {
+            url: "http://example.com".to_string(),
+            ..Default::default()
+        };
+        let mut map = IndexMap::new();
+        map.insert(
+            "http://example.com/1.html".to_string(),
+            page("http://example.com/2.html#intro"),
+        );
+        map.insert(
+            "http://example.com/guide/2.html".to_string(),
+            page("http://example.com/1#intro"),
+        );
+        map.insert(
+            "http://example.com/2.html".to_string(),
+            page("http://example.com/1.html#intro"),
+        );
+
+        let (parts, url_to_page_num) = merge_pdf_objects(None, map, vec![]).unwrap();
+        let mut pdf = build_pdf_from_objects(&parts, false).unwrap();
+        let dests = get_named_dests(&pdf).unwrap();
+        assert_eq!(
+            dests
+                .keys()
+                .map(|k| String::from_utf8_lossy(k))
+                .collect::<Vec<_>>(),
+            ["/1#intro", "/guide/2#intro", "/2#intro"]
+        );
+
+        let mut trace = vec![];
+        let (problem_urls, problem_anchors) =
+            rewrite_vitepress_links(&conf, &mut pdf, &url_to_page_num, &mut trace).unwrap();
+        assert!(problem_urls.is_empty());
+        assert!(problem_anchors.is_empty());
+        assert_eq!(
+            trace
+                .iter()
+                .map(|t| t.canonical.as_deref().unwrap())
+                .collect::<Vec<_>>(),
+            ["/2#intro", "/1#intro", "/1#intro"]
+        );
+
+        // Each link lands on the page it names, not the first page with the anchor
+        let pages = pdf.get_pages();
+        for (page_num, target) in [(1, 3), (2, 1), (3, 1)] {
+            let annotation = &pdf.get_page_annotations(pages[&page_num]).unwrap()[0];
+            let dest = annotation.get_deref(b"Dest", &pdf).unwrap();
+            assert_eq!(
+                dest.as_array().unwrap()[0].as_reference().unwrap(),
+                pages[&target]
+            );
+        }
+    }
+
+    #[test]
+    fn test_broken_links() {
+        let mut map = IndexMap::new();
+        map.insert(
+            "http://example.com/1.html".to_string(),
+            generate_pdf_with_link("http://example.com/2.html".to_string()),
+        );
+        map.insert(
+            "http://example.com/2.html".to_string(),
+            generate_pdf_with_link("http://example.com/guide/3.html#setup".to_string()),
+        );
+        let (parts, url_to_page_num) = merge_pdf_objects(None, map, vec![]).unwrap();
+
+        // The URI of the link on page 2, `None` without its annotation
+        let rewrite = |broken_links| {
+            let conf = Config {
+                url: "http://example.com".to_string(),
+                broken_links,
+                public_url: Some("https://docs.example.com".to_string()),
+                ..Default::default()
+            };
+            let mut pdf = build_pdf_from_objects(&parts, false).unwrap();
+            let (problem_urls, problem_anchors) =
+                rewrite_vitepress_links(&conf, &mut pdf, &url_to_page_num, &mut vec![]).unwrap();
+            // Reported whatever happens to the link
+            assert!(problem_urls.is_empty());
+            assert_eq!(problem_anchors.len(), 1);
+
+            let pages = pdf.get_pages();
+            assert!(pdf.get_page_annotations(pages[&1]).unwrap()[0]
+                .get(b"Dest")
+                .is_ok());
+            let annotations = pdf.get_page_annotations(pages[&2]).unwrap();
+            annotations.first().map(|a| {
+                a.get_deref(b"A", &pdf)
+                    .and_then(Object::as_dict)
+                    .and_then(|a| a.get(b"URI"))
+                    .and_then(Object::as_string)
+                    .unwrap()
+                    .to_string()
+            })
+        };
+
+        assert_eq!(
+            rewrite(BrokenLinks::Keep).as_deref(),
+            Some("http://example.com/guide/3.html#setup")
+        );
+        assert_eq!(rewrite(BrokenLinks::Remove), None);
+        assert_eq!(
+            rewrite(BrokenLinks::PointToSite).as_deref(),
+            Some("https://docs.example.com/guide/3.html#setup")
+        );
+    }
+
+    #[test]
+    fn test_rewrite_cross_page_anchors() {
+        let page = |link: &str, anchor: Option<&str>| {
+            let mut doc = generate_pdf_with_link(link.to_string());
+            if let Some(anchor) = anchor {
+                let page_id = doc.page_iter().next().unwrap();
+                let dests_id = doc.add_object(dictionary! {
+                    anchor => vec![page_id.into(), "Fit".into()],
+                });
+                let catalog_id = doc.trailer.get(b"Root").unwrap().as_reference().unwrap();
+                doc.get_dictionary_mut(catalog_id)
+                    .unwrap()
+                    .set("Dests", dests_id);
+            }
+            doc
+        };
+        let conf = Config {
+            url: "http://example.com".to_string(),
+            ..Default::default()
+        };
+        // Only page 1 has a `setup` anchor, page 3 links to a page left out
+        let mut map = IndexMap::new();
+        map.insert(
+            "http://example.com/1.html".to_string(),
+            page("http://example.com/2.html#setup", Some("setup")),
+        );
+        map.insert(
+            "http://example.com/2.html".to_string(),
+            page("http://example.com/1.html#setup", None),
+        );
+        map.insert(
+            "http://example.com/3.html".to_string(),
+            page("http://example.com/4.html#setup", None),
+        );
+
+        let (parts, url_to_page_num) = merge_pdf_objects(None, map, vec![]).unwrap();
+        let mut pdf = build_pdf_from_objects(&parts, false).unwrap();
+        let (problem_urls, problem_anchors) =
+            rewrite_vitepress_links(&conf, &mut pdf, &url_to_page_num, &mut vec![]).unwrap();
+
+        assert!(problem_urls.is_empty());
+        assert_eq!(
+            problem_anchors.iter().map(ToString::to_string).collect::<Vec<_>>(),
+            vec![
+                "Page No. 1: http://example.com/2.html#setup (no #setup on http://example.com/2.html)"
+                    .to_string(),
+                "Page No. 3: http://example.com/4.html#setup (http://example.com/4.html is not in the PDF)"
+                    .to_string(),
+            ]
+        );
+
+        let pages = pdf.get_pages();
+        let annotation = &pdf.get_page_annotations(pages[&2]).unwrap()[0];
+        let dest = annotation.get_deref(b"Dest", &pdf).unwrap();
+        assert_eq!(
+            dest.as_array().unwrap()[0].as_reference().unwrap(),
+            pages[&1]
+        );
+        let annotation = &pdf.get_page_annotations(pages[&1]).unwrap()[0];
+        assert!(annotation.get(b"Dest").is_err());
+    }
+
+    #[test]
+    fn test_non_ascii_headings() {
+        let mut doc = generate_pdf_with_headings();
+        let catalog_id = doc.trailer.get(b"Root").unwrap().as_reference().unwrap();
+        let catalog = doc.get_dictionary(catalog_id).unwrap();
+        let outlines_id = catalog.get(b"Outlines").unwrap().as_reference().unwrap();
+        let dests_id = catalog.get(b"Dests").unwrap().as_reference().unwrap();
+        let child = |doc: &Document, id| {
+            doc.get_dictionary(id)
+                .unwrap()
+                .get(b"First")
+                .unwrap()
+                .as_reference()
+                .unwrap()
+        };
+        let intro_id = child(&doc, outlines_id);
+        let details_id = child(&doc, intro_id);
+
+        // UTF-16 and BOM less UTF-8 titles
+        doc.get_dictionary_mut(intro_id)
+            .unwrap()
+            .set("Title", encode_text_string("はじめに 🚀"));
+        doc.get_dictionary_mut(details_id)
+            .unwrap()
+            .set("Title", Object::string_literal("詳細".as_bytes()));
+
+        // Move the destinations into a name tree with UTF-16 keys
+        let dests = doc.get_dictionary(dests_id).unwrap().clone();
+        let root = doc.add_object(dictionary! {
+            "Names" => vec![
+                encode_text_string("はじめに"),
+                dests.get(b"intro").unwrap().clone(),
+                encode_text_string("詳細"),
+                dests.get(b"details").unwrap().clone(),
+            ],
+        });
+        let catalog = doc.get_dictionary_mut(catalog_id).unwrap();
+        catalog.remove(b"Dests");
+        catalog.set("Names", dictionary! { "Dests" => root });
+
+        let mut map = IndexMap::new();
+        map.insert("http://example.com/1.html".to_string(), doc);
+        let (parts, url_to_page_num) = merge_pdf_objects(None, map, vec![]).unwrap();
+        let mut pdf = build_pdf_from_objects(&parts, false).unwrap();
+
+        let file = tempfile::NamedTempFile::new().unwrap();
+        pdf.save(file.path()).unwrap();
+        let saved = Document::load(file.path()).unwrap();
+        let page_map = build_page_map(&saved, &url_to_page_num, None).unwrap();
+
+        let intro = &page_map["http://example.com/1.html"].headings[0];
+        assert_eq!(
+            (intro.title.as_str(), intro.dest.as_deref()),
+            ("はじめに 🚀", Some("はじめに"))
+        );
+        let details = &intro.headings[0];
+        assert_eq!(
+            (details.title.as_str(), details.dest.as_deref()),
+            ("詳細", Some("詳細"))
+        );
+
+        // Titles are written as UTF-16BE with a BOM
+        let title = saved
+            .objects
+            .values()
+            .filter_map(|o| o.as_dict().ok())
+            .filter_map(|d| d.get(b"Title").and_then(Object::as_str).ok())
+            .find(|t| decode_text_string(t) == "詳細")
+            .unwrap();
+        assert!(title.starts_with(&[0xFE, 0xFF]));
+    }
+
+    #[test]
+    fn test_merge_toc() {
+        let mut map = IndexMap::new();
+        map.insert(
+            "http://example.com/1.html".to_string(),
+            generate_pdf_with_outline(),
+        );
+        map.insert(
+            "http://example.com/2.html".to_string(),
+            generate_pdf_with_outline(),
+        );
+        map.insert(
+            "http://example.com/3.html".to_string(),
+            generate_pdf_with_outline(),
+        );
+
+        let (parts, _) = merge_pdf_objects(None, map, vec![]).unwrap();
+
+        let pdf = build_pdf_from_objects(&parts, false).unwrap();
+
+        //let mut  pdf = generate_pdf_with_outline();
+        let cat = pdf.catalog().unwrap();
+        insta::assert_debug_snapshot!(outline(
+            &pdf,
+            pdf.get_object(cat.get(b"Outlines").unwrap().as_reference().unwrap())
+                .unwrap()
+                .as_dict()
+                .unwrap()
+        ));
+    }
+
+    #[test]
+    fn test_merge_without_types() {
+        let mut map = IndexMap::new();
+        for i in 1..=2 {
+            let mut doc = generate_pdf_with_link("http://example.com".to_string());
+            for object in doc.objects.values_mut() {
+                if let Ok(dict) = object.as_dict_mut() {
+                    dict.remove(b"Type");
+                }
+            }
+            map.insert(format!("http://example.com/{i}.html"), doc);
+        }
+
+        let (parts, url_to_page_num) = merge_pdf_objects(None, map, vec![]).unwrap();
+        let mut pdf = build_pdf_from_objects(&parts, false).unwrap();
+        assert_eq!(url_to_page_num["http://example.com/2.html"], 1);
+
+        let file = tempfile::NamedTempFile::new().unwrap();
+        pdf.save(file.path()).unwrap();
+        let saved = Document::load(file.path()).unwrap();
+        assert_eq!(saved.get_pages().len(), 2);
+        assert_eq!(saved.catalog().unwrap().type_name().unwrap(), "Catalog");
+    }
+
+    // Save `count` linked PDFs into `dir` and return the map `merge_pdfs` expects
+    fn save_pdfs(dir: &Path, count: usize) -> IndexMap<String, PathBuf> {
+        (1..=count)
+            .map(|i| {
+                let path = dir.join(format!("{i}.pdf"));
+                generate_pdf_with_link(format!("http://example.com/{}.html", i + 1))
+                    .save(&path)
+                    .unwrap();
+                (format!("http://example.com/{i}.html"), path)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_external_links() {
+        let mut conf = Config {
+            url: "http://example.com".to_string(),
+            skip_external_link_hosts: vec!["flaky.example.org".to_string()],
+            ..Default::default()
+        };
+        let mut map = IndexMap::new();
+        for (page, link) in [
+            ("1", "https://github.com/whamcloud"),
+            ("2", "http://example.com/1.html"),
+            ("3", "https://flaky.example.org/"),
+            ("4", "https://github.com/whamcloud"),
+        ] {
+            map.insert(
+                format!("http://example.com/{page}.html"),
+                generate_pdf_with_link(link.to_string()),
+            );
+        }
+
+        // Nothing is collected unless asked for
+        assert!(assemble(&conf, map.clone())
+            .unwrap()
+            .external_links
+            .is_empty());
+
+        conf.check_external_links = true;
+        let merged = assemble(&conf, map).unwrap();
+        assert_eq!(
+            merged.external_links,
+            IndexMap::from([("https://github.com/whamcloud".to_string(), vec![1, 4])])
+        );
+        // The annotations are left as they are
+        let pages = merged.pdf.get_pages();
+        let annotation = &merged.pdf.get_page_annotations(pages[&1]).unwrap()[0];
+        assert!(annotation.get(b"Dest").is_err());
+        assert!(annotation.get(b"A").is_ok());
+    }
+
+    #[test]
+    fn test_dedupe_resources() {
+        // Every page embeds the same font program, as Chrome does with web fonts
+        let page = |i: usize| {
+            let mut doc = generate_pdf_with_link(format!("http://example.com/{}.html", i + 1));
+            let mut seed = 1u32;
+            let program: Vec<u8> = (0..32 * 1024)
+                .map(|_| {
+                    seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                    (seed >> 16) as u8
+                })
+                .collect();
+            let file_id = doc.add_object(Stream::new(dictionary! {}, program));
+            let descriptor_id = doc.add_object(dictionary! {
+                "Type" => "FontDescriptor",
+                "FontName" => "Courier",
+                "FontFile" => file_id,
+            });
+            let font_id = doc
+                .objects
+                .iter()
+                .find(|(_, o)| {
+                    o.as_dict()
+                        .and_then(|d| d.get(b"Type"))
+                        .and_then(Object::as_name_str)
+                        .is_ok_and(|t| t == "Font")
+                })
+                .map(|(id, _)| *id)
+                .unwrap();
+            doc.get_dictionary_mut(font_id)
+                .unwrap()
+                .set("FontDescriptor", descriptor_id);
+            doc
+        };
+        let map: IndexMap<String, Document> = (1..=20)
+            .map(|i| (format!("http://example.com/{i}.html"), page(i)))
+            .collect();
+
+        let saved = |dedupe_resources| {
+            let conf = Config {
+                url: "http://example.com".to_string(),
+                dedupe_resources,
+                ..Default::default()
+            };
+            let merged = assemble(&conf, map.clone()).unwrap();
+            assert_eq!(merged.deduped_objects.is_some(), dedupe_resources);
+            let file = tempfile::NamedTempFile::new().unwrap();
+            let mut pdf = merged.pdf;
+            save_pdf(&mut pdf, file.path(), None).unwrap();
+            let size = fs::metadata(file.path()).unwrap().len();
+            let doc = Document::load(file.path()).unwrap();
+            let text = doc.extract_text(&(1..=20).collect::<Vec<u32>>()).unwrap();
+            (size, text, doc.get_pages().len())
+        };
+        let (size, text, pages) = saved(false);
+        let (deduped_size, deduped_text, deduped_pages) = saved(true);
+
+        // One font program instead of twenty
+        assert!(deduped_size * 10 < size, "{deduped_size} vs {size}");
+        assert_eq!(deduped_text, text);
+        assert_eq!(deduped_pages, pages);
+    }
+
+    #[test]
+    fn test_encrypted_output() {
+        let dir = tempfile::tempdir().unwrap();
+        let encryption = Encryption {
+            user_password: "reader".to_string(),
+            owner_password: "owner".to_string(),
+            allow_print: true,
+            allow_copy: false,
+            allow_modify: false,
+        };
+        let mut pdf = generate_pdf_with_link("http://example.com/".to_string());
+        let path = dir.path().join("encrypted.pdf");
+        save_pdf(&mut pdf, &path, Some(&encryption)).unwrap();
+        // The fixture is PDF 1.5, AESV2 needs 1.6
+        assert!(fs::read(&path).unwrap().starts_with(b"%PDF-1.6"));
+
+        // Merging it again fails instead of producing garbage
+        assert!(load_pdf(None, &path, false).is_err());
+    }
+
+    #[test]
+    fn test_linearize() {
+        let dir = tempfile::tempdir().unwrap();
+        let map: IndexMap<String, Document> = (1..=3)
+            .map(|i| {
+                (
+                    format!("http://example.com/{i}.html"),
+                    generate_pdf_with_link(format!("http://example.com/{}.html", i % 3 + 1)),
+                )
+            })
+            .collect();
+        let conf = Config {
+            url: "http://example.com".to_string(),
+            linearize: true,
+            ..Default::default()
+        };
+        let mut pdf = assemble(&conf, map).unwrap().pdf;
+        let path = dir.path().join("merged.pdf");
+        save_pdf(&mut pdf, &path, None).unwrap();
+
+        // The catalog, page tree root and page 1 with its content, font and link come first
+        let saved = Document::load(&path).unwrap();
+        let pages = saved.get_pages();
+        assert_eq!(
+            saved.trailer.get(b"Root").unwrap().as_reference().unwrap(),
+            (1, 0)
+        );
+        assert_eq!(pages[&1], (3, 0));
+        let first_page_objects = |page_id| {
+            let page = saved.get_dictionary(page_id).unwrap();
+            let mut ids = vec![page_id];
+            collect_page_references(page.get(b"Contents").unwrap(), &mut ids);
+            collect_page_references(page.get(b"Resources").unwrap(), &mut ids);
+            collect_page_references(page.get(b"Annots").unwrap(), &mut ids);
+            ids
+        };
+        let last = first_page_objects(pages[&1]).into_iter().max().unwrap();
+        assert!(first_page_objects(pages[&2]).iter().all(|id| *id > last));
+
+        // Links still point at the right pages
+        let annotation = &saved.get_page_annotations(pages[&1]).unwrap()[0];
+        let dest = annotation.get_deref(b"Dest", &saved).unwrap();
+        assert_eq!(
+            dest.as_array().unwrap()[0].as_reference().unwrap(),
+            pages[&2]
+        );
+
+        // qpdf isn't always installed
+        if let Ok(output) = std::process::Command::new("qpdf")
+            .arg("--check")
+            .arg(&path)
+            .output()
+        {
+            assert!(
+                output.status.success(),
+                "{}",
+                String::from_utf8_lossy(&output.stdout)
+            );
+        }
+    }
+
+    #[test]
+    fn test_strict_links() {
+        let dir = tempfile::tempdir().unwrap();
+        // The last page links to a page which wasn't rendered
+        let map = save_pdfs(dir.path(), 2);
+        let mut conf = Config {
+            url: "http://example.com".to_string(),
+            output_pdf: dir.path().join("merged.pdf"),
+            ..Default::default()
+        };
+
+        // Written anyway, the exit code reports the problem
+        assert_eq!(merge_pdfs(&conf, map.clone()).unwrap(), ExitCode::FAILURE);
+        assert!(conf.output_pdf.exists());
+        fs::remove_file(&conf.output_pdf).unwrap();
+
+        conf.strict_links = true;
+        let err = merge_pdfs(&conf, map).unwrap_err().to_string();
+        assert!(err.starts_with(
+            "Unable to remap these URLS.\n  * Page No. 2: http://example.com/3.html\n"
+        ));
+        assert!(err.ends_with("because strict_links is set"));
+        assert!(!conf.output_pdf.exists());
+    }
+
+    #[test]
+    fn test_report() {
+        let dir = tempfile::tempdir().unwrap();
+        let map = save_pdfs(dir.path(), 2);
+        let mut conf = Config {
+            url: "http://example.com".to_string(),
+            output_pdf: dir.path().join("merged.pdf"),
+            report: Some(dir.path().join("report.json")),
+            ..Default::default()
+        };
+
+        assert_eq!(merge_pdfs(&conf, map.clone()).unwrap(), ExitCode::FAILURE);
+        let json = fs::read_to_string(dir.path().join("report.json")).unwrap();
+        let report: MergeReport = serde_json::from_str(&json).unwrap();
+        let problem = LinkProblem {
+            page: 2,
+            target: "http://example.com/3.html".to_string(),
+            source_url: Some("http://example.com/2.html".to_string()),
+            reason: None,
+        };
+        assert_eq!(
+            report,
+            MergeReport {
+                problem_urls: vec![problem.clone()],
+                problem_anchors: vec![],
+                counts: ReportCounts {
+                    problem_urls: 1,
+                    problem_anchors: 0,
+                    dead_links: 0,
+                },
+                pages: 2,
+                output_size: Some(fs::metadata(&conf.output_pdf).unwrap().len()),
+            }
+        );
+
+        // The field names are stable
+        assert_eq!(
+            serde_json::to_value(&report).unwrap(),
+            serde_json::json!({
+                "problem_urls": [{
+                    "page": 2,
+                    "target": "http://example.com/3.html",
+                    "source_url": "http://example.com/2.html",
+                    "reason": null,
+                }],
+                "problem_anchors": [],
+                "counts": {"problem_urls": 1, "problem_anchors": 0, "dead_links": 0},
+                "pages": 2,
+                "output_size": report.output_size,
+            })
+        );
+        let round_trip: MergeReport =
+            serde_json::from_str(&serde_json::to_string(&report).unwrap()).unwrap();
+        assert_eq!(round_trip, report);
+
+        // Written without an output size when strict_links fails the merge
+        fs::remove_file(&conf.output_pdf).unwrap();
+        conf.strict_links = true;
+        assert!(merge_pdfs(&conf, map.clone()).is_err());
+        let json = fs::read_to_string(dir.path().join("report.json")).unwrap();
+        let report: MergeReport = serde_json::from_str(&json).unwrap();
+        assert_eq!(report.problem_urls, vec![problem.clone()]);
+        assert_eq!(report.output_size, None);
+
+        // Pages are numbered in the merged PDF, after the table of contents
+        conf.strict_links = false;
+        conf.page_bookmarks = true;
+        conf.toc = Some(toml::from_str("").unwrap());
+        assert_eq!(merge_pdfs(&conf, map).unwrap(), ExitCode::FAILURE);
+        let json = fs::read_to_string(dir.path().join("report.json")).unwrap();
+        let report: MergeReport = serde_json::from_str(&json).unwrap();
+        assert_eq!(report.pages, 3);
+        assert_eq!(
+            report.problem_urls,
+            vec![LinkProblem { page: 3, ..problem }]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_merge_pdfs_async() {
+        let dir = tempfile::tempdir().unwrap();
+        let conf = Arc::new(Config {
+            url: "http://example.com".to_string(),
+            output_pdf: dir.path().join("out/merged.pdf"),
+            ..Default::default()
+        });
+        let map = save_pdfs(dir.path(), LOAD_CHUNK_SIZE + 1);
+
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+        let err = merge_pdfs_async(conf.clone(), map.clone(), cancel)
+            .await
+            .unwrap_err();
+        assert_eq!(err.downcast_ref(), Some(&MergeError::Cancelled));
+        assert!(!conf.output_pdf.exists());
+
+        merge_pdfs_async(conf.clone(), map, CancellationToken::new())
+            .await
+            .unwrap();
+        assert_eq!(
+            Document::load(&conf.output_pdf).unwrap().get_pages().len(),
+            LOAD_CHUNK_SIZE + 1
+        );
+        assert!(!conf.output_pdf.with_extension("pdf.partial").exists());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_merge_cancelled_while_saving() {
+        use std::{io::Read, os::unix::ffi::OsStrExt};
+
+        let dir = tempfile::tempdir().unwrap();
+        let conf = Arc::new(Config {
+            url: "http://example.com".to_string(),
+            output_pdf: dir.path().join("merged.pdf"),
+            ..Default::default()
+        });
+        let map = save_pdfs(dir.path(), 2);
+
+        // Saving blocks opening the FIFO until it is read, so the token is cancelled after
+        // the pages were merged and while the output is written
+        let partial = conf.output_pdf.with_extension("pdf.partial");
+        let fifo = std::ffi::CString::new(partial.as_os_str().as_bytes()).unwrap();
+        // SAFETY: fifo is a valid NUL terminated path
+        assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o600) }, 0);
+        let cancel = CancellationToken::new();
+        let reader = tokio::task::spawn_blocking({
+            let (partial, cancel) = (partial.clone(), cancel.clone());
+            move || {
+                let mut file = fs::File::open(partial).unwrap();
+                cancel.cancel();
+                let mut pdf = vec![];
+                file.read_to_end(&mut pdf).unwrap();
+                pdf
+            }
+        });
+
+        let err = merge_pdfs_async(conf.clone(), map, cancel)
+            .await
+            .unwrap_err();
+        assert_eq!(err.downcast_ref(), Some(&MergeError::Cancelled));
+        assert!(reader.await.unwrap().starts_with(b"%PDF-"));
+        assert!(!partial.exists());
+        assert!(!conf.output_pdf.exists());
+    }
+
+    #[test]
+    fn test_skip_streams() {
+        let conf = Config {
+            url: "http://example.com".to_string(),
+            ..Default::default()
+        };
+        let merge = |skip_streams| {
+            let mut map = IndexMap::new();
+            map.insert(
+                "http://example.com/1.html".to_string(),
+                generate_pdf_with_link("http://example.com/3.html".to_string()),
+            );
+            map.insert(
+                "http://example.com/2.html".to_string(),
+                generate_pdf_with_link("http://example.com/1.html".to_string()),
+            );
+            let (parts, url_to_page_num) = merge_pdf_objects(None, map, vec![]).unwrap();
+            let mut pdf = build_pdf_from_objects(&parts, skip_streams).unwrap();
+            let problems =
+                rewrite_vitepress_links(&conf, &mut pdf, &url_to_page_num, &mut vec![]).unwrap();
+            (pdf, problems)
+        };
+
+        let (full, full_problems) = merge(false);
+        let (lean, lean_problems) = merge(true);
+        assert_eq!(full_problems, lean_problems);
+        assert_eq!(full.get_pages().len(), lean.get_pages().len());
+        assert!(lean
+            .objects
+            .values()
+            .filter_map(|o| o.as_stream().ok())
+            .all(|s| s.content.is_empty()));
+
+        // Nor are they loaded
+        let file = tempfile::NamedTempFile::new().unwrap();
+        generate_pdf_with_link("http://example.com".to_string())
+            .save(file.path())
+            .unwrap();
+        let full = parse_pdf(file.path(), false).unwrap();
+        let lean = parse_pdf(file.path(), true).unwrap();
+        assert_eq!(full.get_pages().len(), lean.get_pages().len());
+        assert!(full.objects.values().any(|o| o.as_stream().is_ok()));
+        assert!(lean
+            .objects
+            .values()
+            .filter_map(|o| o.as_stream().ok())
+            .all(|s| s.content.is_empty()));
+    }
+
+    #[test]
+    fn test_rewrite_export_query_urls() {
+        let conf = Config {
+            url: "http://example.com".to_string(),
+            export_query: Some("pdf-export=1".to_string()),
+            ..Default::default()
+        };
+        let mut map = IndexMap::new();
+        map.insert(
+            "http://example.com/1.html".to_string(),
+            generate_pdf_with_link("http://example.com/2.html?pdf-export=1".to_string()),
+        );
+        map.insert(
+            "http://example.com/2.html".to_string(),
+            generate_pdf_with_link("http://example.com/1.html".to_string()),
+        );
+        let (parts, url_to_page_num) = merge_pdf_objects(None, map, vec![]).unwrap();
+        let mut pdf = build_pdf_from_objects(&parts, false).unwrap();
+
+        let mut trace = vec![];
+        let (problem_urls, _problem_anchors) =
+            rewrite_vitepress_links(&conf, &mut pdf, &url_to_page_num, &mut trace).unwrap();
+        assert!(problem_urls.is_empty(), "{problem_urls:?}");
+        assert!(trace
+            .iter()
+            .all(|t| matches!(t.action, LinkAction::RewrittenToPage)));
+    }
+
+    // This tests re-writing URLs to PDF Destinations
+    // 1. We generate 3 pdfs each of which have a link to the next
+    // 2. We merged the pdfs together.
+    // 3. We assert that the 3rd pdf's was not remapped.
+    // 4. We go through the pages and ensure the Annotation points to the next page
+    #[test]
+    fn test_rewrite_urls() {
+        let conf = Config {
+            url: "http://example.com".to_string(),
+            ..Default::default()
+        };
+        let (parts, url_to_page_num) = merge_pdf_objects(None, linked_pdfs(), vec![]).unwrap();
+
+        let mut pdf = build_pdf_from_objects(&parts, false).unwrap();
+
+        let (problem_urls, _problem_anchors) =
+            rewrite_vitepress_links(&conf, &mut pdf, &url_to_page_num, &mut vec![]).unwrap();
+
+        assert_eq!(
+            problem_urls
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>(),
+            vec!["Page No. 3: http://example.com/4.html".to_string()]
+        );
+
+        let page_num_to_id = pdf.get_pages();
+        for (page_num, page_id) in pdf.page_iter().enumerate() {
+            // Only the first two pages are remapped
+            if page_num == page_num_to_id.len() - 1 {
+                break;
+            }
+
+            let annotations = pdf.get_page_annotations(page_id).unwrap();
+
+            // Assert that there's no stray annotations
+            assert_eq!(annotations.len(), 1);
+
+            let annotation = annotations.first().unwrap();
+
+            let dest = annotation.get_deref(b"Dest", &pdf).unwrap();
+
+            let next_page_id = page_num_to_id.get(&(page_num as u32 + 2)).unwrap(); // +2 because page_num is 0 index and get_pages is 1 indexed.
+
+            assert_eq!(
+                *next_page_id,
+                dest.as_array()
+                    .unwrap()
+                    .first()
+                    .unwrap()
+                    .as_reference()
+                    .unwrap()
+            );
+        }
+    }
+
+    // Three pages each linking to the next, the last one to a page which wasn't rendered
+    fn linked_pdfs() -> IndexMap<String, Document> {
+        (1..=3)
+            .map(|i| {
+                (
+                    format!("http://example.com/{i}.html"),
+                    generate_pdf_with_link(format!("http://example.com/{}.html", i + 1)),
+                )
+            })
+            .collect()
+    }
+
+    // Links to excluded or opted out pages are reported with the reason and traced
+    #[test]
+    fn test_rewrite_urls_skipped_pages() {
+        let (parts, url_to_page_num) = merge_pdf_objects(None, linked_pdfs(), vec![]).unwrap();
+        let skipped = ["http://example.com/4.html".to_string()];
+        for (conf, reason) in [
+            (
+                Config {
+                    url: "http://example.com".to_string(),
+                    excluded: skipped.clone().into(),
+                    ..Default::default()
+                },
+                "excluded",
+            ),
+            (
+                Config {
+                    url: "http://example.com".to_string(),
+                    opted_out: skipped.clone().into(),
+                    ..Default::default()
+                },
+                "opted out",
+            ),
+        ] {
+            let mut pdf = build_pdf_from_objects(&parts, false).unwrap();
+            let mut trace = vec![];
+            let (problem_urls, _problem_anchors) =
+                rewrite_vitepress_links(&conf, &mut pdf, &url_to_page_num, &mut trace).unwrap();
+            assert_eq!(
+                problem_urls
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>(),
+                vec![format!("Page No. 3: http://example.com/4.html ({reason})")]
+            );
+            assert_eq!(
+                trace
+                    .iter()
+                    .map(|t| (t.page, t.source_url.as_deref().unwrap(), &t.action))
+                    .collect::<Vec<_>>(),
+                vec![
+                    (1, "http://example.com/1.html", &LinkAction::RewrittenToPage),
+                    (2, "http://example.com/2.html", &LinkAction::RewrittenToPage),
+                    (3, "http://example.com/3.html", &LinkAction::Problem),
+                ]
+            );
+            assert_eq!(trace[2].table, Some(LinkTable::Urls));
+        }
+        assert_eq!(truncate(&"a".repeat(600)).len(), TRACE_MAX_LEN + 3);
+    }
+
+    #[test]
+    fn test_cover_pdf() {
+        let cover = tempfile::NamedTempFile::new().unwrap();
+        generate_cover().save(cover.path()).unwrap();
+        let conf = Config {
+            url: "http://example.com".to_string(),
+            cover_pdf: Some(cover.path().to_path_buf()),
+            page_number: Some(page_number("Page {page}")),
+            ..Default::default()
+        };
+        let mut map = IndexMap::new();
+        map.insert(
+            "http://example.com/1.html".to_string(),
+            generate_pdf_with_link("http://example.com/2.html".to_string()),
+        );
+        map.insert(
+            "http://example.com/2.html".to_string(),
+            generate_pdf_with_headings(),
         );
+        let merged = assemble(&conf, map).unwrap();
+        assert!(merged.problem_urls.is_empty());
+        let pdf = merged.pdf;
 
-        doc.objects.insert(
-            node_2,
-            Object::Dictionary(dictionary! {
-                "Title" => Object::string_literal("Node 2"),
-                "Parent" => node_1,
-                "Next" => node_3,
-                "Count" => 1,
-            }),
+        let pages = pdf.get_pages();
+        assert_eq!(pages.len(), 4);
+        for page_num in [1, 2] {
+            let cover_page = pdf.get_dictionary(pages[&page_num]).unwrap();
+            assert!(cover_page.has(b"MediaBox"));
+            assert!(cover_page.has(b"Resources"));
+            let content = pdf.get_page_content(pages[&page_num]).unwrap();
+            assert!(!String::from_utf8_lossy(&content).contains("Page"));
+        }
+        let content = pdf.get_page_content(pages[&3]).unwrap();
+        assert!(String::from_utf8_lossy(&content).contains("(Page 1)"));
+
+        // The link to the second URL still lands on its first page
+        let annotations = pdf.get_page_annotations(pages[&3]).unwrap();
+        let dest = annotations[0].get_deref(b"Dest", &pdf).unwrap();
+        assert_eq!(
+            dest.as_array().unwrap()[0].as_reference().unwrap(),
+            pages[&4]
         );
 
-        doc.objects.insert(
-            node_3,
-            Object::Dictionary(dictionary! {
-                "Title" => Object::string_literal("Node 3"),
-                "Parent" => node_1,
-                "Prev" => node_2,
-                "Count" => 1,
-            }),
+        // Only the rendered pages' bookmarks are kept, and they still resolve
+        let headings = document_headings(&pdf).unwrap();
+        assert_eq!(headings.len(), 1);
+        assert_eq!((headings[0].title.as_str(), headings[0].page), ("Intro", 4));
+    }
+
+    #[test]
+    fn test_cover_url() {
+        let conf = Config {
+            url: "http://example.com".to_string(),
+            cover_url: Some("http://example.com/pdf-cover.html".to_string()),
+            ..Default::default()
+        };
+        let mut map = IndexMap::new();
+        map.insert(
+            "http://example.com/pdf-cover.html".to_string(),
+            generate_pdf_with_headings(),
+        );
+        map.insert(
+            "http://example.com/1.html".to_string(),
+            generate_pdf_with_link("http://example.com/1.html".to_string()),
         );
+        let merged = assemble(&conf, map).unwrap();
+        assert!(merged.problem_urls.is_empty());
+        let pdf = merged.pdf;
 
-        let page_id = doc.add_object(dictionary! {
-            "Type" => "Page",
-            "Parent" => pages_id,
-            "Contents" => content_id,
-            "Resources" => resources_id,
-            "MediaBox" => vec![0.into(), 0.into(), 595.into(), 842.into()],
+        let pages = pdf.get_pages();
+        assert_eq!(pages.len(), 2);
+        let annotations = pdf.get_page_annotations(pages[&2]).unwrap();
+        let dest = annotations[0].get_deref(b"Dest", &pdf).unwrap();
+        assert_eq!(
+            dest.as_array().unwrap()[0].as_reference().unwrap(),
+            pages[&2]
+        );
+        // The cover's headings aren't bookmarked
+        assert!(document_headings(&pdf).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_back_matter() {
+        // Pages listed in the opposite order to their object IDs, the first linking to the second
+        let mut doc = generate_cover();
+        let pages: Vec<ObjectId> = doc.get_pages().into_values().collect();
+        let node_id = doc
+            .get_dictionary(pages[0])
+            .unwrap()
+            .get(b"Parent")
+            .unwrap()
+            .as_reference()
+            .unwrap();
+        doc.get_dictionary_mut(node_id)
+            .unwrap()
+            .set("Kids", vec![pages[1].into(), pages[0].into()]);
+        let annot_id = doc.add_object(dictionary! {
+            "Type" => "Annot",
+            "Subtype" => "Link",
+            "Rect" => vec![0.into(), 0.into(), 595.into(), 842.into()],
+            "Dest" => vec![pages[0].into(), "Fit".into()],
         });
+        doc.get_dictionary_mut(pages[1])
+            .unwrap()
+            .set("Annots", vec![annot_id.into()]);
+        let legal = tempfile::NamedTempFile::new().unwrap();
+        doc.save(legal.path()).unwrap();
 
-        let pages = dictionary! {
-            "Type" => "Pages",
-            "Kids" => vec![page_id.into()],
-            "Count" => 1,
+        let conf = Config {
+            url: "http://example.com".to_string(),
+            back_matter: vec![crate::config::BackMatter {
+                path: legal.path().to_path_buf(),
+                title: Some("Legal".to_string()),
+                bookmark: true,
+            }],
+            number_back_matter: false,
+            page_number: Some(page_number("Page {page}")),
+            ..Default::default()
         };
-        doc.objects.insert(pages_id, Object::Dictionary(pages));
-        let catalog_id = doc.add_object(dictionary! {
-            "Type" => "Catalog",
-            "Pages" => pages_id,
-            "Outlines" => outline_dict,
-        });
-        doc.trailer.set("Root", catalog_id);
+        let mut map = IndexMap::new();
+        map.insert(
+            "http://example.com/1.html".to_string(),
+            generate_pdf_with_link("http://example.com/1.html".to_string()),
+        );
+        let pdf = assemble(&conf, map).unwrap().pdf;
 
-        doc
+        let pages = pdf.get_pages();
+        assert_eq!(pages.len(), 3);
+        let content = pdf.get_page_content(pages[&1]).unwrap();
+        assert!(String::from_utf8_lossy(&content).contains("(Page 1)"));
+        for page_num in [2, 3] {
+            let content = pdf.get_page_content(pages[&page_num]).unwrap();
+            assert!(!String::from_utf8_lossy(&content).contains("Page"));
+        }
+
+        // The internal link still points at the back matter's second page
+        let annotations = pdf.get_page_annotations(pages[&2]).unwrap();
+        let dest = annotations[0].get_deref(b"Dest", &pdf).unwrap();
+        assert_eq!(
+            dest.as_array().unwrap()[0].as_reference().unwrap(),
+            pages[&3]
+        );
+
+        // Its own outline is replaced by a single bookmark
+        let headings = document_headings(&pdf).unwrap();
+        assert_eq!(headings.len(), 1);
+        assert_eq!((headings[0].title.as_str(), headings[0].page), ("Legal", 2));
+
+        let conf = Config {
+            back_matter: vec![crate::config::BackMatter {
+                path: PathBuf::from("missing.pdf"),
+                title: None,
+                bookmark: true,
+            }],
+            ..Default::default()
+        };
+        let err = assemble(&conf, IndexMap::new()).err().unwrap();
+        assert!(err.to_string().contains("missing.pdf"));
     }
 
     #[test]
-    fn test_merge_toc() {
+    fn test_toc() {
         let mut map = IndexMap::new();
         map.insert(
             "http://example.com/1.html".to_string(),
-            generate_pdf_with_outline(),
+            generate_pdf_with_link("http://example.com/2.html".to_string()),
         );
         map.insert(
             "http://example.com/2.html".to_string(),
-            generate_pdf_with_outline(),
-        );
-        map.insert(
-            "http://example.com/3.html".to_string(),
-            generate_pdf_with_outline(),
+            generate_pdf_with_headings(),
         );
+        let page_map = tempfile::NamedTempFile::new().unwrap();
+        let conf = Config {
+            toc: Some(toml::from_str("depth = 1").unwrap()),
+            page_map: Some(page_map.path().to_path_buf()),
+            ..Default::default()
+        };
+        let pdf = assemble(&conf, map).unwrap().pdf;
 
-        let (parts, _) = merge_pdf_objects(map).unwrap();
+        let pages = pdf.get_pages();
+        assert_eq!(pages.len(), 3);
+        let toc_page = pdf.get_dictionary(pages[&1]).unwrap();
+        let annots = toc_page.get(b"Annots").unwrap().as_array().unwrap();
+        assert_eq!(annots.len(), 1);
+        let link = pdf
+            .get_dictionary(annots[0].as_reference().unwrap())
+            .unwrap();
+        let dest = link.get(b"Dest").unwrap().as_array().unwrap();
+        assert_eq!(dest[0].as_reference().unwrap(), pages[&3]);
 
-        let pdf = build_pdf_from_objects(&parts).unwrap();
+        let content =
+            String::from_utf8_lossy(&pdf.get_page_content(pages[&1]).unwrap()).to_string();
+        assert!(content.contains("(Contents)"));
+        assert!(content.contains("(Intro)"));
+        assert!(content.contains("(3)"));
+        // Below the configured depth
+        assert!(!content.contains("Details"));
 
-        //let mut  pdf = generate_pdf_with_outline();
-        let cat = pdf.catalog().unwrap();
-        insta::assert_debug_snapshot!(outline(
-            &pdf,
-            pdf.get_object(cat.get(b"Outlines").unwrap().as_reference().unwrap())
-                .unwrap()
-                .as_dict()
-                .unwrap()
-        ));
+        let page_map: serde_json::Value =
+            serde_json::from_slice(&fs::read(page_map.path()).unwrap()).unwrap();
+        assert_eq!(page_map["http://example.com/1.html"]["page"], 2);
+        assert_eq!(page_map["http://example.com/2.html"]["page"], 3);
+    }
+
+    // Black Helvetica page numbers printed with `format`
+    fn page_number(format: &str) -> crate::config::PageNumber {
+        toml::from_str(&format!(
+            "color = {{ r = 0.0, g = 0.0, b = 0.0 }}\nfont = 'Helvetica'\nsize = 12\nx = 1.0\ny = 1.0\nformat = {format:?}"
+        ))
+        .unwrap()
+    }
+
+    // The last string shown on each page, which is its page number
+    fn printed_page_numbers(pdf: &Document) -> Vec<Vec<u8>> {
+        pdf.page_iter()
+            .map(|page_id| {
+                let content = Content::decode(&pdf.get_page_content(page_id).unwrap()).unwrap();
+                content
+                    .operations
+                    .iter()
+                    .filter(|op| op.operator == "Tj")
+                    .last()
+                    .unwrap()
+                    .operands[0]
+                    .as_str()
+                    .unwrap()
+                    .to_vec()
+            })
+            .collect()
+    }
+
+    // Check the font of the last text shown on each page is in the page's resources
+    fn assert_fonts_registered(pdf: &Document) {
+        for page_id in pdf.page_iter() {
+            let content = Content::decode(&pdf.get_page_content(page_id).unwrap()).unwrap();
+            let tf = content
+                .operations
+                .iter()
+                .filter(|op| op.operator == "Tf")
+                .last()
+                .unwrap();
+            let name = tf.operands[0].as_name().unwrap();
+            let resources = own_resources(pdf, page_id);
+            let fonts = resources.get(b"Font").unwrap().as_dict().unwrap();
+            assert!(fonts.has(name));
+        }
     }
 
-    // This tests re-writing URLs to PDF Destinations
-    // 1. We generate 3 pdfs each of which have a link to the next
-    // 2. We merged the pdfs together.
-    // 3. We assert that the 3rd pdf's was not remapped.
-    // 4. We go through the pages and ensure the Annotation points to the next page
     #[test]
-    fn test_rewrite_urls() {
+    fn test_page_number_format() {
         let conf = Config {
-            chrome_cache: PathBuf::new(),
-            chrome_version: None,
-            output_pdf: PathBuf::new(),
-            url: "http://example.com".to_string(),
-            urls: IndexSet::new(),
-            vitepress_links: Vec::new(),
-            page_number: None,
-            print_to_pdf: PrintToPdfOptions::default(),
+            page_number: Some(page_number("{page} / {total}")),
+            ..Default::default()
         };
         let mut map = IndexMap::new();
-        map.insert(
-            "http://example.com/1.html".to_string(),
-            generate_pdf_with_link("http://example.com/2.html".to_string()),
+        for i in 1..=2 {
+            map.insert(
+                format!("http://example.com/{i}.html"),
+                generate_pdf_with_link("http://example.com".to_string()),
+            );
+        }
+        let pdf = assemble(&conf, map).unwrap().pdf;
+        assert_eq!(
+            printed_page_numbers(&pdf),
+            vec![b"1 / 2".to_vec(), b"2 / 2".to_vec()]
         );
-        map.insert(
-            "http://example.com/2.html".to_string(),
-            generate_pdf_with_link("http://example.com/3.html".to_string()),
+        assert_fonts_registered(&pdf);
+    }
+
+    // One page PDF per MediaBox
+    fn pages_with_media_boxes(media_boxes: &[[i64; 4]]) -> IndexMap<String, Document> {
+        media_boxes
+            .iter()
+            .enumerate()
+            .map(|(i, media_box)| {
+                let mut doc = generate_pdf_with_link("http://example.com".to_string());
+                let page_id = doc.page_iter().next().unwrap();
+                doc.get_dictionary_mut(page_id).unwrap().set(
+                    "MediaBox",
+                    media_box
+                        .iter()
+                        .map(|v| Object::from(*v))
+                        .collect::<Vec<_>>(),
+                );
+                (format!("http://example.com/{i}.html"), doc)
+            })
+            .collect()
+    }
+
+    // Translation of the page number's text matrix on each page
+    fn page_number_positions(pdf: &Document) -> Vec<(f64, f64)> {
+        pdf.page_iter()
+            .map(|page_id| {
+                let content = Content::decode(&pdf.get_page_content(page_id).unwrap()).unwrap();
+                let tm = content
+                    .operations
+                    .iter()
+                    .filter(|op| op.operator == "Tm")
+                    .last()
+                    .unwrap();
+                (
+                    tm.operands[4].as_float().unwrap() as f64,
+                    tm.operands[5].as_float().unwrap() as f64,
+                )
+            })
+            .collect()
+    }
+
+    fn assert_near(actual: Vec<f64>, expected: &[f64]) {
+        assert_eq!(actual.len(), expected.len());
+        for (a, e) in actual.iter().zip(expected) {
+            assert!((a - e).abs() < 0.01, "{actual:?} != {expected:?}");
+        }
+    }
+
+    #[test]
+    fn test_page_number_align() {
+        // An A4 page followed by a US Letter one
+        let map = pages_with_media_boxes(&[[0, 0, 595, 842], [0, 0, 612, 792]]);
+        let tm_x = |align: Align, x: f64| -> Vec<f64> {
+            let mut style = page_number("{page}");
+            style.align = align;
+            style.x = x;
+            let conf = Config {
+                page_number: Some(style),
+                ..Default::default()
+            };
+            let pdf = assemble(&conf, map.clone()).unwrap().pdf;
+            page_number_positions(&pdf)
+                .into_iter()
+                .map(|(x, _)| x)
+                .collect()
+        };
+
+        // "1" in 12 unit Helvetica is 6.672 wide, A4 is 2479.167 dots wide and Letter 2550
+        assert_near(tm_x(Align::Left, 1.0), &[300.0, 300.0]);
+        assert_near(tm_x(Align::Center, 0.0), &[1236.247, 1271.664]);
+        assert_near(tm_x(Align::Center, -0.5), &[1086.247, 1121.664]);
+        assert_near(tm_x(Align::Right, 0.5), &[2322.495, 2393.328]);
+    }
+
+    #[test]
+    fn test_page_number_y_from() {
+        // A4, US Letter and US Letter with its origin moved up
+        let map = pages_with_media_boxes(&[[0, 0, 595, 842], [0, 0, 612, 792], [0, 100, 612, 892]]);
+        let tm_y = |y_from: YFrom| -> Vec<f64> {
+            let mut style = page_number("{page}");
+            style.y = 0.5;
+            style.y_from = y_from;
+            let conf = Config {
+                page_number: Some(style),
+                ..Default::default()
+            };
+            let pdf = assemble(&conf, map.clone()).unwrap().pdf;
+            page_number_positions(&pdf)
+                .into_iter()
+                .map(|(_, y)| y)
+                .collect()
+        };
+
+        // Text space runs up from the bottom left of the MediaBox, A4 is 3508.333 dots tall
+        // and Letter 3300
+        let heights = [3508.333, 3300.0, 3300.0];
+        let margins: Vec<f64> = tm_y(YFrom::Top)
+            .iter()
+            .zip(heights)
+            .map(|(y, height)| height - y)
+            .collect();
+        assert_near(margins, &[150.0, 150.0, 150.0]);
+        assert_near(tm_y(YFrom::Bottom), &[150.0, 150.0, 150.0]);
+
+        // Drawn after the page content is restored, scaled to dots from the MediaBox origin
+        let mut style = page_number("{page}");
+        style.y = 0.5;
+        let conf = Config {
+            page_number: Some(style),
+            ..Default::default()
+        };
+        let pdf = assemble(&conf, map.clone()).unwrap().pdf;
+        let page_id = pdf.page_iter().nth(2).unwrap();
+        let ops = Content::decode(&pdf.get_page_content(page_id).unwrap())
+            .unwrap()
+            .operations;
+        assert_eq!(ops[0].operator, "q");
+        let cm: Vec<f64> = ops
+            .iter()
+            .filter(|op| op.operator == "cm")
+            .last()
+            .unwrap()
+            .operands
+            .iter()
+            .map(|o| o.as_float().unwrap() as f64)
+            .collect();
+        assert_near(cm, &[0.24, 0.0, 0.0, 0.24, 0.0, 100.0]);
+    }
+
+    #[test]
+    fn test_front_matter() {
+        let map = || {
+            (1..=4)
+                .map(|i| {
+                    (
+                        format!("http://example.com/{i}.html"),
+                        generate_pdf_with_link("http://example.com".to_string()),
+                    )
+                })
+                .collect::<IndexMap<_, _>>()
+        };
+        let mut style = page_number("{page} of {total}");
+        style.front_matter_pages = 2;
+        let conf = Config {
+            page_number: Some(style),
+            ..Default::default()
+        };
+        let pdf = assemble(&conf, map()).unwrap().pdf;
+        assert_eq!(
+            printed_page_numbers(&pdf),
+            vec![
+                b"i of 2".to_vec(),
+                b"ii of 2".to_vec(),
+                b"1 of 2".to_vec(),
+                b"2 of 2".to_vec()
+            ]
         );
-        map.insert(
-            "http://example.com/3.html".to_string(),
-            generate_pdf_with_link("http://example.com/4.html".to_string()),
+
+        let mut style = page_number("{page}");
+        style.front_matter_pages = 5;
+        let conf = Config {
+            page_number: Some(style),
+            ..Default::default()
+        };
+        assert!(assemble(&conf, map()).is_err());
+    }
+
+    #[test]
+    fn test_chapter_breaks() {
+        // Chapters of 1, 2, 1 and 1 pages, the first linking to the last
+        let chapters: [&[&str]; 4] = [&["a"], &["b1", "b2"], &["c"], &["d"]];
+        let url = |page: &str| format!("http://example.com/{page}.html");
+        let merge = |number_blank_pages| {
+            let map: IndexMap<String, Document> = chapters
+                .iter()
+                .flat_map(|pages| pages.iter())
+                .map(|page| (url(page), generate_pdf_with_link(url("d"))))
+                .collect();
+            let conf = Config {
+                url: "http://example.com".to_string(),
+                chapter_breaks: ChapterBreaks::Odd,
+                chapter_starts: chapters.iter().map(|pages| url(pages[0])).collect(),
+                page_number: Some(page_number("{page}")),
+                number_blank_pages,
+                ..Default::default()
+            };
+            assemble(&conf, map).unwrap().pdf
+        };
+
+        // Blank pages go before b1 and d
+        let pdf = merge(false);
+        let pages = pdf.get_pages();
+        assert_eq!(pages.len(), 7);
+        let blank: Vec<u32> = pages
+            .iter()
+            .filter(|(_, id)| !pdf.get_dictionary(**id).unwrap().has(b"Contents"))
+            .map(|(n, _)| *n)
+            .collect();
+        assert_eq!(blank, [2, 6]);
+        for page_id in pages.values() {
+            assert_eq!(media_box(&pdf, *page_id), media_box(&pdf, pages[&1]));
+        }
+
+        // Links follow their pages, numbers count the blank pages without being printed on them
+        let annotation = &pdf.get_page_annotations(pages[&1]).unwrap()[0];
+        let dest = annotation.get_deref(b"Dest", &pdf).unwrap();
+        assert_eq!(
+            dest.as_array().unwrap()[0].as_reference().unwrap(),
+            pages[&7]
+        );
+        let printed = |pdf: &Document| -> Vec<String> {
+            pdf.page_iter()
+                .map(|page_id| {
+                    let content = pdf.get_page_content(page_id).unwrap();
+                    Content::decode(&content)
+                        .unwrap()
+                        .operations
+                        .iter()
+                        .filter(|op| op.operator == "Tj")
+                        .last()
+                        .map(|op| {
+                            String::from_utf8_lossy(op.operands[0].as_str().unwrap()).to_string()
+                        })
+                        .unwrap_or_default()
+                })
+                .collect()
+        };
+        assert_eq!(printed(&pdf), ["1", "", "3", "4", "5", "", "7"]);
+        let pdf = merge(true);
+        assert_eq!(printed(&pdf), ["1", "2", "3", "4", "5", "6", "7"]);
+        // Blank pages are numbered with a font of their own, in the same place as the others
+        assert_fonts_registered(&pdf);
+        let positions = page_number_positions(&pdf);
+        assert!(
+            positions.iter().all(|p| *p == positions[0]),
+            "{positions:?}"
         );
 
-        let (parts, url_to_page_num) = merge_pdf_objects(map).unwrap();
+        // The blank page after the table of contents isn't numbered either
+        let map: IndexMap<String, Document> = chapters
+            .iter()
+            .flat_map(|pages| pages.iter())
+            .map(|page| (url(page), generate_pdf_with_link(url("d"))))
+            .collect();
+        let conf = Config {
+            url: "http://example.com".to_string(),
+            chapter_breaks: ChapterBreaks::Odd,
+            chapter_starts: chapters.iter().map(|pages| url(pages[0])).collect(),
+            page_number: Some(page_number("{page}")),
+            page_bookmarks: true,
+            toc: Some(toml::from_str("").unwrap()),
+            ..Default::default()
+        };
+        let pdf = assemble(&conf, map).unwrap().pdf;
+        assert_eq!(printed(&pdf), ["1", "", "3", "", "5", "6", "7", "", "9"]);
+    }
 
-        let mut pdf = build_pdf_from_objects(&parts).unwrap();
+    #[test]
+    fn test_running_header() {
+        let url = |page: &str| format!("http://example.com/{page}.html");
+        let map: IndexMap<String, Document> = ["guide", "install", "reference"]
+            .iter()
+            .map(|page| (url(page), generate_pdf_with_link(url("guide"))))
+            .collect();
+        let style = toml::from_str(
+            "color = { r = 0.0, g = 0.0, b = 0.0 }\nsize = 10\nx = 0.5\ny = 0.5\nalign = 'right'\nmirror = true\nformat = '{chapter}: {title}'",
+        )
+        .unwrap();
+        let conf = Config {
+            url: "http://example.com".to_string(),
+            running_header: Some(style),
+            page_titles: [
+                ("guide", "Guide"),
+                ("install", "Install"),
+                ("reference", "API"),
+            ]
+            .iter()
+            .map(|(page, title)| (url(page), title.to_string()))
+            .collect(),
+            chapter_starts: [url("guide"), url("reference")].into_iter().collect(),
+            ..Default::default()
+        };
+        let pdf = assemble(&conf, map).unwrap().pdf;
 
-        let (problem_urls, _problem_anchors) =
-            rewrite_vitepress_links(&conf, &mut pdf, url_to_page_num).unwrap();
+        assert_eq!(
+            printed_page_numbers(&pdf),
+            [
+                b"Guide: Guide".to_vec(),
+                b"Guide: Install".to_vec(),
+                b"API: API".to_vec()
+            ]
+        );
+        // The outer edge is on the right of odd pages and the left of even ones
+        let x: Vec<f64> = page_number_positions(&pdf)
+            .into_iter()
+            .map(|(x, _)| x)
+            .collect();
+        assert!(x[0] > 1000.0 && x[2] > 1000.0);
+        assert_near(vec![x[1]], &[150.0]);
+    }
 
-        assert_eq!(problem_urls, vec!["Page No. 3: http://example.com/4.html".to_string()]);
+    #[test]
+    fn test_running_header_win_ansi() {
+        let url = "http://example.com/install.html".to_string();
+        let map = IndexMap::from([(
+            url.clone(),
+            generate_pdf_with_link("http://example.com".to_string()),
+        )]);
+        let style =
+            toml::from_str("color = { r = 0.0, g = 0.0, b = 0.0 }\nsize = 10\ny = 0.5").unwrap();
+        let conf = Config {
+            running_header: Some(style),
+            page_titles: IndexMap::from([(url, "Chapter 3 — Installation".to_string())]),
+            ..Default::default()
+        };
+        let pdf = assemble(&conf, map).unwrap().pdf;
+        assert_eq!(
+            printed_page_numbers(&pdf),
+            [b"Chapter 3 \x97 Installation".to_vec()]
+        );
+    }
 
-        let page_num_to_id = pdf.get_pages();
-        for (page_num, page_id) in pdf.page_iter().enumerate() {
-            // Only the first two pages are remapped
-            if page_num == page_num_to_id.len() - 1 {
-                break;
-            }
+    #[test]
+    fn test_page_labels() {
+        let map = || {
+            let mut map: IndexMap<String, Document> = (1..=4)
+                .map(|i| {
+                    (
+                        format!("http://example.com/{i}.html"),
+                        generate_pdf_with_link("http://example.com".to_string()),
+                    )
+                })
+                .collect();
+            map.insert(
+                "http://example.com/pdf-cover.html".to_string(),
+                generate_pdf_with_link("http://example.com".to_string()),
+            );
+            map
+        };
+        let page_labels = |style: crate::config::PageNumber| {
+            let dir = tempfile::tempdir().unwrap();
+            let conf = Config {
+                url: "http://example.com".to_string(),
+                cover_url: Some("http://example.com/pdf-cover.html".to_string()),
+                page_number: Some(style),
+                ..Default::default()
+            };
+            let mut pdf = assemble(&conf, map()).unwrap().pdf;
+            let path = dir.path().join("labels.pdf");
+            save_pdf(&mut pdf, &path, None).unwrap();
 
-            let annotations = pdf.get_page_annotations(page_id).unwrap();
+            let saved = Document::load(&path).unwrap();
+            let labels = saved.catalog().unwrap().get_deref(b"PageLabels", &saved);
+            labels
+                .unwrap()
+                .as_dict()
+                .unwrap()
+                .get(b"Nums")
+                .unwrap()
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|o| match o {
+                    Object::Integer(i) => i.to_string(),
+                    o => o
+                        .as_dict()
+                        .unwrap()
+                        .get(b"S")
+                        .and_then(Object::as_name_str)
+                        .unwrap_or("")
+                        .to_string(),
+                })
+                .collect::<Vec<_>>()
+        };
 
-            // Assert that there's no stray annotations
-            assert_eq!(annotations.len(), 1);
+        // The cover isn't labelled, the front matter is roman and the rest decimal from 1
+        let mut style = page_number("{page}");
+        style.front_matter_pages = 2;
+        assert_eq!(page_labels(style), ["0", "", "1", "r", "3", "D"]);
 
-            let annotation = annotations.first().unwrap();
+        let mut style = page_number("{page}");
+        style.front_matter_pages = 2;
+        style.front_matter_style = FrontMatterStyle::None;
+        assert_eq!(page_labels(style), ["0", "", "3", "D"]);
 
-            let dest = annotation.get_deref(b"Dest", &pdf).unwrap();
+        assert_eq!(page_labels(page_number("{page}")), ["0", "", "1", "D"]);
+    }
 
-            let next_page_id = page_num_to_id.get(&(page_num as u32 + 2)).unwrap(); // +2 because page_num is 0 index and get_pages is 1 indexed.
+    #[test]
+    fn test_embed_standard_font() {
+        let fonts = tempfile::tempdir().unwrap();
+        fs::write(
+            fonts.path().join("NimbusSans-Regular.otf"),
+            crate::font::test_font(),
+        )
+        .unwrap();
+        let conf = Config {
+            embed_standard_fonts: true,
+            standard_fonts_dir: fonts.path().to_path_buf(),
+            page_number: Some(page_number("Page {page}")),
+            ..Default::default()
+        };
+        let mut pdf = generate_pdf_with_link("http://example.com".to_string());
+        add_page_numbers(&mut pdf, &conf, 0..1, &HashSet::new()).unwrap();
+        assert_fonts_registered(&pdf);
 
-            assert_eq!(
-                *next_page_id,
-                dest.as_array()
-                    .unwrap()
-                    .first()
-                    .unwrap()
+        let font_files = pdf
+            .objects
+            .values()
+            .filter_map(|o| o.as_dict().ok())
+            .filter_map(|d| d.get(b"FontFile2").ok())
+            .count();
+        assert_eq!(font_files, 1);
+    }
+
+    #[test]
+    fn test_standard_font_name() {
+        let mut doc = Document::with_version("1.5");
+        let font =
+            add_standard_font(&mut doc, &Config::default(), "Times−Bold", "Watermark").unwrap();
+        let base_font = doc
+            .get_dictionary(font.id)
+            .unwrap()
+            .get(b"BaseFont")
+            .unwrap();
+        assert_eq!(base_font.as_name().unwrap(), b"Times-Bold");
+    }
+
+    #[test]
+    fn test_page_number_font_file() {
+        let fonts = tempfile::tempdir().unwrap();
+        let path = fonts.path().join("Corporate Sans.ttf");
+        fs::write(&path, crate::font::test_font()).unwrap();
+        let mut style = page_number("Page {page}");
+        style.font_file = Some(path);
+        let conf = Config {
+            page_number: Some(style),
+            ..Default::default()
+        };
+        let mut pdf = generate_pdf_with_link("http://example.com".to_string());
+        add_page_numbers(&mut pdf, &conf, 0..1, &HashSet::new()).unwrap();
+
+        let font = pdf
+            .objects
+            .values()
+            .filter_map(|o| o.as_dict().ok())
+            .find(|d| d.get(b"Subtype").and_then(Object::as_name_str).ok() == Some("Type0"))
+            .unwrap();
+        let base_font = font.get(b"BaseFont").unwrap().as_name_str().unwrap();
+        assert!(base_font.ends_with("+CorporateSans"), "{base_font}");
+        assert!(font.has(b"ToUnicode"));
+        let cid_font = pdf
+            .get_dictionary(
+                font.get(b"DescendantFonts").unwrap().as_array().unwrap()[0]
                     .as_reference()
+                    .unwrap(),
+            )
+            .unwrap();
+        let descriptor = pdf
+            .get_dictionary(
+                cid_font
+                    .get(b"FontDescriptor")
                     .unwrap()
+                    .as_reference()
+                    .unwrap(),
+            )
+            .unwrap();
+        assert!(descriptor.has(b"FontFile2"));
+        // Two byte glyph IDs, the test font shows letters and space with glyph 1 and digits with 2
+        assert_eq!(
+            printed_page_numbers(&pdf),
+            vec![vec![0u8, 1, 0, 1, 0, 1, 0, 1, 0, 1, 0, 2]]
+        );
+    }
+
+    #[test]
+    fn test_page_bookmarks() {
+        let mut installed = generate_pdf_with_outline();
+        let info_id = installed.add_object(dictionary! {
+            "Title" => Object::string_literal("Install | Docs"),
+        });
+        installed.trailer.set("Info", info_id);
+        let map = IndexMap::from([
+            (
+                "http://example.com/guide.html".to_string(),
+                generate_pdf_with_outline(),
+            ),
+            ("http://example.com/install.html".to_string(), installed),
+            (
+                "http://example.com/plain.html".to_string(),
+                generate_pdf_with_link("http://example.com".to_string()),
+            ),
+        ]);
+        let conf = Config {
+            page_bookmarks: true,
+            page_titles: IndexMap::from([(
+                "http://example.com/guide.html".to_string(),
+                "Guide".to_string(),
+            )]),
+            ..Default::default()
+        };
+        let pdf = assemble(&conf, map).unwrap().pdf;
+
+        let outlines_id = pdf
+            .catalog()
+            .unwrap()
+            .get(b"Outlines")
+            .unwrap()
+            .as_reference()
+            .unwrap();
+        let outlines = pdf.get_dictionary(outlines_id).unwrap();
+        let root = outline(&pdf, outlines).unwrap();
+
+        // Sidebar text, then the page title, then the URL
+        let titles: Vec<&str> = root.childern.iter().map(|n| n.title.as_str()).collect();
+        assert_eq!(
+            titles,
+            ["Guide", "Install | Docs", "http://example.com/plain.html"]
+        );
+        for page in &root.childern {
+            assert_eq!(page.parent.as_deref(), Some("Outline Dictionary"));
+        }
+        for page in &root.childern[..2] {
+            assert_eq!(page.childern.len(), 1);
+            assert_eq!(page.childern[0].title, "Node 1");
+            assert_eq!(
+                page.childern[0].parent.as_deref(),
+                Some(page.title.as_str())
             );
+            assert_eq!(page.childern[0].childern.len(), 2);
+        }
+        assert!(root.childern[2].childern.is_empty());
+
+        // Each page bookmark is open and counts the three below it
+        assert_eq!(outlines.get(b"Count").unwrap().as_i64().unwrap(), 9);
+        let mut item = outlines.get(b"First").and_then(Object::as_reference).ok();
+        let mut counts = vec![];
+        while let Some(id) = item {
+            let dict = pdf.get_dictionary(id).unwrap();
+            counts.push(dict.get(b"Count").and_then(Object::as_i64).unwrap_or(0));
+            item = dict.get(b"Next").and_then(Object::as_reference).ok();
         }
+        assert_eq!(counts, [3, 3, 0]);
+    }
+
+    #[test]
+    fn test_outline_depth() {
+        // Titles indented by depth and the Count of each item
+        fn tree(doc: &Document, id: ObjectId, indent: usize, out: &mut Vec<String>) {
+            for child in outline_children(doc, id) {
+                let dict = doc.get_dictionary(child).unwrap();
+                let title = dict.get(b"Title").unwrap().as_string().unwrap();
+                let count = dict.get(b"Count").and_then(Object::as_i64).ok();
+                out.push(format!("{}{title} {count:?}", "  ".repeat(indent)));
+                tree(doc, child, indent + 1, out);
+            }
+        }
+        let map = || {
+            IndexMap::from([
+                (
+                    "http://example.com/1.html".to_string(),
+                    generate_pdf_with_outline(),
+                ),
+                (
+                    "http://example.com/2.html".to_string(),
+                    generate_pdf_with_outline(),
+                ),
+            ])
+        };
+        let outline_at = |depth: Option<usize>| -> String {
+            let conf = Config {
+                page_bookmarks: true,
+                outline_depth: depth,
+                ..Default::default()
+            };
+            let pdf = assemble(&conf, map()).unwrap().pdf;
+            let Ok(outlines_id) = pdf
+                .catalog()
+                .unwrap()
+                .get(b"Outlines")
+                .and_then(Object::as_reference)
+            else {
+                return "No outline".to_string();
+            };
+            let count = pdf
+                .get_dictionary(outlines_id)
+                .unwrap()
+                .get(b"Count")
+                .unwrap()
+                .as_i64()
+                .unwrap();
+            let mut out = vec![format!("Count {count}")];
+            tree(&pdf, outlines_id, 0, &mut out);
+            out.join("\n")
+        };
+
+        // The fixture's childless items have a Count, recounting drops it
+        insta::assert_snapshot!("outline_depth_unlimited", outline_at(None));
+        insta::assert_snapshot!("outline_depth_3", outline_at(Some(3)));
+        insta::assert_snapshot!("outline_depth_2", outline_at(Some(2)));
+        insta::assert_snapshot!("outline_depth_1", outline_at(Some(1)));
+        insta::assert_snapshot!("outline_depth_0", outline_at(Some(0)));
+
+        // The table of contents still lists what's pruned from the outline
+        let conf = Config {
+            page_bookmarks: true,
+            outline_depth: Some(0),
+            toc: Some(toml::from_str("").unwrap()),
+            ..Default::default()
+        };
+        let pdf = assemble(&conf, map()).unwrap().pdf;
+        assert!(pdf.catalog().unwrap().get(b"Outlines").is_err());
+        let toc_page = pdf.page_iter().next().unwrap();
+        let content = String::from_utf8_lossy(&pdf.get_page_content(toc_page).unwrap()).to_string();
+        assert!(content.contains("(http://example.com/2.html)"), "{content}");
     }
 
     #[derive(Eq, Debug, Hash, PartialEq)]
@@ -0,0 +1,211 @@
+// Copyright (c) 2024 DDN. All rights reserved.
+// Use of this source code is governed by a MIT-style
+// license that can be found in the LICENSE file.
+
+use crate::{config::Metadata, text::encode_text_string};
+use anyhow::Result;
+use chrono::{DateTime, FixedOffset, Local};
+use lopdf::{dictionary, Document, Object, Stream};
+
+const PRODUCER: &str = concat!("vitepress-pdf-export ", env!("CARGO_PKG_VERSION"));
+
+// Escape the characters XML gives a meaning to
+fn escape_xml(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// A date in the PDF format, e.g. D:20240801120000+02'00'
+fn pdf_date(date: &DateTime<FixedOffset>) -> String {
+    let offset = date.offset().local_minus_utc() / 60;
+    let sign = if offset < 0 { '-' } else { '+' };
+    format!(
+        "{}{sign}{:02}'{:02}'",
+        date.format("D:%Y%m%d%H%M%S"),
+        offset.abs() / 60,
+        offset.abs() % 60
+    )
+}
+
+// An XMP packet with the Dublin Core title and creator, creation date and producer
+fn xmp_packet(metadata: &Metadata, created: &DateTime<FixedOffset>) -> String {
+    let mut properties = String::new();
+    if let Some(title) = &metadata.title {
+        properties.push_str(&format!(
+            "   <dc:title><rdf:Alt><rdf:li xml:lang=\"x-default\">{}</rdf:li></rdf:Alt></dc:title>\n",
+            escape_xml(title)
+        ));
+    }
+    if let Some(author) = &metadata.author {
+        properties.push_str(&format!(
+            "   <dc:creator><rdf:Seq><rdf:li>{}</rdf:li></rdf:Seq></dc:creator>\n",
+            escape_xml(author)
+        ));
+    }
+    format!(
+        r#"<?xpacket begin="{bom}" id="W5M0MpCehiHzreSzNTczkc9d"?>
+<x:xmpmeta xmlns:x="adobe:ns:meta/">
+ <rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+  <rdf:Description rdf:about=""
+    xmlns:dc="http://purl.org/dc/elements/1.1/"
+    xmlns:xmp="http://ns.adobe.com/xap/1.0/"
+    xmlns:pdf="http://ns.adobe.com/pdf/1.3/">
+   <dc:format>application/pdf</dc:format>
+{properties}   <xmp:CreateDate>{created}</xmp:CreateDate>
+   <pdf:Producer>{producer}</pdf:Producer>
+  </rdf:Description>
+ </rdf:RDF>
+</x:xmpmeta>
+<?xpacket end="w"?>"#,
+        bom = '\u{feff}',
+        created = created.to_rfc3339_opts(chrono::SecondsFormat::Secs, false),
+        producer = escape_xml(PRODUCER),
+    )
+}
+
+fn write_metadata(
+    doc: &mut Document,
+    metadata: &Metadata,
+    created: &DateTime<FixedOffset>,
+) -> Result<()> {
+    let mut info = dictionary! {
+        "Producer" => encode_text_string(PRODUCER),
+        "CreationDate" => Object::string_literal(pdf_date(created)),
+    };
+    if let Some(title) = &metadata.title {
+        info.set("Title", encode_text_string(title));
+    }
+    if let Some(author) = &metadata.author {
+        info.set("Author", encode_text_string(author));
+    }
+    let info_id = doc.add_object(info);
+    doc.trailer.set("Info", info_id);
+
+    // Left uncompressed so archival tools can find it without decoding the PDF
+    let xmp = Stream::new(
+        dictionary! {
+            "Type" => "Metadata",
+            "Subtype" => "XML",
+        },
+        xmp_packet(metadata, created).into_bytes(),
+    )
+    .with_compression(false);
+    let xmp_id = doc.add_object(xmp);
+    let catalog_id = doc.trailer.get(b"Root")?.as_reference()?;
+    doc.get_dictionary_mut(catalog_id)?.set("Metadata", xmp_id);
+    Ok(())
+}
+
+/// Write `metadata` to `doc` as both its Info dictionary and the catalog's XMP Metadata stream.
+pub(crate) fn add_metadata(doc: &mut Document, metadata: &Metadata) -> Result<()> {
+    write_metadata(doc, metadata, &Local::now().fixed_offset())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::text::decode_text_string;
+    use regex::Regex;
+
+    // Text of each `tag` element in `xml`, unescaped
+    fn elements(xml: &str, tag: &str) -> Vec<String> {
+        let re = Regex::new(&format!("<{tag}[^>]*>([^<]*)</{tag}>")).unwrap();
+        re.captures_iter(xml)
+            .map(|caps| {
+                caps[1]
+                    .replace("&lt;", "<")
+                    .replace("&gt;", ">")
+                    .replace("&quot;", "\"")
+                    .replace("&apos;", "'")
+                    .replace("&amp;", "&")
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_metadata() {
+        let metadata = Metadata {
+            title: Some("Lustre <Manual> & \"Guide\"".to_string()),
+            author: Some("Rhône Team".to_string()),
+        };
+        let created = DateTime::parse_from_rfc3339("2024-08-01T12:30:00+02:00").unwrap();
+        let mut doc = Document::with_version("1.7");
+        let pages_id = doc.add_object(dictionary! {
+            "Type" => "Pages",
+            "Kids" => Vec::<Object>::new(),
+            "Count" => 0,
+        });
+        let catalog_id = doc.add_object(dictionary! {
+            "Type" => "Catalog",
+            "Pages" => pages_id,
+        });
+        doc.trailer.set("Root", catalog_id);
+        write_metadata(&mut doc, &metadata, &created).unwrap();
+
+        let file = tempfile::NamedTempFile::new().unwrap();
+        doc.compress();
+        doc.save(file.path()).unwrap();
+        let doc = Document::load(file.path()).unwrap();
+
+        let metadata_id = doc
+            .catalog()
+            .unwrap()
+            .get(b"Metadata")
+            .unwrap()
+            .as_reference()
+            .unwrap();
+        let stream = doc.get_object(metadata_id).unwrap().as_stream().unwrap();
+        assert_eq!(
+            stream.dict.get(b"Type").unwrap().as_name_str().unwrap(),
+            "Metadata"
+        );
+        assert_eq!(
+            stream.dict.get(b"Subtype").unwrap().as_name_str().unwrap(),
+            "XML"
+        );
+        assert!(!stream.dict.has(b"Filter"));
+
+        let xml = String::from_utf8(stream.content.clone()).unwrap();
+        assert!(xml.starts_with("<?xpacket begin=\"\u{feff}\""));
+        assert!(xml.ends_with("<?xpacket end=\"w\"?>"));
+        assert_eq!(
+            elements(&xml, "rdf:li"),
+            ["Lustre <Manual> & \"Guide\"", "Rhône Team"]
+        );
+        assert_eq!(
+            elements(&xml, "xmp:CreateDate"),
+            ["2024-08-01T12:30:00+02:00"]
+        );
+        assert_eq!(elements(&xml, "pdf:Producer"), [PRODUCER]);
+
+        let info_id = doc.trailer.get(b"Info").unwrap().as_reference().unwrap();
+        let info = doc.get_dictionary(info_id).unwrap();
+        assert_eq!(
+            info.get(b"CreationDate").unwrap().as_str().unwrap(),
+            b"D:20240801123000+02'00'"
+        );
+        assert_eq!(
+            decode_text_string(info.get(b"Author").unwrap().as_str().unwrap()),
+            "Rhône Team"
+        );
+    }
+
+    #[test]
+    fn test_metadata_without_title() {
+        let created = DateTime::parse_from_rfc3339("2024-08-01T12:30:00-05:30").unwrap();
+        let xml = xmp_packet(&Metadata::default(), &created);
+        assert!(!xml.contains("dc:title"));
+        assert!(!xml.contains("dc:creator"));
+        assert_eq!(pdf_date(&created), "D:20240801123000-05'30'");
+    }
+}
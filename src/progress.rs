@@ -0,0 +1,197 @@
+// Copyright (c) 2024 DDN. All rights reserved.
+// Use of this source code is governed by a MIT-style
+// license that can be found in the LICENSE file.
+
+use anyhow::{anyhow, Error};
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    io::IsTerminal as _,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+/// How render and merge progress is reported, see `--progress`
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ProgressMode {
+    /// An indicatif progress bar, only on a terminal
+    #[default]
+    Bar,
+    /// One line per event
+    Plain,
+    /// One JSON object per event on stdout, everything else goes to stderr
+    Json,
+}
+
+impl FromStr for ProgressMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bar" => Ok(ProgressMode::Bar),
+            "plain" => Ok(ProgressMode::Plain),
+            "json" => Ok(ProgressMode::Json),
+            _ => Err(anyhow!("Unknown progress mode {s}, use plain, json or bar")),
+        }
+    }
+}
+
+impl ProgressMode {
+    /// Progress bars turn into garbage in CI logs, fall back to `Plain` when stdout isn't a terminal
+    pub fn detect(self) -> Self {
+        match self {
+            ProgressMode::Bar if !std::io::stdout().is_terminal() => ProgressMode::Plain,
+            mode => mode,
+        }
+    }
+
+    /// Report `event`, the progress bar shows it instead in `Bar` mode
+    pub fn event(&self, event: &ProgressEvent) {
+        match self {
+            ProgressMode::Bar => {}
+            ProgressMode::Plain => println!("{event}"),
+            ProgressMode::Json => match serde_json::to_string(event) {
+                Ok(json) => println!("{json}"),
+                Err(e) => eprintln!("Unable to serialize progress event: {e}"),
+            },
+        }
+    }
+
+    /// Print a human readable message, to stderr in `Json` mode so stdout stays parsable
+    pub fn println(&self, message: impl fmt::Display) {
+        match self {
+            ProgressMode::Json => eprintln!("{message}"),
+            _ => println!("{message}"),
+        }
+    }
+}
+
+/// Progress events. The JSON field names are stable, CI pipelines parse them.
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum ProgressEvent<'a> {
+    /// Rendering is starting, crawling may add URLs later
+    Start { total: usize },
+    /// `url` was rendered, `index` counts from 1
+    Rendered {
+        url: &'a str,
+        index: usize,
+        total: usize,
+        secs: f64,
+        timings: PageTimings,
+    },
+    /// `url` couldn't be rendered
+    Failed { url: &'a str, error: String },
+    /// The PDFs were merged, `output` is `None` with `--no-output`
+    Merged {
+        output: Option<&'a Path>,
+        pages: usize,
+    },
+}
+
+impl fmt::Display for ProgressEvent<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProgressEvent::Start { total } => write!(f, "rendering {total} URLs"),
+            ProgressEvent::Rendered {
+                url,
+                index,
+                total,
+                secs,
+                ..
+            } => write!(f, "rendered {index}/{total}: {url} in {secs:.1}s"),
+            ProgressEvent::Failed { url, error } => write!(f, "failed {url}: {error}"),
+            ProgressEvent::Merged {
+                output: Some(output),
+                pages,
+            } => write!(f, "merged {pages} pages into {}", output.display()),
+            ProgressEvent::Merged {
+                output: None,
+                pages,
+            } => write!(f, "merged {pages} pages"),
+        }
+    }
+}
+
+/// Where the time rendering a page went, in seconds
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct PageTimings {
+    /// Navigating to the page until it loaded
+    pub navigation_secs: f64,
+    /// `expand_details`, `force_lazy_images` and `settle`
+    pub settle_secs: f64,
+    /// Chrome's printToPDF
+    pub print_secs: f64,
+    /// The whole render, including the above
+    pub total_secs: f64,
+}
+
+/// What rendering the URLs produced
+#[derive(Debug, Default)]
+pub struct RenderOutcome {
+    /// The PDF of every rendered URL
+    pub pdfs: IndexMap<String, PathBuf>,
+    /// URLs skipped by `continue_on_error`, with their error
+    pub failed: Vec<String>,
+    /// `render_deadline_secs` passed before every URL was rendered
+    pub deadline_exceeded: bool,
+    /// How long each URL rendered by this run took
+    pub timings: IndexMap<String, PageTimings>,
+    /// URLs skipped because the page opts out with a `pdf-export` meta tag
+    pub opted_out: Vec<String>,
+    /// The scale `auto_scale` picked for each URL it applied to
+    pub scales: IndexMap<String, f64>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_progress_mode() {
+        assert_eq!("json".parse::<ProgressMode>().unwrap(), ProgressMode::Json);
+        assert_eq!(
+            "plain".parse::<ProgressMode>().unwrap(),
+            ProgressMode::Plain
+        );
+        assert!("fancy".parse::<ProgressMode>().is_err());
+        assert_eq!(ProgressMode::Plain.detect(), ProgressMode::Plain);
+    }
+
+    #[test]
+    fn test_progress_events() {
+        let rendered = ProgressEvent::Rendered {
+            url: "http://localhost:5173/guide.html",
+            index: 12,
+            total: 200,
+            secs: 1.8,
+            timings: PageTimings {
+                navigation_secs: 0.4,
+                settle_secs: 0.1,
+                print_secs: 1.2,
+                total_secs: 1.8,
+            },
+        };
+        assert_eq!(
+            rendered.to_string(),
+            "rendered 12/200: http://localhost:5173/guide.html in 1.8s"
+        );
+        assert_eq!(
+            serde_json::to_string(&rendered).unwrap(),
+            r#"{"event":"rendered","url":"http://localhost:5173/guide.html","index":12,"total":200,"secs":1.8,"timings":{"navigation_secs":0.4,"settle_secs":0.1,"print_secs":1.2,"total_secs":1.8}}"#
+        );
+        assert_eq!(
+            serde_json::to_string(&ProgressEvent::Start { total: 200 }).unwrap(),
+            r#"{"event":"start","total":200}"#
+        );
+        assert_eq!(
+            serde_json::to_string(&ProgressEvent::Merged {
+                output: Some(Path::new("out.pdf")),
+                pages: 300
+            })
+            .unwrap(),
+            r#"{"event":"merged","output":"out.pdf","pages":300}"#
+        );
+    }
+}
@@ -2,30 +2,86 @@
 // Use of this source code is governed by a MIT-style
 // license that can be found in the LICENSE file.
 
-use crate::Config;
+use crate::{
+    cache::CacheStats,
+    config::{normalize_link, split_origin, ColorScheme, HeadlessMode, Settle},
+    progress::{PageTimings, ProgressEvent, ProgressMode, RenderOutcome},
+    render_cache::{content_hash, RenderCache},
+    Config,
+};
 use anyhow::{anyhow, Result};
-use headless_chrome::{FetcherOptions, LaunchOptions, Revision};
-use indexmap::IndexMap;
+use headless_chrome::{
+    protocol::cdp::{
+        types::Event,
+        Emulation::{
+            MediaFeature, SetDeviceMetricsOverride, SetEmulatedMedia, SetLocaleOverride,
+            SetTimezoneOverride,
+        },
+        Log::LogEntryLevel,
+        Network::{self, CookieParam},
+        Page::{CaptureScreenshotFormatOption, Viewport},
+        Runtime::{self, ConsoleAPICalledEventTypeOption},
+        Target,
+    },
+    types::PrintToPdfOptions,
+    FetcherOptions, LaunchOptions, Revision,
+};
+use indexmap::{IndexMap, IndexSet};
 use indicatif::{style::ProgressStyle, ProgressBar};
+use percent_encoding::percent_decode_str;
+use reqwest::{
+    header::{HeaderMap, HeaderName},
+    StatusCode,
+};
 use serde::Deserialize;
 use std::{
-    ffi::OsStr,
+    collections::{HashMap, HashSet, VecDeque},
+    ffi::{OsStr, OsString},
+    fmt,
     fs::{self, create_dir_all},
+    panic::{self, AssertUnwindSafe},
     path::{Path, PathBuf},
-    time::Duration,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
 };
+use tokio::task::JoinSet;
 
-#[cfg(target_os = "linux")]
-const PLATFORM: &str = "linux";
-#[cfg(all(target_os = "macos", target_arch = "aarch64"))]
-const PLATFORM: &str = "mac_arm";
-#[cfg(all(target_os = "macos", not(target_arch = "aarch64")))]
-const PLATFORM: &str = "mac";
+// A platform the Chromium snapshots the fetcher downloads are built for
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct ChromePlatform {
+    // The fetcher's name for it, part of the cached revision directory
+    name: &'static str,
+    // The Chrome binary inside the extracted download
+    bin: &'static str,
+}
 
-#[cfg(target_os = "linux")]
-const PLATFORM_BIN: &str = "chrome-linux/chrome";
-#[cfg(target_os = "macos")]
-const PLATFORM_BIN: &str = "chrome-mac/Chromium.app/Contents/MacOS/Chromium";
+impl ChromePlatform {
+    // `None` for platforms without snapshots, e.g. Linux on ARM
+    fn of(os: &str, arch: &str) -> Option<Self> {
+        let (name, bin) = match (os, arch) {
+            ("linux", "x86_64") => ("linux", "chrome-linux/chrome"),
+            ("macos", "aarch64") => ("mac_arm", "chrome-mac/Chromium.app/Contents/MacOS/Chromium"),
+            ("macos", _) => ("mac", "chrome-mac/Chromium.app/Contents/MacOS/Chromium"),
+            // Windows on ARM runs the x64 build
+            ("windows", _) => ("win64", "chrome-win/chrome.exe"),
+            _ => return None,
+        };
+        Some(ChromePlatform { name, bin })
+    }
+
+    fn current() -> Result<Self> {
+        let (os, arch) = (std::env::consts::OS, std::env::consts::ARCH);
+        Self::of(os, arch).ok_or_else(|| {
+            anyhow!(
+                "No Chrome build can be downloaded for {os} {arch}, install Chromium from your distribution, e.g. `apt install chromium`, and set chrome_path to it"
+            )
+        })
+    }
+}
 
 #[derive(Deserialize)]
 struct KnownGoodVersions {
@@ -37,10 +93,61 @@ struct Version {
     revision: String,
 }
 
-/// Ask google for the latest Known Good Revision of Chrome
-pub async fn get_latest_revision() -> Result<String> {
-    let resp = reqwest::get("https://googlechromelabs.github.io/chrome-for-testing/known-good-versions-with-downloads.json").await?;
-    let kgv = resp.json::<KnownGoodVersions>().await?;
+const KNOWN_GOOD_VERSIONS_URL: &str =
+    "https://googlechromelabs.github.io/chrome-for-testing/known-good-versions-with-downloads.json";
+
+// Appended to errors looking up or downloading Chrome, which aren't about the site
+const NO_DOWNLOAD_HINT: &str =
+    "set chrome_path, or offline with a chrome_version in chrome_cache, to render without downloading Chrome";
+
+// With a proxy a failed connection is a failed connection to the proxy
+fn request_error(config: &Config, url: &str, e: reqwest::Error) -> anyhow::Error {
+    match &config.proxy {
+        Some(proxy) if e.is_connect() => {
+            anyhow!("Proxy connection to {proxy} failed while requesting {url}: {e}")
+        }
+        _ => anyhow!("Unable to reach {url}: {e}"),
+    }
+}
+
+// HTTP client honoring `proxy` and `no_proxy`
+fn http_client(config: &Config) -> Result<reqwest::ClientBuilder> {
+    let mut client = reqwest::Client::builder();
+    if let Some(proxy) = &config.proxy {
+        client = client.proxy(
+            reqwest::Proxy::all(proxy)
+                .map_err(|e| anyhow!("Invalid proxy {proxy}: {e}"))?
+                .no_proxy(reqwest::NoProxy::from_string(&config.no_proxy.join(","))),
+        );
+    }
+    if config.insecure_tls {
+        client = client.danger_accept_invalid_certs(true);
+    }
+    Ok(client)
+}
+
+/// Ask google, or `chrome_versions_url`, for the latest Known Good Revision of Chrome
+pub async fn get_latest_revision(config: &Config) -> Result<String> {
+    let url = config
+        .chrome_versions_url
+        .as_deref()
+        .unwrap_or(KNOWN_GOOD_VERSIONS_URL);
+    let resp = http_client(config)?
+        .build()?
+        .get(url)
+        .send()
+        .await
+        .and_then(reqwest::Response::error_for_status)
+        .map_err(|e| {
+            anyhow!(
+                "Unable to download Chrome, looking up its latest revision failed: {}. {NO_DOWNLOAD_HINT}",
+                request_error(config, url, e)
+            )
+        })?;
+    let kgv = resp
+        .json::<KnownGoodVersions>()
+        .await
+        .map_err(|e| anyhow!("Invalid known good versions JSON from {url}: {e}"))?;
     Ok(kgv
         .versions
         .last()
@@ -49,93 +156,3070 @@ pub async fn get_latest_revision() -> Result<String> {
         .to_string())
 }
 
+// Number of preflight requests in flight at once
+const PREFLIGHT_CONCURRENCY: usize = 32;
+
+// HTTP client requesting pages like Chrome does, with `extra_headers` and `user_agent`
+fn page_client(config: &Config) -> Result<reqwest::Client> {
+    let mut headers = HeaderMap::new();
+    for (name, value) in &config.extra_headers {
+        headers.insert(HeaderName::from_bytes(name.as_bytes())?, value.parse()?);
+    }
+    let mut client = http_client(config)?
+        .default_headers(headers)
+        .timeout(Duration::from_secs(
+            config.navigation_timeout_secs.unwrap_or(30),
+        ));
+    if let Some(user_agent) = &config.user_agent {
+        client = client.user_agent(user_agent);
+    }
+    Ok(client.build()?)
+}
+
+// `cookies` Chrome would send to `url`, as a `Cookie` header value
+fn cookie_header(config: &Config, url: &str) -> Option<String> {
+    let url = reqwest::Url::parse(url).ok()?;
+    let host = url.host_str()?;
+    let cookies: Vec<String> = config
+        .cookies
+        .iter()
+        .filter(|c| {
+            let domain = c
+                .domain
+                .as_deref()
+                .unwrap_or_default()
+                .trim_start_matches('.');
+            (host == domain || host.ends_with(&format!(".{domain}")))
+                && url.path().starts_with(&c.path)
+                && (!c.secure || url.scheme() == "https")
+        })
+        .map(|c| format!("{}={}", c.name, c.value))
+        .collect();
+    (!cookies.is_empty()).then(|| cookies.join("; "))
+}
+
+// GET `url` with `client` from `page_client` the way its tab requests it, with `http_auth`
+// and `cookies`
+fn page_request(client: &reqwest::Client, config: &Config, url: &str) -> reqwest::RequestBuilder {
+    let mut request = client.get(url);
+    if let Some(auth) = &config.http_auth {
+        request = request.basic_auth(&auth.username, auth.password.as_ref());
+    }
+    if let Some(cookies) = cookie_header(config, url) {
+        request = request.header(reqwest::header::COOKIE, cookies);
+    }
+    request
+}
+
+/// GET `url` the way the pages are requested, with `extra_headers`, `user_agent`, `http_auth`
+/// and `cookies`
+pub(crate) async fn fetch_text(config: &Config, url: &str) -> Result<String> {
+    page_request(&page_client(config)?, config, url)
+        .send()
+        .await
+        .and_then(reqwest::Response::error_for_status)
+        .map_err(|e| request_error(config, url, e))?
+        .text()
+        .await
+        .map_err(|e| anyhow!("Unable to read {url}: {e}"))
+}
+
+/// Request every URL before launching Chrome and fail with those which don't return 200 OK
+pub async fn preflight(config: &Config) -> Result<()> {
+    let client = page_client(config)?;
+
+    let mut urls = config.urls.iter().enumerate();
+    let mut requests = JoinSet::new();
+    let mut failed = vec![];
+    loop {
+        while requests.len() < PREFLIGHT_CONCURRENCY {
+            let Some((i, url)) = urls.next() else {
+                break;
+            };
+            let request = page_request(&client, config, url);
+            let url = url.clone();
+            requests.spawn(async move {
+                match request.send().await {
+                    Ok(resp) if resp.status() == StatusCode::OK => None,
+                    Ok(resp) => Some((i, format!("{url}: HTTP {}", resp.status()))),
+                    Err(e) => Some((i, format!("{url}: {e}"))),
+                }
+            });
+        }
+        match requests.join_next().await {
+            Some(result) => failed.extend(result?),
+            None => break,
+        }
+    }
+
+    if failed.is_empty() {
+        config.progress.println(format_args!(
+            "Preflight of {} URLs passed",
+            config.urls.len()
+        ));
+        return Ok(());
+    }
+    failed.sort();
+    Err(anyhow!(
+        "Preflight failed for {} of {} URLs.\n{}",
+        failed.len(),
+        config.urls.len(),
+        failed
+            .iter()
+            .map(|(_, s)| format!("  * {s}"))
+            .collect::<Vec<String>>()
+            .join("\n")
+    ))
+}
+
+// Number of external link checks in flight at once
+const LINK_CHECK_CONCURRENCY: usize = 16;
+
+/// Request every URL of `links`, each with the pages linking to it, and list those which
+/// can't be reached or answer with an HTTP error.
+///
+/// A HEAD request is tried first, servers which don't allow it get a GET.
+pub async fn check_external_links(
+    config: &Config,
+    links: &IndexMap<String, Vec<usize>>,
+) -> Result<Vec<String>> {
+    let mut client = http_client(config)?.timeout(Duration::from_secs(
+        config.navigation_timeout_secs.unwrap_or(30),
+    ));
+    if let Some(user_agent) = &config.user_agent {
+        client = client.user_agent(user_agent);
+    }
+    let client = client.build()?;
+
+    let mut links = links.iter().enumerate();
+    let mut requests = JoinSet::new();
+    let mut dead = vec![];
+    loop {
+        while requests.len() < LINK_CHECK_CONCURRENCY {
+            let Some((i, (url, pages))) = links.next() else {
+                break;
+            };
+            let client = client.clone();
+            let url = url.clone();
+            let pages = pages
+                .iter()
+                .map(usize::to_string)
+                .collect::<Vec<String>>()
+                .join(", ");
+            requests.spawn(async move {
+                let resp = match client.head(&url).send().await {
+                    Ok(resp)
+                        if resp.status() == StatusCode::METHOD_NOT_ALLOWED
+                            || resp.status() == StatusCode::NOT_IMPLEMENTED =>
+                    {
+                        client.get(&url).send().await
+                    }
+                    resp => resp,
+                };
+                let problem = match resp {
+                    Ok(resp)
+                        if resp.status().is_client_error() || resp.status().is_server_error() =>
+                    {
+                        format!("HTTP {}", resp.status())
+                    }
+                    Ok(_) => return None,
+                    Err(e) => e.to_string(),
+                };
+                Some((i, format!("Page No. {pages}: {url} ({problem})")))
+            });
+        }
+        match requests.join_next().await {
+            Some(result) => dead.extend(result?),
+            None => break,
+        }
+    }
+
+    dead.sort();
+    Ok(dead.into_iter().map(|(_, s)| s).collect())
+}
+
+// Number of times a failed Chrome download is attempted
+const CHROME_DOWNLOAD_ATTEMPTS: u64 = 3;
+
+// Download the Chrome archive at `url` and extract it into `dir`, the same layout the
+// fetcher leaves behind so the result is picked up as a cached revision
+async fn download_chrome(config: &Config, url: &str, dir: &Path) -> Result<()> {
+    let archive = http_client(config)?
+        .build()?
+        .get(url)
+        .send()
+        .await
+        .and_then(reqwest::Response::error_for_status)
+        .map_err(|e| request_error(config, url, e))?
+        .bytes()
+        .await
+        .map_err(|e| request_error(config, url, e))?;
+    zip::ZipArchive::new(std::io::Cursor::new(archive))
+        .and_then(|mut zip| zip.extract(dir))
+        .map_err(|e| anyhow!("Unable to extract Chrome downloaded from {url}: {e}"))
+}
+
+// Directory the fetcher extracts `revision` into inside `cache`
+fn cached_revision_dir(cache: &Path, platform: ChromePlatform, revision: &str) -> PathBuf {
+    cache.join(format!("{}-{revision}", platform.name))
+}
+
+// Path of the Chrome binary for `revision` inside `cache`
+fn cached_chrome_bin(cache: &Path, platform: ChromePlatform, revision: &str) -> PathBuf {
+    platform
+        .bin
+        .split('/')
+        .fold(cached_revision_dir(cache, platform, revision), |p, c| {
+            p.join(c)
+        })
+}
+
+// Canonicalize without the Windows `\\?\` verbatim prefix, which some launchers can't handle
+fn canonicalize(path: &Path) -> Result<PathBuf> {
+    Ok(strip_verbatim(path.canonicalize()?))
+}
+
+fn strip_verbatim(path: PathBuf) -> PathBuf {
+    match path.to_str().and_then(|p| p.strip_prefix(r"\\?\")) {
+        // UNC paths need the prefix
+        Some(p) if !p.starts_with("UNC") => PathBuf::from(p),
+        _ => path,
+    }
+}
+
+// Fail early if `path` can't be launched
+fn check_executable(path: &Path) -> Result<()> {
+    let meta = fs::metadata(path)
+        .map_err(|e| anyhow!("chrome_path {} is not usable: {e}", path.display()))?;
+    #[cfg(unix)]
+    let executable = {
+        use std::os::unix::fs::PermissionsExt as _;
+        meta.permissions().mode() & 0o111 != 0
+    };
+    #[cfg(not(unix))]
+    let executable = true;
+    if !meta.is_file() || !executable {
+        return Err(anyhow!(
+            "chrome_path {} is not an executable file",
+            path.display()
+        ));
+    }
+    Ok(())
+}
+
+// Command line switches for every Chrome launch
+fn launch_args(config: &Config) -> Vec<OsString> {
+    let mut args = vec![OsString::from("--generate-pdf-document-outline")];
+    // headless_chrome only knows the legacy `--headless`, which it leaves out for the new mode
+    if config.headless_mode == HeadlessMode::New {
+        args.push("--headless=new".into());
+    }
+    if let Some(proxy) = &config.proxy {
+        args.push(format!("--proxy-server={proxy}").into());
+        if !config.no_proxy.is_empty() {
+            args.push(format!("--proxy-bypass-list={}", config.no_proxy.join(";")).into());
+        }
+    }
+    if config.insecure_tls {
+        args.push("--ignore-certificate-errors".into());
+    }
+    args.extend(config.chrome_args.iter().map(OsString::from));
+    args
+}
+
 /// Spin up Browser instance. If we don't have a copy of Chrome we will download a copy.
 pub async fn get_chrome(config: &Config) -> Result<headless_chrome::Browser> {
-    let revision = match &config.chrome_version {
-        Some(r) => r.to_string(),
-        None => get_latest_revision().await?.to_string(),
+    Ok(launch_chrome(config).await?.0)
+}
+
+// Launch the Chrome binary at `path` again, without looking up or downloading a revision
+fn relaunch_chrome(config: &Config, path: &Path) -> Result<headless_chrome::Browser> {
+    let args = launch_args(config);
+    headless_chrome::Browser::new(
+        LaunchOptions::default_builder()
+            .path(Some(path.to_path_buf()))
+            .args(args.iter().map(OsString::as_os_str).collect())
+            .headless(config.headless_mode == HeadlessMode::Old)
+            .devtools(false)
+            .build()?,
+    )
+}
+
+// `get_chrome` which also returns the Chrome binary, so restarts can reuse it
+async fn launch_chrome(config: &Config) -> Result<(headless_chrome::Browser, PathBuf)> {
+    if let Some(path) = &config.chrome_path {
+        check_executable(path)?;
+        if let Some(version) = &config.chrome_version {
+            config.progress.println(format_args!(
+                "Both chrome_path and chrome_version are set, ignoring chrome_version {version}"
+            ));
+        }
+        config
+            .progress
+            .println(format_args!("Using Chrome at {}", path.display()));
+
+        return Ok((relaunch_chrome(config, path)?, path.clone()));
+    }
+
+    let platform = ChromePlatform::current()?;
+    let revision = match (&config.chrome_version, config.offline) {
+        (Some(r), _) => r.to_string(),
+        (None, true) => {
+            return Err(anyhow!(
+                "offline is set, set chrome_version to a revision in chrome_cache or chrome_path"
+            ))
+        }
+        (None, false) => get_latest_revision(config).await?.to_string(),
     };
 
+    let revision_dir = cached_revision_dir(&config.chrome_cache, platform, &revision);
+    let chrome_bin = cached_chrome_bin(&config.chrome_cache, platform, &revision);
+    let cached = check_executable(&chrome_bin).is_ok();
+
+    // A mistyped cache must not turn into a download
+    if config.offline && (!cached || config.force_chrome_download) {
+        return Err(anyhow!(
+            "Chrome revision {revision} is not cached at {} and offline is set, check chrome_cache and chrome_version",
+            chrome_bin.display()
+        ));
+    }
+
+    if revision_dir.exists() && (!cached || config.force_chrome_download) {
+        if cached {
+            config
+                .progress
+                .println(format_args!("Removing cached Chrome revision {revision}"));
+        } else {
+            config.progress.println(format_args!(
+                "Cached Chrome revision {revision} is incomplete, downloading it again"
+            ));
+        }
+        fs::remove_dir_all(&revision_dir)?;
+    }
+
     if !config.chrome_cache.exists() {
         create_dir_all(&config.chrome_cache)?;
     }
 
-    let chrome_path = config.chrome_cache.join(format!("{PLATFORM}-{revision}"));
+    if chrome_bin.exists() {
+        config
+            .progress
+            .println(format_args!("Using cached Chrome revision {}", &revision));
+
+        let chrome_bin = canonicalize(&chrome_bin)?;
+        let chrome = relaunch_chrome(config, &chrome_bin)
+            .map_err(|e| anyhow!("Unable to launch cached Chrome revision {revision}: {e}"))?;
+        return Ok((chrome, chrome_bin));
+    }
+
+    let pb = match config.progress {
+        ProgressMode::Bar => ProgressBar::new_spinner(),
+        _ => {
+            config
+                .progress
+                .println(format_args!("Downloading Chrome revision {revision}"));
+            ProgressBar::hidden()
+        }
+    };
+
+    pb.enable_steady_tick(Duration::from_millis(50));
+
+    pb.set_style(ProgressStyle::with_template(&format!(
+        "{{spinner:.green}} Downloading Chrome revision {}.",
+        &revision
+    ))?);
+
+    // The fetcher's download URL is fixed, mirrors are downloaded by us and launched like a cached revision
+    let download_url = config.chrome_download_url_template.as_ref().map(|t| {
+        t.replace("{revision}", &revision)
+            .replace("{platform}", platform.name)
+    });
+
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        let chrome = match &download_url {
+            Some(url) => match download_chrome(config, url, &revision_dir).await {
+                Ok(()) if check_executable(&chrome_bin).is_err() => {
+                    Err(anyhow!("{url} doesn't contain {}", platform.bin))
+                }
+                Ok(()) => relaunch_chrome(config, &canonicalize(&chrome_bin)?),
+                Err(e) => Err(e),
+            },
+            // Blocking, so it runs off the runtime and render_deadline_secs can stop waiting
+            None => {
+                let args = launch_args(config);
+                let install_dir = canonicalize(&config.chrome_cache)?;
+                let headless = config.headless_mode == HeadlessMode::Old;
+                let revision = revision.clone();
+                tokio::task::spawn_blocking(move || -> Result<headless_chrome::Browser> {
+                    let args: Vec<&OsStr> = args.iter().map(OsString::as_os_str).collect();
+                    headless_chrome::Browser::new(
+                        LaunchOptions::default_builder()
+                            .fetcher_options(
+                                FetcherOptions::default()
+                                    .with_revision(Revision::Specific(revision))
+                                    .with_install_dir(Some(install_dir)),
+                            )
+                            .args(args)
+                            .headless(headless)
+                            .devtools(false)
+                            .build()?,
+                    )
+                })
+                .await?
+            }
+        };
+
+        // The fetcher downloads and launches in one go, a usable binary means the download worked
+        if check_executable(&chrome_bin).is_ok() {
+            pb.finish_with_message("Finished Downloading Chrome");
+            let chrome = chrome.map_err(|e| {
+                anyhow!("Downloaded Chrome revision {revision} but unable to launch it: {e}")
+            })?;
+            return Ok((chrome, canonicalize(&chrome_bin)?));
+        }
+
+        // Don't leave a partial extraction behind to be mistaken for a cached Chrome
+        if revision_dir.exists() {
+            fs::remove_dir_all(&revision_dir)?;
+        }
+        let e = chrome
+            .err()
+            .map_or("no Chrome binary was extracted".to_string(), |e| {
+                e.to_string()
+            });
+        if attempt >= CHROME_DOWNLOAD_ATTEMPTS {
+            pb.finish_and_clear();
+            return Err(anyhow!(
+                "Downloading Chrome revision {revision} failed after {attempt} attempts: {e}. {NO_DOWNLOAD_HINT}"
+            ));
+        }
+        note(
+            config,
+            &pb,
+            format!(
+                "Attempt {attempt} to download Chrome revision {revision} failed, retrying: {e}"
+            ),
+        );
+        tokio::time::sleep(Duration::from_secs(2 * attempt)).await;
+    }
+}
+
+// Emulate the print media type, or go back to screen, keeping `color_scheme`
+fn emulate_media(tab: &headless_chrome::Tab, config: &Config, print: bool) -> Result<()> {
+    tab.call_method(SetEmulatedMedia {
+        media: print.then(|| "print".to_string()),
+        features: config.color_scheme.map(|scheme| {
+            vec![MediaFeature {
+                name: "prefers-color-scheme".to_string(),
+                value: scheme.as_str().to_string(),
+            }]
+        }),
+    })?;
+    Ok(())
+}
+
+// Widest element on the page in CSS pixels. Wide tables and code blocks live in
+// horizontally scrolling containers so we have to look at them individually. The document
+// itself is left out, it is at least as wide as the viewport rather than the paper.
+const WIDEST_ELEMENT_JS: &str = r#"
+Math.max(
+    0,
+    ...Array.from(document.querySelectorAll("table, pre, img, svg"), e => e.scrollWidth)
+)
+"#;
+
+// Removes nodes which don't contribute to the printed page, e.g. VitePress's inline
+// local search index, hidden search modal and aria-hidden overlays. Built-in selectors
+// only remove nodes without a layout box and that removal is undone if the document size
+// changes. `extra` selectors are removed either way. Returns the number of nodes removed
+// or -1 when the layout guard restored the page.
+const STRIP_HIDDEN_DOM_JS: &str = r#"
+((extra) => {
+    const doc = document.documentElement;
+    const size = () => [doc.scrollWidth, doc.scrollHeight].join("x");
+    const before = size();
+    const removed = [];
+    for (const el of document.querySelectorAll("script, .VPLocalSearchBox, [aria-hidden='true']")) {
+        if (el.isConnected && (el.tagName === "SCRIPT" || el.getClientRects().length === 0)) {
+            removed.push([el, el.parentNode, el.nextSibling]);
+            el.remove();
+        }
+    }
+    const restored = size() !== before;
+    if (restored) {
+        for (const [el, parent, next] of removed.reverse()) {
+            parent.insertBefore(el, next);
+        }
+    }
+    const extraRemoved = extra.length > 0 ? document.querySelectorAll(extra.join(",")) : [];
+    extraRemoved.forEach((el) => el.remove());
+    return restored ? -1 : removed.length + extraRemoved.length;
+})
+"#;
+
+// Runs a user supplied script and returns the exception as a string, if one was thrown
+const INJECT_JS: &str = r#"
+((script) => {
+    try {
+        eval(script);
+        return null;
+    } catch (e) {
+        return String((e && e.stack) || e);
+    }
+})
+"#;
+
+// True when the page opts out of the PDF, e.g. with VitePress frontmatter
+// `head: [['meta', { name: 'pdf-export', content: 'false' }]]`
+const OPTED_OUT_JS: &str =
+    r#"document.querySelector('meta[name="pdf-export"][content="false" i]') !== null"#;
+
+// Stores the appearance used by VitePress's theme toggle and applies it to the loaded page
+const THEME_APPEARANCE_JS: &str = r#"
+((appearance) => {
+    localStorage.setItem("vitepress-theme-appearance", appearance);
+    const dark = appearance === "dark" ||
+        (appearance === "auto" && matchMedia("(prefers-color-scheme: dark)").matches);
+    document.documentElement.classList.toggle("dark", dark);
+})
+"#;
+
+// Opens every `details` element, including VitePress `::: details` containers, and clicks
+// the elements matching `selector`. Returns an exception as a string, if one was thrown.
+const EXPAND_DETAILS_JS: &str = r#"
+((selector) => {
+    try {
+        document.querySelectorAll("details").forEach(d => d.open = true);
+        if (selector) {
+            document.querySelectorAll(selector).forEach(e => e.click());
+        }
+        return null;
+    } catch (e) {
+        return String(e);
+    }
+})
+"#;
+
+// Loads `loading="lazy"` images straight away, returns how many there were
+const EAGER_IMAGES_JS: &str = r#"
+(() => {
+    const lazy = document.querySelectorAll('img[loading="lazy"]');
+    lazy.forEach(img => img.loading = "eager");
+    return lazy.length;
+})()
+"#;
+
+// True once every image has loaded or failed
+const IMAGES_COMPLETE_JS: &str = "Array.from(document.images).every(img => img.complete)";
+
+// Make lazy images load and wait for them, failing once the navigation timeout has passed
+fn force_lazy_images(tab: &headless_chrome::Tab, config: &Config, url: &str) -> Result<()> {
+    let lazy = tab
+        .evaluate(EAGER_IMAGES_JS, false)?
+        .value
+        .and_then(|v| v.as_u64())
+        .unwrap_or_default();
+    if lazy == 0 {
+        return Ok(());
+    }
+    let timeout = config.navigation_timeout();
+    let start = Instant::now();
+    while !tab
+        .evaluate(IMAGES_COMPLETE_JS, false)?
+        .value
+        .and_then(|v| v.as_bool())
+        .unwrap_or(true)
+    {
+        if start.elapsed() >= timeout {
+            return Err(anyhow!(
+                "{lazy} lazy images on {url} did not load within {} s",
+                timeout.as_secs()
+            ));
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    Ok(())
+}
+
+// Resolves once every web font in use has loaded or failed
+const FONTS_READY_JS: &str = "document.fonts.ready.then(() => true)";
+
+// Wait for web fonts, bounded by the tab's default timeout which is the navigation timeout
+fn wait_for_fonts(tab: &headless_chrome::Tab, config: &Config, url: &str) -> Result<()> {
+    tab.evaluate(FONTS_READY_JS, true).map_err(|e| {
+        anyhow!(
+            "Fonts on {url} did not load within {} s: {e}",
+            config.navigation_timeout().as_secs()
+        )
+    })?;
+    Ok(())
+}
+
+// Same origin link targets on the page, as a JSON array
+const CRAWL_LINKS_JS: &str = r#"
+JSON.stringify(
+    Array.from(document.querySelectorAll("a[href]"), a => a.href)
+        .filter(href => href.startsWith(location.origin))
+)
+"#;
+
+// Normalize a crawled link the way `build_links` normalizes sidebar links.
+// Anything outside `base` or with a file extension other than `.html` isn't a page.
+fn crawl_link(base: &str, href: &str) -> Option<String> {
+    let href = href.split(['#', '?']).next()?;
+    let path = href.strip_prefix(base)?;
+    if !path.is_empty() && !path.starts_with('/') {
+        return None;
+    }
+    let page = path.rsplit('/').next().unwrap_or_default();
+    if page.contains('.') && !page.ends_with(".html") {
+        return None;
+    }
+    Some(normalize_link(
+        base,
+        if path.is_empty() { "/" } else { path },
+    ))
+}
+
+// Windows doesn't allow these as file names, with or without an extension
+const RESERVED_FILE_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+// Longest slug in bytes, leaving room for a de-duplication suffix and `.pdf`
+const MAX_SLUG_BYTES: usize = 200;
+
+// File name, without `.pdf`, for the PDF of `url`, e.g. `guide-getting-started.html`.
+// Characters invalid on Windows, macOS or Linux are replaced with `_`, anything else,
+// including non-ASCII letters, is kept.
+fn pdf_slug(base: &str, url: &str) -> String {
+    let path = url.strip_prefix(base).unwrap_or(url);
+    let path = path.split(['#', '?']).next().unwrap_or_default();
+    let path = percent_decode_str(path).decode_utf8_lossy();
+    let mut slug: String = path
+        .trim_matches('/')
+        .chars()
+        .map(|c| match c {
+            '/' => '-',
+            '<' | '>' | ':' | '"' | '\\' | '|' | '?' | '*' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    // Windows drops trailing dots and spaces
+    slug.truncate(slug.trim_end_matches(['.', ' ']).len());
+    if slug.is_empty() {
+        return "index".to_string();
+    }
+    if slug.len() > MAX_SLUG_BYTES {
+        let mut end = MAX_SLUG_BYTES;
+        while !slug.is_char_boundary(end) {
+            end -= 1;
+        }
+        slug.truncate(end);
+    }
+    let stem = slug.split('.').next().unwrap_or_default();
+    if RESERVED_FILE_NAMES
+        .iter()
+        .any(|r| r.eq_ignore_ascii_case(stem))
+    {
+        slug.insert(0, '_');
+    }
+    slug
+}
+
+// Record the HTTP status of the tab's main document. Document requests share their id with
+// the loader and the main frame's id is the tab's target id, which rules out sub-resources
+// and iframes.
+fn watch_document_status(tab: &headless_chrome::Tab) -> Result<Arc<AtomicU32>> {
+    let status = Arc::new(AtomicU32::new(0));
+    tab.call_method(Network::Enable {
+        ..Default::default()
+    })?;
+    let target_id = tab.get_target_id().clone();
+    tab.add_event_listener(Arc::new({
+        let status = status.clone();
+        move |event: &Event| {
+            if let Event::NetworkResponseReceived(e) = event {
+                let params = &e.params;
+                if params.request_id == params.loader_id
+                    && params.frame_id.as_deref() == Some(target_id.as_str())
+                {
+                    status.store(params.response.status as u32, Ordering::SeqCst);
+                }
+            }
+        }
+    }))?;
+    Ok(status)
+}
+
+/// The result of rendering a single URL
+#[derive(Default)]
+pub struct RenderedPage {
+    pub pdf: Vec<u8>,
+    /// The scale picked by `auto_scale`, if it applied to the URL
+    pub scale: Option<f64>,
+    /// Size of the PDF printed before `strip_hidden_dom` removed anything, to measure the saving
+    pub unstripped_pdf_bytes: Option<usize>,
+    /// Absolute `href`s of the links on the page when crawling
+    pub links: Vec<String>,
+    /// Console errors and warnings, uncaught exceptions and failed requests not in `console_ignore`
+    pub console: Vec<String>,
+    /// Full page PNG taken just before printing, for `--debug-screenshots`
+    pub screenshot: Option<Vec<u8>>,
+    pub timings: PageTimings,
+    /// The page has `<meta name="pdf-export" content="false">`, nothing was printed
+    pub opted_out: bool,
+}
+
+// Size of the whole document in CSS pixels, as a JSON array
+const DOCUMENT_SIZE_JS: &str =
+    "JSON.stringify([document.documentElement.scrollWidth, document.documentElement.scrollHeight])";
+
+// PNG of the whole page as it is laid out, not just the viewport
+fn full_page_screenshot(tab: &headless_chrome::Tab) -> Result<Vec<u8>> {
+    let json = tab
+        .evaluate(DOCUMENT_SIZE_JS, false)?
+        .value
+        .and_then(|v| v.as_str().map(str::to_string))
+        .ok_or_else(|| anyhow!("Unable to measure the document"))?;
+    let [width, height]: [f64; 2] = serde_json::from_str(&json)?;
+    tab.capture_screenshot(
+        CaptureScreenshotFormatOption::Png,
+        None,
+        Some(Viewport {
+            x: 0.0,
+            y: 0.0,
+            width,
+            height,
+            scale: 1.0,
+        }),
+        true,
+    )
+}
+
+// Collect console errors and warnings, uncaught exceptions and failed requests, e.g.
+// `error: Failed to load resource: ... (http://localhost:5173/diagram.svg)`
+fn watch_console(tab: &headless_chrome::Tab) -> Result<Arc<Mutex<Vec<String>>>> {
+    let messages = Arc::new(Mutex::new(vec![]));
+    tab.enable_log()?;
+    tab.call_method(Runtime::Enable(None))?;
+    tab.add_event_listener(Arc::new({
+        let messages = messages.clone();
+        move |event: &Event| {
+            let message = match event {
+                Event::LogEntryAdded(e) => {
+                    let entry = &e.params.entry;
+                    let level = match entry.level {
+                        LogEntryLevel::Error => "error",
+                        LogEntryLevel::Warning => "warning",
+                        _ => return,
+                    };
+                    match &entry.url {
+                        Some(url) => format!("{level}: {} ({url})", entry.text),
+                        None => format!("{level}: {}", entry.text),
+                    }
+                }
+                // console.error() and console.warn() only come as Runtime events
+                Event::RuntimeConsoleAPICalled(e) => {
+                    let level = match e.params.Type {
+                        ConsoleAPICalledEventTypeOption::Error
+                        | ConsoleAPICalledEventTypeOption::Assert => "error",
+                        ConsoleAPICalledEventTypeOption::Warning => "warning",
+                        _ => return,
+                    };
+                    let text = e
+                        .params
+                        .args
+                        .iter()
+                        .map(|arg| match &arg.value {
+                            Some(serde_json::Value::String(s)) => s.clone(),
+                            Some(v) => v.to_string(),
+                            None => arg.description.clone().unwrap_or_default(),
+                        })
+                        .collect::<Vec<String>>()
+                        .join(" ");
+                    format!("{level}: {text}")
+                }
+                Event::RuntimeExceptionThrown(e) => {
+                    let details = &e.params.exception_details;
+                    let description = details
+                        .exception
+                        .as_ref()
+                        .and_then(|e| e.description.clone())
+                        .unwrap_or_else(|| details.text.clone());
+                    format!("error: {description}")
+                }
+                _ => return,
+            };
+            if let Ok(mut messages) = messages.lock() {
+                messages.push(message);
+            }
+        }
+    }))?;
+    Ok(messages)
+}
+
+// Pick the first scale at which the widest element fits between the margins
+// Width of the printed page between the margins in CSS pixels. Chrome swaps the paper's
+// width and height for `landscape`.
+fn printable_width_px(options: &PrintToPdfOptions) -> f64 {
+    // Chrome DevTool Protocol defaults, US Letter with 1cm margins
+    let paper_inches = match options.landscape {
+        Some(true) => options.paper_height.unwrap_or(11.0),
+        _ => options.paper_width.unwrap_or(8.5),
+    };
+    let printable_inches = paper_inches
+        - options.margin_left.unwrap_or(0.3937)
+        - options.margin_right.unwrap_or(0.3937);
+    printable_inches * 96.0 // CSS pixels are defined as 1/96th of an inch
+}
+
+fn auto_scale(
+    tab: &headless_chrome::Tab,
+    config: &Config,
+    options: &PrintToPdfOptions,
+    scales: &[f64],
+) -> Result<f64> {
+    let printable_px = printable_width_px(options);
+
+    // Measured with the print stylesheet, which e.g. drops the sidebar and unwraps code
+    // blocks, like printToPDF lays the page out
+    if !config.emulate_media {
+        emulate_media(tab, config, true)?;
+    }
+    let widest = tab.evaluate(WIDEST_ELEMENT_JS, false);
+    if !config.emulate_media {
+        emulate_media(tab, config, false)?;
+    }
+    let widest = widest?
+        .value
+        .and_then(|v| v.as_f64())
+        .ok_or(anyhow!("Unable to measure page width"))?;
+
+    Ok(*scales
+        .iter()
+        .find(|scale| widest * *scale <= printable_px)
+        .or(scales.last())
+        .ok_or(anyhow!("No auto_scale scales defined"))?)
+}
+
+// Chrome reports proxy failures as net::ERR_PROXY_CONNECTION_FAILED or ERR_TUNNEL_CONNECTION_FAILED
+fn navigation_error(config: &Config, url: &str, e: anyhow::Error) -> anyhow::Error {
+    let message = e.to_string();
+    match &config.proxy {
+        Some(proxy)
+            if message.contains("ERR_PROXY_CONNECTION_FAILED")
+                || message.contains("ERR_TUNNEL_CONNECTION_FAILED") =>
+        {
+            anyhow!("Proxy connection to {proxy} failed while loading {url}: {message}")
+        }
+        _ => anyhow!("Navigation to {url} did not complete: {message}"),
+    }
+}
+
+// In flight request ids of a tab and when the last one started or finished
+struct NetworkActivity {
+    in_flight: HashSet<String>,
+    last_change: Instant,
+}
+
+fn watch_network(tab: &headless_chrome::Tab) -> Result<Arc<Mutex<NetworkActivity>>> {
+    let activity = Arc::new(Mutex::new(NetworkActivity {
+        in_flight: HashSet::new(),
+        last_change: Instant::now(),
+    }));
+    tab.call_method(Network::Enable {
+        ..Default::default()
+    })?;
+    tab.add_event_listener(Arc::new({
+        let activity = activity.clone();
+        move |event: &Event| {
+            let Ok(mut activity) = activity.lock() else {
+                return;
+            };
+            match event {
+                Event::NetworkRequestWillBeSent(e) => {
+                    activity.in_flight.insert(e.params.request_id.clone());
+                }
+                Event::NetworkLoadingFinished(e) => {
+                    activity.in_flight.remove(&e.params.request_id);
+                }
+                Event::NetworkLoadingFailed(e) => {
+                    activity.in_flight.remove(&e.params.request_id);
+                }
+                _ => return,
+            }
+            activity.last_change = Instant::now();
+        }
+    }))?;
+    Ok(activity)
+}
+
+// Wait for `settle` after navigation, failing once the navigation timeout has passed
+fn settle(
+    config: &Config,
+    url: &str,
+    settle: &Settle,
+    network: Option<&Mutex<NetworkActivity>>,
+) -> Result<()> {
+    let timeout = config.navigation_timeout();
+    if let Some(delay) = settle.delay_ms {
+        thread::sleep(Duration::from_millis(delay).min(timeout));
+    }
+    if let (Some(idle_ms), Some(network)) = (settle.network_idle_ms, network) {
+        let idle = Duration::from_millis(idle_ms);
+        let start = Instant::now();
+        loop {
+            let (in_flight, since) = {
+                let network = network
+                    .lock()
+                    .map_err(|_| anyhow!("Network activity of {url} is unavailable"))?;
+                (network.in_flight.len(), network.last_change.elapsed())
+            };
+            if in_flight == 0 && since >= idle {
+                break;
+            }
+            if start.elapsed() >= timeout {
+                return Err(anyhow!(
+                    "{url} did not settle within {} s, {in_flight} requests still in flight",
+                    timeout.as_secs()
+                ));
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+    Ok(())
+}
+
+// The `render_cache` of this run
+struct PageCache {
+    client: reqwest::Client,
+    // Entries of the previous run, empty with `--no-cache`
+    previous: RenderCache,
+    // Entries of the pages rendered or reused by this run
+    current: RenderCache,
+    // Content hash of every URL looked up, `None` if its HTML couldn't be fetched
+    hashes: HashMap<String, Option<String>>,
+    stats: CacheStats,
+}
+
+impl PageCache {
+    fn new(config: &Config) -> Result<Option<Self>> {
+        let Some(file) = &config.render_cache_file else {
+            return Ok(None);
+        };
+        Ok(Some(PageCache {
+            client: page_client(config)?,
+            previous: match config.no_cache {
+                true => RenderCache::default(),
+                false => RenderCache::load(file),
+            },
+            current: RenderCache::default(),
+            hashes: HashMap::new(),
+            stats: CacheStats::default(),
+        }))
+    }
+
+    // A page which can't be fetched is rendered, it just isn't cached. The HTML is requested
+    // like its tab does, so a login page isn't mistaken for the content.
+    async fn hash(&self, config: &Config, url: &str) -> Option<String> {
+        let html = page_request(&self.client, config, &config.export_url(url))
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .ok()?
+            .bytes()
+            .await
+            .ok()?;
+        Some(content_hash(&html, &render_options(config, url)))
+    }
 
-    if chrome_path.exists() {
-        println!("Using cached Chrome revision {}", &revision);
+    // The PDF of a previous run for `url` if its content is unchanged
+    async fn lookup(&mut self, config: &Config, url: &str) -> Option<PathBuf> {
+        let page = split_origin(url).1;
+        if !self.hashes.contains_key(url) {
+            let hash = self.hash(config, url).await;
+            match hash.as_ref().and_then(|h| self.previous.get(page, h)) {
+                Some(_) => self.stats.hits += 1,
+                None => self.stats.misses += 1,
+            }
+            self.hashes.insert(url.to_string(), hash);
+        }
+        let hash = self.hashes.get(url)?.as_ref()?;
+        let pdf = self.previous.get(page, hash)?.clone();
+        self.current.insert(page, hash.clone(), pdf.clone());
+        Some(pdf)
+    }
+
+    // Cache the PDF just rendered for `url`
+    fn record(&mut self, url: &str, pdf: &Path) {
+        if let Some(Some(hash)) = self.hashes.get(url) {
+            self.current
+                .insert(split_origin(url).1, hash.clone(), pdf.to_path_buf());
+        }
+    }
+}
 
-        headless_chrome::Browser::new(
-            LaunchOptions::default_builder()
-                .path(Some(chrome_path.join(PLATFORM_BIN).canonicalize()?))
-                .args(vec![OsStr::new("--generate-pdf-document-outline")])
-                .headless(true)
-                .devtools(false)
-                .build()
-                .unwrap(),
+// Everything besides its HTML which changes the PDF of `url`, including the header and
+// footer templates in `print_to_pdf`
+fn render_options(config: &Config, url: &str) -> String {
+    format!(
+        "{:?}",
+        (
+            &config.print_to_pdf,
+            config.url_override(url),
+            (&config.injected_css, &config.injected_js),
+            (
+                config.emulate_media,
+                config.color_scheme,
+                config.color_scheme_local_storage,
+            ),
+            (&config.timezone, &config.locale),
+            (&config.export_class, &config.export_query),
+            (config.strip_hidden_dom, &config.strip_selectors),
+            (config.expand_details, &config.expand_selector),
+            (
+                &config.auto_scale,
+                &config.viewport,
+                config.force_lazy_images
+            ),
+            (
+                &config.wait_for_selector,
+                config.wait_for_fonts,
+                &config.settle
+            ),
+            (config.headless_mode, &config.user_agent),
+            (&config.cookies, &config.extra_headers),
         )
+    )
+}
+
+/// Render a single URL into a PDF using a fresh tab, which is closed afterwards as open
+/// tabs keep their page's memory
+fn render_url(
+    chrome: &headless_chrome::Browser,
+    config: &Config,
+    url: &str,
+) -> Result<RenderedPage> {
+    if !config.isolated_contexts {
+        let tab = chrome.new_tab()?;
+        let page = render_in_tab(&tab, config, url);
+        if let Err(e) = tab.close(false) {
+            config
+                .progress
+                .println(format_args!("Unable to close the tab of {url}: {e}"));
+        }
+        return page;
+    }
+
+    // A fresh incognito context starts with empty storage, cookies are set on its tab
+    let context = chrome.new_context()?;
+    let tab = context.new_tab()?;
+    let page = render_in_tab(&tab, config, url);
+    if let Err(e) = chrome.call_method(Target::DisposeBrowserContext {
+        browser_context_id: context.get_id().to_string(),
+    }) {
+        config.progress.println(format_args!(
+            "Unable to dispose the browser context of {url}: {e}"
+        ));
+    }
+    page
+}
+
+fn render_in_tab(tab: &headless_chrome::Tab, config: &Config, url: &str) -> Result<RenderedPage> {
+    let start = Instant::now();
+    let mut timings = PageTimings::default();
+    let console = watch_console(tab)?;
+    let status = watch_document_status(tab)?;
+    let network = match &config.settle {
+        Some(Settle {
+            network_idle_ms: Some(_),
+            ..
+        }) => Some(watch_network(tab)?),
+        _ => None,
+    };
+    if let Some(timeout) = config.navigation_timeout_secs {
+        tab.set_default_timeout(Duration::from_secs(timeout));
+    }
+    if !config.cookies.is_empty() {
+        tab.set_cookies(
+            config
+                .cookies
+                .iter()
+                .map(|c| CookieParam {
+                    name: c.name.clone(),
+                    value: c.value.clone(),
+                    domain: c.domain.clone(),
+                    path: Some(c.path.clone()),
+                    secure: Some(c.secure),
+                    ..Default::default()
+                })
+                .collect(),
+        )?;
+    }
+    if let Some(user_agent) = &config.user_agent {
+        tab.set_user_agent(user_agent, None, None)?;
+    }
+    if !config.extra_headers.is_empty() {
+        tab.set_extra_http_headers(
+            config
+                .extra_headers
+                .iter()
+                .map(|(k, v)| (k.as_str(), v.as_str()))
+                .collect(),
+        )?;
+    }
+    if let Some(viewport) = &config.viewport {
+        tab.call_method(SetDeviceMetricsOverride {
+            width: viewport.width,
+            height: viewport.height,
+            device_scale_factor: viewport.device_scale_factor,
+            mobile: false,
+            ..Default::default()
+        })?;
+    }
+    // Chrome only checks the time zone ID here, so the error names the config value
+    if let Some(timezone) = &config.timezone {
+        tab.call_method(SetTimezoneOverride {
+            timezone_id: timezone.clone(),
+        })
+        .map_err(|e| anyhow!("Invalid timezone {timezone}: {e}"))?;
+    }
+    if let Some(locale) = &config.locale {
+        tab.call_method(SetLocaleOverride {
+            locale: Some(locale.clone()),
+        })
+        .map_err(|e| anyhow!("Invalid locale {locale}: {e}"))?;
+    }
+    // Emulated before navigation so everything measured on the page sees the print layout
+    if config.emulate_media || config.color_scheme.is_some() {
+        emulate_media(tab, config, config.emulate_media)?;
+    }
+    // Chrome only sends Fetch.authRequired, which the credentials answer, with the Fetch
+    // domain handling auth requests. The tab's default interceptor continues every request.
+    if let Some(auth) = &config.http_auth {
+        tab.enable_fetch(None, Some(true))?;
+        tab.authenticate(Some(auth.username.clone()), auth.password.clone())?;
+    }
+    let navigation = Instant::now();
+    tab.navigate_to(&config.export_url(url))
+        .and_then(|tab| tab.wait_until_navigated())
+        .map_err(|e| navigation_error(config, url, e))?;
+    timings.navigation_secs = navigation.elapsed().as_secs_f64();
+
+    // 0 when Chrome didn't report the status, e.g. for a `file://` URL
+    let status = status.load(Ordering::SeqCst);
+    if status == 401 && config.http_auth.is_some() {
+        return Err(anyhow!(
+            "Authentication failed (HTTP 401) for {url}, check http_auth"
+        ));
+    }
+    if status >= 400 && !config.allow_status.contains(&(status as u16)) {
+        return Err(anyhow!(
+            "{url} returned HTTP {status}, add it to allow_status to render it anyway"
+        ));
+    }
+
+    // Before anything waits on the page so the theme's export styles are in place
+    if let Some(class) = &config.export_class {
+        tab.evaluate(
+            &format!(
+                "document.documentElement.classList.add({})",
+                serde_json::to_string(class)?
+            ),
+            false,
+        )?;
+    }
+
+    let opted_out = !config.ignore_pdf_export_meta
+        && tab
+            .evaluate(OPTED_OUT_JS, false)?
+            .value
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false);
+    if opted_out {
+        return Ok(RenderedPage {
+            opted_out,
+            timings: PageTimings {
+                total_secs: start.elapsed().as_secs_f64(),
+                ..timings
+            },
+            ..Default::default()
+        });
+    }
+
+    if let (true, Some(scheme)) = (config.color_scheme_local_storage, config.color_scheme) {
+        let appearance = match scheme {
+            ColorScheme::NoPreference => "auto",
+            s => s.as_str(),
+        };
+        tab.evaluate(&format!("{THEME_APPEARANCE_JS}(\"{appearance}\")"), false)?;
+    }
+
+    if !config.injected_css.is_empty() {
+        tab.evaluate(
+            &format!(
+                "{{ const style = document.createElement('style'); style.textContent = {}; document.head.appendChild(style); }}",
+                serde_json::to_string(&config.injected_css)?
+            ),
+            false,
+        )?;
+    }
+
+    let url_override = config.url_override(url);
+    if let Some(selector) = url_override
+        .and_then(|o| o.wait_for_selector.as_ref())
+        .or(config.wait_for_selector.as_ref())
+    {
+        tab.wait_for_element(selector)
+            .map_err(|e| anyhow!("Selector {selector} never appeared on {url}: {e}"))?;
+    }
+
+    if let Some(script) = url_override
+        .and_then(|o| o.injected_js.as_ref())
+        .or(config.injected_js.as_ref())
+    {
+        let exception = tab
+            .evaluate(
+                &format!("{INJECT_JS}({})", serde_json::to_string(script)?),
+                false,
+            )?
+            .value
+            .and_then(|v| v.as_str().map(str::to_string));
+        if let Some(exception) = exception {
+            return Err(anyhow!(
+                "inject_js threw an exception on {url}: {exception}"
+            ));
+        }
+    }
+
+    // Before settling so images in the expanded content load and the layout reflows
+    let settling = Instant::now();
+    if config.expand_details {
+        let exception = tab
+            .evaluate(
+                &format!(
+                    "{EXPAND_DETAILS_JS}({})",
+                    serde_json::to_string(&config.expand_selector)?
+                ),
+                false,
+            )?
+            .value
+            .and_then(|v| v.as_str().map(str::to_string));
+        if let Some(exception) = exception {
+            return Err(anyhow!("expand_selector failed on {url}: {exception}"));
+        }
+    }
+
+    if config.force_lazy_images {
+        force_lazy_images(tab, config, url)?;
+    }
+
+    // Before settling so the settle delay is measured from the final layout
+    if config.wait_for_fonts {
+        wait_for_fonts(tab, config, url)?;
+    }
+
+    if let Some(s) = &config.settle {
+        settle(config, url, s, network.as_deref())?;
+    }
+    timings.settle_secs = settling.elapsed().as_secs_f64();
+
+    let mut options = url_override
+        .and_then(|o| o.print_options.as_ref())
+        .unwrap_or(&config.print_to_pdf)
+        .clone();
+    let scale = match config.auto_scale.as_ref().filter(|a| a.matches(url)) {
+        Some(a) => Some(auto_scale(tab, config, &options, &a.scales)?),
+        None => None,
+    };
+    if scale.is_some() {
+        options.scale = scale;
+    }
+    if let Some(ranges) = url_override.and_then(|o| o.page_ranges.as_ref()) {
+        options.page_ranges = Some(ranges.clone());
+    }
+
+    let mut unstripped_pdf_bytes = None;
+    if config.strip_hidden_dom {
+        // Printed as is too, to measure how much smaller stripping makes the PDF
+        unstripped_pdf_bytes = Some(tab.print_to_pdf(Some(options.clone()))?.len());
+        let stripped = tab
+            .evaluate(
+                &format!(
+                    "{STRIP_HIDDEN_DOM_JS}({})",
+                    serde_json::to_string(&config.strip_selectors)?
+                ),
+                false,
+            )?
+            .value
+            .and_then(|v| v.as_i64())
+            .unwrap_or_default();
+        if stripped < 0 {
+            config.progress.println(format_args!(
+                "Stripping hidden DOM changed the layout of {url}, leaving it untouched"
+            ));
+        }
+    }
+
+    let links = if config.crawl {
+        let json = tab
+            .evaluate(CRAWL_LINKS_JS, false)?
+            .value
+            .and_then(|v| v.as_str().map(str::to_string))
+            .unwrap_or_else(|| "[]".to_string());
+        serde_json::from_str(&json)?
     } else {
-        let pb = ProgressBar::new_spinner();
+        vec![]
+    };
 
-        pb.enable_steady_tick(Duration::from_millis(50));
+    let screenshot = match &config.debug_screenshots {
+        Some(_) => match full_page_screenshot(tab) {
+            Ok(png) => Some(png),
+            Err(e) => {
+                config
+                    .progress
+                    .println(format_args!("Unable to take a screenshot of {url}: {e}"));
+                None
+            }
+        },
+        None => None,
+    };
 
-        pb.set_style(ProgressStyle::with_template(&format!(
-            "{{spinner:.green}} Downloading Chrome revision {}.",
-            &revision
-        ))?);
+    let printing = Instant::now();
+    let pdf = tab.print_to_pdf(Some(options))?;
+    timings.print_secs = printing.elapsed().as_secs_f64();
 
-        let chrome = headless_chrome::Browser::new(
-            LaunchOptions::default_builder()
-                .fetcher_options(
-                    FetcherOptions::default()
-                        .with_revision(Revision::Specific(revision))
-                        .with_install_dir(Some(config.chrome_cache.canonicalize()?)),
-                )
-                .args(vec![OsStr::new("--generate-pdf-document-outline")])
-                .headless(true)
-                .devtools(false)
-                .build()?,
+    let console: Vec<String> = console
+        .lock()
+        .map_err(|_| anyhow!("Console messages of {url} are unavailable"))?
+        .iter()
+        .filter(|m| !config.is_console_ignored(m))
+        .cloned()
+        .collect();
+    if config.fail_on_console_error {
+        if let Some(error) = console.iter().find(|m| m.starts_with("error: ")) {
+            return Err(anyhow!(
+                "{url} logged {} console error(s) or failed request(s), e.g. {error}",
+                console.iter().filter(|m| m.starts_with("error: ")).count()
+            ));
+        }
+    }
+
+    Ok(RenderedPage {
+        pdf,
+        scale,
+        unstripped_pdf_bytes,
+        links,
+        console,
+        screenshot,
+        timings: PageTimings {
+            total_secs: start.elapsed().as_secs_f64(),
+            ..timings
+        },
+        opted_out: false,
+    })
+}
+
+// Number of pages listed in the slowest pages summary
+const SLOWEST_PAGES: usize = 10;
+
+// Percentage of `max_memory_mb` or `max_cpu` at which fewer pages are rendered at once, and
+// with one page at a time Chrome is restarted for memory
+const THROTTLE_PERCENT: u64 = 90;
+
+// Percentage of `max_memory_mb` and `max_cpu` below which more pages are rendered at once again
+const RAMP_UP_PERCENT: u64 = 70;
+
+// Memory and CPU used by the exporter and Chrome
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct Usage {
+    memory_mb: u64,
+    // Cores busy since the previous sample, `None` for the first one
+    cpus: Option<f64>,
+}
+
+impl Usage {
+    // Use as a percentage of the nearest of `max_memory_mb` and `max_cpu`, `None` if neither is set
+    fn percent(&self, config: &Config) -> Option<u64> {
+        let memory = config.max_memory_mb.map(|max| self.memory_mb * 100 / max);
+        let cpu = config
+            .max_cpu
+            .zip(self.cpus)
+            .map(|(max, cpus)| (cpus * 100.0 / max) as u64);
+        memory.max(cpu)
+    }
+
+    fn memory_high(&self, config: &Config) -> bool {
+        config
+            .max_memory_mb
+            .is_some_and(|max| self.memory_mb * 100 >= max * THROTTLE_PERCENT)
+    }
+}
+
+// PPid and VmRSS in KiB from /proc/<pid>/status
+#[cfg(target_os = "linux")]
+fn parse_proc_status(status: &str) -> (u32, u64) {
+    let field = |name: &str| {
+        status
+            .lines()
+            .find_map(|l| l.strip_prefix(name))
+            .and_then(|v| v.split_whitespace().next())
+    };
+    (
+        field("PPid:")
+            .and_then(|v| v.parse().ok())
+            .unwrap_or_default(),
+        field("VmRSS:")
+            .and_then(|v| v.parse().ok())
+            .unwrap_or_default(),
+    )
+}
+
+// utime plus stime in clock ticks from /proc/<pid>/stat. The command before them is in
+// parentheses and may contain spaces, so fields are counted from the last ')'.
+#[cfg(target_os = "linux")]
+fn parse_proc_stat_ticks(stat: &str) -> Option<u64> {
+    let fields: Vec<&str> = stat.rsplit_once(')')?.1.split_whitespace().collect();
+    // Fields 14 and 15 of proc(5), the first after the command is field 3
+    let field = |n: usize| fields.get(n - 3)?.parse::<u64>().ok();
+    Some(field(14)? + field(15)?)
+}
+
+// Resident memory in KiB and CPU time in clock ticks of `root` and its descendants
+#[cfg(target_os = "linux")]
+fn process_tree_usage(root: u32) -> Option<(u64, u64)> {
+    let mut children: HashMap<u32, Vec<u32>> = HashMap::new();
+    let mut usage = HashMap::new();
+    for entry in fs::read_dir("/proc").ok()?.flatten() {
+        let Some(pid) = entry.file_name().to_str().and_then(|p| p.parse().ok()) else {
+            continue;
+        };
+        // Processes may exit while we walk /proc
+        let Ok(status) = fs::read_to_string(entry.path().join("status")) else {
+            continue;
+        };
+        let (ppid, kb) = parse_proc_status(&status);
+        let ticks = fs::read_to_string(entry.path().join("stat"))
+            .ok()
+            .and_then(|stat| parse_proc_stat_ticks(&stat))
+            .unwrap_or_default();
+        children.entry(ppid).or_default().push(pid);
+        usage.insert(pid, (kb, ticks));
+    }
+
+    let (mut kb, mut ticks) = (0, 0);
+    let mut pids = vec![root];
+    while let Some(pid) = pids.pop() {
+        let (k, t) = usage.get(&pid).copied().unwrap_or_default();
+        kb += k;
+        ticks += t;
+        pids.extend(children.remove(&pid).unwrap_or_default());
+    }
+    Some((kb, ticks))
+}
+
+// Samples the memory and CPU use of the exporter and Chrome, from /proc on Linux and with
+// sysinfo elsewhere
+struct ResourceMonitor {
+    // Time and CPU ticks of the previous sample
+    #[cfg(target_os = "linux")]
+    previous: Option<(Instant, u64)>,
+    #[cfg(not(target_os = "linux"))]
+    system: sysinfo::System,
+    #[cfg(not(target_os = "linux"))]
+    sampled: bool,
+}
+
+impl ResourceMonitor {
+    #[cfg(target_os = "linux")]
+    fn new() -> Self {
+        Self { previous: None }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn new() -> Self {
+        Self {
+            system: sysinfo::System::new(),
+            sampled: false,
+        }
+    }
+
+    // `None` if use can't be sampled on this platform
+    #[cfg(target_os = "linux")]
+    fn sample(&mut self) -> Option<Usage> {
+        let (kb, ticks) = process_tree_usage(std::process::id())?;
+        let now = Instant::now();
+        // SAFETY: sysconf only reads a system setting
+        let ticks_per_sec = unsafe { libc::sysconf(libc::_SC_CLK_TCK) }.max(1) as f64;
+        let cpus = self.previous.map(|(at, previous)| {
+            let secs = now.duration_since(at).as_secs_f64().max(0.001);
+            ticks.saturating_sub(previous) as f64 / ticks_per_sec / secs
+        });
+        self.previous = Some((now, ticks));
+        Some(Usage {
+            memory_mb: kb / 1024,
+            cpus,
+        })
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn sample(&mut self) -> Option<Usage> {
+        use sysinfo::{Pid, ProcessesToUpdate};
+
+        self.system.refresh_processes(ProcessesToUpdate::All);
+        let mut children: HashMap<Pid, Vec<Pid>> = HashMap::new();
+        for (pid, process) in self.system.processes() {
+            if let Some(parent) = process.parent() {
+                children.entry(parent).or_default().push(*pid);
+            }
+        }
+
+        let root = Pid::from_u32(std::process::id());
+        self.system.process(root)?;
+        let (mut bytes, mut percent) = (0, 0.0);
+        let mut pids = vec![root];
+        while let Some(pid) = pids.pop() {
+            if let Some(process) = self.system.process(pid) {
+                bytes += process.memory();
+                percent += f64::from(process.cpu_usage());
+            }
+            pids.extend(children.remove(&pid).unwrap_or_default());
+        }
+        // CPU use is measured between refreshes, the first one has nothing to compare with
+        let cpus = self.sampled.then_some(percent / 100.0);
+        self.sampled = true;
+        Some(Usage {
+            memory_mb: bytes / 1024 / 1024,
+            cpus,
+        })
+    }
+}
+
+// Stops the render loop on Ctrl-C so Chrome is shut down with the tabs closed instead of
+// orphaned, `render_urls` then returns `RenderError::Interrupted`. A second Ctrl-C kills
+// Chrome so the pages being rendered fail rather than finish.
+struct CtrlC {
+    interrupted: Arc<AtomicBool>,
+    // One per browser instance
+    chrome_pids: Arc<Vec<AtomicU32>>,
+    // Stopped on drop, Ctrl-C is the caller's again once rendering finished
+    listener: tokio::task::JoinHandle<()>,
+}
+
+impl CtrlC {
+    fn new(instances: usize) -> Self {
+        let interrupted = Arc::new(AtomicBool::new(false));
+        let chrome_pids: Arc<Vec<AtomicU32>> =
+            Arc::new((0..instances).map(|_| AtomicU32::new(0)).collect());
+        let listener = tokio::spawn({
+            let interrupted = interrupted.clone();
+            let chrome_pids = chrome_pids.clone();
+            async move {
+                while tokio::signal::ctrl_c().await.is_ok() {
+                    if interrupted.swap(true, Ordering::SeqCst) {
+                        eprintln!("Interrupted again, stopping Chrome");
+                        for pid in chrome_pids.iter() {
+                            kill(pid.load(Ordering::SeqCst));
+                        }
+                    } else {
+                        eprintln!(
+                            "Interrupted, stopping after the current page. Press Ctrl-C again to stop Chrome now"
+                        );
+                    }
+                }
+            }
+        });
+        CtrlC {
+            interrupted,
+            chrome_pids,
+            listener,
+        }
+    }
+
+    fn set_chrome(&self, index: usize, chrome: &headless_chrome::Browser) {
+        self.chrome_pids[index].store(
+            chrome.get_process_id().unwrap_or_default(),
+            Ordering::SeqCst,
         );
+    }
 
-        pb.finish_with_message("Finished Downloading Chrome");
+    // Forget an instance's Chrome once it is gone, so its PID isn't killed after being reused
+    fn clear_chrome(&self, index: usize) {
+        self.chrome_pids[index].store(0, Ordering::SeqCst);
+    }
+
+    // Kill every Chrome so the pages being rendered fail now, rather than relaunching it
+    fn kill_chrome(&self) {
+        self.interrupted.store(true, Ordering::SeqCst);
+        for pid in self.chrome_pids.iter() {
+            kill(pid.load(Ordering::SeqCst));
+        }
+    }
 
-        chrome
+    fn interrupted(&self) -> bool {
+        self.interrupted.load(Ordering::SeqCst)
     }
 }
 
-/// Use Chrome to render URLs into PDFs
-pub async fn render_urls(
-    config: &Config,
-    pdf_temp_dir: &Path,
-) -> Result<IndexMap<String, PathBuf>> {
-    let chrome = get_chrome(config).await?;
+impl Drop for CtrlC {
+    fn drop(&mut self) {
+        self.listener.abort();
+    }
+}
+
+/// Errors specific to `render_urls`
+#[derive(Debug, PartialEq)]
+pub enum RenderError {
+    /// Ctrl-C was pressed, `rendered` of `total` URLs were rendered by then
+    Interrupted { rendered: usize, total: usize },
+}
+
+impl fmt::Display for RenderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RenderError::Interrupted { rendered, total } => {
+                write!(f, "Interrupted after rendering {rendered} of {total} URLs")
+            }
+        }
+    }
+}
+
+impl std::error::Error for RenderError {}
+
+#[cfg(unix)]
+fn kill(pid: u32) {
+    if pid != 0 {
+        // SAFETY: kill has no memory safety requirements
+        unsafe {
+            libc::kill(pid as libc::pid_t, libc::SIGKILL);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn kill(_pid: u32) {}
+
+// One of the `browser_instances` Chrome processes
+struct Instance {
+    // `None` once it died more often than `max_chrome_relaunches` allows
+    chrome: Option<headless_chrome::Browser>,
+    // Position in `CtrlC`'s pids
+    index: usize,
+    // Pages rendered by the current Chrome, for browser_recycle_every
+    rendered: usize,
+}
+
+impl Instance {
+    // The old Chrome is gone before the new one starts, restarts may be for memory
+    fn relaunch(&mut self, config: &Config, chrome_bin: &Path, ctrl_c: &CtrlC) -> Result<()> {
+        ctrl_c.clear_chrome(self.index);
+        self.chrome = None;
+        let chrome = relaunch_chrome(config, chrome_bin)?;
+        ctrl_c.set_chrome(self.index, &chrome);
+        self.chrome = Some(chrome);
+        self.rendered = 0;
+        Ok(())
+    }
+}
+
+// What the instances rendering URLs share
+struct Shared<'a> {
+    config: &'a Config,
+    chrome_bin: &'a Path,
+    ctrl_c: &'a CtrlC,
+    pb: &'a ProgressBar,
+    // Chrome relaunches of all instances, for max_chrome_relaunches
+    relaunches: AtomicU32,
+    // Earliest start of the next navigation, for min_interval_ms
+    next_navigation: Mutex<Option<Instant>>,
+}
+
+impl Shared<'_> {
+    // Wait for the next navigation slot, navigations start `min_interval_ms` apart across
+    // all instances. The first one doesn't wait and nothing waits after the last.
+    async fn throttle(&self) {
+        let interval = Duration::from_millis(self.config.min_interval_ms);
+        if interval.is_zero() {
+            return;
+        }
+        let start = {
+            let Ok(mut next) = self.next_navigation.lock() else {
+                return;
+            };
+            let now = Instant::now();
+            let start = next.map_or(now, |next| next.max(now));
+            *next = Some(start + interval);
+            start
+        };
+        tokio::time::sleep(start.saturating_duration_since(Instant::now())).await;
+    }
+}
+
+// Render `url` on `instance`, retrying per `render_retries` and relaunching Chrome when it
+// died. The inner error is a URL skipped by `continue_on_error`, or one to render elsewhere
+// when the instance was retired. The outer error ends the run.
+async fn render_with_retries(
+    instance: &mut Instance,
+    shared: &Shared,
+    url: &str,
+) -> Result<Result<RenderedPage>> {
+    let Shared {
+        config,
+        chrome_bin,
+        ctrl_c,
+        pb,
+        relaunches,
+        ..
+    } = shared;
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        shared.throttle().await;
+        let chrome = instance
+            .chrome
+            .as_ref()
+            .ok_or_else(|| anyhow!("Chrome isn't running"))?;
+        match render_url(chrome, config, url) {
+            Ok(page) => {
+                instance.rendered += 1;
+                return Ok(Ok(page));
+            }
+            // A dead browser fails every tab, relaunch it and render the same URL again
+            Err(e) if chrome.get_version().is_err() && !ctrl_c.interrupted() => {
+                let max = config.max_chrome_relaunches;
+                let counted = relaunches.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                    (n < max).then_some(n + 1)
+                });
+                let relaunched = match counted {
+                    Ok(_) => {
+                        note(
+                            config,
+                            pb,
+                            format!("Chrome died while rendering {url}, relaunching it: {e}"),
+                        );
+                        instance.relaunch(config, chrome_bin, ctrl_c)
+                    }
+                    Err(n) => Err(anyhow!(
+                        "Chrome died while rendering {url}, already relaunched it {n} time(s): {e}"
+                    )),
+                };
+                match relaunched {
+                    // The crash doesn't count against the URL's retries
+                    Ok(()) => attempt -= 1,
+                    // Retire this instance, the others carry on
+                    Err(e) if config.continue_on_error && config.browser_instances > 1 => {
+                        note(config, pb, format!("Retiring a Chrome instance: {e}"));
+                        ctrl_c.clear_chrome(instance.index);
+                        instance.chrome = None;
+                        return Ok(Err(e));
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+            Err(e) if attempt <= config.render_retries && !ctrl_c.interrupted() => {
+                let delay = config
+                    .render_retry_delay_ms
+                    .saturating_mul(2u64.saturating_pow(attempt - 1));
+                note(
+                    config,
+                    pb,
+                    format!("Attempt {attempt} to render {url} failed, retrying in {delay}ms: {e}"),
+                );
+                tokio::time::sleep(Duration::from_millis(delay)).await;
+            }
+            Err(e) if config.continue_on_error => {
+                note(
+                    config,
+                    pb,
+                    format!("Unable to render {url} after {attempt} attempt(s), skipping it: {e}"),
+                );
+                return Ok(Err(e));
+            }
+            Err(e) => {
+                return Err(anyhow!(
+                    "Unable to render {url} after {attempt} attempt(s): {e}"
+                ))
+            }
+        }
+    }
+}
+
+// Printed above the progress bar, or as a line of its own without one
+fn note(config: &Config, pb: &ProgressBar, message: String) {
+    match config.progress {
+        ProgressMode::Bar => pb.println(message),
+        mode => mode.println(message),
+    }
+}
+
+/// Use Chrome to render URLs into PDFs in `pdf_temp_dir`.
+///
+/// Also returns the URLs which failed to render, with their error, when `continue_on_error` is set.
+/// Rendering runs on a runtime of its own in another thread, so this may be called from any
+/// runtime, including a current_thread one, and blocks the calling thread until it is done.
+pub async fn render_urls(config: &Config, pdf_temp_dir: &Path) -> Result<RenderOutcome> {
+    // The render loop blocks one of the runtime's workers while the pages render on threads of
+    // their own, which panics on a current_thread runtime
+    thread::scope(|s| {
+        s.spawn(|| {
+            tokio::runtime::Builder::new_multi_thread()
+                .enable_all()
+                .build()?
+                .block_on(render_urls_on_runtime(config, pdf_temp_dir))
+        })
+        .join()
+        .map_err(|_| anyhow!("Rendering URLs panicked"))?
+    })
+}
+
+async fn render_urls_on_runtime(config: &Config, pdf_temp_dir: &Path) -> Result<RenderOutcome> {
+    // Covers looking up and downloading Chrome too
+    let deadline = config
+        .render_deadline_secs
+        .map(|secs| Instant::now() + Duration::from_secs(secs));
+    let mut deadline_exceeded = false;
+
+    if config.insecure_tls {
+        config.progress.println(format_args!(
+            "WARNING: insecure_tls is set, TLS certificates are NOT verified by Chrome, the Chrome revision lookup or preflight"
+        ));
+    }
+
+    if config.preflight {
+        preflight(config).await?;
+    }
+
+    let ctrl_c = CtrlC::new(config.browser_instances);
+    // Dropping the browsers on any return below terminates Chrome
+    let launched = match deadline {
+        Some(d) => tokio::time::timeout_at(d.into(), launch_chrome(config))
+            .await
+            .ok(),
+        None => Some(launch_chrome(config).await),
+    };
+    let Some(launched) = launched else {
+        config.progress.println(format_args!(
+            "render_deadline_secs passed before Chrome was downloaded and launched"
+        ));
+        return Ok(RenderOutcome {
+            failed: match config.continue_on_error {
+                true => config
+                    .cover_url
+                    .iter()
+                    .chain(&config.urls)
+                    .map(|url| format!("{url}: not rendered before render_deadline_secs"))
+                    .collect(),
+                false => vec![],
+            },
+            deadline_exceeded: true,
+            ..Default::default()
+        });
+    };
+    let (chrome, chrome_bin) = launched?;
+    ctrl_c.set_chrome(0, &chrome);
+    let mut instances = vec![Instance {
+        chrome: Some(chrome),
+        index: 0,
+        rendered: 0,
+    }];
+    // The other instances launch the same Chrome, nothing is downloaded again
+    for index in 1..config.browser_instances {
+        let chrome = relaunch_chrome(config, &chrome_bin)?;
+        ctrl_c.set_chrome(index, &chrome);
+        instances.push(Instance {
+            chrome: Some(chrome),
+            index,
+            rendered: 0,
+        });
+    }
+    let mut recycles = 0;
+
+    // Pages rendered at once, lowered when use gets close to `max_memory_mb` or `max_cpu` and
+    // raised again once it has dropped
+    let instances_len = instances.len();
+    let mut concurrency = instances_len;
+    let mut monitor = ResourceMonitor::new();
+    let limited = config.max_memory_mb.is_some() || config.max_cpu.is_some();
+    if limited && monitor.sample().is_none() {
+        config.progress.println(format_args!(
+            "Unable to sample memory and CPU use, ignoring max_memory_mb and max_cpu"
+        ));
+    }
+    let mut peak_memory_mb = 0;
+    let mut peak_cpus: f64 = 0.0;
+    let mut peak_concurrency = 0;
+    let mut throttles = 0;
+    let mut restarts = 0;
 
-    let pb = ProgressBar::new(config.urls.len() as u64);
+    // Crawled URLs are appended as they are found, which keeps them in breadth first order.
+    // The cover is rendered first like any other page, `merge_pdfs` takes it out again.
+    let mut urls: IndexSet<String> = config
+        .cover_url
+        .iter()
+        .chain(&config.urls)
+        .cloned()
+        .collect();
+    if config.crawl {
+        // Pages kept from a previous crawl aren't rendered again so their links aren't seen
+        urls.extend(
+            config
+                .resume
+                .keys()
+                .filter(|url| !config.is_excluded(url))
+                .cloned(),
+        );
+    }
+    let mut crawl_limit_hit = false;
+
+    let pb = match config.progress {
+        ProgressMode::Bar => ProgressBar::new(urls.len() as u64),
+        _ => ProgressBar::hidden(),
+    };
 
     pb.enable_steady_tick(Duration::from_millis(50));
+    config
+        .progress
+        .event(&ProgressEvent::Start { total: urls.len() });
+    let shared = Shared {
+        config,
+        chrome_bin: &chrome_bin,
+        ctrl_c: &ctrl_c,
+        pb: &pb,
+        relaunches: AtomicU32::new(0),
+        next_navigation: Mutex::new(None),
+    };
 
     let mut map: IndexMap<String, PathBuf> = IndexMap::new();
+    let mut console: IndexMap<String, Vec<String>> = IndexMap::new();
+    let mut timings: IndexMap<String, PageTimings> = IndexMap::new();
+    let mut failed = vec![];
+    let mut opted_out = vec![];
+    let mut scales: IndexMap<String, f64> = IndexMap::new();
+    // Pages measured by `strip_hidden_dom`, with their PDF sizes before and after stripping
+    let (mut stripped_pages, mut unstripped_pdf_bytes, mut stripped_pdf_bytes) = (0, 0, 0);
 
-    for (i, url) in config.urls.iter().enumerate() {
-        pb.set_style(ProgressStyle::with_template(&format!(
-            "{{spinner}} {{bar:.cyan}} {{pos}}/{{len}} rendering {url}"
-        ))?);
+    // PDFs from a previous run which are kept and the ones written so far, new PDFs must not overwrite them
+    let mut used_paths: HashSet<PathBuf> = config
+        .resume
+        .iter()
+        .filter(|(url, _)| urls.contains(*url))
+        .map(|(_, path)| path.clone())
+        .collect();
+    let mut resumed = 0;
+    let mut page_cache = PageCache::new(config)?;
+    let resumed_pdf = |url: &str| {
+        config
+            .resume
+            .get(url)
+            .filter(|p| fs::metadata(p).is_ok_and(|m| m.len() > 0))
+            .cloned()
+    };
 
-        let tab = chrome.new_tab()?;
-        let page_pdf = tab
-            .navigate_to(url)?
-            .wait_until_navigated()?
-            .print_to_pdf(Some(config.print_to_pdf.clone()))?;
+    // A URL handed to an instance, and what came of it
+    struct Done {
+        index: usize,
+        url: String,
+        instance: Instance,
+        result: Result<Result<RenderedPage>>,
+        secs: f64,
+    }
 
-        let path = pdf_temp_dir.join(format!("{i}.pdf"));
+    // Each instance takes the next URL as soon as it is idle. Rendering blocks, so it runs on
+    // threads of its own and this loop hands out the URLs and writes the PDFs.
+    let handle = tokio::runtime::Handle::current();
+    let (done_tx, mut done_rx) = tokio::sync::mpsc::unbounded_channel::<Done>();
+    let mut idle = instances;
+    let mut in_flight = 0;
+    // URLs of a retired instance, rendered by another one before any new URL
+    let mut requeued: VecDeque<(usize, String)> = VecDeque::new();
+    let mut restart_for_memory = false;
+    let mut i = 0;
+    tokio::task::block_in_place(|| {
+        thread::scope(|s| {
+            handle.block_on(async {
+                loop {
+                    if !deadline_exceeded && deadline.is_some_and(|d| Instant::now() >= d) {
+                        note(
+                            config,
+                            &pb,
+                            format!(
+                                "render_deadline_secs passed after rendering {} of {} URLs, {} remaining",
+                                map.len(),
+                                urls.len(),
+                                requeued.len() + urls.len() - i + in_flight
+                            ),
+                        );
+                        if config.continue_on_error {
+                            failed.extend(
+                                requeued
+                                    .iter()
+                                    .map(|(_, url)| url)
+                                    .chain(urls.iter().skip(i))
+                                    .map(|url| {
+                                        format!("{url}: not rendered before render_deadline_secs")
+                                    }),
+                            );
+                        }
+                        // The pages being printed fail once their Chrome is gone
+                        if in_flight > 0 {
+                            ctrl_c.kill_chrome();
+                        }
+                        deadline_exceeded = true;
+                    }
 
-        fs::write(&path, page_pdf)?;
+                    // Sampled as each page finishes, which is when use changes the most
+                    let usage = if limited && !restart_for_memory {
+                        monitor.sample()
+                    } else {
+                        None
+                    };
+                    if let Some(usage) = usage {
+                        peak_memory_mb = peak_memory_mb.max(usage.memory_mb);
+                        peak_cpus = peak_cpus.max(usage.cpus.unwrap_or_default());
+                        let percent = usage.percent(config).unwrap_or_default();
+                        let used = match usage.cpus {
+                            Some(cpus) => format!("{} MiB, {cpus:.1} CPUs", usage.memory_mb),
+                            None => format!("{} MiB", usage.memory_mb),
+                        };
+                        if percent >= THROTTLE_PERCENT && concurrency > 1 {
+                            concurrency -= 1;
+                            throttles += 1;
+                            note(
+                                config,
+                                &pb,
+                                format!(
+                                    "Use of {used} is close to max_memory_mb or max_cpu, rendering {concurrency} pages at once"
+                                ),
+                            );
+                        } else if usage.memory_high(config) {
+                            note(
+                                config,
+                                &pb,
+                                format!(
+                                    "Memory use of {used} is close to max_memory_mb, restarting Chrome after the pages being rendered"
+                                ),
+                            );
+                            restart_for_memory = true;
+                        } else if percent < RAMP_UP_PERCENT && concurrency < instances_len {
+                            concurrency += 1;
+                            note(
+                                config,
+                                &pb,
+                                format!(
+                                    "Use of {used} has dropped, rendering {concurrency} pages at once"
+                                ),
+                            );
+                        }
+                    }
 
-        map.insert(url.clone(), path);
+                    while !idle.is_empty()
+                        && in_flight < concurrency
+                        && !deadline_exceeded
+                        && !restart_for_memory
+                    {
+                        if deadline.is_some_and(|d| Instant::now() >= d) {
+                            break;
+                        }
+                        if ctrl_c.interrupted() {
+                            break;
+                        }
 
-        pb.inc(1);
-    }
+                        let (index, url) = match requeued.pop_front() {
+                            Some(job) => job,
+                            None if i < urls.len() => {
+                                i += 1;
+                                (i - 1, urls[i - 1].clone())
+                            }
+                            None => break,
+                        };
+                        if let Some(path) = resumed_pdf(&url) {
+                            map.insert(url, path);
+                            resumed += 1;
+                            pb.inc(1);
+                            continue;
+                        }
+                        if let Some(cache) = &mut page_cache {
+                            if let Some(path) = cache.lookup(config, &url).await {
+                                used_paths.insert(path.clone());
+                                map.insert(url, path);
+                                pb.inc(1);
+                                continue;
+                            }
+                        }
+
+                        let mut instance = idle
+                            .pop()
+                            .ok_or_else(|| anyhow!("No idle Chrome instance"))?;
+                        if config
+                            .browser_recycle_every
+                            .is_some_and(|every| instance.rendered >= every)
+                        {
+                            instance.relaunch(config, &chrome_bin, &ctrl_c)?;
+                            recycles += 1;
+                        }
+                        pb.set_style(ProgressStyle::with_template(&format!(
+                            "{{spinner}} {{bar:.cyan}} {{pos}}/{{len}} ETA {{eta}} rendering {url}"
+                        ))?);
+                        in_flight += 1;
+                        peak_concurrency = peak_concurrency.max(in_flight);
+                        let (shared, handle, done_tx) = (&shared, &handle, done_tx.clone());
+                        s.spawn(move || {
+                            let started = Instant::now();
+                            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                                handle.block_on(render_with_retries(&mut instance, shared, &url))
+                            }))
+                            .unwrap_or_else(|_| Err(anyhow!("Rendering {url} panicked")));
+                            // The receiver outlives the scope, this can't fail
+                            let _ = done_tx.send(Done {
+                                index,
+                                url,
+                                instance,
+                                result,
+                                secs: started.elapsed().as_secs_f64(),
+                            });
+                        });
+                    }
+
+                    // Report it above first
+                    if !deadline_exceeded && deadline.is_some_and(|d| Instant::now() >= d) {
+                        continue;
+                    }
+                    if in_flight == 0 {
+                        if ctrl_c.interrupted() && !deadline_exceeded {
+                            return Err(RenderError::Interrupted {
+                                rendered: map.len(),
+                                total: urls.len(),
+                            }
+                            .into());
+                        }
+                        if restart_for_memory {
+                            for instance in idle.iter_mut() {
+                                instance.relaunch(config, &chrome_bin, &ctrl_c)?;
+                            }
+                            restarts += 1;
+                            restart_for_memory = false;
+
+                            let max = config.max_memory_mb.unwrap_or_default();
+                            let used = monitor.sample().unwrap_or_default().memory_mb;
+                            if used >= max {
+                                return Err(anyhow!(
+                                    "Memory use {used} MiB exceeds max_memory_mb {max} after restarting Chrome"
+                                ));
+                            }
+                            continue;
+                        }
+                        if idle.is_empty() && (!requeued.is_empty() || i < urls.len()) {
+                            return Err(anyhow!(
+                                "Every Chrome instance died more than max_chrome_relaunches allows"
+                            ));
+                        }
+                        break;
+                    }
+
+                    // Stops waiting at the deadline, e.g. for a Chrome hanging in printToPDF
+                    let received = match deadline.filter(|_| !deadline_exceeded) {
+                        Some(d) => tokio::time::timeout_at(d.into(), done_rx.recv())
+                            .await
+                            .ok(),
+                        None => Some(done_rx.recv().await),
+                    };
+                    let Some(received) = received else {
+                        continue;
+                    };
+                    let Some(Done {
+                        index,
+                        url,
+                        instance,
+                        result,
+                        secs,
+                    }) = received
+                    else {
+                        break;
+                    };
+                    in_flight -= 1;
+                    let url = &url;
+                    let retired = instance.chrome.is_none();
+                    if !retired {
+                        idle.push(instance);
+                    }
+                    let page = match result {
+                        Ok(Ok(page)) => page,
+                        // Its Chrome was killed at the deadline
+                        Ok(Err(_)) | Err(_) if deadline_exceeded => {
+                            if config.continue_on_error {
+                                failed.push(format!(
+                                    "{url}: not rendered before render_deadline_secs"
+                                ));
+                            }
+                            pb.inc(1);
+                            continue;
+                        }
+                        // Its Chrome was killed by a second Ctrl-C
+                        Ok(Err(_)) | Err(_) if ctrl_c.interrupted() => {
+                            pb.inc(1);
+                            continue;
+                        }
+                        // Another instance is still running to render it
+                        Ok(Err(_)) if retired && (!idle.is_empty() || in_flight > 0) => {
+                            note(
+                                config,
+                                &pb,
+                                format!("Rendering {url} on another Chrome instance"),
+                            );
+                            requeued.push_back((index, url.clone()));
+                            continue;
+                        }
+                        Ok(Err(e)) => {
+                            config.progress.event(&ProgressEvent::Failed {
+                                url,
+                                error: e.to_string(),
+                            });
+                            failed.push(format!("{url}: {e}"));
+                            pb.inc(1);
+                            continue;
+                        }
+                        Err(e) => {
+                            config.progress.event(&ProgressEvent::Failed {
+                                url,
+                                error: e.to_string(),
+                            });
+                            return Err(e);
+                        }
+                    };
+                    if page.opted_out {
+                        note(
+                            config,
+                            &pb,
+                            format!(
+                                "Skipping {url}, it opts out of the PDF with a pdf-export meta tag"
+                            ),
+                        );
+                        opted_out.push(url.clone());
+                        pb.inc(1);
+                        continue;
+                    }
+
+                    let slug = pdf_slug(&config.url, url);
+                    let mut path = pdf_temp_dir.join(format!("{slug}.pdf"));
+                    let mut n = 1;
+                    while used_paths.contains(&path) {
+                        path = pdf_temp_dir.join(format!("{slug}-{n}.pdf"));
+                        n += 1;
+                    }
+                    used_paths.insert(path.clone());
+
+                    if let Some(unstripped) = page.unstripped_pdf_bytes {
+                        stripped_pages += 1;
+                        unstripped_pdf_bytes += unstripped;
+                        stripped_pdf_bytes += page.pdf.len();
+                    }
+                    fs::write(&path, page.pdf)?;
+                    if let Some(cache) = &mut page_cache {
+                        cache.record(url, &path);
+                    }
+                    if let (Some(dir), Some(png)) = (&config.debug_screenshots, page.screenshot) {
+                        let png_path =
+                            dir.join(path.with_extension("png").file_name().unwrap_or_default());
+                        if let Err(e) =
+                            fs::create_dir_all(dir).and_then(|_| fs::write(&png_path, png))
+                        {
+                            note(
+                                config,
+                                &pb,
+                                format!("Unable to write {}: {e}", png_path.display()),
+                            );
+                        }
+                    }
+
+                    if let Some(scale) = page.scale {
+                        note(config, &pb, format!("Auto scaled {url} to {scale}"));
+                        scales.insert(url.clone(), scale);
+                    }
+
+                    if !page.console.is_empty() {
+                        console.insert(url.clone(), page.console);
+                    }
+                    timings.insert(url.clone(), page.timings);
+
+                    for link in page
+                        .links
+                        .iter()
+                        .filter_map(|l| crawl_link(&config.url, &config.strip_export_query(l)))
+                    {
+                        if urls.contains(&link) || config.is_excluded(&link) {
+                            continue;
+                        }
+                        if urls.len() >= config.crawl_max_pages {
+                            if !crawl_limit_hit {
+                                note(
+                                    config,
+                                    &pb,
+                                    format!(
+                                        "Reached crawl_max_pages {}, not following further links",
+                                        config.crawl_max_pages
+                                    ),
+                                );
+                                crawl_limit_hit = true;
+                            }
+                            break;
+                        }
+                        urls.insert(link);
+                    }
+                    pb.set_length(urls.len() as u64);
+
+                    map.insert(url.clone(), path);
+
+                    config.progress.event(&ProgressEvent::Rendered {
+                        url,
+                        index: index + 1,
+                        total: urls.len(),
+                        secs,
+                        timings: page.timings,
+                    });
+                    pb.inc(1);
+                }
+                Ok::<(), anyhow::Error>(())
+            })
+        })
+    })?;
+
+    // Instances finish out of order, put everything back in URL order
+    let by_url = |a: &String, b: &String| urls.get_index_of(a).cmp(&urls.get_index_of(b));
+    map.sort_by(|a, _, b, _| by_url(a, b));
+    console.sort_by(|a, _, b, _| by_url(a, b));
+    timings.sort_by(|a, _, b, _| by_url(a, b));
+    scales.sort_by(|a, _, b, _| by_url(a, b));
 
     pb.finish_with_message("Finished Rendering URLs into PDFs");
-    Ok(map)
+
+    if !config.resume.is_empty() {
+        config.progress.println(format_args!(
+            "Resumed {resumed} of {} URLs from the previous run",
+            urls.len()
+        ));
+    }
+
+    if let (Some(cache), Some(file)) = (&page_cache, &config.render_cache_file) {
+        cache.current.save(file)?;
+        config.progress.println(format_args!(
+            "Render cache: {} hits, {} misses",
+            cache.stats.hits, cache.stats.misses
+        ));
+    }
+
+    if limited {
+        let memory = match config.max_memory_mb {
+            Some(max) => format!("{peak_memory_mb} of {max} MiB"),
+            None => format!("{peak_memory_mb} MiB"),
+        };
+        let cpu = match config.max_cpu {
+            Some(max) => format!("{peak_cpus:.1} of {max} CPUs"),
+            None => format!("{peak_cpus:.1} CPUs"),
+        };
+        config.progress.println(format_args!(
+            "Peak use {memory}, {cpu}, {peak_concurrency} of {instances_len} pages at once. Rendered fewer pages at once {throttles} time(s), restarted Chrome {restarts} time(s)"
+        ));
+    }
+
+    if recycles > 0 {
+        config
+            .progress
+            .println(format_args!("Recycled Chrome {recycles} time(s)"));
+    }
+
+    let relaunches = shared.relaunches.into_inner();
+    if relaunches > 0 {
+        config.progress.println(format_args!(
+            "Relaunched Chrome {relaunches} time(s) after it died"
+        ));
+    }
+
+    if !opted_out.is_empty() {
+        config.progress.println(format_args!(
+            "Skipped {} URLs with <meta name=\"pdf-export\" content=\"false\">.\n{}",
+            opted_out.len(),
+            opted_out
+                .iter()
+                .map(|url| format!("  * {url}"))
+                .collect::<Vec<String>>()
+                .join("\n")
+        ));
+    }
+
+    if config.auto_scale.is_some() {
+        config.progress.println(format_args!(
+            "Auto scaled {} of {} URLs.{}",
+            scales.len(),
+            urls.len(),
+            scales
+                .iter()
+                .map(|(url, scale)| format!("\n  * {scale} {url}"))
+                .collect::<String>()
+        ));
+    }
+
+    if config.crawl {
+        config
+            .progress
+            .println(format_args!("Crawled {} URLs", urls.len()));
+    }
+
+    if !console.is_empty() {
+        config.progress.println(format_args!(
+            "Console errors, warnings and failed requests on {} URLs.\n{}",
+            console.len(),
+            console
+                .iter()
+                .map(|(url, messages)| format!("  * {url}\n      {}", messages.join("\n      ")))
+                .collect::<Vec<String>>()
+                .join("\n")
+        ));
+    }
+    if let Some(report) = &config.console_report {
+        fs::write(report, serde_json::to_string_pretty(&console)?)?;
+    }
+
+    if !timings.is_empty() {
+        let mut slowest: Vec<(&String, &PageTimings)> = timings.iter().collect();
+        slowest.sort_by(|a, b| b.1.total_secs.total_cmp(&a.1.total_secs));
+        config.progress.println(format_args!(
+            "Slowest pages, total (navigation / settle / print) in seconds.\n{}",
+            slowest
+                .iter()
+                .take(SLOWEST_PAGES)
+                .map(|(url, t)| format!(
+                    "  * {:.1} ({:.1} / {:.1} / {:.1}) {url}",
+                    t.total_secs, t.navigation_secs, t.settle_secs, t.print_secs
+                ))
+                .collect::<Vec<String>>()
+                .join("\n")
+        ));
+    }
+
+    if stripped_pages > 0 {
+        // Signed, stripping isn't guaranteed to make every PDF smaller
+        let saved = unstripped_pdf_bytes as i64 - stripped_pdf_bytes as i64;
+        config.progress.println(format_args!(
+            "Stripping hidden DOM made page PDFs {} KiB smaller on average, {}% of {} KiB",
+            saved / 1024 / stripped_pages,
+            saved * 100 / unstripped_pdf_bytes.max(1) as i64,
+            unstripped_pdf_bytes / 1024 / stripped_pages as usize
+        ));
+    }
+    Ok(RenderOutcome {
+        pdfs: map,
+        failed,
+        deadline_exceeded,
+        timings,
+        opted_out,
+        scales,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Cookie;
+
+    #[test]
+    fn test_chrome_paths() {
+        let linux = ChromePlatform::of("linux", "x86_64").unwrap();
+        let bin = cached_chrome_bin(Path::new("cache"), linux, "1336641");
+        assert_eq!(
+            bin,
+            Path::new("cache")
+                .join("linux-1336641")
+                .join("chrome-linux")
+                .join("chrome")
+        );
+        assert_eq!(
+            ChromePlatform::of("macos", "aarch64").unwrap().name,
+            "mac_arm"
+        );
+        assert_eq!(ChromePlatform::of("macos", "x86_64").unwrap().name, "mac");
+        assert_eq!(
+            ChromePlatform::of("windows", "x86_64").unwrap().name,
+            "win64"
+        );
+        assert_eq!(ChromePlatform::of("linux", "aarch64"), None);
+
+        assert_eq!(
+            strip_verbatim(PathBuf::from(r"\\?\C:\Users\docs\chrome")),
+            PathBuf::from(r"C:\Users\docs\chrome")
+        );
+        assert_eq!(
+            strip_verbatim(PathBuf::from(r"\\?\UNC\server\share")),
+            PathBuf::from(r"\\?\UNC\server\share")
+        );
+        assert_eq!(
+            strip_verbatim(PathBuf::from("/tmp/chrome")),
+            PathBuf::from("/tmp/chrome")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_preflight() {
+        // Answers /index.html with 200 OK and anything else with 404
+        let site = tempfile::tempdir().unwrap();
+        fs::write(site.path().join("index.html"), "<p>Page</p>").unwrap();
+        let server = crate::serve::StaticServer::start(site.path(), "").unwrap();
+        let url = server.url();
+
+        let mut config = Config {
+            urls: [format!("{url}/index.html")].into(),
+            ..Default::default()
+        };
+        preflight(&config).await.unwrap();
+
+        config.urls.insert(format!("{url}/missing.html"));
+        let err = preflight(&config).await.unwrap_err().to_string();
+        assert_eq!(
+            err,
+            format!(
+                "Preflight failed for 1 of 2 URLs.\n  * {url}/missing.html: HTTP 404 Not Found"
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn test_check_external_links() {
+        use std::io::{BufRead as _, BufReader, Write as _};
+
+        // /ok answers anything, /get only GET requests and /gone is missing
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let mut request = String::new();
+                BufReader::new(&stream).read_line(&mut request).unwrap();
+                let status = match request.split(' ').take(2).collect::<Vec<_>>()[..] {
+                    [_, "/ok"] | ["GET", "/get"] => "200 OK",
+                    [_, "/get"] => "405 Method Not Allowed",
+                    _ => "404 Not Found",
+                };
+                write!(
+                    stream,
+                    "HTTP/1.1 {status}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                )
+                .unwrap();
+            }
+        });
+
+        let links = IndexMap::from([
+            (format!("{url}/ok"), vec![1]),
+            (format!("{url}/gone"), vec![2, 5]),
+            (format!("{url}/get"), vec![3]),
+        ]);
+        let dead = check_external_links(&Config::default(), &links)
+            .await
+            .unwrap();
+        assert_eq!(
+            dead,
+            [format!("Page No. 2, 5: {url}/gone (HTTP 404 Not Found)")]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_offline() {
+        // Without Chrome builds for this platform every lookup fails the same way
+        let Ok(platform) = ChromePlatform::current() else {
+            return;
+        };
+        let cache = tempfile::tempdir().unwrap();
+        let mut config = Config {
+            chrome_cache: cache.path().join("typo"),
+            offline: true,
+            ..Default::default()
+        };
+        let err = get_chrome(&config).await.unwrap_err().to_string();
+        assert!(err.contains("set chrome_version"), "{err}");
+
+        // Without a network the revision lookup fails as a Chrome download, not the site
+        let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        config.chrome_versions_url = Some(format!("http://{}/", closed.local_addr().unwrap()));
+        drop(closed);
+        config.offline = false;
+        let err = get_chrome(&config).await.unwrap_err().to_string();
+        assert!(err.starts_with("Unable to download Chrome"), "{err}");
+        assert!(err.contains("render without downloading Chrome"), "{err}");
+        config.offline = true;
+
+        config.chrome_version = Some("1336641".to_string());
+        let err = get_chrome(&config).await.unwrap_err().to_string();
+        assert!(err.contains("1336641 is not cached"), "{err}");
+        assert!(!config.chrome_cache.exists());
+
+        // A half extracted revision isn't a cached Chrome
+        config.chrome_cache = cache.path().to_path_buf();
+        fs::create_dir_all(cached_revision_dir(cache.path(), platform, "1336641")).unwrap();
+        let err = get_chrome(&config).await.unwrap_err().to_string();
+        assert!(err.contains("1336641 is not cached"), "{err}");
+    }
+
+    // The class is on `<html>` by the time the page is printed: with it the export only block
+    // starts a second page
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore = "needs Chrome, which may be downloaded"]
+    async fn test_export_class() {
+        let site = tempfile::tempdir().unwrap();
+        fs::write(
+            site.path().join("index.html"),
+            "<html><head><style>\
+            .export { display: none; break-before: page; } \
+            html.pdf-export .export { display: block; }\
+            </style></head><body><p>Page</p><p class=\"export\">Export</p></body></html>",
+        )
+        .unwrap();
+        let server = crate::serve::StaticServer::start(site.path(), "").unwrap();
+        let url = format!("{}/index.html", server.url());
+
+        let mut config = Config::default();
+        let chrome = get_chrome(&config).await.unwrap();
+        let pages = |config: &Config| {
+            let page = render_url(&chrome, config, &url).unwrap();
+            lopdf::Document::load_mem(&page.pdf)
+                .unwrap()
+                .get_pages()
+                .len()
+        };
+        assert_eq!(pages(&config), 1);
+        config.export_class = Some("pdf-export".to_string());
+        assert_eq!(pages(&config), 2);
+    }
+
+    #[test]
+    fn test_printable_width() {
+        // US Letter with 1cm margins
+        let letter = PrintToPdfOptions::default();
+        assert!((printable_width_px(&letter) - (8.5 - 0.7874) * 96.0).abs() < 0.01);
+        // Landscape prints across the paper's height
+        let landscape = PrintToPdfOptions {
+            landscape: Some(true),
+            ..Default::default()
+        };
+        assert!((printable_width_px(&landscape) - (11.0 - 0.7874) * 96.0).abs() < 0.01);
+        let a4 = PrintToPdfOptions {
+            landscape: Some(true),
+            paper_width: Some(8.27),
+            paper_height: Some(11.69),
+            margin_left: Some(0.5),
+            margin_right: Some(0.5),
+            ..Default::default()
+        };
+        assert!((printable_width_px(&a4) - 10.69 * 96.0).abs() < 0.01);
+    }
+
+    // Each page's tab is closed once it is printed, so Chrome's memory stays flat
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore = "needs Chrome, which may be downloaded"]
+    async fn test_render_url_closes_tabs() {
+        let site = tempfile::tempdir().unwrap();
+        for n in 0..5 {
+            fs::write(site.path().join(format!("{n}.html")), format!("<p>{n}</p>")).unwrap();
+        }
+        let server = crate::serve::StaticServer::start(site.path(), "").unwrap();
+
+        let config = Config::default();
+        let chrome = get_chrome(&config).await.unwrap();
+        let targets = || chrome.get_tabs().lock().unwrap().len();
+        let before = targets();
+        for n in 0..5 {
+            render_url(&chrome, &config, &format!("{}/{n}.html", server.url())).unwrap();
+        }
+        // Closed tabs leave the list once Chrome reports them destroyed
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while targets() > before && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        assert_eq!(targets(), before);
+    }
+
+    // A failing URL ends the run with Chrome shut down. Chrome doesn't pass unknown switches on
+    // to its helpers, so the marker finds the browser process, which holds the profile lock.
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    #[ignore = "needs Chrome, which may be downloaded"]
+    async fn test_failed_render_stops_chrome() {
+        // Nothing listens on the port once the listener is dropped
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/index.html", listener.local_addr().unwrap());
+        drop(listener);
+
+        let marker_dir = tempfile::tempdir().unwrap();
+        let marker = format!(
+            "--vitepress-pdf-export-test={}",
+            marker_dir.path().display()
+        );
+        let config = Config {
+            urls: IndexSet::from([url]),
+            browser_instances: 1,
+            chrome_args: vec![marker.clone()],
+            ..Default::default()
+        };
+        let pdf_temp_dir = tempfile::tempdir().unwrap();
+        assert!(render_urls(&config, pdf_temp_dir.path()).await.is_err());
+
+        let running = fs::read_dir("/proc")
+            .unwrap()
+            .flatten()
+            .filter(|entry| {
+                fs::read(entry.path().join("cmdline"))
+                    .is_ok_and(|cmdline| String::from_utf8_lossy(&cmdline).contains(&marker))
+            })
+            .count();
+        assert_eq!(running, 0);
+    }
+
+    #[tokio::test]
+    async fn test_clear_chrome() {
+        let ctrl_c = CtrlC::new(2);
+        ctrl_c.chrome_pids[1].store(4242, Ordering::SeqCst);
+        ctrl_c.clear_chrome(1);
+        assert_eq!(ctrl_c.chrome_pids[1].load(Ordering::SeqCst), 0);
+    }
+
+    // Stripping removes the scripts and hidden nodes without moving anything visible, and is
+    // undone when it would
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore = "needs Chrome, which may be downloaded"]
+    async fn test_strip_hidden_dom() {
+        let site = tempfile::tempdir().unwrap();
+        fs::write(
+            site.path().join("index.html"),
+            r#"<html><body>
+<script>window.__VP_SEARCH_INDEX__ = {"guide": "setup"};</script>
+<div class="VPLocalSearchBox" style="display: none">Search</div>
+<div aria-hidden="true" style="display: none">Overlay</div>
+<p aria-hidden="true">Decoration</p>
+<p class="page">Page</p>
+<p class="banner">Banner</p>
+</body></html>"#,
+        )
+        .unwrap();
+        // A visible script, removing it moves the page up
+        fs::write(
+            site.path().join("shift.html"),
+            r#"<html><body>
+<style>script { display: block; }</style><script>/* Shown */</script>
+<p class="page">Page</p>
+<p class="banner">Banner</p>
+</body></html>"#,
+        )
+        .unwrap();
+        let server = crate::serve::StaticServer::start(site.path(), "").unwrap();
+
+        let chrome = get_chrome(&Config::default()).await.unwrap();
+        let tab = chrome.new_tab().unwrap();
+        let eval = |js: &str| tab.evaluate(js, false).unwrap().value.unwrap();
+        let layout = "JSON.stringify(document.querySelector('.page').getBoundingClientRect())";
+        let strip = format!("{STRIP_HIDDEN_DOM_JS}(['.banner'])");
+        let count = |selector: &str| {
+            eval(&format!("document.querySelectorAll(\"{selector}\").length")).as_u64()
+        };
+
+        tab.navigate_to(&format!("{}/index.html", server.url()))
+            .unwrap()
+            .wait_until_navigated()
+            .unwrap();
+        let before = eval(layout);
+        // The script, search box, overlay and banner, the visible decoration is kept
+        assert_eq!(eval(&strip).as_i64(), Some(4));
+        assert_eq!(eval(layout), before);
+        assert_eq!(count("script"), Some(0));
+        assert_eq!(count("[aria-hidden]"), Some(1));
+        assert_eq!(count(".banner"), Some(0));
+
+        tab.navigate_to(&format!("{}/shift.html", server.url()))
+            .unwrap()
+            .wait_until_navigated()
+            .unwrap();
+        let before = eval(layout);
+        assert_eq!(eval(&strip).as_i64(), Some(-1));
+        assert_eq!(eval(layout), before);
+        assert_eq!(count("script"), Some(1));
+        assert_eq!(count(".banner"), Some(0));
+    }
+
+    // `tokio::test` runs on a current_thread runtime, where blocking a worker panics
+    #[tokio::test]
+    #[ignore = "needs Chrome, which may be downloaded"]
+    async fn test_render_urls_current_thread() {
+        let site = tempfile::tempdir().unwrap();
+        fs::write(site.path().join("index.html"), "<p>Page</p>").unwrap();
+        let server = crate::serve::StaticServer::start(site.path(), "").unwrap();
+        let url = format!("{}/index.html", server.url());
+        let config = Config {
+            urls: IndexSet::from([url.clone()]),
+            browser_instances: 1,
+            ..Default::default()
+        };
+        let pdf_temp_dir = tempfile::tempdir().unwrap();
+        let outcome = render_urls(&config, pdf_temp_dir.path()).await.unwrap();
+        assert!(outcome.pdfs[&url].exists());
+    }
+
+    #[tokio::test]
+    async fn test_throttle() {
+        let config = Config {
+            min_interval_ms: 50,
+            ..Default::default()
+        };
+        let (ctrl_c, pb) = (CtrlC::new(1), ProgressBar::hidden());
+        let shared = Shared {
+            config: &config,
+            chrome_bin: Path::new("chrome"),
+            ctrl_c: &ctrl_c,
+            pb: &pb,
+            relaunches: AtomicU32::new(0),
+            next_navigation: Mutex::new(None),
+        };
+        let start = Instant::now();
+        shared.throttle().await;
+        assert!(start.elapsed() < Duration::from_millis(50));
+        shared.throttle().await;
+        shared.throttle().await;
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn test_render_options() {
+        let mut config = Config::default();
+        let url = "http://localhost:5173/guide.html";
+        let options = render_options(&config, url);
+        config.print_to_pdf.footer_template = Some("<span class=pageNumber></span>".to_string());
+        let footer = render_options(&config, url);
+        assert_ne!(options, footer);
+        config.timezone = Some("Europe/Berlin".to_string());
+        assert_ne!(footer, render_options(&config, url));
+        let timezone = render_options(&config, url);
+        config.export_class = Some("pdf-export".to_string());
+        assert_ne!(timezone, render_options(&config, url));
+        let export_class = render_options(&config, url);
+        config.injected_css = "h1 { color: red }".to_string();
+        assert_ne!(export_class, render_options(&config, url));
+
+        // Each of these changes what the page looks like when printed
+        let mut previous = render_options(&config, url);
+        let changes: [fn(&mut Config); 8] = [
+            |c| c.export_query = Some("pdf-export=1".to_string()),
+            |c| c.wait_for_selector = Some("#app".to_string()),
+            |c| c.wait_for_fonts = !c.wait_for_fonts,
+            |c| c.settle = toml::from_str("delay_ms = 500").ok(),
+            |c| c.headless_mode = HeadlessMode::New,
+            |c| c.user_agent = Some("docs-export".to_string()),
+            |c| {
+                c.cookies.push(Cookie {
+                    name: "session".to_string(),
+                    value: "1".to_string(),
+                    domain: Some("localhost".to_string()),
+                    path: "/".to_string(),
+                    secure: false,
+                });
+            },
+            |c| {
+                c.extra_headers
+                    .insert("X-Export".to_string(), "1".to_string());
+            },
+        ];
+        for change in changes {
+            change(&mut config);
+            let options = render_options(&config, url);
+            assert_ne!(previous, options);
+            previous = options;
+        }
+    }
+
+    #[test]
+    fn test_cookie_header() {
+        let mut config = Config::default();
+        let url = "https://docs.example.com/guide/setup.html";
+        assert_eq!(cookie_header(&config, url), None);
+        for (name, domain, path, secure) in [
+            ("session", ".example.com", "/", true),
+            ("lang", "docs.example.com", "/guide", false),
+            ("other", "example.org", "/", false),
+            ("api", "docs.example.com", "/api", false),
+        ] {
+            config.cookies.push(Cookie {
+                name: name.to_string(),
+                value: "1".to_string(),
+                domain: Some(domain.to_string()),
+                path: path.to_string(),
+                secure,
+            });
+        }
+        assert_eq!(
+            cookie_header(&config, url).as_deref(),
+            Some("session=1; lang=1")
+        );
+        assert_eq!(
+            cookie_header(&config, "http://docs.example.com/guide/").as_deref(),
+            Some("lang=1")
+        );
+    }
+
+    #[test]
+    fn test_launch_args() {
+        let mut config = Config::default();
+        assert_eq!(
+            launch_args(&config),
+            vec!["--generate-pdf-document-outline"]
+        );
+
+        config.proxy = Some("http://proxy.corp:3128".to_string());
+        config.no_proxy = vec!["localhost".to_string(), "*.corp".to_string()];
+        config.chrome_args = vec!["--no-sandbox".to_string(), "--lang=de DE".to_string()];
+        assert_eq!(
+            launch_args(&config),
+            vec![
+                "--generate-pdf-document-outline",
+                "--proxy-server=http://proxy.corp:3128",
+                "--proxy-bypass-list=localhost;*.corp",
+                "--no-sandbox",
+                "--lang=de DE",
+            ]
+        );
+
+        config.headless_mode = HeadlessMode::New;
+        config.insecure_tls = true;
+        assert_eq!(
+            launch_args(&config)[..2],
+            ["--generate-pdf-document-outline", "--headless=new"]
+        );
+        assert!(launch_args(&config).contains(&"--ignore-certificate-errors".into()));
+
+        let e = navigation_error(
+            &config,
+            "http://example.com",
+            anyhow!("net::ERR_PROXY_CONNECTION_FAILED"),
+        );
+        assert!(e
+            .to_string()
+            .starts_with("Proxy connection to http://proxy.corp:3128 failed"));
+        let e = navigation_error(
+            &config,
+            "http://example.com",
+            anyhow!("net::ERR_NAME_NOT_RESOLVED"),
+        );
+        assert!(e
+            .to_string()
+            .starts_with("Navigation to http://example.com"));
+    }
+
+    #[test]
+    fn test_crawl_link() {
+        let base = "http://localhost:5173";
+        assert_eq!(
+            crawl_link(base, "http://localhost:5173/guide/setup#install").as_deref(),
+            Some("http://localhost:5173/guide/setup.html")
+        );
+        assert_eq!(
+            crawl_link(base, "http://localhost:5173/guide/?q=1").as_deref(),
+            Some("http://localhost:5173/guide/index.html")
+        );
+        assert_eq!(
+            crawl_link(base, "http://localhost:5173").as_deref(),
+            Some("http://localhost:5173/index.html")
+        );
+        assert_eq!(
+            crawl_link(base, "http://localhost:5173/api.html").as_deref(),
+            Some("http://localhost:5173/api.html")
+        );
+        assert_eq!(crawl_link(base, "http://localhost:5173/logo.svg"), None);
+        assert_eq!(crawl_link(base, "http://localhost:51730/other"), None);
+        assert_eq!(crawl_link(base, "https://example.com/guide"), None);
+    }
+
+    #[test]
+    fn test_pdf_slug() {
+        let base = "http://localhost:5173/docs";
+        assert_eq!(
+            pdf_slug(
+                base,
+                "http://localhost:5173/docs/guide/getting-started.html"
+            ),
+            "guide-getting-started.html"
+        );
+        assert_eq!(
+            pdf_slug(base, "http://localhost:5173/docs/a/b/c/index.html"),
+            "a-b-c-index.html"
+        );
+        assert_eq!(pdf_slug(base, "http://localhost:5173/docs/"), "index");
+        assert_eq!(
+            pdf_slug(
+                base,
+                "http://localhost:5173/docs/ja/%E3%82%AC%E3%82%A4%E3%83%89.html"
+            ),
+            "ja-ガイド.html"
+        );
+        assert_eq!(
+            pdf_slug(base, "http://localhost:5173/docs/ü/straße.html"),
+            "ü-straße.html"
+        );
+        assert_eq!(
+            pdf_slug(base, "http://localhost:5173/docs/a:b*c.html?x=1#y"),
+            "a_b_c.html"
+        );
+        assert_eq!(
+            pdf_slug(base, "http://localhost:5173/docs/con.html"),
+            "_con.html"
+        );
+        assert_eq!(
+            pdf_slug(base, "http://localhost:5173/docs/guide./"),
+            "guide"
+        );
+        // Outside of base the whole URL is used
+        assert_eq!(
+            pdf_slug(base, "http://example.com/x.html"),
+            "http_--example.com-x.html"
+        );
+        let long = format!("{base}/{}.html", "é".repeat(200));
+        let slug = pdf_slug(base, &long);
+        assert!(slug.len() <= MAX_SLUG_BYTES);
+        assert_eq!(slug, pdf_slug(base, &long));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_process_memory() {
+        let status = "Name:\tchrome\nPPid:\t4242\nVmRSS:\t  204800 kB\n";
+        assert_eq!(parse_proc_status(status), (4242, 204800));
+        assert_eq!(parse_proc_status("Name:\tkthreadd\nPPid:\t0\n"), (0, 0));
+
+        let stat = "4242 (Web Content) S 1 4242 4242 0 -1 4194560 5 0 0 0 120 30 0 0 20 0";
+        assert_eq!(parse_proc_stat_ticks(stat), Some(150));
+        assert_eq!(parse_proc_stat_ticks("4242 (chrome) S 1"), None);
+
+        assert!(process_tree_usage(std::process::id()).unwrap().0 > 0);
+        let mut monitor = ResourceMonitor::new();
+        assert_eq!(monitor.sample().unwrap().cpus, None);
+        assert!(monitor.sample().unwrap().cpus.is_some());
+    }
+
+    #[test]
+    fn test_usage_percent() {
+        let mut config = Config::default();
+        let usage = Usage {
+            memory_mb: 900,
+            cpus: Some(1.5),
+        };
+        assert_eq!(usage.percent(&config), None);
+        config.max_memory_mb = Some(1000);
+        assert_eq!(usage.percent(&config), Some(90));
+        assert!(usage.memory_high(&config));
+        config.max_cpu = Some(1.0);
+        assert_eq!(usage.percent(&config), Some(150));
+        config.max_memory_mb = None;
+        assert!(!usage.memory_high(&config));
+        let first = Usage {
+            cpus: None,
+            ..usage
+        };
+        assert_eq!(first.percent(&config), None);
+    }
 }
@@ -0,0 +1,97 @@
+// Copyright (c) 2024 DDN. All rights reserved.
+// Use of this source code is governed by a MIT-style
+// license that can be found in the LICENSE file.
+
+use anyhow::{anyhow, Result};
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::hash_map::DefaultHasher,
+    fs,
+    hash::{Hash, Hasher},
+    path::{Path, PathBuf},
+};
+
+/// PDFs rendered by previous runs, see `render_cache`.
+///
+/// Entries are keyed by the URL path so a new `serve_dir` port still hits, and are reused
+/// while the hash of the page's HTML and render options matches. The hash uses the standard
+/// library's hasher, a new Rust version may change it which only costs a full render.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct RenderCache {
+    pages: IndexMap<String, RenderCacheEntry>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct RenderCacheEntry {
+    hash: String,
+    pdf: PathBuf,
+}
+
+impl RenderCache {
+    /// Read `path`, a missing or unreadable cache is empty
+    pub fn load(path: &Path) -> Self {
+        fs::read(path)
+            .ok()
+            .and_then(|json| serde_json::from_slice(&json).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        fs::write(path, serde_json::to_string_pretty(self)?)
+            .map_err(|e| anyhow!("Unable to write render cache {}: {e}", path.display()))
+    }
+
+    /// The PDF cached for `page` with `hash`, if it is still there
+    pub fn get(&self, page: &str, hash: &str) -> Option<&PathBuf> {
+        self.pages
+            .get(page)
+            .filter(|entry| entry.hash == hash)
+            .map(|entry| &entry.pdf)
+            .filter(|pdf| fs::metadata(pdf).is_ok_and(|m| m.len() > 0))
+    }
+
+    pub fn insert(&mut self, page: &str, hash: String, pdf: PathBuf) {
+        self.pages
+            .insert(page.to_string(), RenderCacheEntry { hash, pdf });
+    }
+}
+
+/// Hash of a page's HTML and the options it is rendered with
+pub fn content_hash(html: &[u8], options: &str) -> String {
+    let mut hasher = DefaultHasher::new();
+    html.hash(&mut hasher);
+    options.hash(&mut hasher);
+    format!("{:016x}", hasher.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let pdf = dir.path().join("guide.html.pdf");
+        fs::write(&pdf, b"%PDF-1.4").unwrap();
+        let hash = content_hash(b"<h1>Guide</h1>", "scale: 1");
+        assert_ne!(hash, content_hash(b"<h1>Guide</h1>", "scale: 0.9"));
+        assert_ne!(hash, content_hash(b"<h1>Guide!</h1>", "scale: 1"));
+
+        let path = dir.path().join("cache.json");
+        let mut cache = RenderCache::load(&path);
+        assert_eq!(cache.get("/guide.html", &hash), None);
+        cache.insert("/guide.html", hash.clone(), pdf.clone());
+        cache.insert("/gone.html", hash.clone(), dir.path().join("gone.pdf"));
+        cache.save(&path).unwrap();
+
+        let cache = RenderCache::load(&path);
+        assert_eq!(cache.get("/guide.html", &hash), Some(&pdf));
+        assert_eq!(cache.get("/guide.html", "0000000000000000"), None);
+        // The PDF was deleted since
+        assert_eq!(cache.get("/gone.html", &hash), None);
+
+        fs::write(&path, "{").unwrap();
+        assert_eq!(RenderCache::load(&path).get("/guide.html", &hash), None);
+    }
+}
@@ -0,0 +1,228 @@
+// Copyright (c) 2024 DDN. All rights reserved.
+// Use of this source code is governed by a MIT-style
+// license that can be found in the LICENSE file.
+
+use anyhow::Result;
+use percent_encoding::percent_decode_str;
+use std::{
+    fs,
+    io::{BufRead as _, BufReader, Write as _},
+    net::{SocketAddr, TcpListener, TcpStream},
+    path::{Component, Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+};
+
+/// Static file server for a built VitePress site, e.g. `docs/.vitepress/dist`.
+///
+/// Files are resolved like `vitepress preview` does: `/guide/setup` is served from
+/// `guide/setup`, `guide/setup.html` or `guide/setup/index.html` and missing files get
+/// `404.html` with a 404 status. The server listens on a random localhost port and is
+/// shut down when dropped.
+pub struct StaticServer {
+    url: String,
+    addr: SocketAddr,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl StaticServer {
+    /// Serve `root` below the `base` path of the site, e.g. `/docs` or an empty string.
+    pub fn start(root: &Path, base: &str) -> Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let root = root.canonicalize()?;
+        let base = base.trim_end_matches('/').to_string();
+
+        let thread = thread::spawn({
+            let stop = stop.clone();
+            move || {
+                for stream in listener.incoming() {
+                    if stop.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    let Ok(stream) = stream else {
+                        continue;
+                    };
+                    let (root, base) = (root.clone(), base.clone());
+                    // Chrome loads a page's assets over several connections at once
+                    thread::spawn(move || {
+                        let _ = handle(stream, &root, &base);
+                    });
+                }
+            }
+        });
+
+        Ok(StaticServer {
+            url: format!("http://{addr}"),
+            addr,
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    /// The server's origin, e.g. `http://127.0.0.1:40123`
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+}
+
+impl Drop for StaticServer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        // Wake up the accept loop so it sees the stop flag
+        let _ = TcpStream::connect(self.addr);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+// Find the file serving the decoded request `path`, `None` if there isn't one
+fn resolve(root: &Path, path: &str) -> Option<PathBuf> {
+    let relative = Path::new(path.trim_start_matches('/'));
+    // Never serve anything outside of root
+    if relative
+        .components()
+        .any(|c| !matches!(c, Component::Normal(_)))
+    {
+        return None;
+    }
+    let file = root.join(relative);
+    let candidates = if path.ends_with('/') {
+        vec![file.join("index.html")]
+    } else {
+        let mut html = file.clone().into_os_string();
+        html.push(".html");
+        vec![file.clone(), PathBuf::from(html), file.join("index.html")]
+    };
+    candidates.into_iter().find(|f| f.is_file())
+}
+
+fn content_type(path: &Path) -> &'static str {
+    match path
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or_default()
+    {
+        "html" => "text/html; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "json" => "application/json",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "ico" => "image/x-icon",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ttf" => "font/ttf",
+        "txt" => "text/plain; charset=utf-8",
+        "xml" => "application/xml",
+        "wasm" => "application/wasm",
+        _ => "application/octet-stream",
+    }
+}
+
+fn handle(mut stream: TcpStream, root: &Path, base: &str) -> Result<()> {
+    let mut reader = BufReader::new(&stream);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    // Skip the headers
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 2 {
+        line.clear();
+    }
+
+    let mut parts = request.split_whitespace();
+    let method = parts.next().unwrap_or_default();
+    let target = parts.next().unwrap_or_default();
+    let path = target.split(['?', '#']).next().unwrap_or_default();
+    let path = percent_decode_str(path).decode_utf8_lossy();
+
+    let (status, file) = if method != "GET" && method != "HEAD" {
+        ("405 Method Not Allowed", None)
+    } else {
+        match path
+            .strip_prefix(base)
+            .filter(|p| p.is_empty() || p.starts_with('/'))
+            .and_then(|p| resolve(root, if p.is_empty() { "/" } else { p }))
+        {
+            Some(file) => ("200 OK", Some(file)),
+            None => ("404 Not Found", resolve(root, "/404.html")),
+        }
+    };
+
+    let (content_type, body) = match file {
+        Some(file) => (content_type(&file), fs::read(&file)?),
+        None => ("text/plain; charset=utf-8", status.as_bytes().to_vec()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    )?;
+    if method != "HEAD" {
+        stream.write_all(&body)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read as _;
+
+    fn get(server: &StaticServer, path: &str) -> String {
+        let mut stream = TcpStream::connect(server.addr).unwrap();
+        write!(stream, "GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn test_static_server() {
+        let dist = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dist.path().join("guide")).unwrap();
+        fs::create_dir_all(dist.path().join("assets")).unwrap();
+        fs::write(dist.path().join("index.html"), "home").unwrap();
+        fs::write(dist.path().join("404.html"), "not found").unwrap();
+        fs::write(dist.path().join("guide/setup.html"), "setup").unwrap();
+        fs::write(dist.path().join("guide/index.html"), "guide").unwrap();
+        fs::write(dist.path().join("assets/app.js"), "app").unwrap();
+
+        assert_eq!(resolve(dist.path(), "/guide/../index.html"), None);
+        assert_eq!(
+            resolve(dist.path(), "/guide/setup"),
+            Some(dist.path().join("guide/setup.html"))
+        );
+        assert_eq!(
+            resolve(dist.path(), "/guide"),
+            Some(dist.path().join("guide/index.html"))
+        );
+
+        let server = StaticServer::start(dist.path(), "/docs").unwrap();
+        let response = get(&server, "/docs/guide/setup.html?v=1");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        assert!(response.contains("Content-Type: text/html; charset=utf-8\r\n"));
+        assert!(response.ends_with("\r\n\r\nsetup"));
+
+        assert!(get(&server, "/docs/guide/").ends_with("guide"));
+        assert!(get(&server, "/docs").ends_with("home"));
+        assert!(get(&server, "/docs/assets/app.js").contains("text/javascript"));
+
+        let response = get(&server, "/docs/missing");
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        assert!(response.ends_with("not found"));
+        assert!(get(&server, "/guide/setup.html").starts_with("HTTP/1.1 404"));
+        assert!(get(&server, "/docs/%2e%2e/secret").starts_with("HTTP/1.1 404"));
+
+        drop(server);
+    }
+}
@@ -0,0 +1,218 @@
+// Copyright (c) 2024 DDN. All rights reserved.
+// Use of this source code is governed by a MIT-style
+// license that can be found in the LICENSE file.
+
+use lopdf::{Object, StringFormat};
+
+// PDFDocEncoding code points which differ from Latin-1, see PDF 32000-1:2008 Annex D.2.
+// 0x7F, 0x9F and 0xAD are undefined.
+const PDF_DOC_DIFFERENCES: [(u8, char); 41] = [
+    (0x18, '\u{02D8}'),
+    (0x19, '\u{02C7}'),
+    (0x1A, '\u{02C6}'),
+    (0x1B, '\u{02D9}'),
+    (0x1C, '\u{02DD}'),
+    (0x1D, '\u{02DB}'),
+    (0x1E, '\u{02DA}'),
+    (0x1F, '\u{02DC}'),
+    (0x80, '\u{2022}'),
+    (0x81, '\u{2020}'),
+    (0x82, '\u{2021}'),
+    (0x83, '\u{2026}'),
+    (0x84, '\u{2014}'),
+    (0x85, '\u{2013}'),
+    (0x86, '\u{0192}'),
+    (0x87, '\u{2044}'),
+    (0x88, '\u{2039}'),
+    (0x89, '\u{203A}'),
+    (0x8A, '\u{2212}'),
+    (0x8B, '\u{2030}'),
+    (0x8C, '\u{201E}'),
+    (0x8D, '\u{201C}'),
+    (0x8E, '\u{201D}'),
+    (0x8F, '\u{2018}'),
+    (0x90, '\u{2019}'),
+    (0x91, '\u{201A}'),
+    (0x92, '\u{2122}'),
+    (0x93, '\u{FB01}'),
+    (0x94, '\u{FB02}'),
+    (0x95, '\u{0141}'),
+    (0x96, '\u{0152}'),
+    (0x97, '\u{0160}'),
+    (0x98, '\u{0178}'),
+    (0x99, '\u{017D}'),
+    (0x9A, '\u{0131}'),
+    (0x9B, '\u{0142}'),
+    (0x9C, '\u{0153}'),
+    (0x9D, '\u{0161}'),
+    (0x9E, '\u{017E}'),
+    (0xA0, '\u{20AC}'),
+    (0xAD, '\u{FFFD}'),
+];
+
+fn pdf_doc_to_char(b: u8) -> char {
+    match PDF_DOC_DIFFERENCES.iter().find(|(code, _)| *code == b) {
+        Some((_, c)) => *c,
+        None if b == 0x7F || b == 0x9F => '\u{FFFD}',
+        None => b as char,
+    }
+}
+
+fn char_to_pdf_doc(c: char) -> Option<u8> {
+    if let Some((code, _)) = PDF_DOC_DIFFERENCES
+        .iter()
+        .find(|(code, d)| *d == c && *code != 0xAD)
+    {
+        return Some(*code);
+    }
+    match c as u32 {
+        // Only tab, line feed and carriage return are defined below 0x18
+        0x09 | 0x0A | 0x0D | 0x20..=0x7E | 0xA1..=0xFF if c != '\u{AD}' => Some(c as u8),
+        _ => None,
+    }
+}
+
+// WinAnsiEncoding code points between 0x80 and 0x9F, the rest of the range is undefined. Codes
+// from 0xA0 up are Latin-1, see PDF 32000-1:2008 Annex D.2.
+const WIN_ANSI_DIFFERENCES: [(u8, char); 27] = [
+    (0x80, '\u{20AC}'),
+    (0x82, '\u{201A}'),
+    (0x83, '\u{0192}'),
+    (0x84, '\u{201E}'),
+    (0x85, '\u{2026}'),
+    (0x86, '\u{2020}'),
+    (0x87, '\u{2021}'),
+    (0x88, '\u{02C6}'),
+    (0x89, '\u{2030}'),
+    (0x8A, '\u{0160}'),
+    (0x8B, '\u{2039}'),
+    (0x8C, '\u{0152}'),
+    (0x8E, '\u{017D}'),
+    (0x91, '\u{2018}'),
+    (0x92, '\u{2019}'),
+    (0x93, '\u{201C}'),
+    (0x94, '\u{201D}'),
+    (0x95, '\u{2022}'),
+    (0x96, '\u{2013}'),
+    (0x97, '\u{2014}'),
+    (0x98, '\u{02DC}'),
+    (0x99, '\u{2122}'),
+    (0x9A, '\u{0161}'),
+    (0x9B, '\u{203A}'),
+    (0x9C, '\u{0153}'),
+    (0x9E, '\u{017E}'),
+    (0x9F, '\u{0178}'),
+];
+
+/// Code of `c` in WinAnsiEncoding, if it has one
+pub fn char_to_win_ansi(c: char) -> Option<u8> {
+    match c as u32 {
+        0x20..=0x7E | 0xA0..=0xFF => Some(c as u8),
+        _ => WIN_ANSI_DIFFERENCES
+            .iter()
+            .find(|(_, d)| *d == c)
+            .map(|(code, _)| *code),
+    }
+}
+
+/// Character shown by `b` in WinAnsiEncoding, if it's defined
+pub fn win_ansi_to_char(b: u8) -> Option<char> {
+    match b {
+        0x20..=0x7E | 0xA0..=0xFF => Some(b as char),
+        _ => WIN_ANSI_DIFFERENCES
+            .iter()
+            .find(|(code, _)| *code == b)
+            .map(|(_, c)| *c),
+    }
+}
+
+/// Encode `s` as a PDF text string, PDFDocEncoding when lossless otherwise UTF-16BE with a BOM
+pub fn encode_text_string(s: &str) -> Object {
+    if let Some(bytes) = s.chars().map(char_to_pdf_doc).collect::<Option<Vec<u8>>>() {
+        // Avoid bytes `decode_text_string` would mistake for UTF-8
+        if bytes.is_ascii() || std::str::from_utf8(&bytes).is_err() {
+            return Object::String(bytes, StringFormat::Literal);
+        }
+    }
+    let mut bytes = vec![0xFE, 0xFF];
+    for unit in s.encode_utf16() {
+        bytes.extend_from_slice(&unit.to_be_bytes());
+    }
+    Object::String(bytes, StringFormat::Hexadecimal)
+}
+
+/// Decode the bytes of a PDF text string, UTF-16BE or UTF-8 with a BOM, otherwise PDFDocEncoding.
+///
+/// Some producers write UTF-8 without a BOM, so non-ASCII bytes which are valid UTF-8 are read as UTF-8.
+pub fn decode_text_string(bytes: &[u8]) -> String {
+    if let Some(utf16) = bytes.strip_prefix(&[0xFE, 0xFF]) {
+        let units: Vec<u16> = utf16
+            .chunks_exact(2)
+            .map(|c| u16::from_be_bytes([c[0], c[1]]))
+            .collect();
+        String::from_utf16_lossy(&units)
+    } else if let Some(utf8) = bytes.strip_prefix(&[0xEF, 0xBB, 0xBF]) {
+        String::from_utf8_lossy(utf8).to_string()
+    } else if let Ok(utf8) = std::str::from_utf8(bytes) {
+        utf8.to_string()
+    } else {
+        bytes.iter().map(|b| pdf_doc_to_char(*b)).collect()
+    }
+}
+
+/// `s` on one line, with each run of whitespace collapsed to a single space
+pub fn one_line(s: &str) -> String {
+    s.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_text_strings() {
+        for s in [
+            "Install",
+            "Café – “quoted”",
+            "é…‘",
+            "日本語の見出し",
+            "Rocket 🚀",
+            "",
+        ] {
+            let Object::String(bytes, _) = encode_text_string(s) else {
+                panic!("Not a string");
+            };
+            assert_eq!(decode_text_string(&bytes), s);
+        }
+
+        let Object::String(bytes, _) = encode_text_string("Café") else {
+            panic!("Not a string");
+        };
+        assert_eq!(bytes, b"Caf\xe9");
+        let Object::String(bytes, _) = encode_text_string("日本") else {
+            panic!("Not a string");
+        };
+        assert_eq!(bytes, vec![0xFE, 0xFF, 0x65, 0xE5, 0x67, 0x2C]);
+
+        // UTF-8 without a BOM
+        assert_eq!(decode_text_string("見出し".as_bytes()), "見出し");
+
+        assert_eq!(one_line("Caf\u{e9}\n  menu "), "Caf\u{e9} menu");
+    }
+
+    #[test]
+    fn test_win_ansi() {
+        assert_eq!(char_to_win_ansi('A'), Some(b'A'));
+        assert_eq!(char_to_win_ansi('é'), Some(0xE9));
+        assert_eq!(char_to_win_ansi('—'), Some(0x97));
+        assert_eq!(char_to_win_ansi('€'), Some(0x80));
+        assert_eq!(char_to_win_ansi('日'), None);
+        assert_eq!(char_to_win_ansi('\n'), None);
+        for b in 0..=255 {
+            if let Some(c) = win_ansi_to_char(b) {
+                assert_eq!(char_to_win_ansi(c), Some(b));
+            }
+        }
+        assert_eq!(win_ansi_to_char(0x81), None);
+    }
+}
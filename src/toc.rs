@@ -0,0 +1,283 @@
+// Copyright (c) 2024 DDN. All rights reserved.
+// Use of this source code is governed by a MIT-style
+// license that can be found in the LICENSE file.
+
+use crate::{
+    config::{ChapterBreaks, Leader},
+    font::TextFont,
+    merge::{add_standard_font, document_headings, media_box, Heading, DEFAULT_MEDIA_BOX},
+    text::one_line,
+    Config,
+};
+use anyhow::Result;
+use lopdf::{
+    content::{Content, Operation},
+    dictionary, Document, Object, ObjectId, Stream,
+};
+use std::collections::BTreeMap;
+
+// Margin around the table of contents, in points
+const MARGIN: f64 = 72.0;
+
+// An entry placed on a table of contents page
+struct Placed<'a> {
+    heading: &'a Heading,
+    x: f64,
+    // Top of the entry's first line
+    y: f64,
+    lines: Vec<String>,
+}
+
+// Headings down to `depth` in outline order
+fn listed<'a>(headings: &'a [Heading], depth: usize, out: &mut Vec<&'a Heading>) {
+    for heading in headings {
+        if heading.level <= depth {
+            out.push(heading);
+            listed(&heading.headings, depth, out);
+        }
+    }
+}
+
+// Shorten `text` with an ellipsis until it's no wider than `max_width`
+fn truncate(text: &str, max_width: f64, width: &impl Fn(&str) -> f64) -> String {
+    if width(text) <= max_width {
+        return text.to_string();
+    }
+    let mut truncated = text.to_string();
+    while !truncated.is_empty() && width(&format!("{truncated}...")) > max_width {
+        truncated.pop();
+    }
+    format!("{}...", truncated.trim_end())
+}
+
+// Break `text` into lines no wider than `max_width`, truncating words too long for a line of their own
+fn wrap(text: &str, max_width: f64, width: impl Fn(&str) -> f64) -> Vec<String> {
+    let mut lines = vec![];
+    let mut line = String::new();
+    for word in text.split(' ') {
+        let joined = if line.is_empty() {
+            word.to_string()
+        } else {
+            format!("{line} {word}")
+        };
+        if width(&joined) <= max_width {
+            line = joined;
+            continue;
+        }
+        if !line.is_empty() {
+            lines.push(line);
+        }
+        line = truncate(word, max_width, &width);
+    }
+    lines.push(line);
+    lines
+}
+
+// Show `text` in `font` with its baseline starting at `x`, `y`
+fn show(ops: &mut Vec<Operation>, font: &TextFont, text: &str, x: f64, y: f64) {
+    ops.push(Operation::new(
+        "Tm",
+        vec![1.into(), 0.into(), 0.into(), 1.into(), x.into(), y.into()],
+    ));
+    ops.push(Operation::new("Tj", vec![font.encode(text)]));
+}
+
+/// Insert pages listing the merged outline after the cover of `doc`, returning how many were
+/// inserted and the blank page keeping chapters on odd pages, if one was added.
+///
+/// Each entry links to its bookmark's destination and shows the page number it ends up on,
+/// counted from the page after the cover and labelled like the printed page numbers.
+pub(crate) fn insert_toc(
+    doc: &mut Document,
+    conf: &Config,
+    cover_pages: usize,
+) -> Result<(usize, Option<ObjectId>)> {
+    let Some(toc) = &conf.toc else {
+        return Ok((0, None));
+    };
+    let headings = document_headings(doc)?;
+    let mut entries = vec![];
+    listed(&headings, toc.depth, &mut entries);
+    if entries.is_empty() {
+        conf.progress
+            .println("The merged PDF has no bookmarks, not adding a table of contents");
+        return Ok((0, None));
+    }
+
+    let font = add_standard_font(doc, conf, &toc.font, "Table of contents")?;
+    let width = |s: &str, size: f64| font.width(s, size);
+
+    let media_box = doc
+        .get_pages()
+        .into_values()
+        .next()
+        .map_or(DEFAULT_MEDIA_BOX, |id| media_box(doc, id));
+    let [x0, y0, x1, y1] = media_box;
+    let (left, right, top, bottom) = (x0 + MARGIN, x1 - MARGIN, y1 - MARGIN, y0 + MARGIN);
+    let size = toc.size;
+    let title_size = size * 1.5;
+    let line_height = size * 1.5;
+    let gap = size;
+    // Room for the widest page number, every entry starting a page of its own is an upper bound
+    let digits = (doc.get_pages().len() + entries.len()).to_string().len();
+    let number_width = width(&"0".repeat(digits), size);
+
+    // Lay out the entries, starting a new page when the next one doesn't fit
+    let mut pages: Vec<Vec<Placed>> = vec![vec![]];
+    let mut y = top - title_size * 2.0;
+    for heading in entries {
+        let x = left + (heading.level - 1) as f64 * line_height;
+        let lines = wrap(
+            &one_line(&heading.title),
+            right - number_width - gap - x,
+            |s| width(s, size),
+        );
+        let height = lines.len() as f64 * line_height;
+        if y - height < bottom && !pages.last().unwrap().is_empty() {
+            pages.push(vec![]);
+            y = top;
+        }
+        pages.last_mut().unwrap().push(Placed {
+            heading,
+            x,
+            y,
+            lines,
+        });
+        y -= height;
+    }
+    // A blank page keeps the chapters after it on odd pages
+    let padded = conf.chapter_breaks == ChapterBreaks::Odd && pages.len() % 2 == 1;
+    if padded {
+        pages.push(vec![]);
+    }
+
+    // Insert the pages first so the page numbers shown account for them
+    let pages_id = doc.catalog()?.get(b"Pages")?.as_reference()?;
+    let page_ids: Vec<ObjectId> = pages
+        .iter()
+        .map(|_| {
+            doc.add_object(dictionary! {
+                "Type" => "Page",
+                "Parent" => pages_id,
+                "MediaBox" => media_box.iter().map(|v| Object::from(*v)).collect::<Vec<_>>(),
+                "Resources" => dictionary! {
+                    "Font" => dictionary! {
+                        "F1" => font.id,
+                    },
+                },
+            })
+        })
+        .collect();
+    let pages_dict = doc.get_dictionary_mut(pages_id)?;
+    let count = pages_dict.get(b"Count")?.as_i64()?;
+    pages_dict.set("Count", count + page_ids.len() as i64);
+    let kids = pages_dict.get_mut(b"Kids")?.as_array_mut()?;
+    for (i, page_id) in page_ids.iter().enumerate() {
+        kids.insert(cover_pages + i, Object::Reference(*page_id));
+    }
+    let page_id_to_num: BTreeMap<ObjectId, u32> =
+        doc.get_pages().into_iter().map(|(n, id)| (id, n)).collect();
+
+    for (i, (page_id, placed)) in page_ids.iter().zip(pages).enumerate() {
+        let mut ops = vec![Operation::new("BT", vec![])];
+        if i == 0 {
+            ops.push(Operation::new("Tf", vec!["F1".into(), title_size.into()]));
+            let title = truncate(&one_line(&toc.title), right - left, &|s: &str| {
+                width(s, title_size)
+            });
+            show(&mut ops, &font, &title, left, top - title_size);
+        }
+        ops.push(Operation::new("Tf", vec!["F1".into(), size.into()]));
+
+        let mut annots = vec![];
+        for entry in placed {
+            let mut baseline = entry.y - size;
+            for line in &entry.lines {
+                show(&mut ops, &font, line, entry.x, baseline);
+                baseline -= line_height;
+            }
+            baseline += line_height;
+
+            let number = entry
+                .heading
+                .target
+                .first()
+                .and_then(|p| p.as_reference().ok())
+                .and_then(|id| page_id_to_num.get(&id))
+                .and_then(|n| {
+                    let n = *n as usize - cover_pages;
+                    match &conf.page_number {
+                        Some(style) => style.label(n),
+                        None => Some(n.to_string()),
+                    }
+                })
+                .unwrap_or_default();
+            let number_x = right - width(&number, size);
+            show(&mut ops, &font, &number, number_x, baseline);
+
+            let leader = match toc.leader {
+                Leader::Dots => Some("."),
+                Leader::Dashes => Some("-"),
+                Leader::None => None,
+            };
+            if let Some(leader) = leader {
+                let start = entry.x + width(entry.lines.last().unwrap(), size) + gap / 2.0;
+                let end = number_x - gap / 2.0;
+                let count = ((end - start) / width(leader, size)).floor();
+                if count >= 1.0 {
+                    let leaders = leader.repeat(count as usize);
+                    show(
+                        &mut ops,
+                        &font,
+                        &leaders,
+                        end - width(&leaders, size),
+                        baseline,
+                    );
+                }
+            }
+
+            let height = entry.lines.len() as f64 * line_height;
+            annots.push(Object::Reference(doc.add_object(dictionary! {
+                "Type" => "Annot",
+                "Subtype" => "Link",
+                "Rect" => vec![entry.x.into(), (entry.y - height).into(), right.into(), entry.y.into()],
+                "Border" => vec![0.into(), 0.into(), 0.into()],
+                "Dest" => entry.heading.target.clone(),
+            })));
+        }
+        ops.push(Operation::new("ET", vec![]));
+
+        let content_id = doc.add_object(Stream::new(
+            dictionary! {},
+            Content { operations: ops }.encode()?,
+        ));
+        let page = doc.get_dictionary_mut(*page_id)?;
+        page.set("Contents", content_id);
+        page.set("Annots", annots);
+    }
+
+    Ok((page_ids.len(), padded.then(|| *page_ids.last().unwrap())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wrap() {
+        // One unit per character
+        let width = |s: &str| s.len() as f64;
+        assert_eq!(
+            wrap("Getting started", 20.0, width),
+            vec!["Getting started"]
+        );
+        assert_eq!(
+            wrap("Configure the metadata server", 14.0, width),
+            vec!["Configure the", "metadata", "server"]
+        );
+        assert_eq!(
+            wrap("Supercalifragilistic words", 10.0, width),
+            vec!["Superca...", "words"]
+        );
+    }
+}
@@ -0,0 +1,725 @@
+// Copyright (c) 2024 DDN. All rights reserved.
+// Use of this source code is governed by a MIT-style
+// license that can be found in the LICENSE file.
+
+use crate::{
+    config::{Watermark, WatermarkPosition},
+    merge::{add_standard_font, media_box},
+    text::one_line,
+    Config,
+};
+use anyhow::{anyhow, Result};
+use lopdf::{
+    content::{Content, Operation},
+    dictionary, Dictionary, Document, Object, ObjectId, Stream, StringFormat,
+};
+use std::{collections::HashMap, fs, path::Path};
+
+// Distance of an image in a corner from the edges of the page
+const MARGIN: f64 = 36.0;
+
+// Height above the baseline `text` is centred on, as a fraction of the font size
+const TEXT_MIDDLE: f64 = 0.35;
+
+// An image XObject and its size in pixels
+struct Image {
+    id: ObjectId,
+    width: f64,
+    height: f64,
+}
+
+// Width and height of a baseline or progressive JPEG and its number of components
+fn jpeg_info(data: &[u8]) -> Result<(u32, u32, u8)> {
+    if !data.starts_with(&[0xFF, 0xD8]) {
+        return Err(anyhow!("not a JPEG"));
+    }
+    let mut i = 2;
+    while i + 4 <= data.len() {
+        if data[i] != 0xFF {
+            return Err(anyhow!("malformed JPEG marker"));
+        }
+        let marker = data[i + 1];
+        let len = u16::from_be_bytes([data[i + 2], data[i + 3]]) as usize;
+        // Start of frame, excluding the DHT, JPG and DAC markers in the same range
+        if (0xC0..=0xCF).contains(&marker) && ![0xC4, 0xC8, 0xCC].contains(&marker) {
+            let frame = data
+                .get(i + 4..i + 10)
+                .ok_or(anyhow!("truncated JPEG frame header"))?;
+            let height = u16::from_be_bytes([frame[1], frame[2]]) as u32;
+            let width = u16::from_be_bytes([frame[3], frame[4]]) as u32;
+            return Ok((width, height, frame[5]));
+        }
+        i += 2 + len;
+    }
+    Err(anyhow!("no JPEG frame header"))
+}
+
+// Undo the PNG filter of each row, `bpp` is the number of bytes per complete pixel
+fn unfilter(data: &[u8], stride: usize, bpp: usize) -> Result<Vec<u8>> {
+    let mut pixels: Vec<u8> = Vec::with_capacity(data.len());
+    for (row, line) in data.chunks(stride + 1).enumerate() {
+        if line.len() != stride + 1 {
+            return Err(anyhow!("truncated PNG image data"));
+        }
+        let start = row * stride;
+        for (i, byte) in line[1..].iter().enumerate() {
+            let a = match i >= bpp {
+                true => pixels[start + i - bpp],
+                false => 0,
+            };
+            let b = match row > 0 {
+                true => pixels[start + i - stride],
+                false => 0,
+            };
+            let c = match i >= bpp && row > 0 {
+                true => pixels[start + i - stride - bpp],
+                false => 0,
+            };
+            let predictor = match line[0] {
+                0 => 0,
+                1 => a,
+                2 => b,
+                3 => ((a as u16 + b as u16) / 2) as u8,
+                4 => {
+                    let p = a as i16 + b as i16 - c as i16;
+                    let (pa, pb, pc) = (
+                        (p - a as i16).abs(),
+                        (p - b as i16).abs(),
+                        (p - c as i16).abs(),
+                    );
+                    match (pa <= pb && pa <= pc, pb <= pc) {
+                        (true, _) => a,
+                        (false, true) => b,
+                        (false, false) => c,
+                    }
+                }
+                filter => return Err(anyhow!("unknown PNG filter {filter}")),
+            };
+            pixels.push(byte.wrapping_add(predictor));
+        }
+    }
+    Ok(pixels)
+}
+
+// Add `pixels` with `bits` per component as a Flate compressed image XObject
+fn add_image(
+    doc: &mut Document,
+    (width, height): (u32, u32),
+    color_space: Object,
+    bits: u8,
+    pixels: Vec<u8>,
+    smask: Option<ObjectId>,
+) -> Result<ObjectId> {
+    let mut dict = dictionary! {
+        "Type" => "XObject",
+        "Subtype" => "Image",
+        "Width" => width as i64,
+        "Height" => height as i64,
+        "ColorSpace" => color_space,
+        "BitsPerComponent" => bits as i64,
+    };
+    if let Some(smask) = smask {
+        dict.set("SMask", smask);
+    }
+    let mut stream = Stream::new(dict, pixels);
+    stream.compress()?;
+    Ok(doc.add_object(stream))
+}
+
+// Non-interlaced gray, RGB, their alpha variants at 8 bits and indexed PNGs
+fn add_png(doc: &mut Document, data: &[u8]) -> Result<Image> {
+    if !data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        return Err(anyhow!("not a PNG"));
+    }
+    let mut header = None;
+    let mut palette = None;
+    let mut idat = vec![];
+    let mut i = 8;
+    while i + 8 <= data.len() {
+        let len = u32::from_be_bytes(data[i..i + 4].try_into()?) as usize;
+        let chunk = data
+            .get(i + 8..i + 8 + len)
+            .ok_or(anyhow!("truncated PNG chunk"))?;
+        match &data[i + 4..i + 8] {
+            b"IHDR" if len == 13 => header = Some(chunk),
+            b"PLTE" => palette = Some(chunk),
+            b"IDAT" => idat.extend_from_slice(chunk),
+            b"IEND" => break,
+            _ => {}
+        }
+        // Length, type, data and CRC
+        i += 12 + len;
+    }
+    let header = header.ok_or(anyhow!("no PNG header"))?;
+    let width = u32::from_be_bytes(header[0..4].try_into()?);
+    let height = u32::from_be_bytes(header[4..8].try_into()?);
+    let (bits, color_type, interlace) = (header[8], header[9], header[12]);
+    if interlace != 0 {
+        return Err(anyhow!("interlaced PNGs aren't supported"));
+    }
+    let (channels, color_space): (usize, Object) = match (color_type, bits) {
+        (0, 8) | (4, 8) => (1, "DeviceGray".into()),
+        (2, 8) | (6, 8) => (3, "DeviceRGB".into()),
+        (3, 1 | 2 | 4 | 8) => {
+            let palette = palette.ok_or(anyhow!("indexed PNG without a palette"))?;
+            let colors = vec![
+                "Indexed".into(),
+                "DeviceRGB".into(),
+                (palette.len() as i64 / 3 - 1).into(),
+                Object::String(palette.to_vec(), StringFormat::Hexadecimal),
+            ];
+            (1, colors.into())
+        }
+        _ => {
+            return Err(anyhow!(
+                "PNG color type {color_type} at {bits} bits isn't supported, use 8 bit gray or RGB"
+            ))
+        }
+    };
+    let alpha = color_type == 4 || color_type == 6;
+
+    let inflated = Stream::new(dictionary! { "Filter" => "FlateDecode" }, idat)
+        .decompressed_content()
+        .map_err(|e| anyhow!("Unable to inflate PNG image data: {e}"))?;
+    let bits_per_pixel = (channels + alpha as usize) * bits as usize;
+    let stride = (width as usize * bits_per_pixel).div_ceil(8);
+    let pixels = unfilter(&inflated, stride, bits_per_pixel.div_ceil(8))?;
+
+    let size = (width, height);
+    let id = match alpha {
+        true => {
+            let (mut color, mut mask) = (vec![], vec![]);
+            for pixel in pixels.chunks_exact(channels + 1) {
+                color.extend_from_slice(&pixel[..channels]);
+                mask.push(pixel[channels]);
+            }
+            let smask = add_image(doc, size, "DeviceGray".into(), 8, mask, None)?;
+            add_image(doc, size, color_space, bits, color, Some(smask))?
+        }
+        false => add_image(doc, size, color_space, bits, pixels, None)?,
+    };
+    Ok(Image {
+        id,
+        width: width as f64,
+        height: height as f64,
+    })
+}
+
+// The JPEG or PNG at `path` as an image XObject
+fn add_image_file(doc: &mut Document, path: &Path) -> Result<Image> {
+    let data = fs::read(path)?;
+    if data.starts_with(&[0xFF, 0xD8]) {
+        let (width, height, components) = jpeg_info(&data)?;
+        let color_space = match components {
+            1 => "DeviceGray",
+            3 => "DeviceRGB",
+            _ => return Err(anyhow!("only gray and RGB JPEGs are supported")),
+        };
+        let mut stream = Stream::new(
+            dictionary! {
+                "Type" => "XObject",
+                "Subtype" => "Image",
+                "Width" => width as i64,
+                "Height" => height as i64,
+                "ColorSpace" => color_space,
+                "BitsPerComponent" => 8,
+                "Filter" => "DCTDecode",
+            },
+            data,
+        );
+        stream.allows_compression = false;
+        return Ok(Image {
+            id: doc.add_object(stream),
+            width: width as f64,
+            height: height as f64,
+        });
+    }
+    add_png(doc, &data)
+}
+
+// A resource name in the `category` dictionary of `resources` which isn't used yet
+pub(crate) fn resource_name(resources: &Dictionary, category: &[u8], prefix: &str) -> String {
+    let used = resources.get(category).and_then(Object::as_dict).ok();
+    (1..)
+        .map(|i| format!("{prefix}{i}"))
+        .find(|name| !used.is_some_and(|d| d.has(name.as_bytes())))
+        .unwrap()
+}
+
+// The resources of `page_id` as a dictionary of its own, so entries can be added for this page
+// alone. Shared, inherited and indirect resources are copied.
+pub(crate) fn own_resources(doc: &Document, page_id: ObjectId) -> Dictionary {
+    let mut node = doc.get_dictionary(page_id).ok();
+    while let Some(dict) = node {
+        if let Ok(resources) = dict.get(b"Resources") {
+            let mut resources = match doc.dereference(resources) {
+                Ok((_, Object::Dictionary(resources))) => resources.clone(),
+                _ => Dictionary::new(),
+            };
+            for (_, category) in resources.iter_mut() {
+                if let Ok((_, Object::Dictionary(dict))) = doc.dereference(category) {
+                    *category = Object::Dictionary(dict.clone());
+                }
+            }
+            return resources;
+        }
+        node = dict
+            .get(b"Parent")
+            .and_then(Object::as_reference)
+            .and_then(|id| doc.get_dictionary(id))
+            .ok();
+    }
+    Dictionary::new()
+}
+
+// Add `value` to the `category` dictionary of `resources` under `name`
+pub(crate) fn add_resource(
+    resources: &mut Dictionary,
+    category: &str,
+    name: &str,
+    value: ObjectId,
+) {
+    match resources.get_mut(category.as_bytes()) {
+        Ok(Object::Dictionary(dict)) => dict.set(name, value),
+        _ => resources.set(category, dictionary! { name => value }),
+    }
+}
+
+// Streams saving and restoring the graphics state, so what's drawn after a page's own content
+// isn't affected by the transformations Chrome leaves in place
+pub(crate) fn add_save_restore(doc: &mut Document) -> (ObjectId, ObjectId) {
+    let save_id = doc.add_object(Stream::new(dictionary! {}, b"q\n".to_vec()));
+    let restore_id = doc.add_object(Stream::new(dictionary! {}, b"\nQ\n".to_vec()));
+    (save_id, restore_id)
+}
+
+// Draw the content stream `stamp_id` over `page_id` in default user space, with `resources` as
+// the page's resources. The page's content is wrapped in the `save_restore` streams first.
+pub(crate) fn draw_over(
+    doc: &mut Document,
+    page_id: ObjectId,
+    (save_id, restore_id): (ObjectId, ObjectId),
+    resources: Dictionary,
+    stamp_id: ObjectId,
+) -> Result<()> {
+    let contents: Vec<Object> = doc
+        .get_page_contents(page_id)
+        .into_iter()
+        .map(Object::Reference)
+        .collect();
+    let contents = [
+        vec![save_id.into()],
+        contents,
+        vec![restore_id.into(), stamp_id.into()],
+    ]
+    .concat();
+    let page = doc.get_dictionary_mut(page_id)?;
+    page.set("Resources", resources);
+    page.set("Contents", contents);
+    Ok(())
+}
+
+// Operations drawing `text`, the encoded string `text_width` wide, or `image` on a page with
+// `media_box`, using the resource `names` of each category
+fn watermark_operations(
+    watermark: &Watermark,
+    media_box: [f64; 4],
+    names: &HashMap<&str, String>,
+    (text, text_width): (Option<&Object>, f64),
+    image: Option<&Image>,
+) -> Vec<Operation> {
+    let [x0, y0, x1, y1] = media_box;
+    let (page_width, page_height) = (x1 - x0, y1 - y0);
+    let mut ops = vec![
+        Operation::new("q", vec![]),
+        Operation::new("gs", vec![names["ExtGState"].as_str().into()]),
+    ];
+    match (text, image) {
+        (_, Some(image)) => {
+            let width = page_width * watermark.scale;
+            let height = width * image.height / image.width;
+            let (x, y) = match watermark.position {
+                WatermarkPosition::Center => {
+                    ((page_width - width) / 2.0, (page_height - height) / 2.0)
+                }
+                WatermarkPosition::TopLeft => (MARGIN, page_height - height - MARGIN),
+                WatermarkPosition::TopRight => {
+                    (page_width - width - MARGIN, page_height - height - MARGIN)
+                }
+                WatermarkPosition::BottomLeft => (MARGIN, MARGIN),
+                WatermarkPosition::BottomRight => (page_width - width - MARGIN, MARGIN),
+            };
+            ops.push(Operation::new(
+                "cm",
+                vec![
+                    width.into(),
+                    0.into(),
+                    0.into(),
+                    height.into(),
+                    (x0 + x).into(),
+                    (y0 + y).into(),
+                ],
+            ));
+            ops.push(Operation::new("Do", vec![names["XObject"].as_str().into()]));
+        }
+        (Some(text), None) => {
+            // Rotate about the centre of the page, the middle of the text goes there
+            let (sin, cos) = watermark.rotation.to_radians().sin_cos();
+            let (dx, dy) = (-text_width / 2.0, -watermark.size * TEXT_MIDDLE);
+            let x = x0 + page_width / 2.0 + dx * cos - dy * sin;
+            let y = y0 + page_height / 2.0 + dx * sin + dy * cos;
+            let color = &watermark.color;
+            ops.extend([
+                Operation::new("rg", vec![color.r.into(), color.g.into(), color.b.into()]),
+                Operation::new("BT", vec![]),
+                Operation::new(
+                    "Tf",
+                    vec![names["Font"].as_str().into(), watermark.size.into()],
+                ),
+                Operation::new(
+                    "Tm",
+                    vec![
+                        cos.into(),
+                        sin.into(),
+                        (-sin).into(),
+                        cos.into(),
+                        x.into(),
+                        y.into(),
+                    ],
+                ),
+                Operation::new("Tj", vec![text.clone()]),
+                Operation::new("ET", vec![]),
+            ]);
+        }
+        (None, None) => {}
+    }
+    ops.push(Operation::new("Q", vec![]));
+    ops
+}
+
+/// Stamp `conf.watermark`, if there is one, on every page of `doc`, centred or placed against
+/// each page's own MediaBox.
+///
+/// The page content is wrapped in `q` and `Q` so the transformations Chrome leaves in place
+/// don't apply to a watermark drawn over it.
+pub(crate) fn add_watermark(doc: &mut Document, conf: &Config) -> Result<()> {
+    let Some(watermark) = &conf.watermark else {
+        return Ok(());
+    };
+    let text = watermark.text.as_deref().map(one_line);
+
+    let gs_id = doc.add_object(dictionary! {
+        "Type" => "ExtGState",
+        "ca" => watermark.opacity,
+        "CA" => watermark.opacity,
+    });
+    let (font, image, text_width) = match (&text, &watermark.image) {
+        (_, Some(path)) => {
+            let image = add_image_file(doc, path)
+                .map_err(|e| anyhow!("Unable to load watermark.image {}: {e}", path.display()))?;
+            (None, Some(image), 0.0)
+        }
+        (Some(text), None) => {
+            let font = add_standard_font(doc, conf, &watermark.font, "Watermark")?;
+            let width = font.width(text, watermark.size);
+            (Some((font.id, font.encode(text))), None, width)
+        }
+        (None, None) => return Ok(()),
+    };
+    let save_restore = add_save_restore(doc);
+
+    let page_ids: Vec<ObjectId> = doc.page_iter().collect();
+    for page_id in page_ids {
+        let mut resources = own_resources(doc, page_id);
+        let mut names = HashMap::new();
+        let mut resource = |category: &'static str, prefix: &str, id: ObjectId| {
+            let name = resource_name(&resources, category.as_bytes(), prefix);
+            add_resource(&mut resources, category, &name, id);
+            names.insert(category, name);
+        };
+        resource("ExtGState", "Wm", gs_id);
+        if let Some((font_id, _)) = &font {
+            resource("Font", "Wm", *font_id);
+        }
+        if let Some(image) = &image {
+            resource("XObject", "Wm", image.id);
+        }
+
+        let ops = watermark_operations(
+            watermark,
+            media_box(doc, page_id),
+            &names,
+            (font.as_ref().map(|(_, shown)| shown), text_width),
+            image.as_ref(),
+        );
+        let stamp_id = doc.add_object(Stream::new(
+            dictionary! {},
+            Content { operations: ops }.encode()?,
+        ));
+
+        if !watermark.under {
+            draw_over(doc, page_id, save_restore, resources, stamp_id)?;
+            continue;
+        }
+        // Under the page content, which hasn't changed the graphics state yet
+        let contents: Vec<Object> = [stamp_id]
+            .into_iter()
+            .chain(doc.get_page_contents(page_id))
+            .map(Object::Reference)
+            .collect();
+        let page = doc.get_dictionary_mut(page_id)?;
+        page.set("Resources", resources);
+        page.set("Contents", contents);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::font::standard_advance;
+
+    // A page of `width` by `height` points drawing a rectangle
+    fn add_page(doc: &mut Document, pages_id: ObjectId, width: i64, height: i64) -> ObjectId {
+        let content_id = doc.add_object(Stream::new(
+            dictionary! {},
+            b"0.24 0 0 -0.24 0 792 cm 0 0 100 100 re f".to_vec(),
+        ));
+        doc.add_object(dictionary! {
+            "Type" => "Page",
+            "Parent" => pages_id,
+            "MediaBox" => vec![0.into(), 0.into(), width.into(), height.into()],
+            "Contents" => content_id,
+        })
+    }
+
+    // A letter and an A3 landscape page
+    fn document() -> Document {
+        let mut doc = Document::with_version("1.7");
+        let pages_id = doc.new_object_id();
+        let kids = vec![
+            add_page(&mut doc, pages_id, 612, 792).into(),
+            add_page(&mut doc, pages_id, 1191, 842).into(),
+        ];
+        doc.objects.insert(
+            pages_id,
+            Object::Dictionary(dictionary! {
+                "Type" => "Pages",
+                "Kids" => kids,
+                "Count" => 2,
+            }),
+        );
+        let catalog_id = doc.add_object(dictionary! {
+            "Type" => "Catalog",
+            "Pages" => pages_id,
+        });
+        doc.trailer.set("Root", catalog_id);
+        doc
+    }
+
+    fn watermark(toml: &str) -> Config {
+        Config {
+            watermark: Some(toml::from_str(toml).unwrap()),
+            ..Default::default()
+        }
+    }
+
+    // Stream contents, which are only compressed when that makes them smaller
+    fn content(stream: &Stream) -> Vec<u8> {
+        match stream.dict.has(b"Filter") {
+            true => stream.decompressed_content().unwrap(),
+            false => stream.content.clone(),
+        }
+    }
+
+    // The operations of each content stream of `page_id`
+    fn page_contents(doc: &Document, page_id: ObjectId) -> Vec<Vec<Operation>> {
+        doc.get_page_contents(page_id)
+            .into_iter()
+            .map(|id| {
+                let stream = doc.get_object(id).unwrap().as_stream().unwrap();
+                Content::decode(&stream.content).unwrap().operations
+            })
+            .collect()
+    }
+
+    fn operands(ops: &[Operation], operator: &str) -> Vec<f64> {
+        ops.iter()
+            .find(|op| op.operator == operator)
+            .unwrap()
+            .operands
+            .iter()
+            .map(|o| o.as_float().unwrap() as f64)
+            .collect()
+    }
+
+    #[test]
+    fn test_text_watermark() {
+        let mut doc = document();
+        let conf = watermark("text = 'DRAFT'\nrotation = 0.0\nsize = 50.0");
+        add_watermark(&mut doc, &conf).unwrap();
+
+        // Each page's watermark is centred on it, drawn over the page content
+        let width = "DRAFT"
+            .chars()
+            .map(|c| standard_advance("Helvetica", c))
+            .sum::<f64>()
+            * 50.0
+            / 1000.0;
+        for (page_id, (page_width, page_height)) in
+            doc.page_iter().zip([(612.0, 792.0), (1191.0, 842.0)])
+        {
+            let contents = page_contents(&doc, page_id);
+            assert_eq!(contents.len(), 4);
+            assert_eq!(contents[0][0].operator, "q");
+            assert_eq!(contents[2][0].operator, "Q");
+            let tm = operands(&contents[3], "Tm");
+            assert!((tm[4] - (page_width - width) / 2.0).abs() < 0.01, "{tm:?}");
+            assert!((tm[5] - (page_height / 2.0 - 50.0 * TEXT_MIDDLE)).abs() < 0.01);
+
+            let resources = doc
+                .get_dictionary(page_id)
+                .unwrap()
+                .get(b"Resources")
+                .unwrap();
+            let gs = resources.as_dict().unwrap().get(b"ExtGState").unwrap();
+            let gs_id = gs
+                .as_dict()
+                .unwrap()
+                .get(b"Wm1")
+                .unwrap()
+                .as_reference()
+                .unwrap();
+            let opacity = doc.get_dictionary(gs_id).unwrap().get(b"ca").unwrap();
+            assert!((opacity.as_float().unwrap() - 0.3).abs() < 0.001);
+        }
+    }
+
+    #[test]
+    fn test_text_watermark_win_ansi() {
+        let mut doc = document();
+        let conf = watermark("text = 'DRAFT — NOT FOR DISTRIBUTION'");
+        add_watermark(&mut doc, &conf).unwrap();
+
+        let page_id = doc.page_iter().next().unwrap();
+        let contents = page_contents(&doc, page_id);
+        let tj = contents[3].iter().find(|op| op.operator == "Tj").unwrap();
+        assert_eq!(
+            tj.operands[0].as_str().unwrap(),
+            b"DRAFT \x97 NOT FOR DISTRIBUTION"
+        );
+    }
+
+    // A PNG of `pixels` with an uncompressed zlib stream
+    fn png(width: u32, height: u32, color_type: u8, channels: usize, pixels: &[u8]) -> Vec<u8> {
+        let mut raw = vec![];
+        for row in pixels.chunks(width as usize * channels) {
+            // Sub filtered
+            raw.push(1);
+            raw.extend(
+                row.iter().enumerate().map(|(i, p)| {
+                    p.wrapping_sub(if i >= channels { row[i - channels] } else { 0 })
+                }),
+            );
+        }
+        let mut zlib = vec![0x78, 0x01, 0x01];
+        zlib.extend_from_slice(&(raw.len() as u16).to_le_bytes());
+        zlib.extend_from_slice(&(!(raw.len() as u16)).to_le_bytes());
+        zlib.extend_from_slice(&raw);
+        let (a, b) = raw.iter().fold((1u32, 0u32), |(a, b), byte| {
+            let a = (a + *byte as u32) % 65521;
+            (a, (b + a) % 65521)
+        });
+        zlib.extend_from_slice(&((b << 16) | a).to_be_bytes());
+
+        let mut data = b"\x89PNG\r\n\x1a\n".to_vec();
+        let mut chunk = |kind: &[u8], body: &[u8]| {
+            data.extend_from_slice(&(body.len() as u32).to_be_bytes());
+            data.extend_from_slice(kind);
+            data.extend_from_slice(body);
+            // CRCs aren't checked
+            data.extend_from_slice(&[0; 4]);
+        };
+        let mut header = vec![];
+        header.extend_from_slice(&width.to_be_bytes());
+        header.extend_from_slice(&height.to_be_bytes());
+        header.extend_from_slice(&[8, color_type, 0, 0, 0]);
+        chunk(b"IHDR", &header);
+        chunk(b"IDAT", &zlib);
+        chunk(b"IEND", &[]);
+        data
+    }
+
+    #[test]
+    fn test_png() {
+        let mut doc = Document::with_version("1.7");
+        let pixels: Vec<u8> = (0..4 * 2 * 4).map(|i| (i * 7) as u8).collect();
+        let image = add_png(&mut doc, &png(4, 2, 6, 4, &pixels)).unwrap();
+        assert_eq!((image.width, image.height), (4.0, 2.0));
+
+        let stream = doc.get_object(image.id).unwrap().as_stream().unwrap();
+        let color: Vec<u8> = pixels.chunks(4).flat_map(|p| p[..3].to_vec()).collect();
+        assert_eq!(content(stream), color);
+        let smask_id = stream.dict.get(b"SMask").unwrap().as_reference().unwrap();
+        let smask = doc.get_object(smask_id).unwrap().as_stream().unwrap();
+        let alpha: Vec<u8> = pixels.chunks(4).map(|p| p[3]).collect();
+        assert_eq!(content(smask), alpha);
+
+        assert!(add_png(&mut doc, b"GIF89a").is_err());
+    }
+
+    #[test]
+    fn test_unfilter() {
+        // Up, Average and Paeth rows of a 2 pixel gray image
+        let data = [0, 10, 20, 2, 1, 1, 3, 5, 5, 4, 1, 1];
+        assert_eq!(
+            unfilter(&data, 2, 1).unwrap(),
+            [10, 20, 11, 21, 10, 20, 11, 21]
+        );
+        assert!(unfilter(&[5, 0, 0], 2, 1).is_err());
+    }
+
+    #[test]
+    fn test_image_watermark() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("logo.jpg");
+        let mut jpeg = vec![];
+        jpeg_encoder::Encoder::new(&mut jpeg, 80)
+            .encode(&[200; 40 * 20 * 3], 40, 20, jpeg_encoder::ColorType::Rgb)
+            .unwrap();
+        fs::write(&path, &jpeg).unwrap();
+
+        let mut doc = document();
+        let conf = watermark(&format!(
+            "image = {path:?}\nscale = 0.25\nposition = 'bottom-right'\nunder = true"
+        ));
+        add_watermark(&mut doc, &conf).unwrap();
+
+        for (page_id, page_width) in doc.page_iter().zip([612.0, 1191.0]) {
+            // Drawn first, a quarter of the page wide in its bottom right corner
+            let contents = page_contents(&doc, page_id);
+            assert_eq!(contents.len(), 2);
+            let cm = operands(&contents[0], "cm");
+            let width = page_width / 4.0;
+            let expected = [
+                width,
+                0.0,
+                0.0,
+                width / 2.0,
+                page_width - width - MARGIN,
+                MARGIN,
+            ];
+            for (a, e) in cm.iter().zip(expected) {
+                assert!((a - e).abs() < 0.01, "{cm:?}");
+            }
+        }
+        let image = doc
+            .objects
+            .values()
+            .filter_map(|o| o.as_stream().ok())
+            .find(|s| s.dict.get(b"Filter").and_then(Object::as_name_str).ok() == Some("DCTDecode"))
+            .unwrap();
+        assert_eq!(image.content, jpeg);
+        assert_eq!(image.dict.get(b"Width").unwrap().as_i64().unwrap(), 40);
+    }
+}